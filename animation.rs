@@ -0,0 +1,388 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+use serde::Deserialize;
+
+use crate::atlas::AtlasRegion;
+use crate::vfs;
+
+// SPRITE SHEET ANIMATION
+// A sprite sheet is one image with every frame of a character in it, plus a JSON file
+// (Aseprite: File -> Export Sprite Sheet, "Array" or "Hash" frames, tags on) that says
+// where the frames are, how long each one is shown, and which frames form which animation.
+// The sheet image itself goes into an atlas like any other image (AtlasBuilder::add_sprite_sheet),
+// the frame rects are turned into uvs inside its region.
+
+#[derive(Deserialize)]
+struct AsepriteRect {
+    x: u32,
+    y: u32,
+    w: u32,
+    h: u32,
+}
+
+#[derive(Deserialize)]
+struct AsepriteFrame {
+    frame: AsepriteRect,
+    // milliseconds
+    duration: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AsepriteFrames {
+    Array(Vec<AsepriteFrame>),
+    // keys are "name 0.aseprite", "name 1.aseprite" ...
+    Hash(serde_json::Map<String, serde_json::Value>),
+}
+
+#[derive(Deserialize)]
+struct AsepriteTag {
+    name: String,
+    from: usize,
+    to: usize,
+    #[serde(default)]
+    direction: String,
+}
+
+#[derive(Deserialize, Default)]
+struct AsepriteMeta {
+    // the sheet image, relative to the json
+    #[serde(default)]
+    image: Option<String>,
+    #[serde(default, rename = "frameTags")]
+    frame_tags: Vec<AsepriteTag>,
+}
+
+#[derive(Deserialize)]
+struct AsepriteSheet {
+    frames: AsepriteFrames,
+    #[serde(default)]
+    meta: AsepriteMeta,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+    // forward then back, the end frames are not repeated
+    PingPong,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct SheetFrame {
+    // x, y, width, height in pixels of the sheet image
+    pub rect: [u32; 4],
+    // seconds
+    pub duration: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct SheetTag {
+    pub from: usize,
+    pub to: usize,
+    pub direction: Direction,
+}
+
+pub struct SpriteSheet {
+    pub frames: Vec<SheetFrame>,
+    pub tags: HashMap<String, SheetTag>,
+    // file name of the sheet image, next to the json
+    pub image: Option<String>,
+}
+
+impl SpriteSheet {
+    pub fn from_aseprite_json(bytes: &[u8]) -> Result<Self> {
+        let sheet: AsepriteSheet = serde_json::from_slice(bytes).context("aseprite sprite sheet")?;
+
+        let frames = match sheet.frames {
+            AsepriteFrames::Array(frames) => frames,
+            AsepriteFrames::Hash(map) => {
+                // the map does not keep the file order, the frame number in the key does
+                let mut entries: Vec<(String, serde_json::Value)> = map.into_iter().collect();
+                entries.sort_by_key(|(name, _)| frame_number(name));
+                entries
+                    .into_iter()
+                    .map(|(name, value)| serde_json::from_value(value).with_context(|| format!("frame '{name}'")))
+                    .collect::<Result<Vec<_>>>()?
+            }
+        };
+        let frames: Vec<SheetFrame> = frames
+            .into_iter()
+            .map(|f| SheetFrame {
+                rect: [f.frame.x, f.frame.y, f.frame.w, f.frame.h],
+                duration: f.duration as f32 / 1000.0,
+            })
+            .collect();
+
+        let mut tags = HashMap::new();
+        for tag in sheet.meta.frame_tags {
+            if tag.from > tag.to || tag.to >= frames.len() {
+                bail!("tag '{}' uses frames {}..={}, the sheet has {}", tag.name, tag.from, tag.to, frames.len());
+            }
+            let direction = match tag.direction.as_str() {
+                "reverse" => Direction::Reverse,
+                "pingpong" => Direction::PingPong,
+                _ => Direction::Forward,
+            };
+            tags.insert(tag.name, SheetTag { from: tag.from, to: tag.to, direction });
+        }
+
+        Ok(Self { frames, tags, image: sheet.meta.image })
+    }
+
+    // The json and the image it names, both through the vfs.
+    pub fn load(json_path: &str) -> Result<(Self, image::RgbaImage)> {
+        let sheet = Self::from_aseprite_json(&vfs::read(json_path)?).with_context(|| json_path.to_string())?;
+        let name = sheet.image.as_deref().with_context(|| format!("{json_path}: no meta.image"))?;
+        // next to the json, under the same mount prefix
+        let dir = json_path.rfind(['/', ':']).map_or("", |i| &json_path[..=i]);
+        let image_path = format!("{dir}{name}");
+        let image = image::load_from_memory(&vfs::read(&image_path)?).with_context(|| image_path.clone())?.to_rgba8();
+
+        for (i, frame) in sheet.frames.iter().enumerate() {
+            let [x, y, w, h] = frame.rect;
+            ensure!(
+                x + w <= image.width() && y + h <= image.height(),
+                "{json_path}: frame {i} is outside the {}x{} image",
+                image.width(),
+                image.height()
+            );
+        }
+        Ok((sheet, image))
+    }
+
+    // `region` is where the sheet image was packed, None for the whole sheet as one animation
+    pub fn animation(&self, tag: Option<&str>, region: &AtlasRegion) -> Result<Animation> {
+        let (from, to, direction) = match tag {
+            Some(name) => {
+                let tag = self.tags.get(name).with_context(|| format!("no animation tag '{name}'"))?;
+                (tag.from, tag.to, tag.direction)
+            }
+            None => (0, self.frames.len().saturating_sub(1), Direction::Forward),
+        };
+
+        let mut indices: Vec<usize> = (from..=to).collect();
+        match direction {
+            Direction::Forward => {}
+            Direction::Reverse => indices.reverse(),
+            Direction::PingPong => {
+                let back: Vec<usize> = indices.iter().rev().skip(1).take(indices.len().saturating_sub(2)).copied().collect();
+                indices.extend(back);
+            }
+        }
+
+        let frames = indices
+            .into_iter()
+            .filter_map(|i| self.frames.get(i))
+            .map(|frame| {
+                let [x, y, w, h] = frame.rect;
+                AnimationFrame {
+                    uv_rect: region.sub_uv_rect(x, y, w, h),
+                    size: [w as f32, h as f32],
+                    duration: frame.duration,
+                }
+            })
+            .collect::<Vec<_>>();
+        if frames.is_empty() {
+            bail!("animation without frames");
+        }
+
+        Ok(Animation { frames, looping: true })
+    }
+}
+
+// last number in a frame key: "walk 12.aseprite" -> 12
+fn frame_number(name: &str) -> u32 {
+    let digits: String = name
+        .chars()
+        .rev()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit())
+        .collect();
+    digits.chars().rev().collect::<String>().parse().unwrap_or(0)
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationFrame {
+    pub uv_rect: [f32; 4],
+    // in pixels, a good default for Sprite::scale
+    pub size: [f32; 2],
+    pub duration: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    pub looping: bool,
+}
+
+impl Animation {
+    // every region shown for `duration` seconds, for animations that are separate images in an atlas
+    pub fn from_regions(regions: &[AtlasRegion], duration: f32) -> Self {
+        Self {
+            frames: regions
+                .iter()
+                .map(|region| AnimationFrame {
+                    uv_rect: region.uv_rect,
+                    size: region.size(),
+                    duration,
+                })
+                .collect(),
+            looping: true,
+        }
+    }
+
+    pub fn total_duration(&self) -> f32 {
+        self.frames.iter().map(|frame| frame.duration).sum()
+    }
+}
+
+// Playback state, one per animated sprite. The animation itself can be shared.
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationPlayer {
+    pub time: f32,
+    pub speed: f32,
+    pub playing: bool,
+}
+
+impl AnimationPlayer {
+    pub fn new() -> Self {
+        Self { time: 0.0, speed: 1.0, playing: true }
+    }
+
+    pub fn update(&mut self, animation: &Animation, dt: f32) {
+        if !self.playing {
+            return;
+        }
+        self.time += dt * self.speed;
+
+        let total = animation.total_duration();
+        if total <= 0.0 {
+            return;
+        }
+        if animation.looping {
+            self.time = self.time.rem_euclid(total);
+        } else if self.time >= total {
+            self.time = total;
+            self.playing = false;
+        }
+    }
+
+    pub fn frame<'a>(&self, animation: &'a Animation) -> &'a AnimationFrame {
+        let mut time = self.time;
+        for frame in &animation.frames {
+            if time < frame.duration {
+                return frame;
+            }
+            time -= frame.duration;
+        }
+        animation.frames.last().expect("animation without frames")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // trimmed Aseprite exports of a 4 frame, 32x16 sheet
+    const ARRAY_JSON: &str = r#"{
+        "frames": [
+            { "filename": "walk 0.aseprite", "frame": { "x": 0, "y": 0, "w": 8, "h": 16 }, "duration": 100 },
+            { "filename": "walk 1.aseprite", "frame": { "x": 8, "y": 0, "w": 8, "h": 16 }, "duration": 100 },
+            { "filename": "walk 2.aseprite", "frame": { "x": 16, "y": 0, "w": 8, "h": 16 }, "duration": 150 },
+            { "filename": "walk 3.aseprite", "frame": { "x": 24, "y": 0, "w": 8, "h": 16 }, "duration": 200 }
+        ],
+        "meta": {
+            "app": "https://www.aseprite.org/",
+            "size": { "w": 32, "h": 16 },
+            "frameTags": [
+                { "name": "walk", "from": 0, "to": 3, "direction": "pingpong" },
+                { "name": "back", "from": 1, "to": 2, "direction": "reverse" }
+            ]
+        }
+    }"#;
+
+    // keys out of order on purpose, "walk 10" must not sort before "walk 2"
+    const HASH_JSON: &str = r#"{
+        "frames": {
+            "walk 10.aseprite": { "frame": { "x": 30, "y": 0, "w": 3, "h": 4 }, "duration": 50 },
+            "walk 2.aseprite": { "frame": { "x": 6, "y": 0, "w": 3, "h": 4 }, "duration": 50 },
+            "walk 0.aseprite": { "frame": { "x": 0, "y": 0, "w": 3, "h": 4 }, "duration": 50 }
+        },
+        "meta": { "size": { "w": 33, "h": 4 } }
+    }"#;
+
+    fn whole_sheet(width: u32, height: u32) -> AtlasRegion {
+        AtlasRegion { uv_rect: [0.0, 0.0, 1.0, 1.0], rect: [0, 0, width, height] }
+    }
+
+    #[test]
+    fn array_frames_and_tags() {
+        let sheet = SpriteSheet::from_aseprite_json(ARRAY_JSON.as_bytes()).unwrap();
+        assert_eq!(sheet.frames.len(), 4);
+        assert_eq!(sheet.frames[2].rect, [16, 0, 8, 16]);
+        assert_eq!(sheet.frames[2].duration, 0.15);
+        assert_eq!(sheet.tags["walk"].direction, Direction::PingPong);
+        assert_eq!(sheet.tags["back"].direction, Direction::Reverse);
+    }
+
+    #[test]
+    fn hash_frames_follow_the_frame_number() {
+        let sheet = SpriteSheet::from_aseprite_json(HASH_JSON.as_bytes()).unwrap();
+        let x: Vec<u32> = sheet.frames.iter().map(|frame| frame.rect[0]).collect();
+        assert_eq!(x, [0, 6, 30]);
+        assert!(sheet.tags.is_empty());
+    }
+
+    #[test]
+    fn tag_directions() {
+        let sheet = SpriteSheet::from_aseprite_json(ARRAY_JSON.as_bytes()).unwrap();
+        let region = whole_sheet(32, 16);
+        let u = |animation: Animation| animation.frames.iter().map(|frame| frame.uv_rect[0] * 4.0).collect::<Vec<_>>();
+
+        // 0 1 2 3 2 1, then it loops back to 0
+        assert_eq!(u(sheet.animation(Some("walk"), &region).unwrap()), [0.0, 1.0, 2.0, 3.0, 2.0, 1.0]);
+        assert_eq!(u(sheet.animation(Some("back"), &region).unwrap()), [2.0, 1.0]);
+        assert_eq!(u(sheet.animation(None, &region).unwrap()), [0.0, 1.0, 2.0, 3.0]);
+        assert!(sheet.animation(Some("run"), &region).is_err());
+    }
+
+    #[test]
+    fn frames_inside_a_packed_region() {
+        let sheet = SpriteSheet::from_aseprite_json(ARRAY_JSON.as_bytes()).unwrap();
+        // the sheet packed into the right half of a 64x16 atlas
+        let region = AtlasRegion { uv_rect: [0.5, 0.0, 1.0, 1.0], rect: [32, 0, 32, 16] };
+        let animation = sheet.animation(None, &region).unwrap();
+        assert_eq!(animation.frames[1].uv_rect, [0.625, 0.0, 0.75, 1.0]);
+        assert_eq!(animation.frames[1].size, [8.0, 16.0]);
+        assert!((animation.total_duration() - 0.55).abs() < 1e-6);
+    }
+
+    #[test]
+    fn loads_the_json_and_its_image_through_the_vfs() {
+        let png = |width, height| {
+            let mut bytes = Vec::new();
+            image::RgbaImage::new(width, height)
+                .write_to(&mut std::io::Cursor::new(&mut bytes), image::ImageFormat::Png)
+                .unwrap();
+            &*Vec::leak(bytes)
+        };
+        let json = &*String::leak(ARRAY_JSON.replace(r#""app""#, r#""image": "walk.png", "app""#));
+        vfs::mount("animation_test:", vfs::Embedded::new(&[("sprites/walk.json", json.as_bytes()), ("sprites/walk.png", png(32, 16))]));
+        vfs::mount("animation_test_small:", vfs::Embedded::new(&[("walk.json", json.as_bytes()), ("walk.png", png(16, 16))]));
+
+        let (sheet, image) = SpriteSheet::load("animation_test:sprites/walk.json").unwrap();
+        assert_eq!(sheet.frames.len(), 4);
+        assert_eq!(image.dimensions(), (32, 16));
+        // frames 2 and 3 are past the edge of a 16 pixel wide image
+        assert!(SpriteSheet::load("animation_test_small:walk.json").is_err());
+        assert!(SpriteSheet::load("animation_test:sprites/missing.json").is_err());
+    }
+
+    #[test]
+    fn rejects_tags_past_the_last_frame() {
+        let json = ARRAY_JSON.replace(r#""from": 1, "to": 2"#, r#""from": 1, "to": 4"#);
+        assert!(SpriteSheet::from_aseprite_json(json.as_bytes()).is_err());
+    }
+}
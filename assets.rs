@@ -0,0 +1,551 @@
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use anyhow::*;
+
+use crate::audio::Clip;
+use crate::compressed::{self, TextureData};
+use crate::cooked::MeshFile;
+use crate::mesh::Mesh;
+use crate::texture::Texture;
+#[cfg(not(target_arch = "wasm32"))]
+use crate::watcher::FileWatcher;
+use crate::Vertex;
+
+// ASSET SERVER
+// load::<T>(path) returns a Handle<T> right away, the file is read and decoded on a loader thread
+// (Asset::load), update() then turns the decoded data into the gpu resource on the main thread
+// (Asset::upload). The same path gives the same asset, as long as a handle to it is alive:
+// handles are reference counted, update() drops assets nobody holds anymore.
+// Files are read through the virtual filesystem (vfs.rs), "builtin:" paths are compiled into the engine.
+// On the web there are no threads, there load() decodes right away.
+//
+// HOT RELOAD
+// Files behind loaded assets are watched (watcher.rs). When one changes on disk update() loads it
+// again, the old value stays in use until the new one is uploaded and then replaces it under the
+// same handle. Whatever was built from an asset checks version() to know when to rebuild
+// (scene_file::prepare_material). Changed files that are not assets (the scene file) are handed
+// out by take_changed().
+
+const LOADER_THREADS: usize = 2;
+
+// A type the server can load: `Data` is the cpu side, made on a loader thread.
+pub trait Asset: Sized + 'static {
+    type Data: Send + 'static;
+
+    // loader thread, no gpu access
+    fn load(path: &str, context: &LoadContext) -> Result<Self::Data>;
+
+    // main thread, in AssetServer::update
+    fn upload(data: Self::Data, device: &wgpu::Device, queue: &wgpu::Queue, path: &str) -> Result<Self>;
+}
+
+// what a loader thread knows about the device
+#[derive(Copy, Clone, Debug)]
+pub struct LoadContext {
+    // picks the compressed texture formats, see compressed.rs
+    pub features: wgpu::Features,
+}
+
+pub struct Handle<T> {
+    index: usize,
+    // the server frees the asset when it holds the last clone
+    refs: Arc<()>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        Self { index: self.index, refs: self.refs.clone(), marker: PhantomData }
+    }
+}
+
+impl<T> PartialEq for Handle<T> {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.refs, &other.refs)
+    }
+}
+
+impl<T> std::fmt::Debug for Handle<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Handle<{}>({})", std::any::type_name::<T>(), self.index)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadState {
+    Loading,
+    Loaded,
+    Failed(String),
+}
+
+struct Entry<T> {
+    path: String,
+    value: Option<T>,
+    error: Option<String>,
+    // a load is in flight, the old value (if any) stays until it is done
+    loading: bool,
+    // results of older loads of this slot are ignored
+    ticket: u64,
+    // +1 every time a new value is uploaded, for everything built from the asset (bind groups)
+    version: u64,
+    refs: Arc<()>,
+}
+
+// (slot, ticket, decoded data)
+type LoadResult<T> = (usize, u64, Result<<T as Asset>::Data>);
+
+pub struct Assets<T: Asset> {
+    entries: Vec<Option<Entry<T>>>,
+    free: Vec<usize>,
+    by_path: HashMap<String, usize>,
+    next_ticket: u64,
+    sender: Sender<LoadResult<T>>,
+    receiver: Receiver<LoadResult<T>>,
+}
+
+impl<T: Asset> Assets<T> {
+    fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Self {
+            entries: Vec::new(),
+            free: Vec::new(),
+            by_path: HashMap::new(),
+            next_ticket: 0,
+            sender,
+            receiver,
+        }
+    }
+
+    fn entry(&self, handle: &Handle<T>) -> &Entry<T> {
+        // a living handle keeps its entry
+        self.entries[handle.index].as_ref().unwrap()
+    }
+
+    fn start_load(&mut self, index: usize, loader: &Loader) {
+        self.next_ticket += 1;
+        let ticket = self.next_ticket;
+        let entry = self.entries[index].as_mut().unwrap();
+        entry.loading = true;
+        entry.ticket = ticket;
+
+        let path = entry.path.clone();
+        let sender = self.sender.clone();
+        loader.run(Box::new(move |context| {
+            let result = T::load(&path, context).with_context(|| format!("loading {}", path));
+            let _ = sender.send((index, ticket, result));
+        }));
+    }
+}
+
+// what the server needs from every Assets<T>, without knowing T
+trait Storage: Any {
+    fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue);
+    fn reload_path(&mut self, path: &str, loader: &Loader) -> bool;
+    fn counts(&self) -> (usize, usize);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl<T: Asset> Storage for Assets<T> {
+    fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        while let std::result::Result::Ok((index, ticket, result)) = self.receiver.try_recv() {
+            let Some(entry) = self.entries.get_mut(index).and_then(|e| e.as_mut()) else { continue };
+            if entry.ticket != ticket {
+                continue;
+            }
+            entry.loading = false;
+            match result.and_then(|data| T::upload(data, device, queue, &entry.path)) {
+                std::result::Result::Ok(value) => {
+                    entry.value = Some(value);
+                    entry.error = None;
+                    entry.version += 1;
+                }
+                Err(e) => {
+                    // a failed reload keeps the last good value
+                    log::error!("{:#}", e);
+                    entry.error = Some(format!("{:#}", e));
+                }
+            }
+        }
+
+        // nobody but the server holds the handle anymore
+        for index in 0..self.entries.len() {
+            let unused = self.entries[index].as_ref().is_some_and(|entry| Arc::strong_count(&entry.refs) == 1);
+            if unused {
+                let entry = self.entries[index].take().unwrap();
+                self.by_path.remove(&entry.path);
+                self.free.push(index);
+            }
+        }
+    }
+
+    fn reload_path(&mut self, path: &str, loader: &Loader) -> bool {
+        match self.by_path.get(path) {
+            Some(&index) => {
+                self.start_load(index, loader);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn counts(&self) -> (usize, usize) {
+        let live = self.entries.iter().flatten();
+        let loading = live.clone().filter(|entry| entry.loading).count();
+        (live.count(), loading)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+type Job = Box<dyn FnOnce(&LoadContext) + Send>;
+
+// Loader threads sharing one job queue.
+struct Loader {
+    #[cfg(not(target_arch = "wasm32"))]
+    jobs: Sender<Job>,
+    #[cfg(target_arch = "wasm32")]
+    context: LoadContext,
+}
+
+impl Loader {
+    fn new(context: LoadContext) -> Self {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            let (jobs, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            for i in 0..LOADER_THREADS {
+                let receiver = receiver.clone();
+                std::thread::Builder::new()
+                    .name(format!("asset loader {}", i))
+                    .spawn(move || loop {
+                        // the lock is only held while waiting for the next job
+                        let job = match receiver.lock().unwrap().recv() {
+                            std::result::Result::Ok(job) => job,
+                            Err(_) => return,
+                        };
+                        job(&context);
+                    })
+                    .expect("spawning an asset loader thread");
+            }
+            Self { jobs }
+        }
+        #[cfg(target_arch = "wasm32")]
+        Self { context }
+    }
+
+    fn run(&self, job: Job) {
+        #[cfg(not(target_arch = "wasm32"))]
+        let _ = self.jobs.send(job);
+        #[cfg(target_arch = "wasm32")]
+        job(&self.context);
+    }
+}
+
+pub struct AssetServer {
+    storages: HashMap<TypeId, Box<dyn Storage>>,
+    loader: Loader,
+    #[cfg(not(target_arch = "wasm32"))]
+    watcher: Option<FileWatcher>,
+    // watched files that changed but are no asset, for take_changed
+    changed: Vec<String>,
+}
+
+impl AssetServer {
+    pub fn new(context: LoadContext) -> Self {
+        let mut server = Self {
+            storages: HashMap::new(),
+            loader: Loader::new(context),
+            #[cfg(not(target_arch = "wasm32"))]
+            watcher: FileWatcher::new().map_err(|e| log::warn!("no asset hot reload: {:#}", e)).ok(),
+            changed: Vec::new(),
+        };
+        server.register::<Texture>();
+        server.register::<LinearTexture>();
+        server.register::<Mesh>();
+        server.register::<Shader>();
+        server.register::<Sound>();
+        server
+    }
+
+    pub fn register<T: Asset>(&mut self) {
+        self.storages.entry(TypeId::of::<T>()).or_insert_with(|| Box::new(Assets::<T>::new()));
+    }
+
+    fn assets<T: Asset>(&self) -> &Assets<T> {
+        self.storages[&TypeId::of::<T>()].as_any().downcast_ref().expect("asset type not registered")
+    }
+
+    // Starts loading, or returns the handle of the asset already loaded from `path`.
+    pub fn load<T: Asset>(&mut self, path: &str) -> Handle<T> {
+        let path = &normalize(path);
+        if !path.starts_with("builtin:") {
+            self.watch(path);
+        }
+        let assets = storage_mut::<T>(&mut self.storages);
+
+        if let Some(&index) = assets.by_path.get(path) {
+            let refs = assets.entries[index].as_ref().unwrap().refs.clone();
+            return Handle { index, refs, marker: PhantomData };
+        }
+
+        let refs = Arc::new(());
+        let entry = Entry {
+            path: path.to_string(),
+            value: None,
+            error: None,
+            loading: false,
+            ticket: 0,
+            version: 0,
+            refs: refs.clone(),
+        };
+        let index = match assets.free.pop() {
+            Some(index) => {
+                assets.entries[index] = Some(entry);
+                index
+            }
+            None => {
+                assets.entries.push(Some(entry));
+                assets.entries.len() - 1
+            }
+        };
+        assets.by_path.insert(path.to_string(), index);
+        assets.start_load(index, &self.loader);
+        Handle { index, refs, marker: PhantomData }
+    }
+
+    // None while loading, or when the first load failed
+    pub fn get<T: Asset>(&self, handle: &Handle<T>) -> Option<&T> {
+        self.assets::<T>().entry(handle).value.as_ref()
+    }
+
+    pub fn state<T: Asset>(&self, handle: &Handle<T>) -> LoadState {
+        let entry = self.assets::<T>().entry(handle);
+        match (&entry.value, &entry.error, entry.loading) {
+            (_, _, true) => LoadState::Loading,
+            (_, Some(error), false) => LoadState::Failed(error.clone()),
+            (_, None, false) => LoadState::Loaded,
+        }
+    }
+
+    // changes every time a new value is uploaded, 0 = nothing yet
+    pub fn version<T: Asset>(&self, handle: &Handle<T>) -> u64 {
+        self.assets::<T>().entry(handle).version
+    }
+
+    pub fn path<T: Asset>(&self, handle: &Handle<T>) -> &str {
+        &self.assets::<T>().entry(handle).path
+    }
+
+    // Loads the file again, the old value stays in use until the new one is uploaded.
+    pub fn reload<T: Asset>(&mut self, handle: &Handle<T>) {
+        storage_mut::<T>(&mut self.storages).start_load(handle.index, &self.loader);
+    }
+
+    // Reloads whatever asset of any type came from `path`, false if none did.
+    pub fn reload_path(&mut self, path: &str) -> bool {
+        let path = &normalize(path);
+        let mut found = false;
+        for storage in self.storages.values_mut() {
+            found |= storage.reload_path(path, &self.loader);
+        }
+        found
+    }
+
+    // Reports changes of a file that is not loaded through the server, see take_changed.
+    pub fn watch(&mut self, path: &str) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(watcher) = &mut self.watcher {
+            if let Err(e) = watcher.watch(path) {
+                log::warn!("{:#}", e);
+            }
+        }
+        #[cfg(target_arch = "wasm32")]
+        let _ = path;
+    }
+
+    // Watched files that changed on disk and are no asset, since the last call.
+    pub fn take_changed(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.changed)
+    }
+
+    // Once per frame: reloads changed files, uploads what the loader threads finished,
+    // frees what nobody uses.
+    pub fn update(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(watcher) = &mut self.watcher {
+            for path in watcher.changed() {
+                crate::vfs::source_changed(&path);
+                if self.reload_path(&path) {
+                    log::info!("reloading {}", path);
+                } else {
+                    self.changed.push(path);
+                }
+            }
+        }
+
+        for storage in self.storages.values_mut() {
+            storage.update(device, queue);
+        }
+    }
+
+    // (assets alive, of those still loading)
+    pub fn counts(&self) -> (usize, usize) {
+        self.storages.values().map(|s| s.counts()).fold((0, 0), |a, b| (a.0 + b.0, a.1 + b.1))
+    }
+}
+
+fn storage_mut<T: Asset>(storages: &mut HashMap<TypeId, Box<dyn Storage>>) -> &mut Assets<T> {
+    storages
+        .get_mut(&TypeId::of::<T>())
+        .and_then(|s| s.as_any_mut().downcast_mut())
+        .expect("asset type not registered")
+}
+
+// FILES
+
+// How paths are keyed, "./textures\\tree.png" and "textures/tree.png" are the same asset.
+pub fn normalize(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    path.strip_prefix("./").unwrap_or(&path).to_string()
+}
+
+// ASSET TYPES
+
+// base color and emissive maps, png / jpg ... or block compressed ktx2, dds, basis
+impl Asset for Texture {
+    type Data = TextureData;
+
+    fn load(path: &str, context: &LoadContext) -> Result<Self::Data> {
+        compressed::decode(&crate::vfs::read(path)?, context.features, true)
+    }
+
+    fn upload(data: Self::Data, device: &wgpu::Device, queue: &wgpu::Queue, path: &str) -> Result<Self> {
+        Ok(upload_texture(data, device, queue, path, true))
+    }
+}
+
+// Normal, metallic-roughness and occlusion maps: data, not color, sampled without the srgb curve.
+// The same file loaded as a Texture and as a LinearTexture is two assets.
+pub struct LinearTexture(pub Texture);
+
+impl Asset for LinearTexture {
+    type Data = TextureData;
+
+    fn load(path: &str, context: &LoadContext) -> Result<Self::Data> {
+        compressed::decode(&crate::vfs::read(path)?, context.features, false)
+    }
+
+    fn upload(data: Self::Data, device: &wgpu::Device, queue: &wgpu::Queue, path: &str) -> Result<Self> {
+        Ok(LinearTexture(upload_texture(data, device, queue, path, false)))
+    }
+}
+
+fn upload_texture(data: TextureData, device: &wgpu::Device, queue: &wgpu::Queue, path: &str, srgb: bool) -> Texture {
+    match data {
+        TextureData::Rgba(rgba) => Texture::from_rgba8(device, queue, &rgba, rgba.width(), rgba.height(), Some(path), srgb),
+        TextureData::Compressed(image) => Texture::from_levels(device, queue, image.format, image.width, image.height, &image.levels, Some(path)),
+    }
+}
+
+pub struct MeshData {
+    vertices: Vec<Vertex>,
+    indices: Vec<u16>,
+}
+
+// "builtin:pentagon", "builtin:ground", a cooked .mesh or a Wavefront .obj (every object merged into one mesh)
+impl Asset for Mesh {
+    type Data = MeshData;
+
+    fn load(path: &str, _context: &LoadContext) -> Result<Self::Data> {
+        match path {
+            "builtin:pentagon" => Ok(MeshData { vertices: crate::VERTICES.to_vec(), indices: crate::INDICES.to_vec() }),
+            // floor under the pentagon so there is something to receive shadows
+            "builtin:ground" => {
+                let (vertices, indices) = Mesh::plane_data(6.0, -0.6);
+                Ok(MeshData { vertices: vertices.to_vec(), indices: indices.to_vec() })
+            }
+            _ => {
+                // a cooked .mesh also comes back for the source path, see cooked.rs
+                let bytes = crate::vfs::read(path)?;
+                if MeshFile::is_mesh(&bytes) {
+                    Ok(MeshFile::decode(&bytes)?.into())
+                } else if path.ends_with(".obj") {
+                    Ok(MeshFile::from_obj(&bytes)?.into())
+                } else {
+                    bail!("unknown mesh format")
+                }
+            }
+        }
+    }
+
+    fn upload(data: Self::Data, device: &wgpu::Device, _queue: &wgpu::Queue, path: &str) -> Result<Self> {
+        Ok(Mesh::new(device, path, &data.vertices, &data.indices))
+    }
+}
+
+impl From<MeshFile> for MeshData {
+    fn from(mesh: MeshFile) -> Self {
+        let vertices = mesh
+            .vertices
+            .iter()
+            .map(|&[x, y, z, u, v, nx, ny, nz]| Vertex { position: [x, y, z], tex_coords: [u, v], normal: [nx, ny, nz] })
+            .collect();
+        MeshData { vertices, indices: mesh.indices }
+    }
+}
+
+// WGSL, validated with naga on the loader thread so a broken file never reaches wgpu
+pub struct Shader {
+    pub source: String,
+    pub module: wgpu::ShaderModule,
+}
+
+impl Asset for Shader {
+    type Data = String;
+
+    fn load(path: &str, _context: &LoadContext) -> Result<Self::Data> {
+        let source = String::from_utf8(crate::vfs::read(path)?)?;
+        let module = wgpu::naga::front::wgsl::parse_str(&source).map_err(|e| anyhow!(e.emit_to_string(&source)))?;
+        wgpu::naga::valid::Validator::new(wgpu::naga::valid::ValidationFlags::all(), wgpu::naga::valid::Capabilities::all())
+            .validate(&module)
+            .map_err(|e| anyhow!("{:?}", e))?;
+        Ok(source)
+    }
+
+    fn upload(data: Self::Data, device: &wgpu::Device, _queue: &wgpu::Queue, path: &str) -> Result<Self> {
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(path),
+            source: wgpu::ShaderSource::Wgsl(data.as_str().into()),
+        });
+        Ok(Shader { source: data, module })
+    }
+}
+
+// wav, ogg or flac, decoded on the loader thread, see audio.rs
+pub struct Sound {
+    pub clip: Arc<Clip>,
+}
+
+impl Asset for Sound {
+    type Data = Clip;
+
+    fn load(path: &str, _context: &LoadContext) -> Result<Self::Data> {
+        let extension = std::path::Path::new(path).extension().and_then(|e| e.to_str());
+        Clip::decode(crate::vfs::read(path)?, extension)
+    }
+
+    fn upload(data: Self::Data, _device: &wgpu::Device, _queue: &wgpu::Queue, _path: &str) -> Result<Self> {
+        Ok(Sound { clip: Arc::new(data) })
+    }
+}
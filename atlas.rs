@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+
+use crate::animation::SpriteSheet;
+use crate::texture::Texture;
+
+// TEXTURE ATLAS
+// Packs many small images into one texture at runtime, so sprites using them share one bind group
+// (one draw call per layer in SpriteBatch instead of one per texture).
+//
+// Every image gets `padding` pixels around it filled with copies of its own edge pixels,
+// so bilinear filtering never pulls in a neighbour. Mips halve the padding, so the atlas only
+// gets as many mips as the padding survives, and every rect starts on a multiple of 2^(mips - 1)
+// so it stays aligned to whole texels in every mip.
+
+// Where an image ended up in the atlas.
+#[derive(Copy, Clone, Debug)]
+pub struct AtlasRegion {
+    // [u_min, v_min, u_max, v_max], what Sprite::uv_rect wants
+    pub uv_rect: [f32; 4],
+    // in pixels, without the padding
+    pub rect: [u32; 4],
+}
+
+impl AtlasRegion {
+    pub fn size(&self) -> [f32; 2] {
+        [self.rect[2] as f32, self.rect[3] as f32]
+    }
+
+    // uv rect of a sub rectangle given in pixels of the original image (a sprite sheet frame)
+    pub fn sub_uv_rect(&self, x: u32, y: u32, width: u32, height: u32) -> [f32; 4] {
+        let [u0, v0, u1, v1] = self.uv_rect;
+        let (du, dv) = ((u1 - u0) / self.rect[2] as f32, (v1 - v0) / self.rect[3] as f32);
+        [
+            u0 + x as f32 * du,
+            v0 + y as f32 * dv,
+            u0 + (x + width) as f32 * du,
+            v0 + (y + height) as f32 * dv,
+        ]
+    }
+}
+
+pub struct Atlas {
+    pub texture: Texture,
+    pub width: u32,
+    pub height: u32,
+    regions: HashMap<String, AtlasRegion>,
+}
+
+impl Atlas {
+    pub fn region(&self, name: &str) -> Option<&AtlasRegion> {
+        self.regions.get(name)
+    }
+}
+
+pub struct AtlasBuilder {
+    images: Vec<(String, image::RgbaImage)>,
+    padding: u32,
+    max_size: u32,
+}
+
+impl AtlasBuilder {
+    pub fn new() -> Self {
+        Self {
+            images: Vec::new(),
+            padding: 4,
+            max_size: 4096,
+        }
+    }
+
+    // pixels of border around every image, 0 turns filtering bleed protection and mips off
+    pub fn padding(mut self, padding: u32) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    // the atlas never grows past max_size x max_size
+    pub fn max_size(mut self, max_size: u32) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn add_image(&mut self, name: &str, image: image::RgbaImage) -> Result<()> {
+        ensure!(image.width() > 0 && image.height() > 0, "atlas image {name} is empty");
+        self.images.push((name.to_string(), image));
+        Ok(())
+    }
+
+    // png, jpg ... same loading as Texture::from_bytes
+    pub fn add_bytes(&mut self, name: &str, bytes: &[u8]) -> Result<()> {
+        let img = image::load_from_memory(bytes).with_context(|| format!("atlas image {name}"))?;
+        self.add_image(name, img.to_rgba8())
+    }
+
+    // An Aseprite export (see animation.rs), the sheet image is packed under `name`.
+    pub fn add_sprite_sheet(&mut self, name: &str, json_path: &str) -> Result<SpriteSheet> {
+        let (sheet, image) = SpriteSheet::load(json_path)?;
+        self.add_image(name, image)?;
+        Ok(sheet)
+    }
+
+    pub fn build(self, device: &wgpu::Device, queue: &wgpu::Queue, label: &str) -> Result<Atlas> {
+        let mip_level_count = self.mip_level_count();
+        let Packing { width, height, positions } = self.pack().with_context(|| label.to_string())?;
+
+        let mut pixels = image::RgbaImage::new(width, height);
+        let mut regions = HashMap::new();
+        for ((name, img), (x, y)) in self.images.iter().zip(&positions) {
+            let (left, top) = (x + self.padding, y + self.padding);
+            blit_extruded(&mut pixels, img, left, top, self.padding);
+            regions.insert(
+                name.clone(),
+                AtlasRegion {
+                    uv_rect: [
+                        left as f32 / width as f32,
+                        top as f32 / height as f32,
+                        (left + img.width()) as f32 / width as f32,
+                        (top + img.height()) as f32 / height as f32,
+                    ],
+                    rect: [left, top, img.width(), img.height()],
+                },
+            );
+        }
+
+        let texture = create_texture(device, queue, pixels, mip_level_count, label);
+        log::info!("{label}: {} images packed into {width}x{height}, {mip_level_count} mips", regions.len());
+
+        Ok(Atlas { texture, width, height, regions })
+    }
+
+    // one mip per halving of the padding, below that neighbours would bleed into each other
+    fn mip_level_count(&self) -> u32 {
+        if self.padding == 0 { 1 } else { self.padding.ilog2() + 1 }
+    }
+
+    // Atlas size and the top left corner of every padded image, no device needed.
+    fn pack(&self) -> Result<Packing> {
+        let align = 1 << (self.mip_level_count() - 1);
+        // padded sizes, rounded up so the next rect starts aligned too
+        let sizes: Vec<(u32, u32)> = self
+            .images
+            .iter()
+            .map(|(_, img)| {
+                (
+                    (img.width() + 2 * self.padding).next_multiple_of(align),
+                    (img.height() + 2 * self.padding).next_multiple_of(align),
+                )
+            })
+            .collect();
+
+        // try bigger and bigger squares (and 2:1 rectangles) until everything fits
+        let first = 64.min(self.max_size);
+        let mut size = (first, first);
+        loop {
+            if let Some(positions) = pack_shelves(&sizes, size.0, size.1) {
+                return Ok(Packing { width: size.0, height: size.1, positions });
+            }
+            if size.0 >= self.max_size && size.1 >= self.max_size {
+                bail!("{} images do not fit into {}x{}", self.images.len(), self.max_size, self.max_size);
+            }
+            size = if size.0 == size.1 { ((size.0 * 2).min(self.max_size), size.1) } else { (size.0, size.0) };
+        }
+    }
+}
+
+struct Packing {
+    width: u32,
+    height: u32,
+    // top left corner of every padded image, in the order they were added
+    positions: Vec<(u32, u32)>,
+}
+
+// Shelf packing: tallest images first, left to right in rows, a new row when the current one is full.
+// Returns the top left corner of every size (in the original order), or None if it does not fit.
+fn pack_shelves(sizes: &[(u32, u32)], width: u32, height: u32) -> Option<Vec<(u32, u32)>> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| std::cmp::Reverse(sizes[i].1));
+
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut y, mut shelf_height) = (0, 0, 0);
+    for i in order {
+        let (w, h) = sizes[i];
+        if w > width {
+            return None;
+        }
+        if x + w > width {
+            y += shelf_height;
+            x = 0;
+            shelf_height = 0;
+        }
+        if y + h > height {
+            return None;
+        }
+        positions[i] = (x, y);
+        x += w;
+        shelf_height = shelf_height.max(h);
+    }
+    Some(positions)
+}
+
+// copy `img` to (left, top) and repeat its edge pixels `padding` times outwards
+fn blit_extruded(target: &mut image::RgbaImage, img: &image::RgbaImage, left: u32, top: u32, padding: u32) {
+    let (w, h) = img.dimensions();
+    let p = padding as i64;
+    for y in -p..h as i64 + p {
+        for x in -p..w as i64 + p {
+            let source = img.get_pixel(x.clamp(0, w as i64 - 1) as u32, y.clamp(0, h as i64 - 1) as u32);
+            target.put_pixel((left as i64 + x) as u32, (top as i64 + y) as u32, *source);
+        }
+    }
+}
+
+fn create_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    pixels: image::RgbaImage,
+    mip_level_count: u32,
+    label: &str,
+) -> Texture {
+    let (width, height) = pixels.dimensions();
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+        mip_level_count,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: wgpu::TextureFormat::Rgba8UnormSrgb,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    // mip chain on the cpu, the rects are aligned so every level halves them exactly
+    let mut level = pixels;
+    for mip in 0..mip_level_count {
+        let (w, h) = level.dimensions();
+        queue.write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &texture,
+                mip_level: mip,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &level,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * w),
+                rows_per_image: Some(h),
+            },
+            wgpu::Extent3d { width: w, height: h, depth_or_array_layers: 1 },
+        );
+        level = image::imageops::resize(&level, (w / 2).max(1), (h / 2).max(1), image::imageops::FilterType::Triangle);
+    }
+
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+        address_mode_u: wgpu::AddressMode::ClampToEdge,
+        address_mode_v: wgpu::AddressMode::ClampToEdge,
+        address_mode_w: wgpu::AddressMode::ClampToEdge,
+        mag_filter: wgpu::FilterMode::Linear,
+        min_filter: wgpu::FilterMode::Linear,
+        mipmap_filter: wgpu::FilterMode::Linear,
+        ..Default::default()
+    });
+
+    Texture { texture, view, sampler }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_shelves_fills_rows_tallest_first() {
+        let sizes = [(30, 20), (30, 10), (30, 20), (40, 5)];
+        let positions = pack_shelves(&sizes, 64, 64).unwrap();
+        assert_eq!(positions, vec![(0, 0), (0, 20), (30, 0), (0, 30)]);
+
+        assert!(pack_shelves(&[(65, 1)], 64, 64).is_none());
+        assert!(pack_shelves(&sizes, 64, 25).is_none());
+        assert_eq!(pack_shelves(&[], 64, 64), Some(vec![]));
+    }
+
+    #[test]
+    fn packs_padded_images_aligned_and_apart() {
+        let mut builder = AtlasBuilder::new().padding(4);
+        for (i, (w, h)) in [(10, 10), (20, 5), (3, 3), (40, 17), (1, 1)].into_iter().enumerate() {
+            builder.add_image(&i.to_string(), image::RgbaImage::new(w, h)).unwrap();
+        }
+        let Packing { width, height, positions } = builder.pack().unwrap();
+
+        // 3 mips, so every padded rect starts on a multiple of 4
+        let rects: Vec<[u32; 4]> = builder
+            .images
+            .iter()
+            .zip(&positions)
+            .map(|((_, img), &(x, y))| [x, y, img.width() + 8, img.height() + 8])
+            .collect();
+        for (i, &[x, y, w, h]) in rects.iter().enumerate() {
+            assert!(x % 4 == 0 && y % 4 == 0);
+            assert!(x + w <= width && y + h <= height);
+            for &[ox, oy, ow, oh] in &rects[i + 1..] {
+                assert!(x + w <= ox || ox + ow <= x || y + h <= oy || oy + oh <= y);
+            }
+        }
+    }
+
+    #[test]
+    fn stays_within_max_size() {
+        let mut builder = AtlasBuilder::new().padding(2).max_size(32);
+        builder.add_image("small", image::RgbaImage::new(10, 10)).unwrap();
+        let packing = builder.pack().unwrap();
+        assert_eq!((packing.width, packing.height), (32, 32));
+
+        builder.add_image("big", image::RgbaImage::new(30, 30)).unwrap();
+        assert!(builder.pack().is_err());
+
+        // grows past the first 64x64 try when allowed to
+        let mut builder = AtlasBuilder::new().padding(0);
+        builder.add_image("wide", image::RgbaImage::new(100, 10)).unwrap();
+        assert_eq!(builder.pack().unwrap().width, 128);
+    }
+
+    #[test]
+    fn rejects_empty_images() {
+        let mut builder = AtlasBuilder::new();
+        assert!(builder.add_image("empty", image::RgbaImage::new(0, 4)).is_err());
+        assert!(builder.add_image("flat", image::RgbaImage::new(4, 0)).is_err());
+        assert!(builder.images.is_empty());
+    }
+}
@@ -0,0 +1,786 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+
+use anyhow::*;
+use cgmath::{EuclideanSpace, InnerSpace, Vector3, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::assets::AssetServer;
+use crate::camera::Camera;
+use crate::ecs::{self, Ecs};
+use crate::scene::Scene;
+
+// AUDIO
+// Clips are decoded whole into stereo f32 (WAV, OGG Vorbis, FLAC via symphonia), usually on an asset
+// loader thread (assets::Sound). Playing one starts a voice in the Mixer: volume, pitch (playback
+// speed), pan, one-shot or looping, on a bus. Every bus has its own volume, the master volume is on top.
+// The mixer runs wherever the output wants samples:
+//   Device - the cpal callback pulls them on the audio thread
+//   Null   - update(dt) mixes dt worth of samples and throws them away, voices still advance and end
+//   Wav    - update(dt) mixes dt worth of samples into a wav file, with a fixed dt (frame capture)
+//            the recording matches the video frame for frame
+// Null and Wav need no sound card, they are what tests and headless runs use.
+//
+// SPATIAL
+// A voice with PlayParams::spatial sits at a world position and is heard from the Listener
+// (the camera, update_listener): distance attenuation, stereo pan from where it is left or right
+// of the listener, and doppler from both velocities along the line between them.
+// Entities with an ecs::Emitter get such a voice at their scene node, sync_emitters moves it along.
+
+pub const SAMPLE_RATE: u32 = 48000;
+
+// CLIPS
+
+pub struct Clip {
+    // interleaved left, right
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+}
+
+impl Clip {
+    // `extension` helps the format probe, it looks at the bytes first
+    pub fn decode(bytes: Vec<u8>, extension: Option<&str>) -> Result<Self> {
+        use symphonia::core::audio::SampleBuffer;
+        use symphonia::core::errors::Error as SymphoniaError;
+
+        let stream = symphonia::core::io::MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+        let mut hint = symphonia::core::probe::Hint::new();
+        if let Some(extension) = extension {
+            hint.with_extension(extension);
+        }
+        let probed = symphonia::default::get_probe().format(&hint, stream, &Default::default(), &Default::default())?;
+        let mut format = probed.format;
+        let track = format.default_track().context("no audio track")?;
+        let track_id = track.id;
+        let sample_rate = track.codec_params.sample_rate.context("unknown sample rate")?;
+        let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &Default::default())?;
+
+        let mut samples = Vec::new();
+        loop {
+            let packet = match format.next_packet() {
+                std::result::Result::Ok(packet) => packet,
+                // the normal end of the stream
+                Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            };
+            if packet.track_id() != track_id {
+                continue;
+            }
+            let decoded = match decoder.decode(&packet) {
+                std::result::Result::Ok(decoded) => decoded,
+                // a broken packet is skipped, not the whole clip
+                Err(SymphoniaError::DecodeError(e)) => {
+                    log::warn!("skipping audio packet: {}", e);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let spec = *decoded.spec();
+            let mut buffer = SampleBuffer::<f32>::new(decoded.capacity() as u64, spec);
+            buffer.copy_interleaved_ref(decoded);
+
+            // mono is played on both sides, channels past the second are dropped
+            let channels = spec.channels.count().max(1);
+            for frame in buffer.samples().chunks_exact(channels) {
+                let (left, right) = if channels == 1 { (frame[0], frame[0]) } else { (frame[0], frame[1]) };
+                samples.push(left);
+                samples.push(right);
+            }
+        }
+        Ok(Self { samples, sample_rate })
+    }
+
+    pub fn frames(&self) -> usize {
+        self.samples.len() / 2
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.frames() as f32 / self.sample_rate as f32
+    }
+
+    // stereo frame `index`, silence past the end
+    fn frame(&self, index: usize) -> (f32, f32) {
+        match self.samples.get(index * 2..index * 2 + 2) {
+            Some(frame) => (frame[0], frame[1]),
+            None => (0.0, 0.0),
+        }
+    }
+}
+
+// MIXER
+
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Bus {
+    Music,
+    #[default]
+    Sfx,
+}
+
+impl Bus {
+    pub const ALL: [Bus; 2] = [Bus::Music, Bus::Sfx];
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PlayParams {
+    pub bus: Bus,
+    pub volume: f32,
+    // playback speed, 2 = an octave up and twice as short
+    pub pitch: f32,
+    // -1 left, 0 center, 1 right
+    pub pan: f32,
+    pub looping: bool,
+    // None = plain stereo, not placed in the world
+    pub spatial: Option<Spatial>,
+}
+
+impl Default for PlayParams {
+    fn default() -> Self {
+        Self { bus: Bus::Sfx, volume: 1.0, pitch: 1.0, pan: 0.0, looping: false, spatial: None }
+    }
+}
+
+impl PlayParams {
+    pub fn music() -> Self {
+        Self { bus: Bus::Music, looping: true, ..Default::default() }
+    }
+}
+
+// distance -> gain, clamped to min_distance..max_distance first (the OpenAL clamped models)
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Default, PartialEq)]
+pub enum Attenuation {
+    // same volume everywhere, still panned
+    None,
+    // min / (min + rolloff * (d - min)), the natural falloff
+    #[default]
+    Inverse,
+    // 1 - rolloff * (d - min) / (max - min), silent at max_distance with rolloff 1
+    Linear,
+    // (d / min) ^ -rolloff
+    Exponential,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Spatial {
+    pub position: Vector3<f32>,
+    // units per second, for the doppler shift
+    pub velocity: Vector3<f32>,
+    pub attenuation: Attenuation,
+    // full volume up to here
+    pub min_distance: f32,
+    // no quieter past here
+    pub max_distance: f32,
+    pub rolloff: f32,
+}
+
+impl Default for Spatial {
+    fn default() -> Self {
+        Self {
+            position: Vector3::zero(),
+            velocity: Vector3::zero(),
+            attenuation: Attenuation::Inverse,
+            min_distance: 1.0,
+            max_distance: 50.0,
+            rolloff: 1.0,
+        }
+    }
+}
+
+impl Spatial {
+    pub fn gain(&self, distance: f32) -> f32 {
+        let min = self.min_distance.max(1e-3);
+        let max = self.max_distance.max(min);
+        let distance = distance.clamp(min, max);
+        let gain = match self.attenuation {
+            Attenuation::None => 1.0,
+            Attenuation::Inverse => min / (min + self.rolloff * (distance - min)),
+            Attenuation::Linear if max > min => 1.0 - self.rolloff * (distance - min) / (max - min),
+            Attenuation::Linear => 1.0,
+            Attenuation::Exponential => (distance / min).powf(-self.rolloff),
+        };
+        gain.clamp(0.0, 1.0)
+    }
+}
+
+// where the sound is heard from, normally the camera
+#[derive(Copy, Clone, Debug)]
+pub struct Listener {
+    pub position: Vector3<f32>,
+    pub forward: Vector3<f32>,
+    pub up: Vector3<f32>,
+    pub velocity: Vector3<f32>,
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Self { position: Vector3::zero(), forward: -Vector3::unit_z(), up: Vector3::unit_y(), velocity: Vector3::zero() }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct VoiceId(u64);
+
+struct Voice {
+    id: VoiceId,
+    clip: Arc<Clip>,
+    params: PlayParams,
+    // in clip frames, fractional with pitch
+    position: f64,
+}
+
+pub struct Mixer {
+    voices: Vec<Voice>,
+    bus_volumes: [f32; Bus::ALL.len()],
+    pub master_volume: f32,
+    pub listener: Listener,
+    // world units per second, 343 = meters
+    pub speed_of_sound: f32,
+    // 0 turns doppler off, 1 is physical
+    pub doppler_factor: f32,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Self {
+            voices: Vec::new(),
+            bus_volumes: [1.0; Bus::ALL.len()],
+            master_volume: 1.0,
+            listener: Listener::default(),
+            speed_of_sound: 343.0,
+            doppler_factor: 1.0,
+        }
+    }
+}
+
+impl Mixer {
+    // Adds every voice into `out` (interleaved, `channels` per frame) and advances them,
+    // voices that reached the end of a one-shot clip are removed.
+    pub fn mix(&mut self, out: &mut [f32], channels: usize, sample_rate: u32) {
+        out.fill(0.0);
+        for voice in &mut self.voices {
+            let clip = &voice.clip;
+            let frames = clip.frames();
+            if frames == 0 {
+                voice.position = f64::INFINITY;
+                continue;
+            }
+            let (spatial_gain, spatial_pan, doppler) = match &voice.params.spatial {
+                Some(spatial) => spatialize(&self.listener, spatial, self.speed_of_sound, self.doppler_factor),
+                None => (1.0, 0.0, 1.0),
+            };
+            let gain = voice.params.volume * spatial_gain * self.bus_volumes[voice.params.bus.index()] * self.master_volume;
+            // constant power, center is -3 dB on both sides
+            let pan = (voice.params.pan + spatial_pan).clamp(-1.0, 1.0);
+            let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+            let (left_gain, right_gain) = (angle.cos() * gain, angle.sin() * gain);
+            let pitch = voice.params.pitch.max(0.0) * doppler;
+            let step = pitch as f64 * clip.sample_rate as f64 / sample_rate as f64;
+
+            for frame in out.chunks_exact_mut(channels) {
+                if voice.position >= frames as f64 {
+                    if !voice.params.looping {
+                        break;
+                    }
+                    voice.position %= frames as f64;
+                }
+                // linear interpolation between the two nearest frames
+                let index = voice.position as usize;
+                let t = (voice.position - index as f64) as f32;
+                let next = if voice.params.looping { (index + 1) % frames } else { index + 1 };
+                let (l0, r0) = clip.frame(index);
+                let (l1, r1) = clip.frame(next);
+                let left = (l0 + (l1 - l0) * t) * left_gain;
+                let right = (r0 + (r1 - r0) * t) * right_gain;
+
+                if channels == 1 {
+                    frame[0] += (left + right) * 0.5;
+                } else {
+                    frame[0] += left;
+                    frame[1] += right;
+                }
+                voice.position += step;
+            }
+        }
+        self.voices.retain(|voice| voice.clip.frames() > 0 && (voice.params.looping || voice.position < voice.clip.frames() as f64));
+
+        for sample in out.iter_mut() {
+            *sample = sample.clamp(-1.0, 1.0);
+        }
+    }
+
+    fn voice_mut(&mut self, id: VoiceId) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|voice| voice.id == id)
+    }
+}
+
+// (gain, pan, pitch factor) of a voice at `spatial` heard by `listener`
+fn spatialize(listener: &Listener, spatial: &Spatial, speed_of_sound: f32, doppler_factor: f32) -> (f32, f32, f32) {
+    let to_source = spatial.position - listener.position;
+    let distance = to_source.magnitude();
+    let gain = spatial.gain(distance);
+    // right in front or on top of the listener: centered, no doppler
+    if distance < 1e-4 {
+        return (gain, 0.0, 1.0);
+    }
+    let direction = to_source / distance;
+
+    // sine of the angle off the forward axis, 1 straight to the right
+    let right = listener.forward.cross(listener.up);
+    let pan = if right.magnitude2() > 0.0 { direction.dot(right.normalize()) } else { 0.0 };
+
+    // f' = f (c + vl) / (c + vs), both velocities along listener -> source: moving towards each other raises
+    // the pitch. Clamped below the speed of sound so a teleport does not flip or explode it.
+    let doppler = if doppler_factor > 0.0 && speed_of_sound > 0.0 {
+        let limit = speed_of_sound * 0.9;
+        let listener_speed = (listener.velocity.dot(direction) * doppler_factor).clamp(-limit, limit);
+        let source_speed = (spatial.velocity.dot(direction) * doppler_factor).clamp(-limit, limit);
+        (speed_of_sound + listener_speed) / (speed_of_sound + source_speed)
+    } else {
+        1.0
+    };
+    (gain, pan, doppler)
+}
+
+// OUTPUT
+
+pub enum Output {
+    // the default output device, Null when there is none
+    Device,
+    Null,
+    Wav(String),
+}
+
+impl Output {
+    // AUDIO_OUTPUT=null or AUDIO_OUTPUT=wav:<path> for headless runs, the device otherwise
+    pub fn from_env() -> Self {
+        match std::env::var("AUDIO_OUTPUT").as_deref() {
+            std::result::Result::Ok("null") => Output::Null,
+            std::result::Result::Ok(value) if value.starts_with("wav:") => Output::Wav(value["wav:".len()..].to_string()),
+            _ => Output::Device,
+        }
+    }
+}
+
+enum Backend {
+    // kept alive, dropping it stops the sound
+    Device { _stream: cpal::Stream },
+    Null,
+    Wav(hound::WavWriter<std::io::BufWriter<std::fs::File>>),
+}
+
+pub struct Audio {
+    // shared with the cpal callback
+    mixer: Arc<Mutex<Mixer>>,
+    backend: Backend,
+    // mixed but not yet written by update(), in frames
+    pending: f64,
+    next_id: u64,
+    buffer: Vec<f32>,
+    // None until the first update_listener, for the velocity
+    listener_position: Option<Vector3<f32>>,
+    // voices of ecs::Emitter entities
+    emitters: HashMap<ecs::Entity, EmitterVoice>,
+}
+
+struct EmitterVoice {
+    voice: VoiceId,
+    // last frame's, for the velocity
+    position: Vector3<f32>,
+}
+
+impl Audio {
+    pub fn new(output: Output) -> Self {
+        let mixer = Arc::new(Mutex::new(Mixer::default()));
+        let backend = match output {
+            Output::Device => match open_device(mixer.clone()) {
+                std::result::Result::Ok(stream) => Backend::Device { _stream: stream },
+                Err(e) => {
+                    log::warn!("no audio device, sound is muted: {:#}", e);
+                    Backend::Null
+                }
+            },
+            Output::Null => Backend::Null,
+            Output::Wav(path) => match open_wav(&path) {
+                std::result::Result::Ok(writer) => Backend::Wav(writer),
+                Err(e) => {
+                    log::error!("{}: {:#}", path, e);
+                    Backend::Null
+                }
+            },
+        };
+        Self {
+            mixer,
+            backend,
+            pending: 0.0,
+            next_id: 0,
+            buffer: Vec::new(),
+            listener_position: None,
+            emitters: HashMap::new(),
+        }
+    }
+
+    pub fn output_name(&self) -> &'static str {
+        match self.backend {
+            Backend::Device { .. } => "device",
+            Backend::Null => "null",
+            Backend::Wav(_) => "wav",
+        }
+    }
+
+    pub fn play(&mut self, clip: &Arc<Clip>, params: PlayParams) -> VoiceId {
+        self.next_id += 1;
+        let id = VoiceId(self.next_id);
+        self.mixer.lock().unwrap().voices.push(Voice { id, clip: clip.clone(), params, position: 0.0 });
+        id
+    }
+
+    pub fn stop(&mut self, id: VoiceId) {
+        self.mixer.lock().unwrap().voices.retain(|voice| voice.id != id);
+    }
+
+    pub fn stop_all(&mut self) {
+        self.mixer.lock().unwrap().voices.clear();
+    }
+
+    pub fn is_playing(&self, id: VoiceId) -> bool {
+        self.mixer.lock().unwrap().voices.iter().any(|voice| voice.id == id)
+    }
+
+    // volume, pitch and pan of a playing voice, false when it has ended
+    pub fn update_voice(&mut self, id: VoiceId, f: impl FnOnce(&mut PlayParams)) -> bool {
+        match self.mixer.lock().unwrap().voice_mut(id) {
+            Some(voice) => {
+                f(&mut voice.params);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn voice_count(&self) -> usize {
+        self.mixer.lock().unwrap().voices.len()
+    }
+
+    pub fn bus_volume(&self, bus: Bus) -> f32 {
+        self.mixer.lock().unwrap().bus_volumes[bus.index()]
+    }
+
+    pub fn set_bus_volume(&mut self, bus: Bus, volume: f32) {
+        self.mixer.lock().unwrap().bus_volumes[bus.index()] = volume.max(0.0);
+    }
+
+    pub fn master_volume(&self) -> f32 {
+        self.mixer.lock().unwrap().master_volume
+    }
+
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.mixer.lock().unwrap().master_volume = volume.max(0.0);
+    }
+
+    pub fn set_doppler_factor(&mut self, factor: f32) {
+        self.mixer.lock().unwrap().doppler_factor = factor.max(0.0);
+    }
+
+    pub fn doppler_factor(&self) -> f32 {
+        self.mixer.lock().unwrap().doppler_factor
+    }
+
+    // Once per frame, the camera is the listener. The velocity comes from the movement since the last call.
+    pub fn update_listener(&mut self, camera: &Camera, dt: f32) {
+        let position = camera.eye.to_vec();
+        let velocity = match self.listener_position {
+            Some(last) if dt > 0.0 => (position - last) / dt,
+            _ => Vector3::zero(),
+        };
+        self.listener_position = Some(position);
+        self.mixer.lock().unwrap().listener = Listener {
+            position,
+            forward: (camera.target - camera.eye).normalize(),
+            up: camera.up,
+            velocity,
+        };
+    }
+
+    // Once per frame after the scene transforms are updated: emitters that got their sound start
+    // playing, playing ones follow their node, the ones whose entity is gone stop.
+    // A one-shot emitter plays once per entity.
+    pub fn sync_emitters(&mut self, ecs: &Ecs, scene: &Scene, assets: &AssetServer, dt: f32) {
+        let mut alive = HashSet::new();
+        for (entity, emitter) in ecs.world.query::<&ecs::Emitter>().iter() {
+            let Some(node) = ecs.node(entity).and_then(|node| scene.get(node)) else { continue };
+            let position = node.world_position();
+
+            if let Some(playing) = self.emitters.get_mut(&entity) {
+                alive.insert(entity);
+                let velocity = if dt > 0.0 { (position - playing.position) / dt } else { Vector3::zero() };
+                playing.position = position;
+                let voice = playing.voice;
+                let params = emitter.params;
+                self.update_voice(voice, |p| {
+                    *p = PlayParams { spatial: Some(Spatial { position, velocity, ..params.spatial.unwrap_or_default() }), ..params };
+                });
+                continue;
+            }
+
+            // still loading
+            let Some(sound) = assets.get(&emitter.sound) else { continue };
+            let params = PlayParams {
+                spatial: Some(Spatial { position, ..emitter.params.spatial.unwrap_or_default() }),
+                ..emitter.params
+            };
+            let voice = self.play(&sound.clip, params);
+            self.emitters.insert(entity, EmitterVoice { voice, position });
+            alive.insert(entity);
+        }
+
+        let gone: Vec<ecs::Entity> = self.emitters.keys().filter(|entity| !alive.contains(entity)).copied().collect();
+        for entity in gone {
+            let playing = self.emitters.remove(&entity).unwrap();
+            self.stop(playing.voice);
+        }
+    }
+
+    // Once per frame. Device output mixes on its own thread, Null and Wav mix `dt` worth of samples here.
+    pub fn update(&mut self, dt: f32) {
+        if matches!(self.backend, Backend::Device { .. }) {
+            return;
+        }
+        self.pending += dt as f64 * SAMPLE_RATE as f64;
+        let frames = self.pending.floor() as usize;
+        self.pending -= frames as f64;
+        if frames == 0 {
+            return;
+        }
+
+        self.buffer.resize(frames * 2, 0.0);
+        self.mixer.lock().unwrap().mix(&mut self.buffer, 2, SAMPLE_RATE);
+        if let Backend::Wav(writer) = &mut self.backend {
+            let result = self.buffer.iter().try_for_each(|&sample| writer.write_sample((sample * i16::MAX as f32) as i16));
+            if let Err(e) = result {
+                log::error!("writing audio: {}", e);
+                self.backend = Backend::Null;
+            }
+        }
+    }
+}
+
+impl Drop for Audio {
+    fn drop(&mut self) {
+        // the wav header gets its final length
+        if let Backend::Wav(writer) = std::mem::replace(&mut self.backend, Backend::Null) {
+            if let Err(e) = writer.finalize() {
+                log::error!("finishing the wav file: {}", e);
+            }
+        }
+    }
+}
+
+fn open_device(mixer: Arc<Mutex<Mixer>>) -> Result<cpal::Stream> {
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+
+    let device = cpal::default_host().default_output_device().context("no default output device")?;
+    // f32 at the device's own rate, the mixer resamples every clip anyway
+    let config = device
+        .supported_output_configs()?
+        .filter(|config| config.sample_format() == cpal::SampleFormat::F32)
+        .max_by_key(|config| config.channels().min(2))
+        .context("no f32 output")?;
+    let sample_rate = SAMPLE_RATE.clamp(config.min_sample_rate().0, config.max_sample_rate().0);
+    let config = config.with_sample_rate(cpal::SampleRate(sample_rate)).config();
+    let channels = config.channels as usize;
+
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _| mixer.lock().unwrap().mix(data, channels, sample_rate),
+        |e| log::error!("audio stream: {}", e),
+        None,
+    )?;
+    stream.play()?;
+    log::info!("audio: {} channels at {} Hz on {}", channels, sample_rate, device.name().unwrap_or_default());
+    Ok(stream)
+}
+
+fn open_wav(path: &str) -> Result<hound::WavWriter<std::io::BufWriter<std::fs::File>>> {
+    let spec = hound::WavSpec {
+        channels: 2,
+        sample_rate: SAMPLE_RATE,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    Ok(hound::WavWriter::create(path, spec)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // mono samples, played on both sides
+    fn clip(samples: &[f32]) -> Arc<Clip> {
+        Arc::new(Clip { samples: samples.iter().flat_map(|&s| [s, s]).collect(), sample_rate: SAMPLE_RATE })
+    }
+
+    fn play(mixer: &mut Mixer, clip: &Arc<Clip>, params: PlayParams) {
+        let id = VoiceId(mixer.voices.len() as u64);
+        mixer.voices.push(Voice { id, clip: clip.clone(), params, position: 0.0 });
+    }
+
+    // `frames` stereo frames of whatever is playing
+    fn mix(mixer: &mut Mixer, frames: usize) -> Vec<(f32, f32)> {
+        let mut out = vec![0.0; frames * 2];
+        mixer.mix(&mut out, 2, SAMPLE_RATE);
+        out.chunks_exact(2).map(|frame| (frame[0], frame[1])).collect()
+    }
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn one_shot_ends_and_is_removed() {
+        let mut mixer = Mixer::default();
+        play(&mut mixer, &clip(&[0.5; 4]), PlayParams { pan: -1.0, ..Default::default() });
+        let out = mix(&mut mixer, 8);
+        assert_eq!(out[..4], [(0.5, 0.0); 4]);
+        assert_eq!(out[4..], [(0.0, 0.0); 4]);
+        assert!(mixer.voices.is_empty());
+    }
+
+    #[test]
+    fn looping_wraps_around() {
+        let mut mixer = Mixer::default();
+        play(&mut mixer, &clip(&[0.1, 0.2, 0.3, 0.4]), PlayParams { pan: -1.0, looping: true, ..Default::default() });
+        let left: Vec<f32> = mix(&mut mixer, 10).iter().map(|frame| frame.0).collect();
+        for (i, sample) in left.iter().enumerate() {
+            assert_close(*sample, [0.1, 0.2, 0.3, 0.4][i % 4]);
+        }
+        assert_eq!(mixer.voices.len(), 1);
+    }
+
+    #[test]
+    fn pan_keeps_constant_power() {
+        for pan in [-1.0, -0.5, 0.0, 0.25, 1.0] {
+            let mut mixer = Mixer::default();
+            play(&mut mixer, &clip(&[1.0]), PlayParams { pan, ..Default::default() });
+            let (left, right) = mix(&mut mixer, 1)[0];
+            assert_close(left * left + right * right, 1.0);
+            assert_close(left, ((pan + 1.0) * std::f32::consts::FRAC_PI_4).cos());
+        }
+        let mut mixer = Mixer::default();
+        play(&mut mixer, &clip(&[1.0]), PlayParams::default());
+        let (left, right) = mix(&mut mixer, 1)[0];
+        assert_close(left, std::f32::consts::FRAC_1_SQRT_2);
+        assert_close(right, std::f32::consts::FRAC_1_SQRT_2);
+    }
+
+    #[test]
+    fn voice_bus_and_master_volumes_multiply() {
+        let mut mixer = Mixer::default();
+        mixer.bus_volumes[Bus::Music.index()] = 0.5;
+        mixer.master_volume = 0.5;
+        play(&mut mixer, &clip(&[1.0]), PlayParams { bus: Bus::Music, volume: 0.8, pan: -1.0, ..Default::default() });
+        play(&mut mixer, &clip(&[1.0]), PlayParams { bus: Bus::Sfx, volume: 0.8, pan: 1.0, ..Default::default() });
+        let (left, right) = mix(&mut mixer, 1)[0];
+        assert_close(left, 0.8 * 0.5 * 0.5);
+        assert_close(right, 0.8 * 0.5);
+    }
+
+    #[test]
+    fn wav_output_records_what_was_mixed() {
+        let path = std::env::temp_dir().join(format!("audio_test_{}.wav", std::process::id()));
+        {
+            let mut audio = Audio::new(Output::Wav(path.to_string_lossy().into_owned()));
+            assert_eq!(audio.output_name(), "wav");
+            audio.play(&clip(&[0.5; 1000]), PlayParams { pan: -1.0, ..Default::default() });
+            // 6000 frames, the clip ends after 1000
+            audio.update(0.125);
+            assert_eq!(audio.voice_count(), 0);
+        }
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().channels, 2);
+        assert_eq!(reader.spec().sample_rate, SAMPLE_RATE);
+        let samples: Vec<i16> = reader.samples::<i16>().map(|sample| sample.unwrap()).collect();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(samples.len(), 6000 * 2);
+        assert_eq!(samples[..2], [(0.5 * i16::MAX as f32) as i16, 0]);
+        assert_eq!(samples[999 * 2], (0.5 * i16::MAX as f32) as i16);
+        assert!(samples[1000 * 2..].iter().all(|&sample| sample == 0));
+    }
+
+    fn spatial(attenuation: Attenuation, min_distance: f32, max_distance: f32, rolloff: f32) -> Spatial {
+        Spatial { attenuation, min_distance, max_distance, rolloff, ..Default::default() }
+    }
+
+    #[test]
+    fn attenuation_models() {
+        let none = spatial(Attenuation::None, 1.0, 10.0, 1.0);
+        assert_close(none.gain(100.0), 1.0);
+
+        let inverse = spatial(Attenuation::Inverse, 1.0, 50.0, 1.0);
+        assert_close(inverse.gain(0.5), 1.0);
+        assert_close(inverse.gain(1.0), 1.0);
+        assert_close(inverse.gain(2.0), 0.5);
+        assert_close(inverse.gain(50.0), 0.02);
+        // no quieter past max_distance
+        assert_close(inverse.gain(500.0), 0.02);
+
+        let linear = spatial(Attenuation::Linear, 1.0, 11.0, 1.0);
+        assert_close(linear.gain(1.0), 1.0);
+        assert_close(linear.gain(6.0), 0.5);
+        assert_close(linear.gain(11.0), 0.0);
+        assert_close(linear.gain(20.0), 0.0);
+
+        let exponential = spatial(Attenuation::Exponential, 1.0, 10.0, 2.0);
+        assert_close(exponential.gain(0.1), 1.0);
+        assert_close(exponential.gain(2.0), 0.25);
+        assert_close(exponential.gain(10.0), 0.01);
+        assert_close(exponential.gain(30.0), 0.01);
+    }
+
+    #[test]
+    fn pan_follows_the_side_of_the_listener() {
+        // the default listener looks down -z with +y up, +x is to its right
+        let listener = Listener::default();
+        let at = |x: f32, z: f32| spatialize(&listener, &Spatial { position: Vector3::new(x, 0.0, z), ..Default::default() }, 343.0, 1.0).1;
+        assert_close(at(5.0, 0.0), 1.0);
+        assert_close(at(-5.0, 0.0), -1.0);
+        assert_close(at(0.0, -5.0), 0.0);
+        assert!(at(3.0, -3.0) > 0.0 && at(3.0, -3.0) < 1.0);
+
+        // a sound to the right only comes out of the right channel
+        let mut mixer = Mixer::default();
+        let params = PlayParams { spatial: Some(Spatial { position: Vector3::new(2.0, 0.0, 0.0), ..Default::default() }), ..Default::default() };
+        play(&mut mixer, &clip(&[1.0]), params);
+        let (left, right) = mix(&mut mixer, 1)[0];
+        assert!(left.abs() < 1e-5);
+        // inverse attenuation at distance 2
+        assert_close(right, 0.5);
+    }
+
+    #[test]
+    fn doppler_rises_when_closing_in() {
+        let listener = Listener::default();
+        let source = |velocity: Vector3<f32>| Spatial { position: Vector3::new(0.0, 0.0, -10.0), velocity, ..Default::default() };
+        let pitch = |listener: &Listener, spatial: &Spatial, factor: f32| spatialize(listener, spatial, 343.0, factor).2;
+
+        let towards = source(Vector3::new(0.0, 0.0, 20.0));
+        let away = source(Vector3::new(0.0, 0.0, -20.0));
+        let passing = source(Vector3::new(20.0, 0.0, 0.0));
+        assert!(pitch(&listener, &towards, 1.0) > 1.0);
+        assert!(pitch(&listener, &away, 1.0) < 1.0);
+        assert_close(pitch(&listener, &passing, 1.0), 1.0);
+        assert_close(pitch(&listener, &towards, 1.0), 343.0 / (343.0 - 20.0));
+
+        // the listener moving towards a still source
+        let moving = Listener { velocity: Vector3::new(0.0, 0.0, -20.0), ..listener };
+        assert_close(pitch(&moving, &source(Vector3::zero()), 1.0), (343.0 + 20.0) / 343.0);
+
+        // factor 0 turns it off
+        assert_close(pitch(&listener, &towards, 0.0), 1.0);
+    }
+}
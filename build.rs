@@ -0,0 +1,6 @@
+fn main() {
+    // intel_tex_2 ships prebuilt C++ kernels (used by the cook binary) that do not link the C++ runtime themselves
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("linux") {
+        println!("cargo:rustc-link-lib=stdc++");
+    }
+}
@@ -0,0 +1,117 @@
+use cgmath::{Matrix4, Point3, Vector3};
+
+// The coordinate system in Wgpu is based on DirectX and Metal's coordinate systems.
+// That means that in normalized device coordinates the x axis and y axis are in the range of -1.0 to +1.0,
+// and the z axis is 0.0 to +1.0. The cgmath crate (as well as most game math crates) is built for
+// OpenGL's coordinate system. This matrix will scale and translate our scene from OpenGL's
+// coordinate system to WGPU's.
+#[rustfmt::skip]
+pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+pub struct Camera {
+    pub eye: Point3<f32>,
+    pub target: Point3<f32>,
+    pub up: Vector3<f32>,
+    pub aspect: f32,
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+impl Camera {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            // position the camera 2 units back and a bit up, so the ground is visible
+            // +z is out of the screen
+            eye: (0.0, 0.6, 2.2).into(),
+            // have it look at the origin
+            target: (0.0, 0.0, 0.0).into(),
+            // which way is "up"
+            up: cgmath::Vector3::unit_y(),
+            aspect: width as f32 / height.max(1) as f32,
+            fovy: 45.0,
+            znear: 0.1,
+            zfar: 100.0,
+        }
+    }
+
+    pub fn build_view_matrix(&self) -> Matrix4<f32> {
+        // The view matrix moves the world to be at the position and rotation of the camera.
+        Matrix4::look_at_rh(self.eye, self.target, self.up)
+    }
+
+    pub fn build_projection_matrix(&self) -> Matrix4<f32> {
+        // The proj matrix warps the scene to give the effect of depth.
+        OPENGL_TO_WGPU_MATRIX * cgmath::perspective(cgmath::Deg(self.fovy), self.aspect, self.znear, self.zfar)
+    }
+
+    pub fn build_view_projection_matrix(&self) -> Matrix4<f32> {
+        self.build_projection_matrix() * self.build_view_matrix()
+    }
+
+    pub fn resize(&mut self, width: u32, height: u32) {
+        self.aspect = width as f32 / height.max(1) as f32;
+    }
+}
+
+// We need this for Rust to store our data correctly for the shaders
+#[repr(C)]
+// This is so we can store this in a buffer
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct CameraUniform {
+    // vec4 instead of vec3 because of the 16 byte uniform alignment
+    view_position: [f32; 4],
+    // We can't use cgmath with bytemuck directly, so we'll have
+    // to convert the Matrix4 into a 4x4 f32 array
+    view_proj: [[f32; 4]; 4],
+    // world -> view space, the shadows pick their cascade by view depth
+    view: [[f32; 4]; 4],
+    // clip -> view space, the skybox turns screen positions back into view rays
+    inv_proj: [[f32; 4]; 4],
+}
+
+impl CameraUniform {
+    pub fn new() -> Self {
+        use cgmath::SquareMatrix;
+        Self {
+            view_position: [0.0; 4],
+            view_proj: cgmath::Matrix4::identity().into(),
+            view: cgmath::Matrix4::identity().into(),
+            inv_proj: cgmath::Matrix4::identity().into(),
+        }
+    }
+
+    pub fn update_view_proj(&mut self, camera: &Camera) {
+        // w = 1.0 because it is a point
+        self.view_position = camera.eye.to_homogeneous().into();
+        self.view_proj = camera.build_view_projection_matrix().into();
+        self.view = camera.build_view_matrix().into();
+        // a perspective matrix is always invertible
+        use cgmath::SquareMatrix;
+        self.inv_proj = camera.build_projection_matrix().invert().unwrap().into();
+    }
+}
+
+pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                // the fragment shader needs view_position for specular highlights
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }
+        ],
+        label: Some("camera_bind_group_layout"),
+    })
+}
@@ -0,0 +1,280 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+
+// FRAME CAPTURE
+// Copies the finished surface texture into a mappable buffer and saves it as png.
+//   screenshot - the next frame, once
+//   sequence   - every frame into numbered files, for a video:
+//                ffmpeg -framerate 60 -i capture/<session>/frame_%06d.png trailer.mp4
+// Rows in the buffer are padded to COPY_BYTES_PER_ROW_ALIGNMENT (256 bytes), the padding is cut off on read.
+// The buffer is mapped after the submit and read a frame or two later, the png encoding
+// runs on a writer thread. The surface needs COPY_SRC usage for this, see State::new.
+
+// frames waiting for the gpu, a sequence blocks on the oldest one when there are more
+const MAX_IN_FLIGHT: usize = 3;
+// images waiting for the writer thread, sending blocks when it falls behind
+const WRITER_QUEUE: usize = 8;
+
+struct Sequence {
+    dir: PathBuf,
+    next_frame: u32,
+    // the frames are captured at this rate, whatever the real frame time is
+    fps: u32,
+}
+
+// The buffer of one frame, created before the render graph runs, copied into by the capture pass.
+pub struct CaptureTarget {
+    buffer: wgpu::Buffer,
+    path: PathBuf,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    // swap red and blue on read
+    bgra: bool,
+    // announce in the log, screenshots yes, sequence frames no
+    announce: bool,
+}
+
+struct Pending {
+    target: CaptureTarget,
+    // set by the map_async callback
+    ready: Arc<AtomicBool>,
+}
+
+struct WriteJob {
+    path: PathBuf,
+    image: image::RgbaImage,
+    announce: bool,
+}
+
+pub struct FrameCapture {
+    screenshot: Option<PathBuf>,
+    sequence: Option<Sequence>,
+    // capture with the debug ui on top, or the image underneath it
+    pub include_ui: bool,
+    pending: Vec<Pending>,
+    writer: Option<mpsc::SyncSender<WriteJob>>,
+    // surface can be copied from and has an 8 bit rgba / bgra format
+    supported: bool,
+}
+
+impl FrameCapture {
+    pub fn new(config: &wgpu::SurfaceConfiguration) -> Self {
+        let format_supported = matches!(
+            config.format,
+            wgpu::TextureFormat::Rgba8Unorm
+                | wgpu::TextureFormat::Rgba8UnormSrgb
+                | wgpu::TextureFormat::Bgra8Unorm
+                | wgpu::TextureFormat::Bgra8UnormSrgb
+        );
+        // no file system on the web
+        let supported = !cfg!(target_arch = "wasm32") && format_supported && config.usage.contains(wgpu::TextureUsages::COPY_SRC);
+        if !supported {
+            log::info!("frame capture not available (surface {:?}, usage {:?})", config.format, config.usage);
+        }
+
+        Self {
+            screenshot: None,
+            sequence: None,
+            include_ui: false,
+            pending: Vec::new(),
+            writer: None,
+            supported,
+        }
+    }
+
+    pub fn is_supported(&self) -> bool {
+        self.supported
+    }
+
+    // screenshots/screenshot_<unix ms>.png
+    pub fn screenshot(&mut self) {
+        let path = Path::new("screenshots").join(format!("screenshot_{}.png", unix_millis()));
+        self.screenshot_to(path);
+    }
+
+    pub fn screenshot_to(&mut self, path: impl Into<PathBuf>) {
+        if !self.supported {
+            log::warn!("screenshot ignored, frame capture not available");
+            return;
+        }
+        self.screenshot = Some(path.into());
+    }
+
+    // dir/frame_000000.png, frame_000001.png ... until stop_sequence()
+    pub fn start_sequence(&mut self, dir: impl Into<PathBuf>, fps: u32) {
+        if !self.supported {
+            log::warn!("sequence ignored, frame capture not available");
+            return;
+        }
+        let dir = dir.into();
+        log::info!("capturing every frame to {} at {} fps", dir.display(), fps);
+        self.sequence = Some(Sequence { dir, next_frame: 0, fps: fps.max(1) });
+    }
+
+    // capture/<unix ms>/
+    pub fn start_default_sequence(&mut self) {
+        self.start_sequence(Path::new("capture").join(unix_millis().to_string()), 60);
+    }
+
+    pub fn stop_sequence(&mut self) {
+        if let Some(sequence) = self.sequence.take() {
+            log::info!("captured {} frames to {}", sequence.next_frame, sequence.dir.display());
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.sequence.is_some()
+    }
+
+    pub fn sequence_frames(&self) -> Option<u32> {
+        self.sequence.as_ref().map(|sequence| sequence.next_frame)
+    }
+
+    // While recording, the frames should advance the scene by this instead of the real frame time,
+    // so the video plays at the right speed however slow the capture is.
+    pub fn fixed_dt(&self) -> Option<f32> {
+        self.sequence.as_ref().map(|sequence| 1.0 / sequence.fps as f32)
+    }
+
+    // Before the render graph: the buffer for this frame, when anything wants it.
+    pub fn next_frame(&mut self, device: &wgpu::Device, texture: &wgpu::Texture) -> Option<CaptureTarget> {
+        if !self.supported {
+            return None;
+        }
+        let (path, announce) = if let Some(path) = self.screenshot.take() {
+            (path, true)
+        } else if let Some(sequence) = &mut self.sequence {
+            let path = sequence.dir.join(format!("frame_{:06}.png", sequence.next_frame));
+            sequence.next_frame += 1;
+            (path, false)
+        } else {
+            return None;
+        };
+
+        let (width, height) = (texture.width(), texture.height());
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (width * 4).div_ceil(align) * align;
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("frame capture buffer"),
+            size: padded_bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        Some(CaptureTarget {
+            buffer,
+            path,
+            width,
+            height,
+            padded_bytes_per_row,
+            bgra: matches!(texture.format(), wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb),
+            announce,
+        })
+    }
+
+    // After queue.submit: start mapping the copy of this frame.
+    pub fn submitted(&mut self, target: CaptureTarget) {
+        let ready = Arc::new(AtomicBool::new(false));
+        let flag = ready.clone();
+        target.buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            if result.is_ok() {
+                flag.store(true, Ordering::Release);
+            }
+        });
+        self.pending.push(Pending { target, ready });
+    }
+
+    // Once per frame: hands every finished copy to the writer thread.
+    pub fn poll(&mut self, device: &wgpu::Device) {
+        if self.pending.is_empty() {
+            return;
+        }
+        // a sequence must not drop frames, so it waits for the gpu instead of piling up buffers
+        let poll = if self.pending.len() > MAX_IN_FLIGHT { wgpu::PollType::wait_indefinitely() } else { wgpu::PollType::Poll };
+        let _ = device.poll(poll);
+
+        let (ready, waiting): (Vec<Pending>, Vec<Pending>) =
+            std::mem::take(&mut self.pending).into_iter().partition(|pending| pending.ready.load(Ordering::Acquire));
+        self.pending = waiting;
+
+        for Pending { target, .. } in ready {
+            let image = read_image(&target);
+            target.buffer.unmap();
+            self.write(WriteJob { path: target.path, image, announce: target.announce });
+        }
+    }
+
+    fn write(&mut self, job: WriteJob) {
+        let writer = self.writer.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::sync_channel::<WriteJob>(WRITER_QUEUE);
+            std::thread::spawn(move || {
+                for job in receiver {
+                    if let Some(dir) = job.path.parent() {
+                        let _ = std::fs::create_dir_all(dir);
+                    }
+                    match job.image.save(&job.path) {
+                        Ok(()) if job.announce => log::info!("saved {}", job.path.display()),
+                        Ok(()) => {}
+                        Err(e) => log::error!("could not save {}: {}", job.path.display(), e),
+                    }
+                }
+            });
+            sender
+        });
+        if writer.send(job).is_err() {
+            log::error!("frame capture writer thread is gone");
+            self.writer = None;
+        }
+    }
+}
+
+impl CaptureTarget {
+    // The capture pass: the whole surface texture into the buffer.
+    pub fn copy(&self, encoder: &mut wgpu::CommandEncoder, texture: &wgpu::Texture) {
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::TexelCopyBufferInfo {
+                buffer: &self.buffer,
+                layout: wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(self.padded_bytes_per_row),
+                    rows_per_image: Some(self.height),
+                },
+            },
+            wgpu::Extent3d {
+                width: self.width,
+                height: self.height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
+
+// Mapped buffer -> tightly packed rgba, the texture format was checked in FrameCapture::new.
+fn read_image(target: &CaptureTarget) -> image::RgbaImage {
+    let data = target.buffer.slice(..).get_mapped_range();
+    let row_bytes = (target.width * 4) as usize;
+    let mut pixels = Vec::with_capacity(row_bytes * target.height as usize);
+    for row in data.chunks(target.padded_bytes_per_row as usize) {
+        pixels.extend_from_slice(&row[..row_bytes]);
+    }
+    drop(data);
+
+    for pixel in pixels.chunks_exact_mut(4) {
+        if target.bgra {
+            pixel.swap(0, 2);
+        }
+        // the window is opaque, whatever the passes left in alpha
+        pixel[3] = 255;
+    }
+    image::RgbaImage::from_raw(target.width, target.height, pixels).unwrap()
+}
+
+fn unix_millis() -> u128 {
+    web_time::SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0)
+}
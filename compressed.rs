@@ -0,0 +1,246 @@
+use std::io::Cursor;
+
+use anyhow::*;
+use wgpu::{AstcBlock, AstcChannel, TextureFormat};
+
+// COMPRESSED TEXTURES
+// KTX2, DDS and Basis Universal files keep their block compressed payload (BC, ETC2, ASTC) on the
+// gpu, 4 to 8 times smaller than Rgba8UnormSrgb. What the device can sample depends on
+// Features::TEXTURE_COMPRESSION_BC / _ETC2 / _ASTC:
+//   KTX2, DDS - uploaded as they are when the format is supported, else level 0 is decoded to rgba
+//   Basis     - transcoded on the loader thread to the best supported format: BC7, ASTC 4x4, ETC2, rgba
+// Anything else (png, jpg ...) goes through the image crate as before.
+// Everything here runs on the asset loader threads, see assets.rs.
+
+pub enum TextureData {
+    Rgba(image::RgbaImage),
+    Compressed(CompressedImage),
+}
+
+// the mip chain of one 2d image, level 0 first
+pub struct CompressedImage {
+    pub format: TextureFormat,
+    pub width: u32,
+    pub height: u32,
+    pub levels: Vec<Vec<u8>>,
+}
+
+const KTX2_MAGIC: &[u8] = &[0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+const DDS_MAGIC: &[u8] = b"DDS ";
+const BASIS_MAGIC: &[u8] = b"sB";
+
+// `srgb` = color data (base color, emissive), picks the srgb variant where the file does not say.
+// Data maps (normal, metallic-roughness) pass false and are read linear even when the file says srgb,
+// the cooker writes every texture as srgb.
+pub fn decode(bytes: &[u8], features: wgpu::Features, srgb: bool) -> Result<TextureData> {
+    let mut image = if bytes.starts_with(KTX2_MAGIC) {
+        load_ktx2(bytes)?
+    } else if bytes.starts_with(DDS_MAGIC) {
+        load_dds(bytes, srgb)?
+    } else if bytes.starts_with(BASIS_MAGIC) {
+        return transcode_basis(bytes, features, srgb);
+    } else {
+        return Ok(TextureData::Rgba(image::load_from_memory(bytes)?.to_rgba8()));
+    };
+    if !srgb {
+        image.format = image.format.remove_srgb_suffix();
+    }
+
+    if supported(image.format, image.width, image.height, features) {
+        return Ok(TextureData::Compressed(image));
+    }
+    log::warn!("{:?} is not supported by the device, decoding to rgba", image.format);
+    Ok(TextureData::Rgba(decode_rgba(&image)?))
+}
+
+// the device can sample it, and wgpu takes the size (whole blocks at level 0)
+fn supported(format: TextureFormat, width: u32, height: u32, features: wgpu::Features) -> bool {
+    let (block_width, block_height) = format.block_dimensions();
+    features.contains(format.required_features()) && width.is_multiple_of(block_width) && height.is_multiple_of(block_height)
+}
+
+// bytes of one mip level
+fn level_size(format: TextureFormat, width: u32, height: u32, level: u32) -> usize {
+    let (block_width, block_height) = format.block_dimensions();
+    let width = (width >> level).max(1).div_ceil(block_width);
+    let height = (height >> level).max(1).div_ceil(block_height);
+    (width * height * format.block_copy_size(None).unwrap_or(4)) as usize
+}
+
+// KTX2
+
+fn load_ktx2(bytes: &[u8]) -> Result<CompressedImage> {
+    let reader = ktx2::Reader::new(bytes).map_err(|e| anyhow!("ktx2: {:?}", e))?;
+    let header = reader.header();
+    ensure!(header.pixel_depth <= 1 && header.layer_count <= 1 && header.face_count == 1, "ktx2: only single 2d images are supported");
+    let format = match header.format {
+        Some(format) => ktx2_format(format).with_context(|| format!("ktx2: unsupported format {:?}", format))?,
+        // BasisLZ / UASTC payloads have no vk format
+        None => bail!("ktx2: basis supercompressed files are not supported, use a .basis file"),
+    };
+
+    let mut levels = Vec::new();
+    for (i, level) in reader.levels().enumerate() {
+        let data = match header.supercompression_scheme {
+            None => level.data.to_vec(),
+            Some(ktx2::SupercompressionScheme::Zstandard) => zstd::bulk::decompress(level.data, level.uncompressed_byte_length as usize)?,
+            Some(scheme) => bail!("ktx2: unsupported supercompression {:?}", scheme),
+        };
+        ensure!(data.len() >= level_size(format, header.pixel_width, header.pixel_height, i as u32), "ktx2: level {} is too short", i);
+        levels.push(data);
+    }
+    ensure!(!levels.is_empty(), "ktx2: no image data");
+
+    Ok(CompressedImage { format, width: header.pixel_width, height: header.pixel_height.max(1), levels })
+}
+
+fn ktx2_format(format: ktx2::Format) -> Option<TextureFormat> {
+    use ktx2::Format as K;
+    Some(match format {
+        K::R8G8B8A8_UNORM => TextureFormat::Rgba8Unorm,
+        K::R8G8B8A8_SRGB => TextureFormat::Rgba8UnormSrgb,
+        K::BC1_RGBA_UNORM_BLOCK => TextureFormat::Bc1RgbaUnorm,
+        K::BC1_RGBA_SRGB_BLOCK => TextureFormat::Bc1RgbaUnormSrgb,
+        K::BC3_UNORM_BLOCK => TextureFormat::Bc3RgbaUnorm,
+        K::BC3_SRGB_BLOCK => TextureFormat::Bc3RgbaUnormSrgb,
+        K::BC4_UNORM_BLOCK => TextureFormat::Bc4RUnorm,
+        K::BC5_UNORM_BLOCK => TextureFormat::Bc5RgUnorm,
+        K::BC7_UNORM_BLOCK => TextureFormat::Bc7RgbaUnorm,
+        K::BC7_SRGB_BLOCK => TextureFormat::Bc7RgbaUnormSrgb,
+        K::ETC2_R8G8B8_UNORM_BLOCK => TextureFormat::Etc2Rgb8Unorm,
+        K::ETC2_R8G8B8_SRGB_BLOCK => TextureFormat::Etc2Rgb8UnormSrgb,
+        K::ETC2_R8G8B8A8_UNORM_BLOCK => TextureFormat::Etc2Rgba8Unorm,
+        K::ETC2_R8G8B8A8_SRGB_BLOCK => TextureFormat::Etc2Rgba8UnormSrgb,
+        K::ASTC_4x4_UNORM_BLOCK => TextureFormat::Astc { block: AstcBlock::B4x4, channel: AstcChannel::Unorm },
+        K::ASTC_4x4_SRGB_BLOCK => TextureFormat::Astc { block: AstcBlock::B4x4, channel: AstcChannel::UnormSrgb },
+        _ => return None,
+    })
+}
+
+// DDS
+
+fn load_dds(bytes: &[u8], srgb: bool) -> Result<CompressedImage> {
+    let dds = ddsfile::Dds::read(&mut Cursor::new(bytes))?;
+    ensure!(dds.get_depth() <= 1 && dds.get_num_array_layers() <= 1, "dds: only single 2d images are supported");
+    let format = if let Some(format) = dds.get_dxgi_format() {
+        dds_dxgi_format(format).with_context(|| format!("dds: unsupported format {:?}", format))?
+    } else {
+        // legacy header, no srgb flag in the file
+        match dds.get_d3d_format() {
+            Some(ddsfile::D3DFormat::DXT1) if srgb => TextureFormat::Bc1RgbaUnormSrgb,
+            Some(ddsfile::D3DFormat::DXT1) => TextureFormat::Bc1RgbaUnorm,
+            Some(ddsfile::D3DFormat::DXT5) if srgb => TextureFormat::Bc3RgbaUnormSrgb,
+            Some(ddsfile::D3DFormat::DXT5) => TextureFormat::Bc3RgbaUnorm,
+            Some(ddsfile::D3DFormat::A8B8G8R8) if srgb => TextureFormat::Rgba8UnormSrgb,
+            Some(ddsfile::D3DFormat::A8B8G8R8) => TextureFormat::Rgba8Unorm,
+            other => bail!("dds: unsupported format {:?}", other),
+        }
+    };
+
+    let (width, height) = (dds.get_width(), dds.get_height());
+    // the levels are packed one after the other
+    let mut levels = Vec::new();
+    let mut offset = 0;
+    for level in 0..dds.get_num_mipmap_levels().max(1) {
+        let size = level_size(format, width, height, level);
+        ensure!(offset + size <= dds.data.len(), "dds: level {} is too short", level);
+        levels.push(dds.data[offset..offset + size].to_vec());
+        offset += size;
+    }
+
+    Ok(CompressedImage { format, width, height, levels })
+}
+
+fn dds_dxgi_format(format: ddsfile::DxgiFormat) -> Option<TextureFormat> {
+    use ddsfile::DxgiFormat as D;
+    Some(match format {
+        D::R8G8B8A8_UNorm => TextureFormat::Rgba8Unorm,
+        D::R8G8B8A8_UNorm_sRGB => TextureFormat::Rgba8UnormSrgb,
+        D::BC1_UNorm => TextureFormat::Bc1RgbaUnorm,
+        D::BC1_UNorm_sRGB => TextureFormat::Bc1RgbaUnormSrgb,
+        D::BC3_UNorm => TextureFormat::Bc3RgbaUnorm,
+        D::BC3_UNorm_sRGB => TextureFormat::Bc3RgbaUnormSrgb,
+        D::BC4_UNorm => TextureFormat::Bc4RUnorm,
+        D::BC5_UNorm => TextureFormat::Bc5RgUnorm,
+        D::BC7_UNorm => TextureFormat::Bc7RgbaUnorm,
+        D::BC7_UNorm_sRGB => TextureFormat::Bc7RgbaUnormSrgb,
+        _ => return None,
+    })
+}
+
+// BASIS UNIVERSAL
+
+fn transcode_basis(bytes: &[u8], features: wgpu::Features, srgb: bool) -> Result<TextureData> {
+    use basis_universal::{TranscodeParameters, Transcoder, TranscoderTextureFormat as B};
+
+    basis_universal::transcoder_init();
+    let mut transcoder = Transcoder::new();
+    transcoder.prepare_transcoding(bytes).map_err(|_| anyhow!("basis: invalid file"))?;
+
+    let info = transcoder.image_level_description(bytes, 0, 0).context("basis: no image")?;
+    let (width, height) = (info.original_width, info.original_height);
+
+    // best first, the first one the device samples
+    let channel = if srgb { AstcChannel::UnormSrgb } else { AstcChannel::Unorm };
+    let targets = [
+        (B::BC7_RGBA, if srgb { TextureFormat::Bc7RgbaUnormSrgb } else { TextureFormat::Bc7RgbaUnorm }),
+        (B::ASTC_4x4_RGBA, TextureFormat::Astc { block: AstcBlock::B4x4, channel }),
+        (B::ETC2_RGBA, if srgb { TextureFormat::Etc2Rgba8UnormSrgb } else { TextureFormat::Etc2Rgba8Unorm }),
+    ];
+    let target = targets.into_iter().find(|(_, format)| supported(*format, width, height, features));
+
+    let Some((basis_format, format)) = target else {
+        let rgba = transcoder
+            .transcode_image_level(bytes, B::RGBA32, TranscodeParameters { image_index: 0, level_index: 0, ..Default::default() })
+            .map_err(|e| anyhow!("basis: {:?}", e))?;
+        let image = image::RgbaImage::from_raw(width, height, rgba).context("basis: wrong rgba size")?;
+        return Ok(TextureData::Rgba(image));
+    };
+
+    let level_count = transcoder.image_level_count(bytes, 0);
+    let levels = (0..level_count)
+        .map(|level| {
+            transcoder
+                .transcode_image_level(bytes, basis_format, TranscodeParameters { image_index: 0, level_index: level, ..Default::default() })
+                .map_err(|e| anyhow!("basis: level {}: {:?}", level, e))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    transcoder.end_transcoding();
+
+    Ok(TextureData::Compressed(CompressedImage { format, width, height, levels }))
+}
+
+// FALLBACK
+
+// Level 0 to rgba on the cpu, for payloads the device cannot sample.
+fn decode_rgba(image: &CompressedImage) -> Result<image::RgbaImage> {
+    let (width, height) = (image.width as usize, image.height as usize);
+    let data = &image.levels[0];
+    if matches!(image.format, TextureFormat::Rgba8Unorm | TextureFormat::Rgba8UnormSrgb) {
+        return image::RgbaImage::from_raw(image.width, image.height, data[..width * height * 4].to_vec()).context("wrong rgba size");
+    }
+
+    let mut pixels = vec![0u32; width * height];
+    let result = match image.format {
+        TextureFormat::Bc1RgbaUnorm | TextureFormat::Bc1RgbaUnormSrgb => texture2ddecoder::decode_bc1(data, width, height, &mut pixels),
+        TextureFormat::Bc3RgbaUnorm | TextureFormat::Bc3RgbaUnormSrgb => texture2ddecoder::decode_bc3(data, width, height, &mut pixels),
+        TextureFormat::Bc4RUnorm => texture2ddecoder::decode_bc4(data, width, height, &mut pixels),
+        TextureFormat::Bc5RgUnorm => texture2ddecoder::decode_bc5(data, width, height, &mut pixels),
+        TextureFormat::Bc7RgbaUnorm | TextureFormat::Bc7RgbaUnormSrgb => texture2ddecoder::decode_bc7(data, width, height, &mut pixels),
+        TextureFormat::Etc2Rgb8Unorm | TextureFormat::Etc2Rgb8UnormSrgb => texture2ddecoder::decode_etc2_rgb(data, width, height, &mut pixels),
+        TextureFormat::Etc2Rgba8Unorm | TextureFormat::Etc2Rgba8UnormSrgb => texture2ddecoder::decode_etc2_rgba8(data, width, height, &mut pixels),
+        TextureFormat::Astc { block: AstcBlock::B4x4, .. } => texture2ddecoder::decode_astc(data, width, height, 4, 4, &mut pixels),
+        other => bail!("no cpu decoder for {:?}", other),
+    };
+    result.map_err(|e| anyhow!("decoding {:?}: {}", image.format, e))?;
+
+    // the decoder writes 0xAARRGGBB
+    let rgba = pixels
+        .iter()
+        .flat_map(|&pixel| {
+            let [b, g, r, a] = pixel.to_le_bytes();
+            [r, g, b, a]
+        })
+        .collect();
+    image::RgbaImage::from_raw(image.width, image.height, rgba).context("wrong rgba size")
+}
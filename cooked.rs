@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use anyhow::*;
+use serde::{Deserialize, Serialize};
+
+// COOKED ASSETS
+// What the cook binary (src/bin/cook.rs) writes and the engine reads, shared by both:
+//   .mesh         - binary mesh, see MESH FORMAT
+//   manifest.ron  - source path -> cooked file, with content hashes
+// The cooked directory mirrors the source directory: textures/tree.png becomes textures/tree.ktx2,
+// models/rock.obj becomes models/rock.mesh, everything else is copied. vfs::mount_defaults mounts
+// the cooked directory when it has a manifest and sends reads of a source path to its cooked file,
+// so scene files keep naming the sources.
+// No engine types in here, the cook binary includes this file on its own.
+
+pub const COOKED_DIR: &str = "cooked";
+pub const MANIFEST_FILE: &str = "manifest.ron";
+
+// bumped when the cook output changes, everything gets cooked again
+pub const COOK_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Manifest {
+    pub version: u32,
+    // by source path the way the engine loads it: relative to the working directory, '/' separated
+    pub entries: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    // relative to the cooked directory
+    pub output: String,
+    // blake3, hex
+    pub source_hash: String,
+    pub output_hash: String,
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(ron::from_str(text)?)
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        Ok(ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?)
+    }
+}
+
+pub fn hash(bytes: &[u8]) -> String {
+    blake3::hash(bytes).to_hex().to_string()
+}
+
+// MESH FORMAT
+// little endian
+//   "MSH1", u32 vertex count, u32 index count
+//   vertices: position xyz, tex_coords uv, normal xyz, f32 each (the engine's Vertex layout)
+//   indices: u16
+// Read straight into vertex and index buffers, no parsing beyond the header.
+
+const MESH_MAGIC: &[u8; 4] = b"MSH1";
+
+#[derive(Clone, Debug, Default)]
+pub struct MeshFile {
+    // position, tex_coords, normal
+    pub vertices: Vec<[f32; 8]>,
+    pub indices: Vec<u16>,
+}
+
+impl MeshFile {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(12 + self.vertices.len() * 32 + self.indices.len() * 2);
+        bytes.extend_from_slice(MESH_MAGIC);
+        bytes.extend_from_slice(&(self.vertices.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.indices.len() as u32).to_le_bytes());
+        for value in self.vertices.iter().flatten() {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        for index in &self.indices {
+            bytes.extend_from_slice(&index.to_le_bytes());
+        }
+        bytes
+    }
+
+    pub fn is_mesh(bytes: &[u8]) -> bool {
+        bytes.starts_with(MESH_MAGIC)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= 12 && &bytes[..4] == MESH_MAGIC, "not a cooked mesh");
+        let vertex_count = u32::from_le_bytes(bytes[4..8].try_into()?) as usize;
+        let index_count = u32::from_le_bytes(bytes[8..12].try_into()?) as usize;
+        // the counts come from the file, a broken one must not overflow (usize is 32 bit on the web)
+        let vertex_size = vertex_count.checked_mul(32).context("mesh: vertex count too large")?;
+        let (vertex_bytes, index_bytes) = bytes[12..].split_at_checked(vertex_size).context("mesh: vertices cut off")?;
+        ensure!(
+            index_count.checked_mul(2) == Some(index_bytes.len()),
+            "mesh: {} index bytes for {} indices",
+            index_bytes.len(),
+            index_count
+        );
+
+        let vertices = vertex_bytes
+            .chunks_exact(32)
+            .map(|vertex| std::array::from_fn(|i| f32::from_le_bytes(vertex[i * 4..i * 4 + 4].try_into().unwrap())))
+            .collect();
+        let indices = index_bytes
+            .chunks_exact(2)
+            .map(|index| u16::from_le_bytes([index[0], index[1]]))
+            .collect::<Vec<_>>();
+        // Mesh::new looks the vertices up by index
+        if let Some(&index) = indices.iter().find(|&&index| index as usize >= vertex_count) {
+            bail!("mesh: index {} out of range, {} vertices", index, vertex_count);
+        }
+        Ok(Self { vertices, indices })
+    }
+
+    // Wavefront .obj, every object merged into one mesh. Materials come from the scene file, .mtl is ignored.
+    pub fn from_obj(bytes: &[u8]) -> Result<Self> {
+        let (models, _) = tobj::load_obj_buf(&mut Cursor::new(bytes), &tobj::GPU_LOAD_OPTIONS, |_| Err(tobj::LoadError::OpenFileFailed))?;
+
+        let mut mesh = Self::default();
+        for model in models {
+            let obj = model.mesh;
+            let base = mesh.vertices.len();
+            for i in 0..obj.positions.len() / 3 {
+                let normal = if obj.normals.is_empty() {
+                    [0.0, 1.0, 0.0]
+                } else {
+                    [obj.normals[i * 3], obj.normals[i * 3 + 1], obj.normals[i * 3 + 2]]
+                };
+                // obj v goes up, texture rows go down
+                let tex_coords = if obj.texcoords.is_empty() {
+                    [0.0, 0.0]
+                } else {
+                    [obj.texcoords[i * 2], 1.0 - obj.texcoords[i * 2 + 1]]
+                };
+                let position = [obj.positions[i * 3], obj.positions[i * 3 + 1], obj.positions[i * 3 + 2]];
+                mesh.push_vertex(position, tex_coords, normal);
+            }
+            mesh.push_indices(base, obj.indices.iter().map(|&index| index as usize))?;
+        }
+        Ok(mesh)
+    }
+
+    pub fn push_vertex(&mut self, position: [f32; 3], tex_coords: [f32; 2], normal: [f32; 3]) {
+        let [x, y, z] = position;
+        let [u, v] = tex_coords;
+        let [nx, ny, nz] = normal;
+        self.vertices.push([x, y, z, u, v, nx, ny, nz]);
+    }
+
+    // indices relative to `base`, the first vertex of the part they belong to
+    pub fn push_indices(&mut self, base: usize, indices: impl Iterator<Item = usize>) -> Result<()> {
+        ensure!(self.vertices.len() <= u16::MAX as usize + 1, "more vertices than 16 bit indices can address");
+        for index in indices {
+            let index = base + index;
+            ensure!(index < self.vertices.len(), "index {} out of range", index);
+            self.indices.push(index as u16);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn triangle() -> MeshFile {
+        let mut mesh = MeshFile::default();
+        mesh.push_vertex([0.0, 0.0, 0.0], [0.0, 0.0], [0.0, 0.0, 1.0]);
+        mesh.push_vertex([1.0, 0.0, 0.0], [1.0, 0.0], [0.0, 0.0, 1.0]);
+        mesh.push_vertex([0.0, 1.0, 0.0], [0.0, 1.0], [0.0, 0.0, 1.0]);
+        mesh.push_indices(0, 0..3).unwrap();
+        mesh
+    }
+
+    #[test]
+    fn mesh_round_trip() {
+        let mesh = triangle();
+        let decoded = MeshFile::decode(&mesh.encode()).unwrap();
+        assert_eq!(decoded.vertices, mesh.vertices);
+        assert_eq!(decoded.indices, mesh.indices);
+    }
+
+    #[test]
+    fn broken_meshes_are_rejected() {
+        let bytes = triangle().encode();
+        assert!(MeshFile::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(MeshFile::decode(&bytes[..20]).is_err());
+
+        // a vertex count that overflows the byte size
+        let mut broken = bytes.clone();
+        broken[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(MeshFile::decode(&broken).is_err());
+
+        // the last index pointing past the three vertices
+        let mut broken = bytes;
+        let last = broken.len() - 2;
+        broken[last..].copy_from_slice(&3u16.to_le_bytes());
+        assert!(MeshFile::decode(&broken).is_err());
+    }
+}
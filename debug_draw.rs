@@ -0,0 +1,361 @@
+use cgmath::{InnerSpace, Point3, Vector3};
+use wgpu::util::DeviceExt;
+
+// DEBUG DRAW
+// Immediate lines for bounds, axes, light gizmos, physics shapes...
+// Call the shape functions anywhere during update, they stay on screen for `duration` seconds
+// (0 = this frame only). Rendered after post processing straight onto the surface,
+// depth tested against the scene depth or, with depth_test = false, on top of everything.
+
+// lines start with room for this many, the buffer doubles when it is not enough
+const INITIAL_CAPACITY: usize = 4096;
+const CIRCLE_SEGMENTS: usize = 32;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct LineVertex {
+    position: [f32; 3],
+    color: [f32; 4],
+}
+
+impl LineVertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x4];
+
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugDrawUniform {
+    // 1 when the target is not srgb and the shader has to encode
+    encode_srgb: u32,
+    _padding: [u32; 3],
+}
+
+struct Line {
+    from: Point3<f32>,
+    to: Point3<f32>,
+    color: [f32; 4],
+    remaining: f32,
+    depth_test: bool,
+}
+
+pub struct DebugDraw {
+    depth_pipeline: wgpu::RenderPipeline,
+    overlay_pipeline: wgpu::RenderPipeline,
+    params_bind_group: wgpu::BindGroup,
+    vertex_buffer: wgpu::Buffer,
+    // in lines
+    capacity: usize,
+    lines: Vec<Line>,
+    // vertex ranges of the last prepare()
+    depth_vertices: u32,
+    overlay_vertices: u32,
+    pub enabled: bool,
+}
+
+impl DebugDraw {
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        camera_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("debug_draw_bind_group_layout"),
+        });
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("debug draw buffer"),
+            contents: bytemuck::cast_slice(&[DebugDrawUniform {
+                encode_srgb: (!color_format.is_srgb()) as u32,
+                _padding: [0; 3],
+            }]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params_buffer.as_entire_binding(),
+            }],
+            label: Some("debug_draw_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("debug draw shader"),
+            // light.wgsl for the Camera struct at group 1
+            source: wgpu::ShaderSource::Wgsl(concat!(include_str!("light.wgsl"), include_str!("debug_draw.wgsl")).into()),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("debug draw pipeline layout"),
+            bind_group_layouts: &[&params_layout, camera_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = |label: &str, depth_compare: wgpu::CompareFunction| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: Some("vs_main"),
+                    buffers: &[LineVertex::desc()],
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: Some("fs_main"),
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: color_format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::LineList,
+                    ..Default::default()
+                },
+                // both pipelines share the pass with the scene depth attached, only the test differs
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: depth_format,
+                    depth_write_enabled: false,
+                    depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+                cache: None,
+            })
+        };
+        let depth_pipeline = pipeline("debug draw pipeline", wgpu::CompareFunction::LessEqual);
+        let overlay_pipeline = pipeline("debug draw overlay pipeline", wgpu::CompareFunction::Always);
+
+        Self {
+            depth_pipeline,
+            overlay_pipeline,
+            params_bind_group,
+            vertex_buffer: create_vertex_buffer(device, INITIAL_CAPACITY),
+            capacity: INITIAL_CAPACITY,
+            lines: Vec::new(),
+            depth_vertices: 0,
+            overlay_vertices: 0,
+            enabled: true,
+        }
+    }
+
+    // Age the lines by `dt` and drop the expired ones. Call once per frame before drawing new ones.
+    pub fn tick(&mut self, dt: f32) {
+        self.lines.retain_mut(|line| {
+            line.remaining -= dt;
+            line.remaining > 0.0
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.lines.clear();
+    }
+
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4], duration: f32, depth_test: bool) {
+        self.lines.push(Line {
+            from,
+            to,
+            color,
+            // a line drawn with duration 0 survives exactly one tick
+            remaining: duration.max(0.0),
+            depth_test,
+        });
+    }
+
+    pub fn aabb(&mut self, min: Point3<f32>, max: Point3<f32>, color: [f32; 4], duration: f32, depth_test: bool) {
+        let corner = |i: usize| {
+            Point3::new(
+                if i & 1 == 0 { min.x } else { max.x },
+                if i & 2 == 0 { min.y } else { max.y },
+                if i & 4 == 0 { min.z } else { max.z },
+            )
+        };
+        // corners whose index differs in one bit share an edge
+        for i in 0..8 {
+            for bit in [1, 2, 4] {
+                if i & bit == 0 {
+                    self.line(corner(i), corner(i | bit), color, duration, depth_test);
+                }
+            }
+        }
+    }
+
+    // three circles, one around every axis
+    pub fn sphere(&mut self, center: Point3<f32>, radius: f32, color: [f32; 4], duration: f32, depth_test: bool) {
+        self.circle(center, Vector3::unit_x(), radius, color, duration, depth_test);
+        self.circle(center, Vector3::unit_y(), radius, color, duration, depth_test);
+        self.circle(center, Vector3::unit_z(), radius, color, duration, depth_test);
+    }
+
+    pub fn circle(&mut self, center: Point3<f32>, normal: Vector3<f32>, radius: f32, color: [f32; 4], duration: f32, depth_test: bool) {
+        let (u, v) = basis(normal);
+        let point = |i: usize| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * std::f32::consts::TAU;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color, duration, depth_test);
+        }
+    }
+
+    // line with a four sided head at `to`, the head is a fifth of the length
+    pub fn arrow(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 4], duration: f32, depth_test: bool) {
+        self.line(from, to, color, duration, depth_test);
+
+        let direction = to - from;
+        let length = direction.magnitude();
+        if length <= f32::EPSILON {
+            return;
+        }
+        let (u, v) = basis(direction);
+        let head = length * 0.2;
+        let base = to - direction / length * head;
+        for side in [u, -u, v, -v] {
+            self.line(to, base + side * head * 0.4, color, duration, depth_test);
+        }
+    }
+
+    // x red, y green, z blue
+    pub fn axes(&mut self, origin: Point3<f32>, size: f32, duration: f32, depth_test: bool) {
+        self.arrow(origin, origin + Vector3::unit_x() * size, [1.0, 0.2, 0.2, 1.0], duration, depth_test);
+        self.arrow(origin, origin + Vector3::unit_y() * size, [0.2, 1.0, 0.2, 1.0], duration, depth_test);
+        self.arrow(origin, origin + Vector3::unit_z() * size, [0.2, 0.4, 1.0, 1.0], duration, depth_test);
+    }
+
+    // `cells` x `cells` squares on the xz plane around `center`
+    pub fn grid(&mut self, center: Point3<f32>, cell_size: f32, cells: u32, color: [f32; 4], duration: f32, depth_test: bool) {
+        let half = cells as f32 * cell_size / 2.0;
+        for i in 0..=cells {
+            let offset = i as f32 * cell_size - half;
+            self.line(
+                center + Vector3::new(offset, 0.0, -half),
+                center + Vector3::new(offset, 0.0, half),
+                color,
+                duration,
+                depth_test,
+            );
+            self.line(
+                center + Vector3::new(-half, 0.0, offset),
+                center + Vector3::new(half, 0.0, offset),
+                color,
+                duration,
+                depth_test,
+            );
+        }
+    }
+
+    // Upload the lines: depth tested ones first, then the overlay ones.
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let mut vertices: Vec<LineVertex> = Vec::with_capacity(self.lines.len() * 2);
+        for depth_test in [true, false] {
+            for line in self.lines.iter().filter(|line| line.depth_test == depth_test) {
+                vertices.push(LineVertex { position: line.from.into(), color: line.color });
+                vertices.push(LineVertex { position: line.to.into(), color: line.color });
+            }
+            if depth_test {
+                self.depth_vertices = vertices.len() as u32;
+            }
+        }
+        self.overlay_vertices = vertices.len() as u32 - self.depth_vertices;
+
+        if self.lines.len() > self.capacity {
+            self.capacity = self.lines.len().next_power_of_two();
+            self.vertex_buffer = create_vertex_buffer(device, self.capacity);
+        }
+        if !vertices.is_empty() {
+            queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&vertices));
+        }
+    }
+
+    pub fn has_lines(&self) -> bool {
+        self.enabled && self.depth_vertices + self.overlay_vertices > 0
+    }
+
+    pub fn render(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+    ) {
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("debug draw pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                depth_slice: None,
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            // the scene depth, only read
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_bind_group(0, &self.params_bind_group, &[]);
+        render_pass.set_bind_group(1, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+
+        if self.depth_vertices > 0 {
+            render_pass.set_pipeline(&self.depth_pipeline);
+            render_pass.draw(0..self.depth_vertices, 0..1);
+        }
+        if self.overlay_vertices > 0 {
+            render_pass.set_pipeline(&self.overlay_pipeline);
+            render_pass.draw(self.depth_vertices..self.depth_vertices + self.overlay_vertices, 0..1);
+        }
+    }
+}
+
+// two unit vectors perpendicular to `normal` and to each other
+fn basis(normal: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let n = normal.normalize();
+    let helper = if n.y.abs() < 0.99 { Vector3::unit_y() } else { Vector3::unit_x() };
+    let u = n.cross(helper).normalize();
+    let v = n.cross(u);
+    (u, v)
+}
+
+fn create_vertex_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("debug draw vertex buffer"),
+        size: (capacity * 2 * std::mem::size_of::<LineVertex>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
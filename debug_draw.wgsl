@@ -0,0 +1,39 @@
+// Debug lines (debug_draw.rs). light.wgsl is glued in front of this file for the camera.
+
+struct DebugDraw {
+    encode_srgb: u32,
+}
+@group(0) @binding(0)
+var<uniform> debug_draw: DebugDraw;
+
+struct LineInput {
+    @location(0) position: vec3<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+struct LineOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) color: vec4<f32>,
+}
+
+@vertex
+fn vs_main(line: LineInput) -> LineOutput {
+    var out: LineOutput;
+    out.clip_position = camera.view_proj * vec4<f32>(line.position, 1.0);
+    out.color = line.color;
+    return out;
+}
+
+fn linear_to_srgb(c: vec3<f32>) -> vec3<f32> {
+    let low = c * 12.92;
+    let high = 1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, c <= vec3<f32>(0.0031308));
+}
+
+@fragment
+fn fs_main(in: LineOutput) -> @location(0) vec4<f32> {
+    if debug_draw.encode_srgb == 1u {
+        return vec4<f32>(linear_to_srgb(in.color.rgb), in.color.a);
+    }
+    return in.color;
+}
@@ -0,0 +1,332 @@
+use wgpu::util::DeviceExt;
+
+use crate::mesh::WireVertex;
+use crate::scene::{Draw, InstanceRaw};
+
+// DEBUG VIEWS
+// Runs after post processing, on the surface, with the scene depth attached:
+//   Wireframe - triangle edges over the shaded image
+//   Normals / Uvs / Depth - the meshes again with the attribute as color, instead of the image
+//   Overdraw - every fragment adds a bit of brightness, no depth test
+// The wireframe uses PolygonMode::Line when the device has POLYGON_MODE_LINE (not on the web),
+// otherwise it draws the un-indexed mesh copy (built on first use) with barycentric coordinates and shades the edges.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DebugView {
+    Off,
+    Wireframe,
+    Normals,
+    Uvs,
+    Depth,
+    Overdraw,
+}
+
+impl DebugView {
+    pub const ALL: [DebugView; 6] = [
+        DebugView::Off,
+        DebugView::Wireframe,
+        DebugView::Normals,
+        DebugView::Uvs,
+        DebugView::Depth,
+        DebugView::Overdraw,
+    ];
+
+    pub fn next(self) -> Self {
+        let index = Self::ALL.iter().position(|&view| view == self).unwrap();
+        Self::ALL[(index + 1) % Self::ALL.len()]
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct DebugViewUniform {
+    wire_color: [f32; 4],
+    // view depth mapped to black .. white
+    depth_range: [f32; 2],
+    // brightness added per fragment
+    overdraw_step: f32,
+    _padding: f32,
+}
+
+pub struct DebugViews {
+    pub view: DebugView,
+    // use the barycentric wireframe even when polygon mode Line is available
+    pub force_barycentric: bool,
+    pub wire_color: [f32; 4],
+    pub depth_range: [f32; 2],
+    line_pipeline: Option<wgpu::RenderPipeline>,
+    barycentric_pipeline: wgpu::RenderPipeline,
+    normals_pipeline: wgpu::RenderPipeline,
+    uvs_pipeline: wgpu::RenderPipeline,
+    depth_pipeline: wgpu::RenderPipeline,
+    overdraw_pipeline: wgpu::RenderPipeline,
+    params_buffer: wgpu::Buffer,
+    params_bind_group: wgpu::BindGroup,
+}
+
+impl DebugViews {
+    // The feature has to be requested with the device, see required_features in State::new.
+    pub fn supports_line_mode(device: &wgpu::Device) -> bool {
+        device.features().contains(wgpu::Features::POLYGON_MODE_LINE)
+    }
+
+    pub fn new(
+        device: &wgpu::Device,
+        color_format: wgpu::TextureFormat,
+        depth_format: wgpu::TextureFormat,
+        camera_layout: &wgpu::BindGroupLayout,
+        vertex_layout: wgpu::VertexBufferLayout<'static>,
+    ) -> Self {
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("debug_view_bind_group_layout"),
+        });
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("debug view buffer"),
+            contents: bytemuck::cast_slice(&[<DebugViewUniform as bytemuck::Zeroable>::zeroed()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let params_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &params_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: params_buffer.as_entire_binding(),
+            }],
+            label: Some("debug_view_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("debug view shader"),
+            // light.wgsl for the Camera struct at group 1, scene.wgsl for the instance transform
+            source: wgpu::ShaderSource::Wgsl(concat!(include_str!("light.wgsl"), include_str!("scene.wgsl"), include_str!("debug_view.wgsl")).into()),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("debug view pipeline layout"),
+            bind_group_layouts: &[&params_layout, camera_layout],
+            push_constant_ranges: &[],
+        });
+
+        struct Desc<'a> {
+            label: &'a str,
+            vertex_entry: &'a str,
+            fragment_entry: &'a str,
+            vertex_layout: wgpu::VertexBufferLayout<'static>,
+            polygon_mode: wgpu::PolygonMode,
+            blend: wgpu::BlendState,
+            depth_compare: wgpu::CompareFunction,
+        }
+
+        let pipeline = |desc: Desc| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(desc.label),
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: Some(desc.vertex_entry),
+                    buffers: &[desc.vertex_layout, InstanceRaw::desc()],
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: Some(desc.fragment_entry),
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: color_format,
+                        blend: Some(desc.blend),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
+                    compilation_options: wgpu::PipelineCompilationOptions::default(),
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    // overdraw and wireframe should show back faces too
+                    cull_mode: None,
+                    polygon_mode: desc.polygon_mode,
+                    ..Default::default()
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: depth_format,
+                    // the depth is the scene's, never changed here
+                    depth_write_enabled: false,
+                    depth_compare: desc.depth_compare,
+                    stencil: wgpu::StencilState::default(),
+                    // pull the edges a bit towards the camera so they win against their own triangle
+                    bias: wgpu::DepthBiasState {
+                        constant: 0,
+                        slope_scale: -1.0,
+                        clamp: 0.0,
+                    },
+                }),
+                multisample: wgpu::MultisampleState::default(),
+                multiview: None,
+                cache: None,
+            })
+        };
+
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::OVER,
+        };
+
+        let line_pipeline = Self::supports_line_mode(device).then(|| {
+            pipeline(Desc {
+                label: "debug wireframe line pipeline",
+                vertex_entry: "vs_main",
+                fragment_entry: "fs_wire_line",
+                vertex_layout: vertex_layout.clone(),
+                polygon_mode: wgpu::PolygonMode::Line,
+                blend: wgpu::BlendState::ALPHA_BLENDING,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+            })
+        });
+        let barycentric_pipeline = pipeline(Desc {
+            label: "debug wireframe barycentric pipeline",
+            vertex_entry: "vs_wire",
+            fragment_entry: "fs_wire_barycentric",
+            vertex_layout: WireVertex::desc(),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            blend: wgpu::BlendState::ALPHA_BLENDING,
+            depth_compare: wgpu::CompareFunction::LessEqual,
+        });
+        let view_pipeline = |label, fragment_entry| {
+            pipeline(Desc {
+                label,
+                vertex_entry: "vs_main",
+                fragment_entry,
+                vertex_layout: vertex_layout.clone(),
+                polygon_mode: wgpu::PolygonMode::Fill,
+                blend: wgpu::BlendState::REPLACE,
+                // the same triangles that produced the scene depth
+                depth_compare: wgpu::CompareFunction::LessEqual,
+            })
+        };
+        let normals_pipeline = view_pipeline("debug normals pipeline", "fs_normals");
+        let uvs_pipeline = view_pipeline("debug uvs pipeline", "fs_uvs");
+        let depth_pipeline = view_pipeline("debug depth pipeline", "fs_depth");
+        let overdraw_pipeline = pipeline(Desc {
+            label: "debug overdraw pipeline",
+            vertex_entry: "vs_main",
+            fragment_entry: "fs_overdraw",
+            vertex_layout,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            blend: additive,
+            depth_compare: wgpu::CompareFunction::Always,
+        });
+
+        Self {
+            view: DebugView::Off,
+            force_barycentric: false,
+            wire_color: [0.0, 1.0, 0.4, 1.0],
+            depth_range: [0.1, 10.0],
+            line_pipeline,
+            barycentric_pipeline,
+            normals_pipeline,
+            uvs_pipeline,
+            depth_pipeline,
+            overdraw_pipeline,
+            params_buffer,
+            params_bind_group,
+        }
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue) {
+        let uniform = DebugViewUniform {
+            wire_color: self.wire_color,
+            depth_range: self.depth_range,
+            overdraw_step: 0.1,
+            _padding: 0.0,
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.view != DebugView::Off
+    }
+
+    // how the wireframe is drawn right now, for the ui
+    pub fn wireframe_mode(&self) -> &'static str {
+        if self.line_pipeline.is_some() && !self.force_barycentric {
+            "polygon mode line"
+        } else {
+            "barycentric"
+        }
+    }
+
+    pub fn render(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+        depth: &wgpu::TextureView,
+        camera_bind_group: &wgpu::BindGroup,
+        draws: &[Draw],
+    ) {
+        // the wireframe goes over the image, the other views replace it
+        let load = match self.view {
+            DebugView::Wireframe => wgpu::LoadOp::Load,
+            _ => wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+        };
+
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("debug view pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                depth_slice: None,
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations { load, store: wgpu::StoreOp::Store },
+            })],
+            depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                view: depth,
+                depth_ops: Some(wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                }),
+                stencil_ops: None,
+            }),
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+
+        render_pass.set_bind_group(0, &self.params_bind_group, &[]);
+        render_pass.set_bind_group(1, camera_bind_group, &[]);
+
+        let pipeline = match self.view {
+            DebugView::Off => return,
+            DebugView::Wireframe => match (&self.line_pipeline, self.force_barycentric) {
+                (Some(line_pipeline), false) => line_pipeline,
+                _ => {
+                    render_pass.set_pipeline(&self.barycentric_pipeline);
+                    for draw in draws {
+                        render_pass.set_vertex_buffer(1, draw.instance);
+                        draw.mesh.draw_wire(device, &mut render_pass);
+                    }
+                    return;
+                }
+            },
+            DebugView::Normals => &self.normals_pipeline,
+            DebugView::Uvs => &self.uvs_pipeline,
+            DebugView::Depth => &self.depth_pipeline,
+            DebugView::Overdraw => &self.overdraw_pipeline,
+        };
+        render_pass.set_pipeline(pipeline);
+        for draw in draws {
+            render_pass.set_vertex_buffer(1, draw.instance);
+            draw.mesh.draw(&mut render_pass);
+        }
+    }
+}
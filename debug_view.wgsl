@@ -0,0 +1,96 @@
+// Debug views (debug_view.rs). light.wgsl and scene.wgsl are glued in front of this file for the camera
+// and the instance transform.
+
+struct DebugView {
+    wire_color: vec4<f32>,
+    depth_range: vec2<f32>,
+    overdraw_step: f32,
+}
+@group(0) @binding(0)
+var<uniform> debug_view: DebugView;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) view_depth: f32,
+}
+
+@vertex
+fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
+    let world_position = instance_model(instance) * vec4<f32>(model.position, 1.0);
+    var out: VertexOutput;
+    out.clip_position = camera.view_proj * world_position;
+    out.tex_coords = model.tex_coords;
+    out.world_normal = instance_normal(instance) * model.normal;
+    out.view_depth = -(camera.view * world_position).z;
+    return out;
+}
+
+@fragment
+fn fs_wire_line(in: VertexOutput) -> @location(0) vec4<f32> {
+    return debug_view.wire_color;
+}
+
+@fragment
+fn fs_normals(in: VertexOutput) -> @location(0) vec4<f32> {
+    // -1..1 -> 0..1
+    return vec4<f32>(normalize(in.world_normal) * 0.5 + 0.5, 1.0);
+}
+
+@fragment
+fn fs_uvs(in: VertexOutput) -> @location(0) vec4<f32> {
+    // the fractional part, so repeating uvs stay visible
+    return vec4<f32>(fract(in.tex_coords), 0.0, 1.0);
+}
+
+@fragment
+fn fs_depth(in: VertexOutput) -> @location(0) vec4<f32> {
+    let range = debug_view.depth_range;
+    let d = clamp((in.view_depth - range.x) / (range.y - range.x), 0.0, 1.0);
+    return vec4<f32>(vec3<f32>(1.0 - d), 1.0);
+}
+
+@fragment
+fn fs_overdraw(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(debug_view.overdraw_step * vec3<f32>(1.0, 0.6, 0.2), 1.0);
+}
+
+// BARYCENTRIC WIREFRAME
+
+struct WireInput {
+    @location(0) position: vec3<f32>,
+    @location(1) barycentric: vec3<f32>,
+}
+
+struct WireOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) barycentric: vec3<f32>,
+}
+
+@vertex
+fn vs_wire(model: WireInput, instance: InstanceInput) -> WireOutput {
+    var out: WireOutput;
+    out.clip_position = camera.view_proj * instance_model(instance) * vec4<f32>(model.position, 1.0);
+    out.barycentric = model.barycentric;
+    return out;
+}
+
+@fragment
+fn fs_wire_barycentric(in: WireOutput) -> @location(0) vec4<f32> {
+    // distance to the closest edge in pixels: the barycentric coordinate over its screen space rate of change
+    let pixels = in.barycentric / fwidth(in.barycentric);
+    let distance = min(min(pixels.x, pixels.y), pixels.z);
+    // ~1 pixel wide line with a soft edge
+    let coverage = 1.0 - smoothstep(0.5, 1.5, distance);
+    if coverage <= 0.0 {
+        discard;
+    }
+    return vec4<f32>(debug_view.wire_color.rgb, debug_view.wire_color.a * coverage);
+}
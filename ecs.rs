@@ -0,0 +1,373 @@
+use std::any::{Any, TypeId};
+use std::collections::{HashMap, HashSet};
+
+use cgmath::{Rad, Rotation3, Vector3};
+use winit::keyboard::KeyCode;
+
+use crate::assets::{Handle, Sound};
+use crate::audio::PlayParams;
+use crate::scene::{MaterialId, MeshId, NodeId, Scene, Transform};
+
+// ECS
+// Entities and components live in a hecs::World (archetype storage, queries).
+// Around it:
+//   Resources - one value per type that systems share (time, input, ...)
+//   Schedule  - systems run once per frame, by stage, in the order they were added
+// State::update runs the schedule, then sync_scene() mirrors every entity with a Transform into the
+// scene graph: Renderable becomes the node's mesh and material, Parent the node's parent.
+// Game logic goes into systems, not into State::update or App::window_event.
+
+pub use hecs::{Entity, World};
+
+// COMPONENTS
+// scene::Transform is the transform component, local to the Parent when there is one.
+
+// node name in the scene graph
+pub struct Name(pub String);
+
+// the entity's node goes under the parent entity's node
+pub struct Parent(pub Entity);
+
+pub struct Renderable {
+    pub mesh: MeshId,
+    pub material: MaterialId,
+}
+
+// the scene node and its children are hidden while this is false
+pub struct Visible(pub bool);
+
+// turns the entity around `axis`, radians per second
+pub struct Spin {
+    pub axis: Vector3<f32>,
+    pub speed: f32,
+}
+
+// plays `sound` at the entity's scene node once it is loaded, see audio::Audio::sync_emitters.
+// The position and velocity of params.spatial come from the node, the rest is used as it is.
+pub struct Emitter {
+    pub sound: Handle<Sound>,
+    pub params: PlayParams,
+}
+
+// RESOURCES
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Time {
+    // seconds since the last frame
+    pub dt: f32,
+    pub elapsed: f32,
+    pub frame: u64,
+}
+
+// Keys held down, fed from App::window_event. `just_pressed` only lasts for one frame.
+#[derive(Debug, Default)]
+pub struct Input {
+    pub pressed: HashSet<KeyCode>,
+    pub just_pressed: HashSet<KeyCode>,
+}
+
+impl Input {
+    pub fn key(&mut self, code: KeyCode, pressed: bool) {
+        if pressed {
+            if self.pressed.insert(code) {
+                self.just_pressed.insert(code);
+            }
+        } else {
+            self.pressed.remove(&code);
+        }
+    }
+
+    pub fn is_pressed(&self, code: KeyCode) -> bool {
+        self.pressed.contains(&code)
+    }
+}
+
+#[derive(Default)]
+pub struct Resources {
+    values: HashMap<TypeId, Box<dyn Any>>,
+}
+
+impl Resources {
+    // replaces the old value of the same type
+    pub fn insert<T: 'static>(&mut self, value: T) {
+        self.values.insert(TypeId::of::<T>(), Box::new(value));
+    }
+
+    pub fn remove<T: 'static>(&mut self) -> Option<T> {
+        self.values.remove(&TypeId::of::<T>()).and_then(|value| value.downcast().ok()).map(|value| *value)
+    }
+
+    pub fn get<T: 'static>(&self) -> Option<&T> {
+        self.values.get(&TypeId::of::<T>()).and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.values.get_mut(&TypeId::of::<T>()).and_then(|value| value.downcast_mut())
+    }
+
+    // for resources every system expects, panics with the type name when it was never inserted
+    pub fn expect<T: 'static>(&self) -> &T {
+        self.get().unwrap_or_else(|| panic!("resource {} missing", std::any::type_name::<T>()))
+    }
+
+    pub fn expect_mut<T: 'static>(&mut self) -> &mut T {
+        self.get_mut().unwrap_or_else(|| panic!("resource {} missing", std::any::type_name::<T>()))
+    }
+}
+
+// SCHEDULE
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    // input handling, spawning
+    PreUpdate,
+    // game logic
+    Update,
+    // reacting to what Update did, before the scene sync
+    PostUpdate,
+}
+
+type System = Box<dyn FnMut(&mut World, &mut Resources)>;
+
+#[derive(Default)]
+pub struct Schedule {
+    // kept sorted by stage, insertion order inside a stage
+    systems: Vec<(Stage, String, System)>,
+}
+
+impl Schedule {
+    pub fn add_system(&mut self, stage: Stage, name: &str, system: impl FnMut(&mut World, &mut Resources) + 'static) {
+        let index = self.systems.partition_point(|(s, _, _)| *s <= stage);
+        self.systems.insert(index, (stage, name.to_string(), Box::new(system)));
+    }
+
+    pub fn system_names(&self) -> impl Iterator<Item = (Stage, &str)> {
+        self.systems.iter().map(|(stage, name, _)| (*stage, name.as_str()))
+    }
+
+    pub fn run(&mut self, world: &mut World, resources: &mut Resources) {
+        for (_, _, system) in &mut self.systems {
+            system(world, resources);
+        }
+    }
+}
+
+// ECS
+
+pub struct Ecs {
+    pub world: World,
+    pub resources: Resources,
+    pub schedule: Schedule,
+    // scene node of every synced entity
+    nodes: HashMap<Entity, NodeId>,
+}
+
+impl Default for Ecs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ecs {
+    pub fn new() -> Self {
+        let mut resources = Resources::default();
+        resources.insert(Time::default());
+        resources.insert(Input::default());
+
+        let mut schedule = Schedule::default();
+        schedule.add_system(Stage::Update, "spin", spin_system);
+
+        Self {
+            world: World::new(),
+            resources,
+            schedule,
+            nodes: HashMap::new(),
+        }
+    }
+
+    // Once per frame: time, every system, then the end of the frame for the input.
+    pub fn run(&mut self, dt: f32) {
+        let time = self.resources.expect_mut::<Time>();
+        time.dt = dt;
+        time.elapsed += dt;
+        time.frame += 1;
+
+        self.schedule.run(&mut self.world, &mut self.resources);
+
+        self.resources.expect_mut::<Input>().just_pressed.clear();
+    }
+
+    pub fn node(&self, entity: Entity) -> Option<NodeId> {
+        self.nodes.get(&entity).copied()
+    }
+
+    // Entities -> scene graph, after run(). Nodes are created for new entities with a Transform
+    // (parents first), updated when a component changed and removed with their entity.
+    pub fn sync_scene(&mut self, scene: &mut Scene) {
+        // despawned entities, or entities that lost their Transform
+        let world = &self.world;
+        self.nodes.retain(|&entity, &mut node| {
+            let alive = world.satisfies::<&Transform>(entity).unwrap_or(false);
+            if !alive {
+                scene.remove(node);
+            }
+            alive
+        });
+        // children of a removed node went with it, they get new nodes below
+        self.nodes.retain(|_, node| scene.get(*node).is_some());
+
+        // new entities, a child waits until its parent has a node
+        loop {
+            let waiting: Vec<(Entity, Option<Entity>)> = self
+                .world
+                .query::<(&Transform, Option<&Parent>)>()
+                .iter()
+                .filter(|(entity, _)| !self.nodes.contains_key(entity))
+                .map(|(entity, (_, parent))| (entity, parent.map(|p| p.0)))
+                .collect();
+            let mut progress = false;
+            for &(entity, parent) in &waiting {
+                let parent_node = match parent {
+                    Some(parent) => match self.nodes.get(&parent) {
+                        Some(&node) => Some(node),
+                        // the parent is new too and not synced yet, or has no Transform
+                        None if self.world.satisfies::<&Transform>(parent).unwrap_or(false) => continue,
+                        None => None,
+                    },
+                    None => None,
+                };
+                let name = self.world.get::<&Name>(entity).map(|name| name.0.clone()).unwrap_or_else(|_| format!("entity {}", entity.id()));
+                let transform = *self.world.get::<&Transform>(entity).unwrap();
+                let node = match parent_node {
+                    Some(parent_node) => scene.add_child(parent_node, &name, transform),
+                    None => scene.add(&name, transform),
+                };
+                self.nodes.insert(entity, node);
+                progress = true;
+            }
+            if waiting.is_empty() {
+                break;
+            }
+            if !progress {
+                // only a parent cycle leaves entities whose parents never get a node
+                log::warn!("{} entities wait for a parent in a cycle, they get no node", waiting.len());
+                break;
+            }
+        }
+
+        // components -> nodes
+        for (entity, (transform, parent, renderable, visible)) in self
+            .world
+            .query::<(&Transform, Option<&Parent>, Option<&Renderable>, Option<&Visible>)>()
+            .iter()
+        {
+            let Some(&node) = self.nodes.get(&entity) else { continue };
+
+            let parent_node = parent.and_then(|parent| self.nodes.get(&parent.0).copied());
+            if scene.get(node).is_some_and(|n| n.parent() != parent_node) {
+                scene.set_parent(node, parent_node);
+            }
+            if scene.get(node).is_some_and(|n| n.transform() != transform) {
+                scene.set_transform(node, *transform);
+            }
+            if let Some(n) = scene.get_mut(node) {
+                n.mesh = renderable.map(|r| r.mesh);
+                n.material = renderable.map(|r| r.material);
+                n.visible = visible.is_none_or(|v| v.0);
+            }
+        }
+    }
+}
+
+// SYSTEMS
+
+pub fn spin_system(world: &mut World, resources: &mut Resources) {
+    let dt = resources.expect::<Time>().dt;
+    for (_, (transform, spin)) in world.query_mut::<(&mut Transform, &Spin)>() {
+        transform.rotation = cgmath::Quaternion::from_axis_angle(spin.axis, Rad(spin.speed * dt)) * transform.rotation;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_advances_time_runs_the_systems_and_ends_just_pressed() {
+        let mut ecs = Ecs::new();
+        let entity = ecs.world.spawn((Transform::default(), Spin { axis: Vector3::unit_y(), speed: 1.0 }));
+        ecs.resources.expect_mut::<Input>().key(KeyCode::Space, true);
+        assert!(ecs.resources.expect::<Input>().just_pressed.contains(&KeyCode::Space));
+
+        ecs.run(0.5);
+        ecs.run(0.25);
+        let time = *ecs.resources.expect::<Time>();
+        assert_eq!((time.dt, time.elapsed, time.frame), (0.25, 0.75, 2));
+        // spin_system turned the entity by 0.75 radians in total
+        let rotation = ecs.world.get::<&Transform>(entity).unwrap().rotation;
+        let expected = cgmath::Quaternion::from_angle_y(Rad(0.75));
+        assert!((rotation.s - expected.s).abs() < 1e-5 && (rotation.v.y - expected.v.y).abs() < 1e-5);
+
+        let input = ecs.resources.expect::<Input>();
+        assert!(input.just_pressed.is_empty());
+        assert!(input.is_pressed(KeyCode::Space));
+    }
+
+    #[test]
+    fn schedule_runs_by_stage_then_in_the_added_order() {
+        let mut schedule = Schedule::default();
+        let log = |name: &'static str| move |_: &mut World, resources: &mut Resources| resources.expect_mut::<Vec<&str>>().push(name);
+        schedule.add_system(Stage::PostUpdate, "post", log("post"));
+        schedule.add_system(Stage::Update, "first", log("first"));
+        schedule.add_system(Stage::PreUpdate, "pre", log("pre"));
+        schedule.add_system(Stage::Update, "second", log("second"));
+
+        let mut resources = Resources::default();
+        resources.insert(Vec::<&str>::new());
+        schedule.run(&mut World::new(), &mut resources);
+        assert_eq!(resources.expect::<Vec<&str>>(), &["pre", "first", "second", "post"]);
+        assert_eq!(
+            schedule.system_names().collect::<Vec<_>>(),
+            [(Stage::PreUpdate, "pre"), (Stage::Update, "first"), (Stage::Update, "second"), (Stage::PostUpdate, "post")]
+        );
+    }
+
+    #[test]
+    fn sync_scene_mirrors_entities_into_nodes() {
+        let mut ecs = Ecs::new();
+        let mut scene = Scene::new();
+        let parent = ecs.world.reserve_entity();
+        // the child comes first and has to wait for its parent's node
+        let child = ecs.world.spawn((Name("child".to_string()), Transform::default(), Parent(parent)));
+        ecs.world.spawn_at(parent, (Name("parent".to_string()), Transform::default()));
+        let no_transform = ecs.world.spawn((Name("no transform".to_string()),));
+
+        ecs.sync_scene(&mut scene);
+        let (parent_node, child_node) = (ecs.node(parent).unwrap(), ecs.node(child).unwrap());
+        assert_eq!(scene.get(child_node).unwrap().parent(), Some(parent_node));
+        assert_eq!(scene.get(child_node).unwrap().name, "child");
+        assert!(ecs.node(no_transform).is_none());
+
+        // components changed since the last sync
+        ecs.world.insert_one(child, Visible(false)).unwrap();
+        ecs.world.remove_one::<Parent>(child).unwrap();
+        ecs.sync_scene(&mut scene);
+        let node = scene.get(child_node).unwrap();
+        assert!(!node.visible);
+        assert_eq!(node.parent(), None);
+
+        // a despawned entity takes its node along
+        ecs.world.despawn(parent).unwrap();
+        ecs.sync_scene(&mut scene);
+        assert!(scene.get(parent_node).is_none());
+        assert_eq!(scene.len(), 1);
+
+        // entities whose parents form a cycle never get a node
+        let a = ecs.world.reserve_entity();
+        let b = ecs.world.spawn((Transform::default(), Parent(a)));
+        ecs.world.spawn_at(a, (Transform::default(), Parent(b)));
+        ecs.sync_scene(&mut scene);
+        assert!(ecs.node(a).is_none() && ecs.node(b).is_none());
+        assert_eq!(scene.len(), 1);
+    }
+}
@@ -0,0 +1,382 @@
+use std::collections::HashSet;
+
+use crate::profiler::GpuTimer;
+
+// RENDER GRAPH
+// Every frame State::render describes its passes and the textures they read and write.
+// The graph then
+//   - runs the passes in the order they were added, which is the order their reads and writes
+//     happen in, so a texture is written before a later pass reads it,
+//   - drops passes whose results nobody uses,
+//   - allocates the transient textures from a pool that lives across frames, and lets two
+//     transients with the same description share one gpu texture when their lifetimes don't overlap,
+//   - records every pass into the command encoder, with gpu timestamps around it when a timer is given.
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct TextureDesc {
+    pub width: u32,
+    pub height: u32,
+    pub format: wgpu::TextureFormat,
+    pub usage: wgpu::TextureUsages,
+}
+
+impl TextureDesc {
+    // the usual transient: rendered to by one pass, sampled by a later one
+    pub fn render_target(width: u32, height: u32, format: wgpu::TextureFormat) -> Self {
+        Self {
+            width: width.max(1),
+            height: height.max(1),
+            format,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ResourceId(usize);
+
+enum Resource<'a> {
+    // owned by someone else (surface texture, shadow map ...), lives outside the graph
+    Imported(&'a wgpu::TextureView),
+    // created by the graph, only valid while the graph executes
+    Transient(TextureDesc),
+}
+
+struct ResourceEntry<'a> {
+    name: String,
+    resource: Resource<'a>,
+}
+
+type PassFn<'a> = Box<dyn FnOnce(&PassContext, &mut wgpu::CommandEncoder) + 'a>;
+
+struct PassNode<'a> {
+    name: String,
+    reads: Vec<ResourceId>,
+    writes: Vec<ResourceId>,
+    record: PassFn<'a>,
+}
+
+// What a pass sees while it records: the views of the textures it declared.
+pub struct PassContext<'r> {
+    entries: &'r [ResourceEntry<'r>],
+    assigned: &'r [Option<usize>],
+    pool: &'r TransientPool,
+}
+
+impl PassContext<'_> {
+    pub fn view(&self, id: ResourceId) -> &wgpu::TextureView {
+        match &self.entries[id.0].resource {
+            Resource::Imported(view) => view,
+            Resource::Transient(_) => {
+                let slot = self.assigned[id.0]
+                    .unwrap_or_else(|| panic!("render graph: '{}' used by a pass that did not declare it", self.entries[id.0].name));
+                &self.pool.slots[slot].view
+            }
+        }
+    }
+}
+
+pub struct RenderGraph<'a> {
+    resources: Vec<ResourceEntry<'a>>,
+    passes: Vec<PassNode<'a>>,
+}
+
+impl<'a> RenderGraph<'a> {
+    pub fn new() -> Self {
+        Self {
+            resources: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+
+    pub fn import_texture(&mut self, name: &str, view: &'a wgpu::TextureView) -> ResourceId {
+        self.resources.push(ResourceEntry {
+            name: name.to_string(),
+            resource: Resource::Imported(view),
+        });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    pub fn create_texture(&mut self, name: &str, desc: TextureDesc) -> ResourceId {
+        self.resources.push(ResourceEntry {
+            name: name.to_string(),
+            resource: Resource::Transient(desc),
+        });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    // `record` runs during execute(), after the passes added before it, unless the pass is culled.
+    pub fn add_pass(
+        &mut self,
+        name: &str,
+        reads: &[ResourceId],
+        writes: &[ResourceId],
+        record: impl FnOnce(&PassContext, &mut wgpu::CommandEncoder) + 'a,
+    ) {
+        for id in reads {
+            let written = self.passes.iter().any(|pass| pass.writes.contains(id));
+            if !written && matches!(self.resources[id.0].resource, Resource::Transient(_)) {
+                log::warn!("render graph: pass '{}' reads '{}' before anything writes it", name, self.resources[id.0].name);
+            }
+        }
+        self.passes.push(PassNode {
+            name: name.to_string(),
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+            record: Box::new(record),
+        });
+    }
+
+    pub fn execute(
+        self,
+        device: &wgpu::Device,
+        pool: &mut TransientPool,
+        encoder: &mut wgpu::CommandEncoder,
+        mut timer: Option<&mut GpuTimer>,
+    ) {
+        let transients: Vec<Option<TextureDesc>> = self
+            .resources
+            .iter()
+            .map(|entry| match entry.resource {
+                Resource::Transient(desc) => Some(desc),
+                Resource::Imported(_) => None,
+            })
+            .collect();
+        let order = schedule(&self.passes, &transients);
+        let lifetimes = lifetimes(&self.passes, &order, &transients);
+        let (slots, slot_descs) = assign_slots(&transients, &lifetimes);
+
+        // one pool texture per slot, named after the first transient in it
+        let textures: Vec<usize> = slot_descs
+            .iter()
+            .enumerate()
+            .map(|(slot, desc)| {
+                let name = slots.iter().position(|&s| s == Some(slot)).map_or("", |id| self.resources[id].name.as_str());
+                pool.acquire(device, desc, name)
+            })
+            .collect();
+        let assigned: Vec<Option<usize>> = slots.iter().map(|slot| slot.map(|slot| textures[slot])).collect();
+
+        let RenderGraph { resources, passes } = self;
+        let mut passes: Vec<Option<PassNode>> = passes.into_iter().map(Some).collect();
+        let context = PassContext {
+            entries: &resources,
+            assigned: &assigned,
+            pool: &*pool,
+        };
+        for &pass in &order {
+            let node = passes[pass].take().unwrap();
+            encoder.push_debug_group(&node.name);
+            if let Some(timer) = timer.as_deref_mut() {
+                timer.begin_scope(encoder, &node.name);
+            }
+            (node.record)(&context, encoder);
+            if let Some(timer) = timer.as_deref_mut() {
+                timer.end_scope(encoder);
+            }
+            encoder.pop_debug_group();
+        }
+
+        pool.end_frame();
+    }
+}
+
+// CULLING
+// The passes that contribute to an imported texture, in the order they were added.
+// A pass is needed when it writes an imported texture or something a needed pass reads.
+// `transients` has the description of every transient resource, None for imported ones.
+fn schedule(passes: &[PassNode], transients: &[Option<TextureDesc>]) -> Vec<usize> {
+    let mut needed = vec![false; passes.len()];
+    let mut needed_resources: HashSet<usize> = HashSet::new();
+    for (pass, node) in passes.iter().enumerate().rev() {
+        let writes_needed = node.writes.iter().any(|id| transients[id.0].is_none() || needed_resources.contains(&id.0));
+        if writes_needed {
+            needed[pass] = true;
+            needed_resources.extend(node.reads.iter().map(|id| id.0));
+        }
+    }
+    (0..passes.len()).filter(|&pass| needed[pass]).collect()
+}
+
+// First and last step of `order` that uses each transient, None for imported or unused resources.
+fn lifetimes(passes: &[PassNode], order: &[usize], transients: &[Option<TextureDesc>]) -> Vec<Option<(usize, usize)>> {
+    let mut lifetimes: Vec<Option<(usize, usize)>> = vec![None; transients.len()];
+    for (step, &pass) in order.iter().enumerate() {
+        let node = &passes[pass];
+        for id in node.reads.iter().chain(&node.writes) {
+            if transients[id.0].is_some() {
+                let lifetime = lifetimes[id.0].get_or_insert((step, step));
+                lifetime.1 = step;
+            }
+        }
+    }
+    lifetimes
+}
+
+// ALIASING
+// Gives every used transient a slot; transients with the same description share one when the
+// first one's last use comes before the other's first. Returns the slot of every resource and
+// the description of every slot.
+fn assign_slots(transients: &[Option<TextureDesc>], lifetimes: &[Option<(usize, usize)>]) -> (Vec<Option<usize>>, Vec<TextureDesc>) {
+    let mut used: Vec<(usize, TextureDesc, (usize, usize))> = transients
+        .iter()
+        .zip(lifetimes)
+        .enumerate()
+        .filter_map(|(id, (desc, lifetime))| Some((id, (*desc)?, (*lifetime)?)))
+        .collect();
+    used.sort_by_key(|&(id, _, (first, _))| (first, id));
+
+    let mut slots = vec![None; transients.len()];
+    // description and last step of the current user of every slot
+    let mut slot_uses: Vec<(TextureDesc, usize)> = Vec::new();
+    for (id, desc, (first, last)) in used {
+        let free = slot_uses.iter().position(|&(slot_desc, slot_last)| slot_desc == desc && slot_last < first);
+        let slot = match free {
+            Some(slot) => {
+                slot_uses[slot].1 = last;
+                slot
+            }
+            None => {
+                slot_uses.push((desc, last));
+                slot_uses.len() - 1
+            }
+        };
+        slots[id] = Some(slot);
+    }
+    (slots, slot_uses.into_iter().map(|(desc, _)| desc).collect())
+}
+
+struct PoolSlot {
+    desc: TextureDesc,
+    // kept alive for the view
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    in_use: bool,
+    last_used_frame: u64,
+}
+
+// Physical textures behind the transients. Lives in State, so textures are reused between frames.
+#[derive(Default)]
+pub struct TransientPool {
+    slots: Vec<PoolSlot>,
+    frame: u64,
+}
+
+// textures nobody asked for in this many frames are freed (old sizes after a resize)
+const POOL_MAX_IDLE_FRAMES: u64 = 3;
+
+impl TransientPool {
+    fn acquire(&mut self, device: &wgpu::Device, desc: &TextureDesc, name: &str) -> usize {
+        if let Some(index) = self.slots.iter().position(|slot| !slot.in_use && slot.desc == *desc) {
+            let slot = &mut self.slots[index];
+            slot.in_use = true;
+            slot.last_used_frame = self.frame;
+            return index;
+        }
+
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(name),
+            size: wgpu::Extent3d {
+                width: desc.width,
+                height: desc.height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: desc.format,
+            usage: desc.usage,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+
+        self.slots.push(PoolSlot {
+            desc: *desc,
+            _texture: texture,
+            view,
+            in_use: true,
+            last_used_frame: self.frame,
+        });
+        self.slots.len() - 1
+    }
+
+    // every texture is free for the next frame's graph
+    fn end_frame(&mut self) {
+        let frame = self.frame;
+        for slot in &mut self.slots {
+            slot.in_use = false;
+        }
+        self.slots.retain(|slot| frame - slot.last_used_frame < POOL_MAX_IDLE_FRAMES);
+        self.frame += 1;
+    }
+
+    // number of gpu textures currently held, handy for stats
+    pub fn texture_count(&self) -> usize {
+        self.slots.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pass(name: &str, reads: &[usize], writes: &[usize]) -> PassNode<'static> {
+        PassNode {
+            name: name.to_string(),
+            reads: reads.iter().map(|&id| ResourceId(id)).collect(),
+            writes: writes.iter().map(|&id| ResourceId(id)).collect(),
+            record: Box::new(|_, _| {}),
+        }
+    }
+
+    fn target(width: u32) -> Option<TextureDesc> {
+        Some(TextureDesc::render_target(width, 64, wgpu::TextureFormat::Rgba16Float))
+    }
+
+    #[test]
+    fn schedule_culls_unused_passes_and_keeps_the_added_order() {
+        // 0 = surface (imported), 1 = scene color, 2 = bloom, 3 = debug overlay nobody reads
+        let transients = [None, target(64), target(64), target(64)];
+        let passes = [
+            pass("scene", &[], &[1]),
+            pass("overlay", &[], &[3]),
+            pass("bloom", &[1], &[2]),
+            pass("post", &[1, 2], &[0]),
+            pass("ui", &[], &[0]),
+        ];
+        assert_eq!(schedule(&passes, &transients), [0, 2, 3, 4]);
+
+        // nothing reaches the surface, nothing runs
+        assert!(schedule(&passes[..3], &transients).is_empty());
+    }
+
+    #[test]
+    fn lifetimes_follow_the_scheduled_steps() {
+        let transients = [None, target(64), target(64), target(64)];
+        let passes = [
+            pass("scene", &[], &[1]),
+            pass("overlay", &[], &[3]),
+            pass("bloom", &[1], &[2]),
+            pass("post", &[2], &[0]),
+        ];
+        let order = schedule(&passes, &transients);
+        assert_eq!(order, [0, 2, 3]);
+        // steps, not pass indices: the culled overlay shifts bloom to step 1
+        assert_eq!(lifetimes(&passes, &order, &transients), [None, Some((0, 1)), Some((1, 2)), None]);
+    }
+
+    #[test]
+    fn transients_share_a_slot_when_their_lifetimes_do_not_overlap() {
+        let transients = [None, target(64), target(64), target(64), target(32)];
+        let lifetimes = [None, Some((0, 1)), Some((1, 2)), Some((2, 3)), Some((2, 3))];
+        let (slots, descs) = assign_slots(&transients, &lifetimes);
+        // 2 overlaps 1, 3 starts after 1 ended and takes its slot, 4 has another size
+        assert_eq!(slots, [None, Some(0), Some(1), Some(0), Some(2)]);
+        assert_eq!(descs, [transients[1].unwrap(), transients[2].unwrap(), transients[4].unwrap()]);
+
+        // a transient that is never used gets no texture
+        let (slots, descs) = assign_slots(&transients[..2], &[None, None]);
+        assert_eq!(slots, [None, None]);
+        assert!(descs.is_empty());
+    }
+}
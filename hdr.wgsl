@@ -0,0 +1,109 @@
+// Fullscreen tonemapping pass: HDR scene texture -> LDR post processing target.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+// One triangle that covers the whole screen:
+// vertex 0 -> (-1, 3), 1 -> (-1, -1), 2 -> (3, -1)
+@vertex
+fn vs_main(@builtin(vertex_index) vi: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let x = f32(i32(vi) / 2) * 4.0 - 1.0;
+    let y = f32(i32(vi) % 2) * -4.0 + 3.0;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.uv = vec2<f32>(x, y) * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+    return out;
+}
+
+const TONEMAP_REINHARD: u32 = 0u;
+const TONEMAP_ACES: u32 = 1u;
+const TONEMAP_AGX: u32 = 2u;
+
+struct Tonemap {
+    // in stops
+    exposure: f32,
+    tonemapper: u32,
+    _padding: vec2<u32>,
+}
+
+@group(0) @binding(0)
+var t_hdr: texture_2d<f32>;
+@group(0) @binding(1)
+var s_hdr: sampler;
+@group(0) @binding(2)
+var<uniform> tonemap: Tonemap;
+
+fn reinhard(color: vec3<f32>) -> vec3<f32> {
+    return color / (1.0 + color);
+}
+
+// Krzysztof Narkowicz, "ACES Filmic Tone Mapping Curve"
+fn aces(color: vec3<f32>) -> vec3<f32> {
+    let a = 2.51;
+    let b = 0.03;
+    let c = 2.43;
+    let d = 0.59;
+    let e = 0.14;
+    return clamp((color * (a * color + b)) / (color * (c * color + d) + e), vec3<f32>(0.0), vec3<f32>(1.0));
+}
+
+// AgX, polynomial fit of the default contrast curve (Benjamin Wrensch, "Minimal AgX")
+fn agx_contrast(x: vec3<f32>) -> vec3<f32> {
+    let x2 = x * x;
+    let x4 = x2 * x2;
+    return 15.5 * x4 * x2
+        - 40.14 * x4 * x
+        + 31.96 * x4
+        - 6.868 * x2 * x
+        + 0.4298 * x2
+        + 0.1191 * x
+        - 0.00232;
+}
+
+fn agx(color: vec3<f32>) -> vec3<f32> {
+    let inset = mat3x3<f32>(
+        vec3<f32>(0.842479062253094, 0.0423282422610123, 0.0423756549057051),
+        vec3<f32>(0.0784335999999992, 0.878468636469772, 0.0784336),
+        vec3<f32>(0.0792237451477643, 0.0791661274605434, 0.879142973793104),
+    );
+    let outset = mat3x3<f32>(
+        vec3<f32>(1.19687900512017, -0.0528968517574562, -0.0529716355144438),
+        vec3<f32>(-0.0980208811401368, 1.15190312990417, -0.0980434501171241),
+        vec3<f32>(-0.0990297440797205, -0.0989611768448433, 1.15107367264116),
+    );
+    let min_ev = -12.47393;
+    let max_ev = 4.026069;
+
+    var c = inset * color;
+    c = clamp(log2(max(c, vec3<f32>(1e-10))), vec3<f32>(min_ev), vec3<f32>(max_ev));
+    c = (c - min_ev) / (max_ev - min_ev);
+    c = agx_contrast(c);
+    c = outset * c;
+
+    // AgX ends display encoded, the srgb target encodes again, so undo the 2.2 gamma
+    return pow(clamp(c, vec3<f32>(0.0), vec3<f32>(1.0)), vec3<f32>(2.2));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    let hdr = textureSample(t_hdr, s_hdr, in.uv);
+    let color = hdr.rgb * exp2(tonemap.exposure);
+
+    var mapped: vec3<f32>;
+    switch tonemap.tonemapper {
+        case TONEMAP_REINHARD: {
+            mapped = reinhard(color);
+        }
+        case TONEMAP_AGX: {
+            mapped = agx(color);
+        }
+        default: {
+            mapped = aces(color);
+        }
+    }
+
+    // the target is srgb, so the gpu gamma encodes on write
+    return vec4<f32>(mapped, 1.0);
+}
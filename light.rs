@@ -0,0 +1,225 @@
+use cgmath::{InnerSpace, Point3, Rad, Vector3};
+
+// Must match MAX_LIGHTS in light.wgsl.
+// Lights live in a uniform buffer (not a storage buffer) because
+// downlevel_webgl2_defaults allows zero storage buffers in the fragment stage.
+pub const MAX_LIGHTS: usize = 16;
+
+const LIGHT_DIRECTIONAL: u32 = 0;
+const LIGHT_POINT: u32 = 1;
+const LIGHT_SPOT: u32 = 2;
+
+#[derive(Copy, Clone, Debug)]
+pub enum Light {
+    // Sun-like light: same direction everywhere, no falloff.
+    Directional {
+        direction: Vector3<f32>,
+        color: [f32; 3],
+        intensity: f32,
+        // rendered with cascaded shadow maps, only the first shadowed directional light gets them
+        cast_shadows: bool,
+    },
+    // Bulb: shines in every direction, fades out at `range`.
+    Point {
+        position: Point3<f32>,
+        color: [f32; 3],
+        intensity: f32,
+        range: f32,
+    },
+    // Flashlight: a point light limited to a cone.
+    // Full intensity inside `inner_angle`, fades to zero at `outer_angle`.
+    Spot {
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        color: [f32; 3],
+        intensity: f32,
+        range: f32,
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+        cast_shadows: bool,
+    },
+}
+
+impl Light {
+    // shadow_layer comes from shadow::assign_layers, -1 = no shadow
+    fn to_raw(self, shadow_layer: i32) -> LightRaw {
+        match self {
+            Light::Directional { direction, color, intensity, .. } => LightRaw {
+                position: [0.0; 3],
+                kind: LIGHT_DIRECTIONAL,
+                direction: direction.normalize().into(),
+                range: 0.0,
+                color,
+                intensity,
+                cone: [0.0; 2],
+                shadow_layer,
+                _padding: 0,
+            },
+            Light::Point { position, color, intensity, range } => LightRaw {
+                position: position.into(),
+                kind: LIGHT_POINT,
+                direction: [0.0; 3],
+                range,
+                color,
+                intensity,
+                cone: [0.0; 2],
+                shadow_layer,
+                _padding: 0,
+            },
+            Light::Spot { position, direction, color, intensity, range, inner_angle, outer_angle, .. } => LightRaw {
+                position: position.into(),
+                kind: LIGHT_SPOT,
+                direction: direction.normalize().into(),
+                range,
+                color,
+                intensity,
+                // the shader compares against dot products, so send cosines
+                cone: [inner_angle.0.cos(), outer_angle.0.cos()],
+                shadow_layer,
+                _padding: 0,
+            },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct LightRaw {
+    position: [f32; 3],
+    kind: u32,
+    direction: [f32; 3],
+    range: f32,
+    color: [f32; 3],
+    intensity: f32,
+    cone: [f32; 2],
+    shadow_layer: i32,
+    // Uniforms require 16 byte (4 float field) spacing
+    _padding: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ShadingMode {
+    // just the texture, no lights
+    Unlit = 0,
+    BlinnPhong = 1,
+    // separate pipeline, see pbr.wgsl
+    Pbr = 2,
+}
+
+impl ShadingMode {
+    pub fn next(self) -> Self {
+        match self {
+            ShadingMode::Unlit => ShadingMode::BlinnPhong,
+            ShadingMode::BlinnPhong => ShadingMode::Pbr,
+            ShadingMode::Pbr => ShadingMode::Unlit,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightsUniform {
+    ambient: [f32; 3],
+    count: u32,
+    shading: u32,
+    shininess: f32,
+    specular_strength: f32,
+    _padding: u32,
+    lights: [LightRaw; MAX_LIGHTS],
+}
+
+impl LightsUniform {
+    pub fn new() -> Self {
+        bytemuck::Zeroable::zeroed()
+    }
+
+    pub fn update(&mut self, lights: &[Light], ambient: [f32; 3], shading: ShadingMode, shadow_layers: &[i32]) {
+        if lights.len() > MAX_LIGHTS {
+            log::warn!("{} lights in the scene, only the first {} are used", lights.len(), MAX_LIGHTS);
+        }
+        let count = lights.len().min(MAX_LIGHTS);
+
+        self.ambient = ambient;
+        self.count = count as u32;
+        self.shading = shading as u32;
+        self.shininess = 32.0;
+        self.specular_strength = 0.5;
+        for (i, (raw, light)) in self.lights.iter_mut().zip(lights).enumerate() {
+            *raw = light.to_raw(shadow_layers.get(i).copied().unwrap_or(-1));
+        }
+    }
+}
+
+pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            // SHADOWS
+            // matrices and settings, see shadow::ShadowMap
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                    sample_type: wgpu::TextureSampleType::Depth,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                count: None,
+            },
+        ],
+        label: Some("light_bind_group_layout"),
+    })
+}
+
+// A small default rig so the scene is not black when lighting is switched on.
+pub fn default_lights() -> Vec<Light> {
+    vec![
+        Light::Directional {
+            direction: Vector3::new(-0.3, -0.5, -1.0),
+            color: [1.0, 0.95, 0.9],
+            intensity: 0.6,
+            cast_shadows: true,
+        },
+        Light::Point {
+            position: Point3::new(0.6, 0.4, 0.5),
+            color: [1.0, 0.3, 0.2],
+            intensity: 1.5,
+            range: 3.0,
+        },
+        Light::Spot {
+            position: Point3::new(-0.5, -0.3, 1.0),
+            direction: Vector3::new(0.4, 0.2, -1.0),
+            color: [0.2, 0.4, 1.0],
+            intensity: 2.0,
+            range: 5.0,
+            inner_angle: cgmath::Deg(10.0).into(),
+            outer_angle: cgmath::Deg(25.0).into(),
+            cast_shadows: true,
+        },
+    ]
+}
@@ -0,0 +1,174 @@
+// Camera and lights shared by every lit pipeline.
+// main.rs glues this file in front of the pipeline's own shader with concat!.
+
+struct Camera {
+    view_pos: vec4<f32>,
+    view_proj: mat4x4<f32>,
+    view: mat4x4<f32>,
+    inv_proj: mat4x4<f32>,
+}
+@group(1) @binding(0)
+var<uniform> camera: Camera;
+
+const LIGHT_DIRECTIONAL: u32 = 0u;
+const LIGHT_POINT: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+
+const SHADING_UNLIT: u32 = 0u;
+const SHADING_BLINN_PHONG: u32 = 1u;
+
+// Must match MAX_LIGHTS in light.rs
+const MAX_LIGHTS: u32 = 16u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    // cos(inner angle), cos(outer angle) for spot lights
+    cone: vec2<f32>,
+    // first layer in t_shadow, -1 = no shadow
+    shadow_layer: i32,
+    _padding: u32,
+}
+
+struct Lights {
+    ambient: vec3<f32>,
+    count: u32,
+    shading: u32,
+    shininess: f32,
+    specular_strength: f32,
+    _padding: u32,
+    lights: array<Light, MAX_LIGHTS>,
+}
+@group(2) @binding(0)
+var<uniform> lights: Lights;
+
+// SHADOWS
+
+// Must match MAX_SHADOW_LAYERS / CASCADE_COUNT in shadow.rs
+const MAX_SHADOW_LAYERS: u32 = 8u;
+const CASCADE_COUNT: u32 = 4u;
+
+struct Shadows {
+    light_view_proj: array<mat4x4<f32>, MAX_SHADOW_LAYERS>,
+    // far end of each cascade in view space
+    cascade_splits: vec4<f32>,
+    depth_bias: f32,
+    normal_bias: f32,
+    pcf_radius: u32,
+    texel_size: f32,
+}
+@group(2) @binding(1)
+var<uniform> shadows: Shadows;
+@group(2) @binding(2)
+var t_shadow: texture_depth_2d_array;
+@group(2) @binding(3)
+var s_shadow: sampler_comparison;
+
+// 1.0 = fully lit, 0.0 = fully in shadow
+fn shadow_factor(light: Light, world_pos: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if light.shadow_layer < 0 {
+        return 1.0;
+    }
+
+    var layer = u32(light.shadow_layer);
+    if light.kind == LIGHT_DIRECTIONAL {
+        // pick the first cascade that still contains this fragment
+        let view_depth = -(camera.view * vec4<f32>(world_pos, 1.0)).z;
+        var cascade = CASCADE_COUNT;
+        for (var i = 0u; i < CASCADE_COUNT; i++) {
+            if view_depth < shadows.cascade_splits[i] {
+                cascade = i;
+                break;
+            }
+        }
+        // past the last cascade there is no shadow information
+        if cascade == CASCADE_COUNT {
+            return 1.0;
+        }
+        layer += cascade;
+    }
+
+    // push the lookup away from the surface against self shadowing
+    let offset_pos = world_pos + normal * shadows.normal_bias;
+    let clip = shadows.light_view_proj[layer] * vec4<f32>(offset_pos, 1.0);
+    if clip.w <= 0.0 {
+        return 1.0;
+    }
+    let ndc = clip.xyz / clip.w;
+    // ndc y points up, texture v points down
+    let uv = ndc.xy * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+    if any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0 {
+        return 1.0;
+    }
+    let depth = ndc.z - shadows.depth_bias;
+
+    // PCF: average a (2r+1)x(2r+1) block of comparisons
+    let radius = i32(shadows.pcf_radius);
+    var lit = 0.0;
+    for (var y = -radius; y <= radius; y++) {
+        for (var x = -radius; x <= radius; x++) {
+            let sample_uv = uv + vec2<f32>(f32(x), f32(y)) * shadows.texel_size;
+            lit += textureSampleCompareLevel(t_shadow, s_shadow, sample_uv, layer, depth);
+        }
+    }
+    let taps = f32((2 * radius + 1) * (2 * radius + 1));
+    return lit / taps;
+}
+
+// Returns the direction from the surface to the light in xyz
+// and how much of the light reaches the surface in w.
+fn light_incidence(light: Light, world_pos: vec3<f32>) -> vec4<f32> {
+    if light.kind == LIGHT_DIRECTIONAL {
+        return vec4<f32>(-light.direction, 1.0);
+    }
+
+    let to_light = light.position - world_pos;
+    let distance = length(to_light);
+    let dir = to_light / distance;
+
+    // inverse square falloff, smoothly forced to zero at the light range (a range of 0 lights nothing)
+    let ratio = distance / max(light.range, 1e-4);
+    let window = clamp(1.0 - ratio * ratio * ratio * ratio, 0.0, 1.0);
+    var attenuation = window * window / (distance * distance + 1.0);
+
+    if light.kind == LIGHT_SPOT {
+        let cos_angle = dot(-dir, light.direction);
+        attenuation *= smoothstep(light.cone.y, light.cone.x, cos_angle);
+    }
+
+    return vec4<f32>(dir, attenuation);
+}
+
+fn blinn_phong(normal: vec3<f32>, world_pos: vec3<f32>, albedo: vec3<f32>) -> vec3<f32> {
+    let view_dir = normalize(camera.view_pos.xyz - world_pos);
+
+    // AMBIENT
+    var result = lights.ambient * albedo;
+
+    for (var i = 0u; i < min(lights.count, MAX_LIGHTS); i++) {
+        let light = lights.lights[i];
+        let incidence = light_incidence(light, world_pos);
+        let light_dir = incidence.xyz;
+        let radiance = light.color * light.intensity * incidence.w * shadow_factor(light, world_pos, normal);
+
+        // DIFFUSE
+        let n_dot_l = max(dot(normal, light_dir), 0.0);
+        let diffuse = albedo * n_dot_l;
+
+        // SPECULAR
+        // Blinn-Phong uses the half vector between the view and light direction
+        // instead of the reflected light direction of plain Phong.
+        let half_dir = normalize(view_dir + light_dir);
+        var specular = pow(max(dot(normal, half_dir), 0.0), lights.shininess) * lights.specular_strength;
+        // no highlight on faces turned away from the light, n_dot_l is already clamped to 0 there
+        specular *= select(0.0, 1.0, dot(normal, light_dir) > 0.0);
+
+        result += (diffuse + vec3<f32>(specular)) * radiance;
+    }
+
+    return result;
+}
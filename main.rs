@@ -1,24 +1,41 @@
+// the modules are the engine, the demo in this file does not use all of their api
+#![allow(dead_code)]
 
-use env_logger::fmt::style::RgbColor;
-use image::GenericImageView;
-#[cfg(target_arch="wasm32")]
-use wgpu::naga::back::spv::SourceLanguage;
-use wgpu::naga::back::INDENT;
-use wgpu::wgc::device::{self, queue};
-use wgpu::wgt::bytemuck_wrapper;
-use wgpu::Buffer;
-use wgpu::{wgc::instance, Surface};
+use std::sync::Arc;
 use wgpu::util::DeviceExt;
-use winit::dpi::Pixel;
-use std::default;
-use std::io::Cursor;
-use std::{any, io::SeekFrom, ops::Not, sync::Arc};
-extern crate rand;
-use rand::{random_range, Rng};
 
+mod animation;
+mod assets;
+mod atlas;
+mod audio;
+mod camera;
+mod capture;
+mod compressed;
+mod cooked;
+mod debug_draw;
+mod debug_view;
+mod ecs;
+mod graph;
+mod hdr;
+mod light;
+mod mesh;
+mod pbr;
+mod post;
+mod profiler;
+mod scene;
+mod scene_file;
+mod shadow;
+mod skybox;
+mod sprite;
+mod text;
+mod ui;
+mod texture;
+mod vfs;
+#[cfg(not(target_arch = "wasm32"))]
+mod watcher;
 
 use winit::{
-    application::ApplicationHandler, event::{Event, KeyEvent, WindowEvent}, event_loop::{self, ActiveEventLoop, ControlFlow, EventLoop}, keyboard::{KeyCode, PhysicalKey}, window::{self, Window, WindowAttributes, WindowId}
+    application::ApplicationHandler, event::{ElementState, KeyEvent, WindowEvent}, event_loop::{ActiveEventLoop, EventLoop}, keyboard::{KeyCode, PhysicalKey}, window::{Window, WindowAttributes}
 };
 
 #[cfg(target_arch = "wasm32")]
@@ -31,11 +48,11 @@ use wasm_bindgen::prelude::*;
 
 
 const VERTICES: &[Vertex] = &[
-    Vertex { position: [-0.0868241, 0.49240386, 0.0], tex_coords: [0.4131759, 0.99240386],}, // A
-    Vertex { position: [-0.49513406, 0.06958647, 0.0], tex_coords: [0.0048659444, 0.56958647],}, // B
-    Vertex { position: [-0.21918549, -0.44939706, 0.0], tex_coords:[0.28081453, 0.05060294],}, // C
-    Vertex { position: [0.35966998, -0.3473291, 0.0], tex_coords: [0.85967, 0.1526709],}, // D
-    Vertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.7347359],}, // E
+    Vertex { position: [-0.0868241, 0.49240386, 0.0], tex_coords: [0.4131759, 0.99240386], normal: [0.0, 0.0, 1.0],}, // A
+    Vertex { position: [-0.49513406, 0.06958647, 0.0], tex_coords: [0.0048659444, 0.56958647], normal: [0.0, 0.0, 1.0],}, // B
+    Vertex { position: [-0.21918549, -0.44939706, 0.0], tex_coords:[0.28081453, 0.05060294], normal: [0.0, 0.0, 1.0],}, // C
+    Vertex { position: [0.35966998, -0.3473291, 0.0], tex_coords: [0.85967, 0.1526709], normal: [0.0, 0.0, 1.0],}, // D
+    Vertex { position: [0.44147372, 0.2347359, 0.0], tex_coords: [0.9414737, 0.7347359], normal: [0.0, 0.0, 1.0],}, // E
 ];
 
 
@@ -44,6 +61,23 @@ const INDICES: &[u16] = &[
     1, 2, 4,
     2, 3, 4,
 ];
+
+// TTF/OTF used for the overlay text, relative to the working directory
+const FONT_PATH: &str = "font.ttf";
+
+// level loaded at startup and by F9, written by F5, .ron or .json
+const SCENE_PATH: &str = "scene.ron";
+
+// Environment cubemap for the skybox and reflections: an equirectangular image, else six faces
+// named after pbr::CUBE_FACES ("sky/+x.png" ...), else the procedural sky
+const ENVIRONMENT_PATH: &str = "sky.hdr";
+const ENVIRONMENT_FACES: &str = "sky";
+
+// color grading LUT strip (post.rs), the identity LUT when there is none
+const LUT_PATH: &str = "lut.png";
+
+// Aseprite export (json + the image it names) for the animated sprite, else the atlas images flip
+const SPRITE_SHEET_PATH: &str = "sprites/sheet.json";
  
 
 #[repr(C)]
@@ -51,7 +85,9 @@ const INDICES: &[u16] = &[
 struct Vertex {
     position: [f32; 3],
     // color: [f32; 3],
-    tex_coords: [f32; 2]
+    tex_coords: [f32; 2],
+    // used by the lighting, the pentagon faces the camera (+z)
+    normal: [f32; 3],
 }
 
 
@@ -70,6 +106,11 @@ impl Vertex {
                 offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
                 shader_location: 1,
                 format: wgpu::VertexFormat::Float32x2,
+            },
+            wgpu::VertexAttribute {
+                offset: std::mem::size_of::<[f32; 5]>() as wgpu::BufferAddress,
+                shader_location: 2,
+                format: wgpu::VertexFormat::Float32x3,
             }
         ]
     }
@@ -85,14 +126,61 @@ pub struct State
     config: wgpu::SurfaceConfiguration,
     is_surface_configured: bool,
     render_pipeline: wgpu::RenderPipeline,
-    vertex_buffer: wgpu::Buffer,
-    num_vertices: u32,
     window: Arc<Window>,
     color: wgpu::Color,
-    index_buffer: wgpu::Buffer,
-    num_indices: u32,
     index_or_vertices: bool,
-    diffuse_bind_group: wgpu::BindGroup,
+    // everything the scene nodes point at with MeshId / MaterialId
+    assets: assets::AssetServer,
+    meshes: Vec<assets::Handle<mesh::Mesh>>,
+    materials: Vec<scene::Material>,
+    // to build the materials of a loaded scene
+    material_layouts: scene_file::MaterialLayouts,
+    // filled from the ecs entities every frame, see Ecs::sync_scene
+    scene: scene::Scene,
+    // entities, game logic systems and their resources
+    ecs: ecs::Ecs,
+    shadow_map: shadow::ShadowMap,
+    // the scene is drawn into an HDR texture, then tonemapped onto the surface
+    hdr: hdr::HdrPipeline,
+    // bloom, color grading, vignette, FXAA, gamma
+    post: post::PostStack,
+    // transient textures of the render graph, kept between frames
+    graph_pool: graph::TransientPool,
+    camera: camera::Camera,
+    camera_uniform: camera::CameraUniform,
+    camera_buffer: wgpu::Buffer,
+    camera_bind_group: wgpu::BindGroup,
+    lights: Vec<light::Light>,
+    ambient: [f32; 3],
+    shading: light::ShadingMode,
+    lights_uniform: light::LightsUniform,
+    light_buffer: wgpu::Buffer,
+    light_bind_group: wgpu::BindGroup,
+    pbr_pipeline: wgpu::RenderPipeline,
+    environment: pbr::Environment,
+    skybox: skybox::Skybox,
+    sprites: sprite::SpriteBatch,
+    // the tree and a white square packed into one atlas
+    sprite_texture: sprite::SpriteTexture,
+    sprite_regions: [atlas::AtlasRegion; 2],
+    sprite_animation: animation::Animation,
+    sprite_player: animation::AnimationPlayer,
+    // time of the last update(), for animations
+    last_update: web_time::Instant,
+    // cpu and gpu timings, see profiler.rs
+    profiler: profiler::Profiler,
+    text: text::TextRenderer,
+    // None when FONT_PATH could not be loaded, then there is no overlay text
+    font: Option<text::FontId>,
+    ui: ui::DebugUi,
+    debug_draw: debug_draw::DebugDraw,
+    debug_views: debug_view::DebugViews,
+    // light gizmos, world axes and a ground grid through debug_draw
+    show_gizmos: bool,
+    show_sprites: bool,
+    // screenshots and frame sequences
+    capture: capture::FrameCapture,
+    audio: audio::Audio,
 }
 
 impl State {
@@ -130,13 +218,22 @@ impl State {
         // Device
         let (device, queue) = adapter.request_device(&wgpu::wgt::DeviceDescriptor {
             label: None,
-            required_features: wgpu::Features::empty(),
+            // only optional features, when the adapter has them: debugging (line polygon mode,
+            // gpu timestamps) and the compressed texture formats (compressed.rs)
+            required_features: adapter.features()
+                & (wgpu::Features::POLYGON_MODE_LINE
+                    | wgpu::Features::TIMESTAMP_QUERY
+                    | wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS
+                    | wgpu::Features::TEXTURE_COMPRESSION_BC
+                    | wgpu::Features::TEXTURE_COMPRESSION_ETC2
+                    | wgpu::Features::TEXTURE_COMPRESSION_ASTC),
             required_limits: if cfg!(target_arch = "wasm32") {
                     wgpu::Limits::downlevel_webgl2_defaults()
                 } else {
                     wgpu::Limits::default()
                 },
             memory_hints: Default::default(),
+            experimental_features: wgpu::ExperimentalFeatures::disabled(),
             trace: wgpu::Trace::Off, 
         })
         .await?; 
@@ -155,7 +252,8 @@ impl State {
         .unwrap_or(surface_caps.formats[0]);
 
         let config = wgpu::wgt::SurfaceConfiguration { 
-                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                    // COPY_SRC for screenshots, see capture.rs
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT | (surface_caps.usages & wgpu::TextureUsages::COPY_SRC),
                     format: surface_format,
                     width: size.width,     //  Make sure that the width and height of the SurfaceTexture are not 0, as that can cause your app to crash.
                     height: size.height,    
@@ -166,73 +264,11 @@ impl State {
                 };
 
 
-        let diffuse_bytes = include_bytes!("happy-tree.png");
-        let diffuse_image = image::load_from_memory(diffuse_bytes).unwrap();
-        let diffuse_rgba = diffuse_image.to_rgb8();
-
-        let dimensions = diffuse_image.dimensions();
-        
-        let texture_size = wgpu::Extent3d {
-            width: dimensions.0,
-            height: dimensions.1,
-            // All textures are stored as 3D, we represent our 2D texture
-            // by setting depth to 1.
-            depth_or_array_layers: 1,
-        };
-
-        let diffuse_texture = device.create_texture(
-        &wgpu::wgt::TextureDescriptor {
-                label: Some("happy-tree texture"), 
-                size: texture_size, 
-                mip_level_count: 1, 
-                sample_count: 1, 
-                dimension: wgpu::TextureDimension::D2, 
-                // Most images are stored using sRGB, so we need to reflect that here.
-                format: wgpu::TextureFormat::Rgba8UnormSrgb, 
-                // TEXTURE_BINDING tells wgpu that we want to use this texture in shaders
-                // COPY_DST means that we want to copy data to this texture
-                usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST, 
-                // This is the same as with the SurfaceConfig. It
-                // specifies what texture formats can be used to
-                // create TextureViews for this texture. The base
-                // texture format (Rgba8UnormSrgb in this case) is
-                // always supported. Note that using a different
-                // texture format is not supported on the WebGL2
-                // backend.
-                view_formats: &[], 
-        });    
-
-        queue.write_texture(
-            wgpu::TexelCopyTextureInfoBase { 
-                texture: &diffuse_texture, 
-                mip_level: 0, 
-                origin: wgpu::Origin3d::ZERO, 
-                aspect: wgpu::TextureAspect::All
-            },
-            // The actual pixel data
-            &diffuse_rgba,
-            // The layout of the texture
-            wgpu::TexelCopyBufferLayout { 
-                offset: 0, 
-                bytes_per_row: Some(4*dimensions.0), 
-                rows_per_image: Some(dimensions.1),
-            },
-            texture_size,
-        );
-
+        let diffuse_bytes = vfs::read("builtin:happy-tree.png")?;
 
-        // We don't need to configure the texture view much, so let's
-        // let wgpu define it.
-        let diffuse_texture_view = diffuse_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let diffuse_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
-            address_mode_u: wgpu::AddressMode::ClampToEdge,
-            address_mode_v: wgpu::AddressMode::ClampToEdge,
-            address_mode_w: wgpu::AddressMode::ClampToEdge,
-            mag_filter: wgpu::FilterMode::Linear,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
-            ..Default::default()
-        });
+        // LEVEL
+        // SCENE_PATH when there is one, otherwise the built in pentagon scene
+        let scene_file = load_scene_file(SCENE_PATH);
 
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -258,110 +294,231 @@ impl State {
                 ],
                 label: Some("texture_bind_group_layout"),
             });
+        // materials come from the scene file, see scene_file::prepare_material
+        let material_layouts = scene_file::MaterialLayouts::new(&device, texture_bind_group_layout);
         
-        let diffuse_bind_group = device.create_bind_group(
-            &wgpu::BindGroupDescriptor {
-                layout: &texture_bind_group_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: wgpu::BindingResource::TextureView(&diffuse_texture_view),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: wgpu::BindingResource::Sampler(&diffuse_sampler),
-                    }
-                ],
-                label: Some("diffuse_bind_group"),
-            }
-        );
+        // HDR
+        let hdr = hdr::HdrPipeline::new(&device);
+        let mut post = post::PostStack::new(&device, &queue, &config);
+        if vfs::exists(LUT_PATH) {
+            load_lut(&mut post, &device, &queue);
+        }
 
-        
         let color = wgpu::Color {
                             r: 0.0,
                             g: 0.0,
                             b: 0.0,
                             a: 1.0,
                         };
-        
-        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
-            label: Some("shader"),
-            source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()) 
+
+        // CAMERA
+        let mut camera = camera::Camera::new(config.width, config.height);
+        if let Some(desc) = &scene_file.camera {
+            desc.apply(&mut camera);
+        }
+
+        let mut camera_uniform = camera::CameraUniform::new();
+        camera_uniform.update_view_proj(&camera);
+
+        let camera_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("camera buffer"),
+                contents: bytemuck::cast_slice(&[camera_uniform]),
+                // COPY_DST so we can update it every frame with queue.write_buffer
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let camera_bind_group_layout = camera::bind_group_layout(&device);
+
+        let camera_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &camera_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: camera_buffer.as_entire_binding(),
+                }
+            ],
+            label: Some("camera_bind_group"),
         });
 
+        // LIGHTS
+        let lights: Vec<light::Light> = scene_file.lights.iter().map(light::Light::from).collect();
+        let ambient = [0.05, 0.05, 0.05];
+        let shading = light::ShadingMode::BlinnPhong;
+
+        // SHADOWS
+        let shadow_map = shadow::ShadowMap::new(&device, Vertex::desc());
+
+        let mut lights_uniform = light::LightsUniform::new();
+        lights_uniform.update(&lights, ambient, shading, &shadow::assign_layers(&lights));
+
+        let light_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some("light buffer"),
+                contents: bytemuck::cast_slice(&[lights_uniform]),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            }
+        );
+
+        let light_bind_group_layout = light::bind_group_layout(&device);
+
+        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &light_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: light_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: shadow_map.buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&shadow_map.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&shadow_map.sampler),
+                },
+            ],
+            label: Some("light_bind_group"),
+        });
+        
         let render_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor { 
             label: Some("render pipeline layout"),
-            bind_group_layouts: &[&texture_bind_group_layout], 
+            bind_group_layouts: &[&material_layouts.diffuse, &camera_bind_group_layout, &light_bind_group_layout], 
             push_constant_ranges: &[]    
         });
 
-        let render_pipeline = device.create_render_pipeline(
-          &wgpu::RenderPipelineDescriptor {
-            label: Some("render pipeline"), 
-            layout: Some(&render_pipeline_layout), 
-            vertex: wgpu::VertexState { 
-                module: &shader, 
-                entry_point: Some("vs_main"), 
-                buffers: &[
-                    // SHADER BUFFER
-                    Vertex::desc(), 
-                ], 
-                compilation_options: wgpu::PipelineCompilationOptions::default(), 
-            }, 
-            fragment: Some(wgpu::FragmentState { 
-                module: &shader, 
-                entry_point: Some("fs_main"), 
-                targets: &[Some(wgpu::ColorTargetState { 
-                    format: config.format, 
-                    blend: Some(wgpu::BlendState::REPLACE), 
-                    write_mask: wgpu::ColorWrites::ALL, 
-                })], 
-                compilation_options: wgpu::PipelineCompilationOptions::default(), 
-            }), 
-
-            // Поле primitive описывает, как интерпретировать наши вершины при преобразовании их в треугольники.
-            primitive: wgpu::PrimitiveState { 
-                topology: wgpu::PrimitiveTopology::TriangleList, 
-                strip_index_format: None, 
-                front_face: wgpu::FrontFace::Ccw, 
-                cull_mode: Some(wgpu::Face::Back),
-                // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE 
-                polygon_mode: wgpu::PolygonMode::Fill, 
-                unclipped_depth: false, 
-                conservative: false, 
-            }, 
-
-            depth_stencil: None, 
-            multisample: wgpu::MultisampleState { 
-                count: 1, 
-                mask: !0,  // !=0
-                alpha_to_coverage_enabled: false }, 
-            multiview: None, 
-            cache: None,
+        let render_pipeline = create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            hdr.format(),
+            Some(texture::Texture::DEPTH_FORMAT),
+            &[Vertex::desc(), scene::InstanceRaw::desc()],
+            wgpu::ShaderModuleDescriptor {
+                label: Some("shader"),
+                source: wgpu::ShaderSource::Wgsl(concat!(include_str!("light.wgsl"), include_str!("scene.wgsl"), include_str!("shader.wgsl")).into()),
+            },
+            "render pipeline",
+        );
+
+        // PBR
+        let environment_bind_group_layout = pbr::environment_bind_group_layout(&device);
+
+        let environment = load_environment(&device, &queue, &environment_bind_group_layout)
+            .unwrap_or_else(|| pbr::Environment::sky(&device, &queue, &environment_bind_group_layout));
+
+        let pbr_pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("pbr pipeline layout"),
+            bind_group_layouts: &[
+                &material_layouts.pbr,
+                &camera_bind_group_layout,
+                &light_bind_group_layout,
+                &environment_bind_group_layout,
+            ],
+            push_constant_ranges: &[]
         });
 
+        let pbr_pipeline = create_render_pipeline(
+            &device,
+            &pbr_pipeline_layout,
+            hdr.format(),
+            Some(texture::Texture::DEPTH_FORMAT),
+            &[Vertex::desc(), scene::InstanceRaw::desc()],
+            wgpu::ShaderModuleDescriptor {
+                label: Some("pbr shader"),
+                source: wgpu::ShaderSource::Wgsl(concat!(include_str!("light.wgsl"), include_str!("scene.wgsl"), include_str!("pbr.wgsl")).into()),
+            },
+            "pbr pipeline",
+        );
 
-        let vertex_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("vertex buffer"),
-                contents: bytemuck::cast_slice(VERTICES),
-                usage: wgpu::BufferUsages::VERTEX,
-            }
+
+        // SKYBOX
+        // draws the same cube the pbr pipeline reflects
+        let skybox = skybox::Skybox::new(
+            &device,
+            hdr.format(),
+            texture::Texture::DEPTH_FORMAT,
+            &camera_bind_group_layout,
+            &environment_bind_group_layout,
         );
 
-        let num_vertices = VERTICES.len() as u32;
+        // SPRITES
+        // drawn over the post processed image, straight onto the surface
+        let mut sprites = sprite::SpriteBatch::new(&device, config.format);
+
+        let mut sprite_atlas = atlas::AtlasBuilder::new().max_size(device.limits().max_texture_dimension_2d);
+        sprite_atlas.add_bytes("happy-tree", &diffuse_bytes)?;
+        sprite_atlas.add_image("white", image::RgbaImage::from_pixel(16, 16, image::Rgba([255, 255, 255, 255])))?;
+        let sprite_sheet = if vfs::exists(SPRITE_SHEET_PATH) {
+            match sprite_atlas.add_sprite_sheet("sheet", SPRITE_SHEET_PATH) {
+                Ok(sheet) => Some(sheet),
+                Err(e) => {
+                    log::warn!("{SPRITE_SHEET_PATH}: {e:#}");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let sprite_atlas = sprite_atlas.build(&device, &queue, "sprite atlas")?;
+
+        let sprite_texture = sprites.add_texture(&device, &sprite_atlas.texture);
+        let sprite_regions = [*sprite_atlas.region("happy-tree").unwrap(), *sprite_atlas.region("white").unwrap()];
+        let sprite_animation = match &sprite_sheet {
+            Some(sheet) => sheet.animation(None, sprite_atlas.region("sheet").unwrap())?,
+            None => animation::Animation::from_regions(&sprite_regions, 0.5),
+        };
 
-        let index_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("index buffer"),
-                contents: bytemuck::cast_slice(INDICES),
-                usage: wgpu::BufferUsages::INDEX,
+        // TEXT
+        let mut text = text::TextRenderer::new(&device, &queue, &mut sprites);
+        let font = match vfs::read(FONT_PATH).and_then(|bytes| text.add_font(&bytes)) {
+            Ok(font) => Some(font),
+            Err(e) => {
+                log::warn!("no overlay text, could not load {}: {}", FONT_PATH, e);
+                None
             }
-        );  // координаты передаются индексом что занимает меньше памяти
+        };
 
-        let num_indices = INDICES.len() as u32;  
-        
-        let mut index_or_vertices = false;
+        // PROFILER
+        let profiler = profiler::Profiler::new(&device, &queue);
+
+        // DEBUG UI
+        let ui = ui::DebugUi::new(&device, &window, config.format);
+
+        // DEBUG DRAW
+        let debug_draw = debug_draw::DebugDraw::new(&device, config.format, texture::Texture::DEPTH_FORMAT, &camera_bind_group_layout);
+
+        // DEBUG VIEWS
+        let debug_views = debug_view::DebugViews::new(
+            &device,
+            config.format,
+            texture::Texture::DEPTH_FORMAT,
+            &camera_bind_group_layout,
+            Vertex::desc(),
+        );
+
+        // FRAME CAPTURE
+        let capture = capture::FrameCapture::new(&config);
+
+        // AUDIO
+        // the sound card when there is one (muted null output otherwise), or what AUDIO_OUTPUT asks for
+        let audio = audio::Audio::new(audio::Output::from_env());
+
+        // SCENE
+        // the level as ecs entities, the scene nodes are created by the first sync in update()
+        let scene = scene::Scene::new();
+        let mut ecs = ecs::Ecs::new();
+        let mut assets = assets::AssetServer::new(assets::LoadContext { features: device.features() });
+        // edits to the scene file reload the level, edits to the LUT regrade, see update()
+        assets.watch(SCENE_PATH);
+        assets.watch(LUT_PATH);
+        let (meshes, materials) = scene_file.instantiate(&mut assets, &mut ecs)?;
+
+        let index_or_vertices = false;
 
         // SELF
         Ok(Self
@@ -372,14 +529,48 @@ impl State {
                 config,
                 is_surface_configured: false,
                 render_pipeline,
-                vertex_buffer,
-                num_vertices,
                 window,
                 color,
-                index_buffer, 
-                num_indices,
                 index_or_vertices,
-                diffuse_bind_group,
+                assets,
+                meshes,
+                materials,
+                material_layouts,
+                scene,
+                ecs,
+                shadow_map,
+                hdr,
+                post,
+                graph_pool: graph::TransientPool::default(),
+                camera,
+                camera_uniform,
+                camera_buffer,
+                camera_bind_group,
+                lights,
+                ambient,
+                shading,
+                lights_uniform,
+                light_buffer,
+                light_bind_group,
+                pbr_pipeline,
+                environment,
+                skybox,
+                sprites,
+                sprite_texture,
+                sprite_regions,
+                sprite_animation,
+                sprite_player: animation::AnimationPlayer::new(),
+                last_update: web_time::Instant::now(),
+                profiler,
+                text,
+                font,
+                ui,
+                debug_draw,
+                debug_views,
+                show_gizmos: false,
+                show_sprites: false,
+                capture,
+                audio,
             })
         // SELF
 
@@ -392,6 +583,10 @@ impl State {
         self.config.height = _height;
         self.surface.configure(&self.device, &self.config);
         self.is_surface_configured = true;
+
+        self.camera.resize(_width, _height);
+        // the scene color and depth are graph transients, they follow config.width/height on their own
+        self.post.resize(&self.device, _width, _height);
         }
     }
     
@@ -404,6 +599,8 @@ impl State {
             return Ok(());
         }
 
+        let render_start = web_time::Instant::now();
+
         // Функция get_current_texture будет ждать, пока , surface не предоставят новый объект SurfaceTexture, который будет использоваться для рендеринга. Мы сохраним его outputдля дальнейшего использования.
         let output = self.surface.get_current_texture()?;
         
@@ -412,13 +609,167 @@ impl State {
         // CommandEncoder для формирования команд для отправки на gpu
         let mut encoder = self.device.create_command_encoder(&wgpu::wgt::CommandEncoderDescriptor { label: Some("render encoder") });
 
+        // RENDER GRAPH
+        // the passes are declared with the textures they touch, the graph orders them,
+        // and creates the scene color/depth from a pool that is reused between frames
+        // vertex and texture uploads of the ui, submitted before the encoder
+        let ui_commands = self.ui.prepare(&self.device, &self.queue, &mut encoder);
+        // the readback buffer when a screenshot or sequence wants this frame
+        let capture_target = self.capture.next_frame(&self.device, &output.texture);
+
+        let mut pool = std::mem::take(&mut self.graph_pool);
+        // taken out like the pool, the passes below borrow the whole state
+        let mut gpu_timer = self.profiler.gpu.take();
+        {
+            // the passes only read the state, the closures share this reference
+            let state = &*self;
+            // every visible scene node with a mesh, shared by the shadow, scene and debug view passes
+            let draws = state.scene.draws(|id| state.assets.get(state.meshes.get(id.0)?));
+            let draws = &draws;
+            let mut graph = graph::RenderGraph::new();
+
+            let backbuffer = graph.import_texture("backbuffer", &view);
+            let shadow_map = graph.import_texture("shadow map", &state.shadow_map.view);
+            let scene_color = graph.create_texture(
+                "scene color",
+                graph::TextureDesc::render_target(state.config.width, state.config.height, hdr::HDR_FORMAT),
+            );
+            let scene_depth = graph.create_texture(
+                "scene depth",
+                graph::TextureDesc::render_target(state.config.width, state.config.height, texture::Texture::DEPTH_FORMAT),
+            );
+
+            // SHADOW PASS
+            // depth from every shadow casting light, sampled by the main pass below
+            graph.add_pass("shadows", &[], &[shadow_map], move |_, encoder| {
+                state.shadow_map.render(encoder, draws);
+            });
+
+            // SCENE PASS
+            // the scene goes to the HDR texture, not straight to the surface
+            graph.add_pass("scene", &[shadow_map], &[scene_color, scene_depth], move |ctx, encoder| {
+                state.render_scene(encoder, ctx.view(scene_color), ctx.view(scene_depth), draws);
+            });
+
+            // POST PROCESSING
+            // HDR texture -> bloom -> tonemapping -> LDR effects -> surface texture
+            // bloom is composited back into the scene color, so the pass writes it too
+            graph.add_pass("post", &[scene_color], &[scene_color, backbuffer], move |ctx, encoder| {
+                state.post.process(&state.device, encoder, &state.hdr, ctx.view(scene_color), ctx.view(backbuffer));
+            });
+
+            // DEBUG VIEWS
+            // wireframe, normals, uvs, depth or overdraw over / instead of the post processed image
+            if state.debug_views.is_active() {
+                graph.add_pass("debug view", &[scene_depth, backbuffer], &[backbuffer], move |ctx, encoder| {
+                    state.debug_views.render(
+                        &state.device,
+                        encoder,
+                        ctx.view(backbuffer),
+                        ctx.view(scene_depth),
+                        &state.camera_bind_group,
+                        draws,
+                    );
+                });
+            }
+
+            // DEBUG DRAW
+            // after post processing so the lines keep their colors, tested against the scene depth
+            if state.debug_draw.has_lines() {
+                graph.add_pass("debug draw", &[scene_depth, backbuffer], &[backbuffer], move |ctx, encoder| {
+                    state.debug_draw.render(encoder, ctx.view(backbuffer), ctx.view(scene_depth), &state.camera_bind_group);
+                });
+            }
+
+            // SPRITES AND TEXT
+            // on top of the finished image
+            if state.sprites.sprite_count() > 0 {
+                graph.add_pass("sprites", &[backbuffer], &[backbuffer], move |ctx, encoder| {
+                    let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                        label: Some("sprite pass"),
+                        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                            depth_slice: None,
+                            view: ctx.view(backbuffer),
+                            resolve_target: None,
+                            ops: wgpu::Operations {
+                                load: wgpu::LoadOp::Load,
+                                store: wgpu::StoreOp::Store,
+                            },
+                        })],
+                        depth_stencil_attachment: None,
+                        timestamp_writes: None,
+                        occlusion_query_set: None,
+                    });
+                    state.sprites.render(&mut render_pass);
+                });
+            }
+
+            // FRAME CAPTURE
+            // the backbuffer into the readback buffer, before or after the ui
+            // declared as a write so the graph keeps it and orders it against the ui pass
+            let capture_pass = capture_target.as_ref().map(|target| {
+                let texture = &output.texture;
+                move |_: &graph::PassContext, encoder: &mut wgpu::CommandEncoder| target.copy(encoder, texture)
+            });
+            let (capture_before_ui, capture_after_ui) = match capture_pass {
+                Some(pass) if state.capture.include_ui => (None, Some(pass)),
+                pass => (pass, None),
+            };
+            if let Some(pass) = capture_before_ui {
+                graph.add_pass("capture", &[backbuffer], &[backbuffer], pass);
+            }
+
+            // DEBUG UI
+            // last, over everything
+            if state.ui.has_output() {
+                graph.add_pass("ui", &[backbuffer], &[backbuffer], move |ctx, encoder| {
+                    state.ui.paint(encoder, ctx.view(backbuffer));
+                });
+            }
+
+            if let Some(pass) = capture_after_ui {
+                graph.add_pass("capture", &[backbuffer], &[backbuffer], pass);
+            }
+
+            graph.execute(&state.device, &mut pool, &mut encoder, gpu_timer.as_mut());
+        }
+        self.graph_pool = pool;
+
+        if let Some(timer) = &mut gpu_timer {
+            timer.resolve(&mut encoder);
+        }
+        self.queue.submit(ui_commands.into_iter().chain(std::iter::once(encoder.finish())));
+        if let Some(timer) = &mut gpu_timer {
+            timer.after_submit();
+        }
+        self.profiler.gpu = gpu_timer;
+        if let Some(target) = capture_target {
+            self.capture.submitted(target);
+        }
+        output.present();
+        self.capture.poll(&self.device);
+
+        self.profiler.record("cpu render", render_start.elapsed().as_secs_f32() * 1000.0);
+
+        Ok(())
+    }
+
+    // Main pass: every mesh with the current shading, into the HDR color and depth targets
+    fn render_scene(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        color_view: &wgpu::TextureView,
+        depth_view: &wgpu::TextureView,
+        draws: &[scene::Draw],
+    ) {
         // Нам нужно использовать , encoder чтобы создать RenderPass. В RenderPass содержатся все методы для отрисовки.
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor { 
                 label: Some("Some render pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
                     depth_slice: None,
-                    view: &view,
+                    // the scene goes to the HDR texture, not straight to the surface
+                    view: color_view,
                     resolve_target: None, // resolve_target — это текстура, которая получит финальное (разрешённое) изображение
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(self.color),
@@ -426,53 +777,535 @@ impl State {
                         store: wgpu::StoreOp::Store, 
                     },
                 })],
-                depth_stencil_attachment: None,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: depth_view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
                 timestamp_writes: None,
                 occlusion_query_set: None 
                 });
 
-            render_pass.set_pipeline(&self.render_pipeline);
+            // SET PIPELINE
+            let pbr = self.shading == light::ShadingMode::Pbr;
+            if pbr {
+                render_pass.set_pipeline(&self.pbr_pipeline);
+                render_pass.set_bind_group(3, &self.environment.bind_group, &[]);
+            }
+            else {
+                render_pass.set_pipeline(&self.render_pipeline);
+            }
 
-            // SET VERTEX BUFFER TO RENDER PASS
-            render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
-            
             // SET BINDGROUP
-            render_pass.set_bind_group(0, &self.diffuse_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.camera_bind_group, &[]);
+            render_pass.set_bind_group(2, &self.light_bind_group, &[]);
+
+            // SCENE NODES
+            for draw in draws {
+                // the texture may still be loading
+                let Some(material) = draw.material.and_then(|id| self.materials.get(id.0)?.bind_groups.as_ref()) else { continue };
+                if pbr {
+                    render_pass.set_bind_group(0, &material.pbr.bind_group, &[]);
+                }
+                else {
+                    render_pass.set_bind_group(0, &material.diffuse, &[]);
+                }
 
-            // SET INDEX BUFFER 
-            render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+                // the node's world matrix, see scene.wgsl
+                render_pass.set_vertex_buffer(1, draw.instance);
 
-            // render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
-            
-            
-            // CHANGE INDEX OR VERTICES DRAW
-            if self.index_or_vertices {
-                render_pass.draw(0..self.num_vertices, 0..1);
+                // CHANGE INDEX OR VERTICES DRAW
+                if self.index_or_vertices {
+                    render_pass.set_vertex_buffer(0, draw.mesh.vertex_buffer.slice(..));
+                    render_pass.draw(0..draw.mesh.num_vertices, 0..1);
+                }
+                else {
+                    draw.mesh.draw(&mut render_pass);
+                }
             }
-            else {
-                render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
 
-            }
+            // SKYBOX
+            // last, so only the pixels the geometry left empty are shaded
+            self.skybox.draw(&mut render_pass, &self.camera_bind_group, &self.environment);
 
             // RENDER PASS DRAW
             // render_pass.draw(0..self.num_vertices, 0..1);
         }
-        
-        self.queue.submit(std::iter::once(encoder.finish()));
-        output.present();
+    }
 
-        Ok(())
+    // LEVEL
+    // Replaces entities, meshes, materials, lights and camera with the file's.
+    // On an error the current level stays as it is.
+    fn load_scene(&mut self, path: &str) {
+        let file = match scene_file::SceneFile::load(path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::error!("{:#}", e);
+                return;
+            }
+        };
+        match file.instantiate(&mut self.assets, &mut self.ecs) {
+            Ok((meshes, materials)) => {
+                self.meshes = meshes;
+                self.materials = materials;
+            }
+            Err(e) => {
+                log::error!("{}: {:#}", path, e);
+                return;
+            }
+        }
+        self.lights = file.lights.iter().map(light::Light::from).collect();
+        if let Some(camera) = &file.camera {
+            camera.apply(&mut self.camera);
+        }
+        log::info!("scene loaded from {} ({} entities)", path, file.entities.len());
+    }
+
+    fn save_scene(&self, path: &str) {
+        let file = scene_file::SceneFile::capture(&self.ecs, &self.assets, &self.meshes, &self.materials, &self.lights, &self.camera);
+        match file.save(path) {
+            Ok(()) => log::info!("scene saved to {} ({} entities)", path, file.entities.len()),
+            Err(e) => log::error!("{:#}", e),
+        }
+    }
+
+    // Sprite batch demo: a grid of trees and white squares over the whole window,
+    // one atlas texture on three layers -> three draw calls for ~2000 sprites,
+    // and an animated sprite on top of it.
+    fn update_sprites(&mut self, dt: f32) {
+        let (width, height) = (self.config.width as f32, self.config.height as f32);
+        let cell = 24.0;
+        let columns = (width / cell).ceil() as usize;
+        let rows = (height / cell).ceil() as usize;
+
+        for row in 0..rows {
+            for column in 0..columns {
+                let i = row * columns + column;
+                let mut sprite = sprite::Sprite::new(
+                    self.sprite_texture,
+                    [(column as f32 + 0.5) * cell, (row as f32 + 0.5) * cell],
+                    [cell * 0.8, cell * 0.8],
+                );
+                sprite.uv_rect = self.sprite_regions[i % 2].uv_rect;
+                sprite.rotation = i as f32 * 0.1;
+                sprite.tint = [
+                    column as f32 / columns as f32,
+                    row as f32 / rows as f32,
+                    1.0,
+                    0.8,
+                ];
+                sprite.layer = (i % 3) as i32;
+                self.sprites.draw(sprite);
+            }
+        }
+
+        self.sprite_player.update(&self.sprite_animation, dt);
+        let frame = self.sprite_player.frame(&self.sprite_animation);
+        let mut animated = sprite::Sprite::new(self.sprite_texture, [width / 2.0, height / 2.0], [128.0, 128.0]);
+        animated.uv_rect = frame.uv_rect;
+        animated.layer = 3;
+        self.sprites.draw(animated);
+    }
+
+    // Everything that otherwise needs a key binding
+    fn debug_window(&mut self, context: &egui::Context) {
+        egui::Window::new("debug").default_pos([8.0, 40.0]).show(context, |ui| {
+            let frame = self.profiler.summary("frame").unwrap_or_default();
+            ui.label(format!("{:.2} ms ({:.0} fps)", frame.avg, 1000.0 / frame.avg.max(1e-6)));
+            ui.label(format!("sprites: {} in {} draw calls", self.sprites.sprite_count(), self.sprites.batch_count()));
+            ui.label(format!("render graph textures: {}", self.graph_pool.texture_count()));
+            ui.label(format!("entities: {}, scene nodes: {}", self.ecs.world.len(), self.scene.len()));
+            let (assets, loading) = self.assets.counts();
+            ui.label(format!("assets: {} ({} loading)", assets, loading));
+
+            ui.collapsing("profiler", |ui| {
+                if self.profiler.gpu.is_none() {
+                    ui.label("no gpu timestamps on this device");
+                }
+                egui::Grid::new("profiler").striped(true).show(ui, |ui| {
+                    for header in ["", "min", "avg", "max", "p99"] {
+                        ui.label(header);
+                    }
+                    ui.end_row();
+                    for (name, s) in self.profiler.summaries() {
+                        ui.label(name);
+                        for value in [s.min, s.avg, s.max, s.p99] {
+                            ui.label(format!("{:.2}", value));
+                        }
+                        ui.end_row();
+                    }
+                });
+                let mut log_stats = self.profiler.log_interval.is_some();
+                if ui.checkbox(&mut log_stats, "log every 5 s").changed() {
+                    self.profiler.log_interval = log_stats.then_some(5.0);
+                }
+            });
+
+            ui.horizontal(|ui| {
+                if ui.button("save scene (F5)").clicked() {
+                    self.save_scene(SCENE_PATH);
+                }
+                if ui.button("load scene (F9)").clicked() {
+                    self.load_scene(SCENE_PATH);
+                }
+                ui.label(SCENE_PATH);
+            });
+
+            ui.collapsing("capture", |ui| {
+                if !self.capture.is_supported() {
+                    ui.label("the surface can not be copied on this device");
+                    return;
+                }
+                ui.checkbox(&mut self.capture.include_ui, "include debug ui");
+                ui.horizontal(|ui| {
+                    if ui.button("screenshot (F12)").clicked() {
+                        self.capture.screenshot();
+                    }
+                    match self.capture.sequence_frames() {
+                        Some(frames) => {
+                            if ui.button(format!("stop sequence (F11), {} frames", frames)).clicked() {
+                                self.capture.stop_sequence();
+                            }
+                        }
+                        None => {
+                            if ui.button("record sequence (F11)").clicked() {
+                                self.capture.start_default_sequence();
+                            }
+                        }
+                    }
+                });
+            });
+
+            ui.collapsing("audio", |ui| {
+                ui.label(format!("output: {}, voices: {}", self.audio.output_name(), self.audio.voice_count()));
+                let mut master = self.audio.master_volume();
+                if ui.add(egui::Slider::new(&mut master, 0.0..=1.0).text("master")).changed() {
+                    self.audio.set_master_volume(master);
+                }
+                for bus in audio::Bus::ALL {
+                    let mut volume = self.audio.bus_volume(bus);
+                    if ui.add(egui::Slider::new(&mut volume, 0.0..=1.0).text(format!("{:?}", bus).to_lowercase())).changed() {
+                        self.audio.set_bus_volume(bus, volume);
+                    }
+                }
+                let mut doppler = self.audio.doppler_factor();
+                if ui.add(egui::Slider::new(&mut doppler, 0.0..=2.0).text("doppler")).changed() {
+                    self.audio.set_doppler_factor(doppler);
+                }
+                if ui.button("stop all").clicked() {
+                    self.audio.stop_all();
+                }
+            });
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                let mut color = [self.color.r as f32, self.color.g as f32, self.color.b as f32];
+                if ui.color_edit_button_rgb(&mut color).changed() {
+                    self.color = wgpu::Color { r: color[0] as f64, g: color[1] as f64, b: color[2] as f64, a: 1.0 };
+                }
+                ui.label("clear color");
+            });
+            ui.checkbox(&mut self.index_or_vertices, "draw without index buffer");
+            ui.checkbox(&mut self.skybox.enabled, "skybox");
+            ui.checkbox(&mut self.show_sprites, "sprite demo");
+            ui.checkbox(&mut self.show_gizmos, "light gizmos");
+
+            ui.separator();
+            egui::ComboBox::from_label("debug view")
+                .selected_text(format!("{:?}", self.debug_views.view))
+                .show_ui(ui, |ui| {
+                    for view in debug_view::DebugView::ALL {
+                        ui.selectable_value(&mut self.debug_views.view, view, format!("{:?}", view));
+                    }
+                });
+            if self.debug_views.view == debug_view::DebugView::Wireframe {
+                ui.checkbox(&mut self.debug_views.force_barycentric, "barycentric wireframe");
+                ui.label(format!("wireframe: {}", self.debug_views.wireframe_mode()));
+            }
+
+            ui.horizontal(|ui| {
+                ui.label("shading");
+                ui.radio_value(&mut self.shading, light::ShadingMode::Unlit, "unlit");
+                ui.radio_value(&mut self.shading, light::ShadingMode::BlinnPhong, "blinn-phong");
+                ui.radio_value(&mut self.shading, light::ShadingMode::Pbr, "pbr");
+            });
+            ui.collapsing("shadows", |ui| {
+                let shadows = &mut self.shadow_map.settings;
+                ui.add(egui::Slider::new(&mut shadows.depth_bias, 0.0..=0.01).logarithmic(true).text("depth bias"));
+                ui.add(egui::Slider::new(&mut shadows.normal_bias, 0.0..=0.1).text("normal bias"));
+                ui.add(egui::Slider::new(&mut shadows.pcf_radius, 0..=4).text("pcf radius"));
+                ui.add(egui::Slider::new(&mut shadows.cascade_split_lambda, 0.0..=1.0).text("cascade split lambda"));
+                ui.add(egui::Slider::new(&mut shadows.max_distance, 1.0..=100.0).text("max distance"));
+            });
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("tonemapper");
+                ui.radio_value(&mut self.hdr.tonemapper, hdr::Tonemapper::Reinhard, "reinhard");
+                ui.radio_value(&mut self.hdr.tonemapper, hdr::Tonemapper::Aces, "aces");
+                ui.radio_value(&mut self.hdr.tonemapper, hdr::Tonemapper::AgX, "agx");
+            });
+            ui.add(egui::Slider::new(&mut self.hdr.exposure, -5.0..=5.0).text("exposure"));
+
+            let post = &mut self.post.settings;
+            ui.checkbox(&mut post.bloom.enabled, "bloom");
+            let mut reload_lut = false;
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut post.color_grading.enabled, "color grading");
+                reload_lut = ui.button(format!("load {}", LUT_PATH)).clicked();
+            });
+            if post.color_grading.enabled {
+                ui.add(egui::Slider::new(&mut post.color_grading.strength, 0.0..=1.0).text("grading strength"));
+            }
+            ui.checkbox(&mut post.vignette.enabled, "vignette");
+            ui.checkbox(&mut post.fxaa.enabled, "fxaa");
+            ui.checkbox(&mut post.gamma.enabled, "gamma");
+            if reload_lut {
+                load_lut(&mut self.post, &self.device, &self.queue);
+            }
+        });
+    }
+
+    // Where the lights are and where they point, plus world axes and a grid on the ground
+    fn draw_gizmos(&mut self) {
+        use cgmath::{EuclideanSpace, InnerSpace};
+
+        self.debug_draw.grid(cgmath::Point3::new(0.0, -0.5, 0.0), 0.25, 16, [0.5, 0.5, 0.5, 0.5], 0.0, true);
+        self.debug_draw.axes(cgmath::Point3::origin(), 0.5, 0.0, false);
+
+        for light in &self.lights {
+            match *light {
+                light::Light::Directional { direction, color, .. } => {
+                    let from = cgmath::Point3::new(0.0, 1.5, 0.0);
+                    self.debug_draw.arrow(from, from + direction.normalize() * 0.5, [color[0], color[1], color[2], 1.0], 0.0, false);
+                }
+                light::Light::Point { position, color, range, .. } => {
+                    let color = [color[0], color[1], color[2], 1.0];
+                    self.debug_draw.sphere(position, 0.05, color, 0.0, false);
+                    self.debug_draw.sphere(position, range, [color[0], color[1], color[2], 0.2], 0.0, true);
+                }
+                light::Light::Spot { position, direction, color, range, outer_angle, .. } => {
+                    let color = [color[0], color[1], color[2], 1.0];
+                    let direction = direction.normalize();
+                    self.debug_draw.arrow(position, position + direction * 0.3, color, 0.0, false);
+                    // the outer cone at the end of its range
+                    let end = position + direction * range;
+                    let radius = range * outer_angle.0.tan();
+                    self.debug_draw.circle(end, direction, radius, [color[0], color[1], color[2], 0.3], 0.0, true);
+                }
+            }
+        }
+    }
+
+    // FPS counter in the top left corner
+    fn draw_overlay(&mut self) {
+        let Some(font) = self.font else {
+            return;
+        };
+        let frame = self.profiler.summary("frame").unwrap_or_default();
+        let fps = if frame.avg > 0.0 { 1000.0 / frame.avg } else { 0.0 };
+        let line = format!("{:.0} fps  {:.2} ms", fps, frame.avg);
+
+        let mut style = text::TextStyle::new(font, 18.0);
+        // shadow first, one pixel down right
+        style.color = [0.0, 0.0, 0.0, 0.8];
+        self.text.draw(&self.queue, &mut self.sprites, &line, [9.0, 9.0], &style);
+        style.color = [1.0, 1.0, 1.0, 1.0];
+        style.layer += 1;
+        self.text.draw(&self.queue, &mut self.sprites, &line, [8.0, 8.0], &style);
     }
 
     pub fn update(&mut self) {
+        let now = web_time::Instant::now();
+        let dt = (now - self.last_update).as_secs_f32();
+        self.last_update = now;
+        self.profiler.begin_frame(&self.device, dt);
+        // a recorded sequence advances by whole frames of the video, not by wall time
+        let dt = self.capture.fixed_dt().unwrap_or(dt);
+
+        self.camera_uniform.update_view_proj(&self.camera);
+        self.queue.write_buffer(&self.camera_buffer, 0, bytemuck::cast_slice(&[self.camera_uniform]));
+
+        // ASSETS
+        // changed files are reloaded, what the loader threads finished goes to the gpu,
+        // materials pick up their new textures
+        self.assets.update(&self.device, &self.queue);
+        for path in self.assets.take_changed() {
+            // before the systems run, so the sync below already sees the new entities
+            if path == SCENE_PATH {
+                self.load_scene(SCENE_PATH);
+            } else if path == LUT_PATH {
+                load_lut(&mut self.post, &self.device, &self.queue);
+            }
+        }
+        for material in &mut self.materials {
+            scene_file::prepare_material(&self.device, &self.queue, &self.material_layouts, &self.assets, material);
+        }
+
+        // GAME LOGIC
+        // the systems, then the entities into the scene graph
+        self.ecs.run(dt);
+        self.ecs.sync_scene(&mut self.scene);
+        self.scene.prepare(&self.device, &self.queue);
+
+        // AUDIO
+        // heard from the camera, emitters follow their nodes' new world positions
+        self.audio.update_listener(&self.camera, dt);
+        self.audio.sync_emitters(&self.ecs, &self.scene, &self.assets, dt);
+        self.audio.update(dt);
+
+        let shadow_layers = self.shadow_map.update(&self.queue, &self.camera, &self.lights);
+        self.lights_uniform.update(&self.lights, self.ambient, self.shading, &shadow_layers);
+        self.queue.write_buffer(&self.light_buffer, 0, bytemuck::cast_slice(&[self.lights_uniform]));
+
+        self.hdr.update(&self.queue);
+        self.post.update(&self.queue);
+        self.debug_views.update(&self.queue);
+
+        // DEBUG DRAW
+        self.debug_draw.tick(dt);
+        if self.show_gizmos {
+            self.draw_gizmos();
+        }
+        self.debug_draw.prepare(&self.device, &self.queue);
+
+        // 2D: everything drawn into the sprite batch this frame
+        self.sprites.begin();
+        if self.show_sprites {
+            self.update_sprites(dt);
+        }
+        self.draw_overlay();
+        self.sprites.prepare(&self.device, &self.queue, self.config.width, self.config.height);
+
+        // DEBUG UI
+        self.ui.begin(&self.window);
+        let context = self.ui.context().clone();
+        self.debug_window(&context);
+        self.ui.end(&self.window);
 
-        // later
+        self.profiler.record("cpu update", now.elapsed().as_secs_f32() * 1000.0);
     }
 
 
     
 }
 
+// ENVIRONMENT_PATH or the ENVIRONMENT_FACES, None when there is neither or it does not load
+fn load_environment(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout) -> Option<pbr::Environment> {
+    let face_paths = pbr::CUBE_FACES.map(|face| format!("{}/{}.png", ENVIRONMENT_FACES, face));
+    let result = if vfs::exists(ENVIRONMENT_PATH) {
+        vfs::read(ENVIRONMENT_PATH).and_then(|bytes| pbr::Environment::from_bytes(device, queue, layout, &bytes, ENVIRONMENT_PATH))
+    } else if face_paths.iter().all(|path| vfs::exists(path)) {
+        face_paths
+            .iter()
+            .map(|path| vfs::read(path))
+            .collect::<anyhow::Result<Vec<_>>>()
+            .and_then(|faces| {
+                let faces: [&[u8]; 6] = std::array::from_fn(|i| faces[i].as_slice());
+                pbr::Environment::from_faces(device, queue, layout, faces, ENVIRONMENT_FACES)
+            })
+    } else {
+        return None;
+    };
+    match result {
+        Ok(environment) => {
+            log::info!("environment loaded");
+            Some(environment)
+        }
+        Err(e) => {
+            log::error!("environment: {:#}", e);
+            None
+        }
+    }
+}
+
+// LUT_PATH into the post stack and grading on, on an error the current LUT stays
+fn load_lut(post: &mut post::PostStack, device: &wgpu::Device, queue: &wgpu::Queue) {
+    match vfs::read(LUT_PATH).and_then(|bytes| post.load_lut(device, queue, &bytes)) {
+        Ok(()) => {
+            post.settings.color_grading.enabled = true;
+            log::info!("color grading LUT loaded from {}", LUT_PATH);
+        }
+        Err(e) => log::error!("{}: {:#}", LUT_PATH, e),
+    }
+}
+
+// The startup level: the file when there is one and it loads, the built in scene otherwise.
+fn load_scene_file(path: &str) -> scene_file::SceneFile {
+    if !vfs::exists(path) {
+        return scene_file::SceneFile::default_scene();
+    }
+    scene_file::SceneFile::load(path).unwrap_or_else(|e| {
+        log::warn!("using the built in scene: {:#}", e);
+        scene_file::SceneFile::default_scene()
+    })
+}
+
+fn create_render_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    color_format: wgpu::TextureFormat,
+    depth_format: Option<wgpu::TextureFormat>,
+    vertex_layouts: &[wgpu::VertexBufferLayout],
+    shader: wgpu::ShaderModuleDescriptor,
+    label: &str,
+) -> wgpu::RenderPipeline {
+    let shader = device.create_shader_module(shader);
+
+    device.create_render_pipeline(
+      &wgpu::RenderPipelineDescriptor {
+        label: Some(label), 
+        layout: Some(layout), 
+        vertex: wgpu::VertexState { 
+            module: &shader, 
+            entry_point: Some("vs_main"), 
+            // SHADER BUFFER
+            buffers: vertex_layouts, 
+            compilation_options: wgpu::PipelineCompilationOptions::default(), 
+        }, 
+        fragment: Some(wgpu::FragmentState { 
+            module: &shader, 
+            entry_point: Some("fs_main"), 
+            targets: &[Some(wgpu::ColorTargetState { 
+                format: color_format, 
+                blend: Some(wgpu::BlendState::REPLACE), 
+                write_mask: wgpu::ColorWrites::ALL, 
+            })], 
+            compilation_options: wgpu::PipelineCompilationOptions::default(), 
+        }), 
+
+        // Поле primitive описывает, как интерпретировать наши вершины при преобразовании их в треугольники.
+        primitive: wgpu::PrimitiveState { 
+            topology: wgpu::PrimitiveTopology::TriangleList, 
+            strip_index_format: None, 
+            front_face: wgpu::FrontFace::Ccw, 
+            cull_mode: Some(wgpu::Face::Back),
+            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE 
+            polygon_mode: wgpu::PolygonMode::Fill, 
+            unclipped_depth: false, 
+            conservative: false, 
+        }, 
+
+        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
+            format,
+            depth_write_enabled: true,
+            // draw fragments closer to the camera over the ones behind
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }), 
+        multisample: wgpu::MultisampleState { 
+            count: 1, 
+            mask: !0,  // !=0
+            alpha_to_coverage_enabled: false }, 
+        multiview: None, 
+        cache: None,
+    })
+}
+
 #[derive(Default)]
 
 struct App {
@@ -509,10 +1342,25 @@ impl ApplicationHandler for App {
             None => return,
         };
 
+        // a key let go while the ui has focus still ends the press, or the game would see it held forever
+        if let WindowEvent::KeyboardInput {
+            event: KeyEvent { physical_key: PhysicalKey::Code(code), state: ElementState::Released, .. },
+            ..
+        } = &event
+        {
+            wgpu_state.ecs.resources.expect_mut::<ecs::Input>().key(*code, false);
+        }
+
+        // the debug ui sees every event first, what it uses does not reach the game
+        if wgpu_state.ui.on_window_event(&wgpu_state.window, &event) {
+            return;
+        }
+
     match event {
                 WindowEvent::CloseRequested => event_loop.exit(),
                 WindowEvent::Resized(size) => wgpu_state.resize(size.width, size.height),
                 WindowEvent::RedrawRequested => {
+                    wgpu_state.update();
                     match wgpu_state.render() {
                         Ok(_) => {}
                         Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
@@ -562,19 +1410,97 @@ impl ApplicationHandler for App {
                             ..
                         },
                     ..
-                } => match (code, state.is_pressed()) {
-                    (KeyCode::Escape, true) => event_loop.exit(),
-                    (KeyCode::KeyV, true) => {
-                        wgpu_state.index_or_vertices = !wgpu_state.index_or_vertices;
+                } => {
+                    // held keys for the ecs systems, see ecs::Input
+                    wgpu_state.ecs.resources.expect_mut::<ecs::Input>().key(code, state.is_pressed());
+                    match (code, state.is_pressed()) {
+                        (KeyCode::Escape, true) => event_loop.exit(),
+                        (KeyCode::F1, true) => {
+                            wgpu_state.ui.visible = !wgpu_state.ui.visible;
+                        }
+                        // QUICK SAVE / QUICK LOAD OF THE LEVEL
+                        (KeyCode::F5, true) => {
+                            wgpu_state.save_scene(SCENE_PATH);
+                        }
+                        (KeyCode::F9, true) => {
+                            wgpu_state.load_scene(SCENE_PATH);
+                        }
+                        // SCREENSHOT / FRAME SEQUENCE ON / OFF
+                        (KeyCode::F12, true) => {
+                            wgpu_state.capture.screenshot();
+                        }
+                        (KeyCode::F11, true) => {
+                            if wgpu_state.capture.is_recording() {
+                                wgpu_state.capture.stop_sequence();
+                            } else {
+                                wgpu_state.capture.start_default_sequence();
+                            }
+                        }
+                        // CYCLE DEBUG VIEW: OFF -> WIREFRAME -> NORMALS -> UVS -> DEPTH -> OVERDRAW
+                        (KeyCode::KeyX, true) => {
+                            wgpu_state.debug_views.view = wgpu_state.debug_views.view.next();
+                            log::info!("debug view: {:?} ({})", wgpu_state.debug_views.view, wgpu_state.debug_views.wireframe_mode());
+                        }
+                        (KeyCode::KeyV, true) => {
+                            wgpu_state.index_or_vertices = !wgpu_state.index_or_vertices;
+                        }
+                        // CYCLE SHADING: UNLIT -> BLINN-PHONG -> PBR
+                        (KeyCode::KeyL, true) => {
+                            wgpu_state.shading = wgpu_state.shading.next();
+                            log::info!("shading: {:?}", wgpu_state.shading);
+                        }
+                        // TONEMAPPER AND EXPOSURE
+                        (KeyCode::KeyT, true) => {
+                            wgpu_state.hdr.tonemapper = wgpu_state.hdr.tonemapper.next();
+                            log::info!("tonemapper: {:?}", wgpu_state.hdr.tonemapper);
+                        }
+                        (KeyCode::Equal, true) => {
+                            wgpu_state.hdr.exposure += 0.5;
+                            log::info!("exposure: {}", wgpu_state.hdr.exposure);
+                        }
+                        (KeyCode::Minus, true) => {
+                            wgpu_state.hdr.exposure -= 0.5;
+                            log::info!("exposure: {}", wgpu_state.hdr.exposure);
+                        }
+                        (KeyCode::KeyK, true) => {
+                            wgpu_state.skybox.enabled = !wgpu_state.skybox.enabled;
+                            log::info!("skybox: {}", wgpu_state.skybox.enabled);
+                        }
+                        (KeyCode::KeyP, true) => {
+                            wgpu_state.show_sprites = !wgpu_state.show_sprites;
+                            log::info!("sprites: {}", wgpu_state.show_sprites);
+                        }
+                        // POST EFFECTS ON / OFF
+                        (KeyCode::KeyB, true) => {
+                            let bloom = &mut wgpu_state.post.settings.bloom;
+                            bloom.enabled = !bloom.enabled;
+                            log::info!("bloom: {}", bloom.enabled);
+                        }
+                        (KeyCode::KeyG, true) => {
+                            let grading = &mut wgpu_state.post.settings.color_grading;
+                            grading.enabled = !grading.enabled;
+                            log::info!("color grading: {}", grading.enabled);
+                        }
+                        (KeyCode::KeyN, true) => {
+                            let vignette = &mut wgpu_state.post.settings.vignette;
+                            vignette.enabled = !vignette.enabled;
+                            log::info!("vignette: {}", vignette.enabled);
+                        }
+                        (KeyCode::KeyF, true) => {
+                            let fxaa = &mut wgpu_state.post.settings.fxaa;
+                            fxaa.enabled = !fxaa.enabled;
+                            log::info!("fxaa: {}", fxaa.enabled);
+                        }
+                        _ => {}
                     }
-                    _ => {}
-                },
+                }
                 _ => {}
             }
         }
         
     fn resumed(&mut self, event_loop: &ActiveEventLoop) {   
 
+        #[cfg_attr(not(target_arch = "wasm32"), allow(unused_mut))]
         let mut window_attributes: WindowAttributes = Window::default_attributes();
 
          #[cfg(target_arch = "wasm32")]
@@ -606,6 +1532,8 @@ impl ApplicationHandler for App {
             // proxy to send the results to the event loop
             if let Some(proxy) = self.proxy.take() {
                 wasm_bindgen_futures::spawn_local(async move {
+                    // the archive has to be fetched, on native mount_defaults opened it
+                    vfs::mount_fetched_pak().await;
                     assert!(proxy
                         .send_event(
                             State::new(window)
@@ -619,6 +1547,7 @@ impl ApplicationHandler for App {
     }
     
     // user event
+    #[cfg_attr(not(target_arch = "wasm32"), allow(unused_variables))]
     fn user_event(&mut self, event_loop: &ActiveEventLoop, event: ()) {    
         // This is where proxy.send_event() ends up
         #[cfg(target_arch="wasm32")] {
@@ -647,6 +1576,9 @@ fn main() -> anyhow::Result<()> {
         console_log::init_with_level(log::Level::Info).unwrap_throw();
     }
 
+    // where assets are read from, see vfs.rs
+    vfs::mount_defaults();
+
     let event_loop = EventLoop::with_user_event().build()?;
     
     let mut app = App::new(
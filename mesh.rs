@@ -0,0 +1,113 @@
+use std::sync::OnceLock;
+
+use wgpu::util::DeviceExt;
+
+use crate::Vertex;
+
+// Un-indexed copy of a mesh for the barycentric wireframe (debug_view.rs):
+// every triangle gets its own three vertices with (1,0,0), (0,1,0), (0,0,1).
+// Built on the first draw_wire, meshes never drawn that way do not pay for it.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct WireVertex {
+    position: [f32; 3],
+    barycentric: [f32; 3],
+}
+
+impl WireVertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 2] = wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x3];
+
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<WireVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+// Vertex + index buffer pair on the gpu
+pub struct Mesh {
+    pub vertex_buffer: wgpu::Buffer,
+    pub index_buffer: wgpu::Buffer,
+    pub num_vertices: u32,
+    pub num_indices: u32,
+    label: String,
+    // what the wireframe copy is built from
+    positions: Vec<[f32; 3]>,
+    indices: Vec<u16>,
+    wire_vertex_buffer: OnceLock<wgpu::Buffer>,
+}
+
+impl Mesh {
+    pub fn new(device: &wgpu::Device, label: &str, vertices: &[Vertex], indices: &[u16]) -> Self {
+        let vertex_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} vertex buffer", label)),
+                contents: bytemuck::cast_slice(vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            }
+        );
+
+        let index_buffer = device.create_buffer_init(
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} index buffer", label)),
+                contents: bytemuck::cast_slice(indices),
+                usage: wgpu::BufferUsages::INDEX,
+            }
+        );  // координаты передаются индексом что занимает меньше памяти
+
+        Self {
+            vertex_buffer,
+            index_buffer,
+            num_vertices: vertices.len() as u32,
+            num_indices: indices.len() as u32,
+            label: label.to_string(),
+            positions: vertices.iter().map(|vertex| vertex.position).collect(),
+            indices: indices.to_vec(),
+            wire_vertex_buffer: OnceLock::new(),
+        }
+    }
+
+    // Flat square on the xz plane facing +y, `size` units wide, centered at height `y`.
+    // The texture repeats once per unit. Vertices and indices for Mesh::new.
+    pub fn plane_data(size: f32, y: f32) -> ([Vertex; 4], [u16; 6]) {
+        let h = size / 2.0;
+        let vertices = [
+            Vertex { position: [-h, y, h], tex_coords: [0.0, size], normal: [0.0, 1.0, 0.0] },
+            Vertex { position: [h, y, h], tex_coords: [size, size], normal: [0.0, 1.0, 0.0] },
+            Vertex { position: [h, y, -h], tex_coords: [size, 0.0], normal: [0.0, 1.0, 0.0] },
+            Vertex { position: [-h, y, -h], tex_coords: [0.0, 0.0], normal: [0.0, 1.0, 0.0] },
+        ];
+        (vertices, [0, 1, 2, 0, 2, 3])
+    }
+
+    pub fn draw<'a>(&'a self, render_pass: &mut wgpu::RenderPass<'a>) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+        render_pass.draw_indexed(0..self.num_indices, 0, 0..1);
+    }
+
+    // for pipelines with WireVertex::desc()
+    pub fn draw_wire<'a>(&'a self, device: &wgpu::Device, render_pass: &mut wgpu::RenderPass<'a>) {
+        let wire_vertex_buffer = self.wire_vertex_buffer.get_or_init(|| {
+            const CORNERS: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+            let wire_vertices: Vec<WireVertex> = self
+                .indices
+                .iter()
+                .enumerate()
+                .map(|(i, &index)| WireVertex {
+                    position: self.positions[index as usize],
+                    barycentric: CORNERS[i % 3],
+                })
+                .collect();
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{} wire vertex buffer", self.label)),
+                contents: bytemuck::cast_slice(&wire_vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            })
+        });
+        render_pass.set_vertex_buffer(0, wire_vertex_buffer.slice(..));
+        render_pass.draw(0..self.num_indices, 0..1);
+    }
+}
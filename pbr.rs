@@ -0,0 +1,440 @@
+use anyhow::*;
+use wgpu::util::DeviceExt;
+
+use crate::texture::Texture;
+
+// glTF metallic-roughness material.
+// Every map is optional in glTF, a missing one is replaced by a 1x1 texture
+// that leaves the factor untouched (see PbrTextures::defaults).
+#[derive(Copy, Clone, Debug)]
+pub struct PbrParams {
+    pub base_color_factor: [f32; 4],
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub normal_scale: f32,
+    pub occlusion_strength: f32,
+    pub emissive_factor: [f32; 3],
+}
+
+impl Default for PbrParams {
+    // same defaults as the glTF spec
+    fn default() -> Self {
+        Self {
+            base_color_factor: [1.0; 4],
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            normal_scale: 1.0,
+            occlusion_strength: 1.0,
+            emissive_factor: [0.0; 3],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PbrUniform {
+    base_color_factor: [f32; 4],
+    emissive_factor: [f32; 3],
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+    _padding: f32,
+}
+
+impl From<PbrParams> for PbrUniform {
+    fn from(params: PbrParams) -> Self {
+        Self {
+            base_color_factor: params.base_color_factor,
+            emissive_factor: params.emissive_factor,
+            metallic_factor: params.metallic_factor,
+            roughness_factor: params.roughness_factor,
+            normal_scale: params.normal_scale,
+            occlusion_strength: params.occlusion_strength,
+            _padding: 0.0,
+        }
+    }
+}
+
+pub struct PbrTextures {
+    // srgb
+    pub base_color: Texture,
+    // linear, roughness in G, metallic in B (glTF layout)
+    pub metallic_roughness: Texture,
+    // linear, tangent space
+    pub normal: Texture,
+    // linear, R channel
+    pub occlusion: Texture,
+    // srgb
+    pub emissive: Texture,
+}
+
+impl PbrTextures {
+    pub fn defaults(device: &wgpu::Device, queue: &wgpu::Queue, base_color: Texture) -> Self {
+        Self {
+            base_color,
+            metallic_roughness: Texture::solid(device, queue, [255, 255, 255, 255], "default metallic roughness", false),
+            // (0, 0, 1) in tangent space = "use the vertex normal"
+            normal: Texture::solid(device, queue, [128, 128, 255, 255], "default normal", false),
+            occlusion: Texture::solid(device, queue, [255, 255, 255, 255], "default occlusion", false),
+            emissive: Texture::solid(device, queue, [255, 255, 255, 255], "default emissive", true),
+        }
+    }
+}
+
+pub struct PbrMaterial {
+    pub params: PbrParams,
+    pub textures: PbrTextures,
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl PbrMaterial {
+    pub fn new(
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        textures: PbrTextures,
+        params: PbrParams,
+        label: &str,
+    ) -> Self {
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(label),
+            contents: bytemuck::cast_slice(&[PbrUniform::from(params)]),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        // one sampler for every map, repeat so tiled uvs work
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&textures.base_color.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&textures.metallic_roughness.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&textures.normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::TextureView(&textures.occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&textures.emissive.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: buffer.as_entire_binding(),
+                },
+            ],
+            label: Some(label),
+        });
+
+        // the bind group keeps the buffer alive, a material with other factors is a new PbrMaterial
+        Self { params, textures, bind_group }
+    }
+}
+
+pub fn material_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+            multisampled: false,
+            view_dimension: wgpu::TextureViewDimension::D2,
+            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+        },
+        count: None,
+    };
+
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            texture_entry(0), // base color
+            texture_entry(1), // metallic roughness
+            texture_entry(2), // normal
+            texture_entry(3), // occlusion
+            texture_entry(4), // emissive
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 6,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            },
+        ],
+        label: Some("pbr_material_bind_group_layout"),
+    })
+}
+
+// IMAGE BASED LIGHTING
+// The environment is an HDR cubemap with a full mip chain, the skybox (skybox.rs) draws the same one.
+// Instead of convolving irradiance / prefiltered specular maps we sample blurrier mips
+// for rougher surfaces, and the diffuse term reads one of the smallest mips.
+pub struct Environment {
+    pub texture: wgpu::Texture,
+    pub view: wgpu::TextureView,
+    pub sampler: wgpu::Sampler,
+    pub bind_group: wgpu::BindGroup,
+}
+
+// Face order of the cube layers, same as wgpu / Vulkan / D3D.
+pub const CUBE_FACES: [&str; 6] = ["+x", "-x", "+y", "-y", "+z", "-z"];
+
+impl Environment {
+    // Equirectangular (latitude/longitude) .hdr / .exr or any other format the image crate can decode,
+    // converted to a cube on the cpu.
+    pub fn from_bytes(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        bytes: &[u8],
+        label: &str,
+    ) -> Result<Self> {
+        let img = image::load_from_memory(bytes)?.to_rgba32f();
+        ensure!(img.width() > 0 && img.height() > 0, "{label}: empty image");
+        // a face covers a quarter of the equator
+        let size = (img.width() / 4).max(1);
+        let faces = equirect_to_cube(&img, size);
+        Ok(Self::from_cube(device, queue, layout, faces, label))
+    }
+
+    // Six square images of the same size, in CUBE_FACES order.
+    pub fn from_faces(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        faces: [&[u8]; 6],
+        label: &str,
+    ) -> Result<Self> {
+        let mut images = Vec::with_capacity(6);
+        for (bytes, name) in faces.iter().zip(CUBE_FACES) {
+            let img = image::load_from_memory(bytes)
+                .with_context(|| format!("{label}: cube face {name}"))?
+                .to_rgba32f();
+            images.push(img);
+        }
+
+        let size = images[0].width();
+        // from_cube needs at least one mip level
+        ensure!(size > 0, "{label}: empty cube faces");
+        for (img, name) in images.iter().zip(CUBE_FACES) {
+            if img.width() != size || img.height() != size {
+                bail!("{label}: cube face {name} is {}x{}, expected {size}x{size}", img.width(), img.height());
+            }
+        }
+
+        let faces: [image::Rgba32FImage; 6] = images.try_into().unwrap();
+        Ok(Self::from_cube(device, queue, layout, faces, label))
+    }
+
+    // Procedural sky used when no environment map is loaded:
+    // blue above the horizon, brownish ground below.
+    pub fn sky(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout) -> Self {
+        let (width, height) = (256, 128);
+        let zenith = [0.25, 0.45, 0.9];
+        let horizon = [0.9, 0.9, 1.0];
+        let ground = [0.3, 0.25, 0.2];
+
+        let img = image::Rgba32FImage::from_fn(width, height, |_, y| {
+            // v = 0 is straight up, 1 straight down
+            let v = (y as f32 + 0.5) / height as f32;
+            let c = if v < 0.5 {
+                let t = (v * 2.0).powf(3.0);
+                [0, 1, 2].map(|i| zenith[i] + (horizon[i] - zenith[i]) * t)
+            } else {
+                let t = ((v - 0.5) * 2.0).powf(0.3);
+                [0, 1, 2].map(|i| horizon[i] + (ground[i] - horizon[i]) * t)
+            };
+            image::Rgba([c[0], c[1], c[2], 1.0])
+        });
+
+        Self::from_cube(device, queue, layout, equirect_to_cube(&img, 64), "sky environment")
+    }
+
+    fn from_cube(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        faces: [image::Rgba32FImage; 6],
+        label: &str,
+    ) -> Self {
+        let size = faces[0].width();
+        let mip_level_count = size.ilog2() + 1;
+
+        // Rgba16Float is filterable everywhere, Rgba32Float needs FLOAT32_FILTERABLE
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d { width: size, height: size, depth_or_array_layers: 6 },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba16Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+
+        // build the mip chain of every face on the cpu, every level is half the previous one
+        for (layer, face) in faces.into_iter().enumerate() {
+            let mut level = face;
+            for mip in 0..mip_level_count {
+                let (w, h) = level.dimensions();
+                let data: Vec<half::f16> = level.as_raw().iter().map(|&v| half::f16::from_f32(v)).collect();
+
+                queue.write_texture(
+                    wgpu::TexelCopyTextureInfo {
+                        texture: &texture,
+                        mip_level: mip,
+                        origin: wgpu::Origin3d { x: 0, y: 0, z: layer as u32 },
+                        aspect: wgpu::TextureAspect::All,
+                    },
+                    bytemuck::cast_slice(&data),
+                    wgpu::TexelCopyBufferLayout {
+                        offset: 0,
+                        // 4 channels * 2 bytes
+                        bytes_per_row: Some(8 * w),
+                        rows_per_image: Some(h),
+                    },
+                    wgpu::Extent3d { width: w, height: h, depth_or_array_layers: 1 },
+                );
+
+                level = image::imageops::resize(
+                    &level,
+                    (w / 2).max(1),
+                    (h / 2).max(1),
+                    image::imageops::FilterType::Triangle,
+                );
+            }
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::Cube),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("environment_bind_group"),
+        });
+
+        Self { texture, view, sampler, bind_group }
+    }
+}
+
+// World direction through texel (u, v) of a cube face, u and v in -1..1, v pointing down.
+fn cube_direction(face: usize, u: f32, v: f32) -> [f32; 3] {
+    match face {
+        0 => [1.0, -v, -u],
+        1 => [-1.0, -v, u],
+        2 => [u, 1.0, v],
+        3 => [u, -1.0, -v],
+        4 => [u, -v, 1.0],
+        _ => [-u, -v, -1.0],
+    }
+}
+
+// Resample an equirectangular image into six size x size faces (bilinear).
+pub fn equirect_to_cube(img: &image::Rgba32FImage, size: u32) -> [image::Rgba32FImage; 6] {
+    let (width, height) = img.dimensions();
+    let texel = |x: i64, y: i64| {
+        // wraps around horizontally, clamps at the poles
+        let x = x.rem_euclid(width as i64) as u32;
+        let y = y.clamp(0, height as i64 - 1) as u32;
+        img.get_pixel(x, y).0
+    };
+
+    std::array::from_fn(|face| {
+        image::Rgba32FImage::from_fn(size, size, |x, y| {
+            let u = (x as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let v = (y as f32 + 0.5) / size as f32 * 2.0 - 1.0;
+            let [dx, dy, dz] = cube_direction(face, u, v);
+            let len = (dx * dx + dy * dy + dz * dz).sqrt();
+
+            // u goes around the horizon, v from straight up (0) to straight down (1)
+            let eu = dz.atan2(dx) / std::f32::consts::TAU + 0.5;
+            let ev = (dy / len).clamp(-1.0, 1.0).acos() / std::f32::consts::PI;
+
+            let fx = eu * width as f32 - 0.5;
+            let fy = ev * height as f32 - 0.5;
+            let (x0, y0) = (fx.floor() as i64, fy.floor() as i64);
+            let (tx, ty) = (fx - fx.floor(), fy - fy.floor());
+
+            let (a, b) = (texel(x0, y0), texel(x0 + 1, y0));
+            let (c, d) = (texel(x0, y0 + 1), texel(x0 + 1, y0 + 1));
+            image::Rgba(std::array::from_fn(|i| {
+                let top = a[i] + (b[i] - a[i]) * tx;
+                let bottom = c[i] + (d[i] - c[i]) * tx;
+                top + (bottom - top) * ty
+            }))
+        })
+    })
+}
+
+pub fn environment_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    multisampled: false,
+                    view_dimension: wgpu::TextureViewDimension::Cube,
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+        label: Some("environment_bind_group_layout"),
+    })
+}
@@ -0,0 +1,192 @@
+// Physically based shading, glTF metallic-roughness model.
+// light.wgsl is glued in front of this file (camera, lights, light_incidence).
+
+const PI: f32 = 3.14159265359;
+
+struct VertexInput {
+    @location(0) position: vec3<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) normal: vec3<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) world_position: vec3<f32>,
+};
+
+@vertex
+fn vs_main(
+    model: VertexInput,
+    instance: InstanceInput,
+) -> VertexOutput {
+    let world_position = instance_model(instance) * vec4<f32>(model.position, 1.0);
+    var out: VertexOutput;
+    out.tex_coords = model.tex_coords;
+    out.world_normal = instance_normal(instance) * model.normal;
+    out.world_position = world_position.xyz;
+    out.clip_position = camera.view_proj * world_position;
+    return out;
+}
+
+// MATERIAL
+
+struct Material {
+    base_color_factor: vec4<f32>,
+    emissive_factor: vec3<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+    _padding: f32,
+}
+
+@group(0) @binding(0)
+var t_base_color: texture_2d<f32>;
+@group(0) @binding(1)
+var t_metallic_roughness: texture_2d<f32>;
+@group(0) @binding(2)
+var t_normal: texture_2d<f32>;
+@group(0) @binding(3)
+var t_occlusion: texture_2d<f32>;
+@group(0) @binding(4)
+var t_emissive: texture_2d<f32>;
+@group(0) @binding(5)
+var s_material: sampler;
+@group(0) @binding(6)
+var<uniform> material: Material;
+
+// ENVIRONMENT (cubemap, the same one the skybox draws)
+
+@group(3) @binding(0)
+var t_environment: texture_cube<f32>;
+@group(3) @binding(1)
+var s_environment: sampler;
+
+fn sample_environment(dir: vec3<f32>, lod: f32) -> vec3<f32> {
+    return textureSampleLevel(t_environment, s_environment, dir, lod).rgb;
+}
+
+// COOK-TORRANCE BRDF
+
+// GGX / Trowbridge-Reitz normal distribution
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith geometry term with the Schlick-GGX approximation for direct lights
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let ggx_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let ggx_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return ggx_v * ggx_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Analytic fit of the split-sum BRDF lookup table (Karis, "Physically Based Shading on Mobile"),
+// saves us from baking and binding a LUT texture.
+fn env_brdf_approx(f0: vec3<f32>, roughness: f32, n_dot_v: f32) -> vec3<f32> {
+    let c0 = vec4<f32>(-1.0, -0.0275, -0.572, 0.022);
+    let c1 = vec4<f32>(1.0, 0.0425, 1.04, -0.04);
+    let r = roughness * c0 + c1;
+    let a004 = min(r.x * r.x, exp2(-9.28 * n_dot_v)) * r.x + r.y;
+    let ab = vec2<f32>(-1.04, 1.04) * a004 + r.zw;
+    return f0 * ab.x + ab.y;
+}
+
+// Tangent frame from screen space derivatives, so the normal map works
+// without a tangent vertex attribute.
+fn cotangent_frame(normal: vec3<f32>, position: vec3<f32>, uv: vec2<f32>) -> mat3x3<f32> {
+    let dp1 = dpdx(position);
+    let dp2 = dpdy(position);
+    let duv1 = dpdx(uv);
+    let duv2 = dpdy(uv);
+
+    let dp2perp = cross(dp2, normal);
+    let dp1perp = cross(normal, dp1);
+    let tangent = dp2perp * duv1.x + dp1perp * duv2.x;
+    let bitangent = dp2perp * duv1.y + dp1perp * duv2.y;
+
+    let invmax = inverseSqrt(max(dot(tangent, tangent), dot(bitangent, bitangent)));
+    return mat3x3<f32>(tangent * invmax, bitangent * invmax, normal);
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // every textureSample first, they need uniform control flow
+    let base_color = textureSample(t_base_color, s_material, in.tex_coords) * material.base_color_factor;
+    let metallic_roughness = textureSample(t_metallic_roughness, s_material, in.tex_coords);
+    let tangent_normal = textureSample(t_normal, s_material, in.tex_coords).xyz * 2.0 - 1.0;
+    let occlusion_sample = textureSample(t_occlusion, s_material, in.tex_coords).r;
+    let emissive = textureSample(t_emissive, s_material, in.tex_coords).rgb * material.emissive_factor;
+
+    // glTF: roughness in G, metallic in B
+    let roughness = clamp(metallic_roughness.g * material.roughness_factor, 0.04, 1.0);
+    let metallic = clamp(metallic_roughness.b * material.metallic_factor, 0.0, 1.0);
+    let occlusion = mix(1.0, occlusion_sample, material.occlusion_strength);
+    let albedo = base_color.rgb;
+
+    let geometry_normal = normalize(in.world_normal);
+    let tbn = cotangent_frame(geometry_normal, in.world_position, in.tex_coords);
+    let normal = normalize(tbn * vec3<f32>(tangent_normal.xy * material.normal_scale, tangent_normal.z));
+
+    let view_dir = normalize(camera.view_pos.xyz - in.world_position);
+    let n_dot_v = max(dot(normal, view_dir), 1e-4);
+
+    // dielectrics reflect ~4%, metals tint the reflection with their albedo
+    let f0 = mix(vec3<f32>(0.04), albedo, metallic);
+
+    // DIRECT LIGHTING
+    var lo = vec3<f32>(0.0);
+    for (var i = 0u; i < min(lights.count, MAX_LIGHTS); i++) {
+        let light = lights.lights[i];
+        let incidence = light_incidence(light, in.world_position);
+        let light_dir = incidence.xyz;
+        let half_dir = normalize(view_dir + light_dir);
+
+        let n_dot_l = max(dot(normal, light_dir), 0.0);
+        let n_dot_h = max(dot(normal, half_dir), 0.0);
+        let h_dot_v = max(dot(half_dir, view_dir), 0.0);
+
+        let d = distribution_ggx(n_dot_h, roughness);
+        let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+        let f = fresnel_schlick(h_dot_v, f0);
+
+        let specular = d * g * f / (4.0 * n_dot_v * n_dot_l + 1e-4);
+        // energy not reflected is refracted, metals have no diffuse
+        let kd = (vec3<f32>(1.0) - f) * (1.0 - metallic);
+
+        let radiance = light.color * light.intensity * incidence.w * shadow_factor(light, in.world_position, geometry_normal);
+        lo += (kd * albedo / PI + specular) * radiance * n_dot_l;
+    }
+
+    // IMAGE BASED LIGHTING
+    let max_lod = f32(textureNumLevels(t_environment) - 1u);
+    let f_ambient = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    let kd_ambient = (vec3<f32>(1.0) - f_ambient) * (1.0 - metallic);
+
+    // one of the smallest mips is a cheap stand-in for the irradiance map
+    let irradiance = sample_environment(normal, max(max_lod - 2.0, 0.0));
+    let diffuse = irradiance * albedo;
+
+    let reflected = reflect(-view_dir, normal);
+    let prefiltered = sample_environment(reflected, roughness * max_lod);
+    let specular = prefiltered * env_brdf_approx(f0, roughness, n_dot_v);
+
+    let ambient = (kd_ambient * diffuse + specular) * occlusion;
+
+    let color = ambient + lo + emissive;
+    return vec4<f32>(color, base_color.a);
+}
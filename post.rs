@@ -0,0 +1,586 @@
+use anyhow::*;
+use image::GenericImageView;
+use wgpu::util::DeviceExt;
+
+use crate::hdr::{HdrPipeline, HDR_FORMAT};
+use crate::texture::Texture;
+
+// Format of the targets between tonemapping and the surface.
+// srgb so 8 bits are enough without banding in the darks, shaders still see linear values.
+pub const LDR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+// bloom mips stop at this count or when they get smaller than 8 pixels
+const MAX_BLOOM_MIPS: usize = 6;
+
+#[derive(Copy, Clone, Debug)]
+pub struct BloomSettings {
+    pub enabled: bool,
+    // HDR brightness where bloom starts
+    pub threshold: f32,
+    // soft transition around the threshold, 0 = hard cut
+    pub knee: f32,
+    pub intensity: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct ColorGradingSettings {
+    pub enabled: bool,
+    // blend between the original (0) and the graded (1) color
+    pub strength: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct VignetteSettings {
+    pub enabled: bool,
+    pub intensity: f32,
+    // distance from the center (0.5 = screen edge) where darkening starts
+    pub radius: f32,
+    pub smoothness: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct FxaaSettings {
+    pub enabled: bool,
+    // longest edge search in pixels
+    pub span_max: f32,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GammaSettings {
+    pub enabled: bool,
+    // > 1 brightens midtones, < 1 darkens them
+    pub gamma: f32,
+}
+
+// Everything the post stack does, can be changed at any time.
+// Order: bloom (HDR) -> tonemap -> color grading -> vignette -> FXAA -> gamma.
+#[derive(Copy, Clone, Debug)]
+pub struct PostSettings {
+    pub bloom: BloomSettings,
+    pub color_grading: ColorGradingSettings,
+    pub vignette: VignetteSettings,
+    pub fxaa: FxaaSettings,
+    pub gamma: GammaSettings,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        Self {
+            bloom: BloomSettings { enabled: true, threshold: 1.0, knee: 0.5, intensity: 0.3 },
+            color_grading: ColorGradingSettings { enabled: false, strength: 1.0 },
+            vignette: VignetteSettings { enabled: true, intensity: 0.4, radius: 0.35, smoothness: 0.45 },
+            fxaa: FxaaSettings { enabled: true, span_max: 8.0 },
+            gamma: GammaSettings { enabled: false, gamma: 1.0 },
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PostUniform {
+    bloom_threshold: f32,
+    bloom_knee: f32,
+    bloom_intensity: f32,
+    color_grading_strength: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    fxaa_span_max: f32,
+    gamma: f32,
+    // 1 when the surface is not srgb and the last pass has to encode by itself
+    encode_srgb: u32,
+    _padding: [u32; 2],
+}
+
+pub struct PostStack {
+    pub settings: PostSettings,
+    surface_is_srgb: bool,
+
+    input_layout: wgpu::BindGroupLayout,
+    params_layout: wgpu::BindGroupLayout,
+    sampler: wgpu::Sampler,
+    params_buffer: wgpu::Buffer,
+    params_bind_group: wgpu::BindGroup,
+    lut: wgpu::TextureView,
+    lut_sampler: wgpu::Sampler,
+
+    prefilter_pipeline: wgpu::RenderPipeline,
+    downsample_pipeline: wgpu::RenderPipeline,
+    upsample_pipeline: wgpu::RenderPipeline,
+    composite_pipeline: wgpu::RenderPipeline,
+    color_grading_pipeline: wgpu::RenderPipeline,
+    vignette_pipeline: wgpu::RenderPipeline,
+    fxaa_pipeline: wgpu::RenderPipeline,
+    output_pipeline: wgpu::RenderPipeline,
+
+    // ping-pong targets for the LDR passes, rebuilt on resize
+    targets: [Texture; 2],
+    target_bind_groups: [wgpu::BindGroup; 2],
+    // half, quarter, ... resolution, rebuilt on resize
+    bloom_mips: Vec<Texture>,
+    bloom_bind_groups: Vec<wgpu::BindGroup>,
+}
+
+impl PostStack {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, config: &wgpu::SurfaceConfiguration) -> Self {
+        let input_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("post_input_bind_group_layout"),
+        });
+
+        let params_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                // color grading LUT
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D3,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("post_params_bind_group_layout"),
+        });
+
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("post sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let lut_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("lut sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("post params buffer"),
+            contents: bytemuck::cast_slice(&[<PostUniform as bytemuck::Zeroable>::zeroed()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let lut = identity_lut(device, queue, 16);
+        let params_bind_group = create_params_bind_group(device, &params_layout, &params_buffer, &lut, &lut_sampler);
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("post pipeline layout"),
+            bind_group_layouts: &[&input_layout, &params_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("post shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("post.wgsl").into()),
+        });
+
+        let additive = wgpu::BlendState {
+            color: wgpu::BlendComponent {
+                src_factor: wgpu::BlendFactor::One,
+                dst_factor: wgpu::BlendFactor::One,
+                operation: wgpu::BlendOperation::Add,
+            },
+            alpha: wgpu::BlendComponent::OVER,
+        };
+        let pipeline = |entry_point: &str, format, blend| {
+            create_pass_pipeline(device, &pipeline_layout, &shader, entry_point, format, blend)
+        };
+
+        let prefilter_pipeline = pipeline("fs_prefilter", HDR_FORMAT, wgpu::BlendState::REPLACE);
+        let downsample_pipeline = pipeline("fs_downsample", HDR_FORMAT, wgpu::BlendState::REPLACE);
+        let upsample_pipeline = pipeline("fs_upsample", HDR_FORMAT, additive);
+        let composite_pipeline = pipeline("fs_composite", HDR_FORMAT, additive);
+        let color_grading_pipeline = pipeline("fs_color_grading", LDR_FORMAT, wgpu::BlendState::REPLACE);
+        let vignette_pipeline = pipeline("fs_vignette", LDR_FORMAT, wgpu::BlendState::REPLACE);
+        let fxaa_pipeline = pipeline("fs_fxaa", LDR_FORMAT, wgpu::BlendState::REPLACE);
+        let output_pipeline = pipeline("fs_output", config.format, wgpu::BlendState::REPLACE);
+
+        let (targets, target_bind_groups) = create_targets(device, &input_layout, &sampler, config.width, config.height);
+        let (bloom_mips, bloom_bind_groups) = create_bloom_mips(device, &input_layout, &sampler, config.width, config.height);
+
+        Self {
+            settings: PostSettings::default(),
+            surface_is_srgb: config.format.is_srgb(),
+            input_layout,
+            params_layout,
+            sampler,
+            params_buffer,
+            params_bind_group,
+            lut,
+            lut_sampler,
+            prefilter_pipeline,
+            downsample_pipeline,
+            upsample_pipeline,
+            composite_pipeline,
+            color_grading_pipeline,
+            vignette_pipeline,
+            fxaa_pipeline,
+            output_pipeline,
+            targets,
+            target_bind_groups,
+            bloom_mips,
+            bloom_bind_groups,
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        (self.targets, self.target_bind_groups) = create_targets(device, &self.input_layout, &self.sampler, width, height);
+        (self.bloom_mips, self.bloom_bind_groups) = create_bloom_mips(device, &self.input_layout, &self.sampler, width, height);
+    }
+
+    // Color grading LUT as a horizontal strip: `size` squares of size x size,
+    // red grows to the right inside a square, green downwards, blue from square to square.
+    pub fn load_lut(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, bytes: &[u8]) -> Result<()> {
+        let img = image::load_from_memory(bytes)?;
+        let (width, height) = img.dimensions();
+        // a zero height would make an empty 3d texture
+        if height == 0 || height.checked_mul(height) != Some(width) {
+            bail!("LUT strip must be size*size x size pixels, got {}x{}", width, height);
+        }
+        let size = height;
+        let rgba = img.to_rgba8();
+
+        let mut data = Vec::with_capacity((size * size * size * 4) as usize);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    data.extend_from_slice(&rgba.get_pixel(b * size + r, g).0);
+                }
+            }
+        }
+
+        self.lut = create_lut(device, queue, size, &data);
+        self.params_bind_group = create_params_bind_group(device, &self.params_layout, &self.params_buffer, &self.lut, &self.lut_sampler);
+        Ok(())
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue) {
+        let s = &self.settings;
+        let uniform = PostUniform {
+            bloom_threshold: s.bloom.threshold,
+            bloom_knee: s.bloom.knee,
+            bloom_intensity: s.bloom.intensity,
+            color_grading_strength: s.color_grading.strength,
+            vignette_intensity: s.vignette.intensity,
+            vignette_radius: s.vignette.radius,
+            vignette_smoothness: s.vignette.smoothness,
+            fxaa_span_max: s.fxaa.span_max,
+            // the output pass always runs (it writes the surface), disabled just means gamma 1
+            gamma: if s.gamma.enabled { s.gamma.gamma } else { 1.0 },
+            encode_srgb: (!self.surface_is_srgb) as u32,
+            _padding: [0; 2],
+        };
+        queue.write_buffer(&self.params_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    // Run every enabled effect: `scene` (the HDR texture) in, `output` (the surface) out.
+    // Bloom is added onto `scene` itself, so it must be writable.
+    pub fn process(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        hdr: &HdrPipeline,
+        scene: &wgpu::TextureView,
+        output: &wgpu::TextureView,
+    ) {
+        if self.settings.bloom.enabled && !self.bloom_mips.is_empty() {
+            self.bloom(device, encoder, scene);
+        }
+
+        // TONEMAP
+        hdr.process(device, encoder, scene, &self.targets[0].view);
+
+        // LDR EFFECTS, ping-pong between the two targets
+        let mut current = 0;
+        let effects = [
+            (self.settings.color_grading.enabled, &self.color_grading_pipeline, "color grading"),
+            (self.settings.vignette.enabled, &self.vignette_pipeline, "vignette"),
+            (self.settings.fxaa.enabled, &self.fxaa_pipeline, "fxaa"),
+        ];
+        for (enabled, pipeline, label) in effects {
+            if !enabled {
+                continue;
+            }
+            self.fullscreen(encoder, label, pipeline, &self.target_bind_groups[current], &self.targets[1 - current].view, true);
+            current = 1 - current;
+        }
+
+        // GAMMA + write to the surface
+        self.fullscreen(encoder, "post output", &self.output_pipeline, &self.target_bind_groups[current], output, true);
+    }
+
+    fn bloom(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder, scene: &wgpu::TextureView) {
+        // bright parts of the HDR image -> half resolution
+        let scene_bind_group = create_input_bind_group(device, &self.input_layout, scene, &self.sampler, "bloom scene bind group");
+        self.fullscreen(encoder, "bloom prefilter", &self.prefilter_pipeline, &scene_bind_group, &self.bloom_mips[0].view, true);
+
+        // blur by going down the mip chain...
+        for i in 1..self.bloom_mips.len() {
+            self.fullscreen(encoder, "bloom downsample", &self.downsample_pipeline, &self.bloom_bind_groups[i - 1], &self.bloom_mips[i].view, true);
+        }
+
+        // ...and back up, adding every level onto the one above it
+        for i in (1..self.bloom_mips.len()).rev() {
+            self.fullscreen(encoder, "bloom upsample", &self.upsample_pipeline, &self.bloom_bind_groups[i], &self.bloom_mips[i - 1].view, false);
+        }
+
+        // add the result onto the HDR image before tonemapping
+        self.fullscreen(encoder, "bloom composite", &self.composite_pipeline, &self.bloom_bind_groups[0], scene, false);
+    }
+
+    fn fullscreen(
+        &self,
+        encoder: &mut wgpu::CommandEncoder,
+        label: &str,
+        pipeline: &wgpu::RenderPipeline,
+        input: &wgpu::BindGroup,
+        output: &wgpu::TextureView,
+        clear: bool,
+    ) {
+        let mut pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some(label),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                depth_slice: None,
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    // additive passes keep what is already in the target
+                    load: if clear { wgpu::LoadOp::Clear(wgpu::Color::BLACK) } else { wgpu::LoadOp::Load },
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        pass.set_pipeline(pipeline);
+        pass.set_bind_group(0, input, &[]);
+        pass.set_bind_group(1, &self.params_bind_group, &[]);
+        pass.draw(0..3, 0..1);
+    }
+}
+
+fn create_pass_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    entry_point: &str,
+    format: wgpu::TextureFormat,
+    blend: wgpu::BlendState,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(entry_point),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: Some("vs_main"),
+            buffers: &[],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: Some(entry_point),
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+            compilation_options: wgpu::PipelineCompilationOptions::default(),
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+        cache: None,
+    })
+}
+
+fn create_input_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    view: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+    label: &str,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+        label: Some(label),
+    })
+}
+
+fn create_params_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffer: &wgpu::Buffer,
+    lut: &wgpu::TextureView,
+    lut_sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(lut),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(lut_sampler),
+            },
+        ],
+        label: Some("post_params_bind_group"),
+    })
+}
+
+fn create_targets(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    width: u32,
+    height: u32,
+) -> ([Texture; 2], [wgpu::BindGroup; 2]) {
+    let targets = [
+        Texture::create_render_target(device, width, height, LDR_FORMAT, "post target a"),
+        Texture::create_render_target(device, width, height, LDR_FORMAT, "post target b"),
+    ];
+    let bind_groups = [
+        create_input_bind_group(device, layout, &targets[0].view, sampler, "post target a bind group"),
+        create_input_bind_group(device, layout, &targets[1].view, sampler, "post target b bind group"),
+    ];
+    (targets, bind_groups)
+}
+
+fn create_bloom_mips(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    width: u32,
+    height: u32,
+) -> (Vec<Texture>, Vec<wgpu::BindGroup>) {
+    let mut mips = Vec::new();
+    let (mut w, mut h) = (width / 2, height / 2);
+    while mips.len() < MAX_BLOOM_MIPS && w >= 8 && h >= 8 {
+        mips.push(Texture::create_render_target(device, w, h, HDR_FORMAT, "bloom mip"));
+        w /= 2;
+        h /= 2;
+    }
+    let bind_groups = mips
+        .iter()
+        .map(|mip| create_input_bind_group(device, layout, &mip.view, sampler, "bloom mip bind group"))
+        .collect();
+    (mips, bind_groups)
+}
+
+// r, g, b map to themselves, grading with this LUT changes nothing
+fn identity_lut(device: &wgpu::Device, queue: &wgpu::Queue, size: u32) -> wgpu::TextureView {
+    let max = (size - 1) as f32;
+    let mut data = Vec::with_capacity((size * size * size * 4) as usize);
+    for b in 0..size {
+        for g in 0..size {
+            for r in 0..size {
+                data.extend_from_slice(&[
+                    (r as f32 / max * 255.0).round() as u8,
+                    (g as f32 / max * 255.0).round() as u8,
+                    (b as f32 / max * 255.0).round() as u8,
+                    255,
+                ]);
+            }
+        }
+    }
+    create_lut(device, queue, size, &data)
+}
+
+fn create_lut(device: &wgpu::Device, queue: &wgpu::Queue, size: u32, data: &[u8]) -> wgpu::TextureView {
+    let extent = wgpu::Extent3d {
+        width: size,
+        height: size,
+        depth_or_array_layers: size,
+    };
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("color grading lut"),
+        size: extent,
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D3,
+        // the LUT maps srgb encoded colors, so store the raw values
+        format: wgpu::TextureFormat::Rgba8Unorm,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+
+    queue.write_texture(
+        wgpu::TexelCopyTextureInfo {
+            texture: &texture,
+            mip_level: 0,
+            origin: wgpu::Origin3d::ZERO,
+            aspect: wgpu::TextureAspect::All,
+        },
+        data,
+        wgpu::TexelCopyBufferLayout {
+            offset: 0,
+            bytes_per_row: Some(4 * size),
+            rows_per_image: Some(size),
+        },
+        extent,
+    );
+
+    texture.create_view(&wgpu::TextureViewDescriptor::default())
+}
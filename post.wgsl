@@ -0,0 +1,214 @@
+// Post processing passes. Every pass is a fullscreen triangle that reads
+// the previous result from group 0 and its settings from group 1.
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vi: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let x = f32(i32(vi) / 2) * 4.0 - 1.0;
+    let y = f32(i32(vi) % 2) * -4.0 + 3.0;
+    out.clip_position = vec4<f32>(x, y, 0.0, 1.0);
+    out.uv = vec2<f32>(x, y) * vec2<f32>(0.5, -0.5) + vec2<f32>(0.5);
+    return out;
+}
+
+struct Post {
+    bloom_threshold: f32,
+    bloom_knee: f32,
+    bloom_intensity: f32,
+    color_grading_strength: f32,
+    vignette_intensity: f32,
+    vignette_radius: f32,
+    vignette_smoothness: f32,
+    fxaa_span_max: f32,
+    gamma: f32,
+    encode_srgb: u32,
+    _padding: vec2<u32>,
+}
+
+@group(0) @binding(0)
+var t_input: texture_2d<f32>;
+@group(0) @binding(1)
+var s_input: sampler;
+
+@group(1) @binding(0)
+var<uniform> post: Post;
+@group(1) @binding(1)
+var t_lut: texture_3d<f32>;
+@group(1) @binding(2)
+var s_lut: sampler;
+
+fn sample_input(uv: vec2<f32>) -> vec3<f32> {
+    return textureSampleLevel(t_input, s_input, uv, 0.0).rgb;
+}
+
+fn input_texel() -> vec2<f32> {
+    return 1.0 / vec2<f32>(textureDimensions(t_input));
+}
+
+fn luminance(color: vec3<f32>) -> f32 {
+    return dot(color, vec3<f32>(0.2126, 0.7152, 0.0722));
+}
+
+fn linear_to_srgb(color: vec3<f32>) -> vec3<f32> {
+    let c = clamp(color, vec3<f32>(0.0), vec3<f32>(1.0));
+    return select(1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055, c * 12.92, c <= vec3<f32>(0.0031308));
+}
+
+fn srgb_to_linear(color: vec3<f32>) -> vec3<f32> {
+    return select(pow((color + 0.055) / 1.055, vec3<f32>(2.4)), color / 12.92, color <= vec3<f32>(0.04045));
+}
+
+// BLOOM
+// Dual filter bloom as in "Next Generation Post Processing in Call of Duty: Advanced Warfare".
+
+// 13 tap downsample, smooths better than a plain 2x2 box
+fn downsample13(uv: vec2<f32>) -> vec3<f32> {
+    let t = input_texel();
+    let a = sample_input(uv + t * vec2<f32>(-2.0, -2.0));
+    let b = sample_input(uv + t * vec2<f32>(0.0, -2.0));
+    let c = sample_input(uv + t * vec2<f32>(2.0, -2.0));
+    let d = sample_input(uv + t * vec2<f32>(-2.0, 0.0));
+    let e = sample_input(uv);
+    let f = sample_input(uv + t * vec2<f32>(2.0, 0.0));
+    let g = sample_input(uv + t * vec2<f32>(-2.0, 2.0));
+    let h = sample_input(uv + t * vec2<f32>(0.0, 2.0));
+    let i = sample_input(uv + t * vec2<f32>(2.0, 2.0));
+    let j = sample_input(uv + t * vec2<f32>(-1.0, -1.0));
+    let k = sample_input(uv + t * vec2<f32>(1.0, -1.0));
+    let l = sample_input(uv + t * vec2<f32>(-1.0, 1.0));
+    let m = sample_input(uv + t * vec2<f32>(1.0, 1.0));
+
+    var result = e * 0.125;
+    result += (a + c + g + i) * 0.03125;
+    result += (b + d + f + h) * 0.0625;
+    result += (j + k + l + m) * 0.125;
+    return result;
+}
+
+@fragment
+fn fs_prefilter(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = downsample13(in.uv);
+
+    // soft knee threshold
+    let brightness = max(color.r, max(color.g, color.b));
+    let knee = post.bloom_threshold * post.bloom_knee + 1e-5;
+    var soft = clamp(brightness - post.bloom_threshold + knee, 0.0, 2.0 * knee);
+    soft = soft * soft / (4.0 * knee);
+    let contribution = max(soft, brightness - post.bloom_threshold) / max(brightness, 1e-5);
+
+    return vec4<f32>(color * contribution, 1.0);
+}
+
+@fragment
+fn fs_downsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(downsample13(in.uv), 1.0);
+}
+
+// 3x3 tent filter, blended additively onto the next bigger mip
+@fragment
+fn fs_upsample(in: VertexOutput) -> @location(0) vec4<f32> {
+    let t = input_texel();
+    var result = sample_input(in.uv) * 4.0;
+    result += (sample_input(in.uv + t * vec2<f32>(-1.0, 0.0))
+        + sample_input(in.uv + t * vec2<f32>(1.0, 0.0))
+        + sample_input(in.uv + t * vec2<f32>(0.0, -1.0))
+        + sample_input(in.uv + t * vec2<f32>(0.0, 1.0))) * 2.0;
+    result += sample_input(in.uv + t * vec2<f32>(-1.0, -1.0))
+        + sample_input(in.uv + t * vec2<f32>(1.0, -1.0))
+        + sample_input(in.uv + t * vec2<f32>(-1.0, 1.0))
+        + sample_input(in.uv + t * vec2<f32>(1.0, 1.0));
+    return vec4<f32>(result / 16.0, 1.0);
+}
+
+@fragment
+fn fs_composite(in: VertexOutput) -> @location(0) vec4<f32> {
+    return vec4<f32>(sample_input(in.uv) * post.bloom_intensity, 0.0);
+}
+
+// COLOR GRADING
+// The LUT is indexed with srgb encoded colors, like the strips exported from image editors.
+@fragment
+fn fs_color_grading(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_input(in.uv);
+    let encoded = linear_to_srgb(color);
+
+    // sample texel centers, not edges
+    let size = f32(textureDimensions(t_lut).x);
+    let coord = encoded * ((size - 1.0) / size) + 0.5 / size;
+    let graded = srgb_to_linear(textureSampleLevel(t_lut, s_lut, coord, 0.0).rgb);
+
+    return vec4<f32>(mix(color, graded, post.color_grading_strength), 1.0);
+}
+
+// VIGNETTE
+@fragment
+fn fs_vignette(in: VertexOutput) -> @location(0) vec4<f32> {
+    let color = sample_input(in.uv);
+
+    // keep the vignette round on wide screens
+    let size = vec2<f32>(textureDimensions(t_input));
+    let offset = (in.uv - 0.5) * vec2<f32>(size.x / size.y, 1.0);
+    let distance = length(offset);
+    let shade = smoothstep(post.vignette_radius, post.vignette_radius + post.vignette_smoothness, distance);
+
+    return vec4<f32>(color * (1.0 - shade * post.vignette_intensity), 1.0);
+}
+
+// FXAA
+// Timothy Lottes' FXAA, the small "console" variant.
+const FXAA_REDUCE_MIN: f32 = 1.0 / 128.0;
+const FXAA_REDUCE_MUL: f32 = 1.0 / 8.0;
+
+@fragment
+fn fs_fxaa(in: VertexOutput) -> @location(0) vec4<f32> {
+    let t = input_texel();
+
+    // edges are detected on perceptual (gamma) luma
+    let luma_nw = sqrt(luminance(sample_input(in.uv + t * vec2<f32>(-1.0, -1.0))));
+    let luma_ne = sqrt(luminance(sample_input(in.uv + t * vec2<f32>(1.0, -1.0))));
+    let luma_sw = sqrt(luminance(sample_input(in.uv + t * vec2<f32>(-1.0, 1.0))));
+    let luma_se = sqrt(luminance(sample_input(in.uv + t * vec2<f32>(1.0, 1.0))));
+    let luma_m = sqrt(luminance(sample_input(in.uv)));
+
+    let luma_min = min(luma_m, min(min(luma_nw, luma_ne), min(luma_sw, luma_se)));
+    let luma_max = max(luma_m, max(max(luma_nw, luma_ne), max(luma_sw, luma_se)));
+
+    // direction along the edge
+    var dir = vec2<f32>(
+        -((luma_nw + luma_ne) - (luma_sw + luma_se)),
+        (luma_nw + luma_sw) - (luma_ne + luma_se),
+    );
+    let dir_reduce = max((luma_nw + luma_ne + luma_sw + luma_se) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    let rcp_dir_min = 1.0 / (min(abs(dir.x), abs(dir.y)) + dir_reduce);
+    dir = clamp(dir * rcp_dir_min, vec2<f32>(-post.fxaa_span_max), vec2<f32>(post.fxaa_span_max)) * t;
+
+    let rgb_a = 0.5 * (
+        sample_input(in.uv + dir * (1.0 / 3.0 - 0.5)) +
+        sample_input(in.uv + dir * (2.0 / 3.0 - 0.5)));
+    let rgb_b = rgb_a * 0.5 + 0.25 * (
+        sample_input(in.uv + dir * -0.5) +
+        sample_input(in.uv + dir * 0.5));
+
+    // the wider blur went past the edge, use the narrow one
+    let luma_b = sqrt(luminance(rgb_b));
+    let use_a = luma_b < luma_min || luma_b > luma_max;
+    return vec4<f32>(select(rgb_b, rgb_a, use_a), 1.0);
+}
+
+// OUTPUT
+// Last pass, always runs: gamma adjustment and the write to the surface.
+@fragment
+fn fs_output(in: VertexOutput) -> @location(0) vec4<f32> {
+    var color = pow(max(sample_input(in.uv), vec3<f32>(0.0)), vec3<f32>(1.0 / post.gamma));
+
+    // srgb surfaces encode in hardware, anything else gets it done here
+    if post.encode_srgb == 1u {
+        color = linear_to_srgb(color);
+    }
+    return vec4<f32>(color, 1.0);
+}
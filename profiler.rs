@@ -0,0 +1,334 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// FRAME STATS
+// Every timing (cpu frame, cpu update/render, gpu time of every render graph pass) is pushed
+// into a RollingStats under a name, the last STATS_WINDOW samples give min/avg/max/p99.
+// All values are milliseconds.
+
+// ~4 seconds at 60 fps
+const STATS_WINDOW: usize = 240;
+
+// GPU TIMESTAMPS
+// Two timestamps per scope, written between the render graph passes with encoder.write_timestamp.
+// That needs TIMESTAMP_QUERY_INSIDE_ENCODERS. With only TIMESTAMP_QUERY every timestamp is the
+// timestamp_writes of an empty render pass instead, without either the gpu part is off.
+// Results are read back a few frames later, so the gpu never waits for the cpu.
+const MAX_SCOPES: u32 = 32;
+const READBACK_FRAMES: usize = 3;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Summary {
+    pub min: f32,
+    pub avg: f32,
+    pub max: f32,
+    pub p99: f32,
+    pub last: f32,
+}
+
+#[derive(Default)]
+pub struct RollingStats {
+    samples: VecDeque<f32>,
+}
+
+impl RollingStats {
+    pub fn push(&mut self, value: f32) {
+        if self.samples.len() == STATS_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(value);
+    }
+
+    pub fn summary(&self) -> Summary {
+        if self.samples.is_empty() {
+            return Summary::default();
+        }
+        let mut sorted: Vec<f32> = self.samples.iter().copied().collect();
+        sorted.sort_by(f32::total_cmp);
+        let p99_index = ((sorted.len() as f32 * 0.99).ceil() as usize).clamp(1, sorted.len()) - 1;
+
+        Summary {
+            min: sorted[0],
+            avg: sorted.iter().sum::<f32>() / sorted.len() as f32,
+            max: sorted[sorted.len() - 1],
+            p99: sorted[p99_index],
+            last: *self.samples.back().unwrap(),
+        }
+    }
+}
+
+pub struct Profiler {
+    // insertion order, so the overlay does not jump around
+    stats: Vec<(String, RollingStats)>,
+    pub gpu: Option<GpuTimer>,
+    // print every summary to the log this often (seconds), None = never
+    pub log_interval: Option<f32>,
+    since_log: f32,
+}
+
+impl Profiler {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let gpu = GpuTimer::new(device, queue);
+        if gpu.is_none() {
+            log::info!("gpu timestamps not supported, only cpu timings");
+        }
+        Self {
+            stats: Vec::new(),
+            gpu,
+            log_interval: None,
+            since_log: 0.0,
+        }
+    }
+
+    pub fn record(&mut self, name: &str, ms: f32) {
+        match self.stats.iter_mut().find(|(n, _)| n == name) {
+            Some((_, stats)) => stats.push(ms),
+            None => {
+                let mut stats = RollingStats::default();
+                stats.push(ms);
+                self.stats.push((name.to_string(), stats));
+            }
+        }
+    }
+
+    pub fn summary(&self, name: &str) -> Option<Summary> {
+        self.stats.iter().find(|(n, _)| n == name).map(|(_, stats)| stats.summary())
+    }
+
+    pub fn summaries(&self) -> impl Iterator<Item = (&str, Summary)> {
+        self.stats.iter().map(|(name, stats)| (name.as_str(), stats.summary()))
+    }
+
+    // Once per frame: picks up finished gpu timings and writes the log when it is time.
+    pub fn begin_frame(&mut self, device: &wgpu::Device, dt: f32) {
+        self.record("frame", dt * 1000.0);
+
+        let gpu_timings = self.gpu.as_mut().map(|gpu| gpu.collect(device)).unwrap_or_default();
+        for (name, ms) in gpu_timings {
+            self.record(&format!("gpu {}", name), ms);
+        }
+
+        if let Some(interval) = self.log_interval {
+            self.since_log += dt;
+            if self.since_log >= interval {
+                self.since_log = 0.0;
+                for (name, s) in self.summaries() {
+                    log::info!("{:<24} min {:6.2}  avg {:6.2}  max {:6.2}  p99 {:6.2} ms", name, s.min, s.avg, s.max, s.p99);
+                }
+            }
+        }
+    }
+}
+
+struct Readback {
+    buffer: wgpu::Buffer,
+    names: Vec<String>,
+    // set by the map_async callback
+    ready: Arc<AtomicBool>,
+    in_flight: bool,
+}
+
+pub struct GpuTimer {
+    query_set: wgpu::QuerySet,
+    resolve_buffer: wgpu::Buffer,
+    readbacks: Vec<Readback>,
+    current: usize,
+    // scopes written this frame
+    names: Vec<String>,
+    open: bool,
+    // nanoseconds per tick
+    period: f32,
+    // 1x1 target of the empty timestamp passes, None when the encoder writes them
+    pass_target: Option<wgpu::TextureView>,
+}
+
+impl GpuTimer {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Option<Self> {
+        if !device.features().contains(wgpu::Features::TIMESTAMP_QUERY) {
+            return None;
+        }
+        let pass_target = (!device.features().contains(wgpu::Features::TIMESTAMP_QUERY_INSIDE_ENCODERS)).then(|| {
+            log::info!("no timestamps inside encoders, gpu timings use render pass timestamps");
+            device
+                .create_texture(&wgpu::TextureDescriptor {
+                    label: Some("gpu timer pass target"),
+                    size: wgpu::Extent3d { width: 1, height: 1, depth_or_array_layers: 1 },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: wgpu::TextureFormat::R8Unorm,
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                    view_formats: &[],
+                })
+                .create_view(&Default::default())
+        });
+
+        let query_set = device.create_query_set(&wgpu::QuerySetDescriptor {
+            label: Some("gpu timer queries"),
+            ty: wgpu::QueryType::Timestamp,
+            count: MAX_SCOPES * 2,
+        });
+        let size = (MAX_SCOPES * 2) as u64 * wgpu::QUERY_SIZE as u64;
+        let resolve_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("gpu timer resolve buffer"),
+            size,
+            usage: wgpu::BufferUsages::QUERY_RESOLVE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let readbacks = (0..READBACK_FRAMES)
+            .map(|_| Readback {
+                buffer: device.create_buffer(&wgpu::BufferDescriptor {
+                    label: Some("gpu timer readback buffer"),
+                    size,
+                    usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                }),
+                names: Vec::new(),
+                ready: Arc::new(AtomicBool::new(false)),
+                in_flight: false,
+            })
+            .collect();
+
+        Some(Self {
+            query_set,
+            resolve_buffer,
+            readbacks,
+            current: 0,
+            names: Vec::new(),
+            open: false,
+            period: queue.get_timestamp_period(),
+            pass_target,
+        })
+    }
+
+    fn write_timestamp(&self, encoder: &mut wgpu::CommandEncoder, index: u32) {
+        let Some(view) = &self.pass_target else {
+            encoder.write_timestamp(&self.query_set, index);
+            return;
+        };
+        // nothing is drawn, the pass only exists for its timestamp
+        encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("gpu timer pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                depth_slice: None,
+                view,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Discard,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: Some(wgpu::RenderPassTimestampWrites {
+                query_set: &self.query_set,
+                beginning_of_pass_write_index: Some(index),
+                end_of_pass_write_index: None,
+            }),
+            occlusion_query_set: None,
+        });
+    }
+
+    pub fn begin_scope(&mut self, encoder: &mut wgpu::CommandEncoder, name: &str) {
+        if self.open || self.names.len() as u32 >= MAX_SCOPES {
+            return;
+        }
+        self.write_timestamp(encoder, self.names.len() as u32 * 2);
+        self.names.push(name.to_string());
+        self.open = true;
+    }
+
+    pub fn end_scope(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        if !self.open {
+            return;
+        }
+        self.write_timestamp(encoder, (self.names.len() as u32 - 1) * 2 + 1);
+        self.open = false;
+    }
+
+    // After the last scope of the frame, copies the timestamps to the next free readback buffer.
+    pub fn resolve(&mut self, encoder: &mut wgpu::CommandEncoder) {
+        let readback = &mut self.readbacks[self.current];
+        if self.names.is_empty() || readback.in_flight {
+            // every readback still waits for the gpu, this frame goes unmeasured
+            self.names.clear();
+            return;
+        }
+        let count = self.names.len() as u32 * 2;
+        encoder.resolve_query_set(&self.query_set, 0..count, &self.resolve_buffer, 0);
+        encoder.copy_buffer_to_buffer(&self.resolve_buffer, 0, &readback.buffer, 0, count as u64 * wgpu::QUERY_SIZE as u64);
+        readback.names = std::mem::take(&mut self.names);
+    }
+
+    // After queue.submit: start mapping what resolve() copied.
+    pub fn after_submit(&mut self) {
+        let readback = &mut self.readbacks[self.current];
+        if readback.names.is_empty() || readback.in_flight {
+            return;
+        }
+        let ready = readback.ready.clone();
+        readback.buffer.slice(..).map_async(wgpu::MapMode::Read, move |result| {
+            if result.is_ok() {
+                ready.store(true, Ordering::Release);
+            }
+        });
+        readback.in_flight = true;
+        self.current = (self.current + 1) % self.readbacks.len();
+    }
+
+    // (scope name, milliseconds) of every frame the gpu finished since the last call
+    fn collect(&mut self, device: &wgpu::Device) -> Vec<(String, f32)> {
+        // non blocking, only runs the callbacks of finished maps
+        let _ = device.poll(wgpu::PollType::Poll);
+
+        let mut results = Vec::new();
+        for readback in &mut self.readbacks {
+            if !readback.in_flight || !readback.ready.load(Ordering::Acquire) {
+                continue;
+            }
+            {
+                let data = readback.buffer.slice(..).get_mapped_range();
+                let ticks: &[u64] = bytemuck::cast_slice(&data);
+                for (i, name) in readback.names.drain(..).enumerate() {
+                    let (start, end) = (ticks[i * 2], ticks[i * 2 + 1]);
+                    let ms = end.saturating_sub(start) as f32 * self.period / 1_000_000.0;
+                    results.push((name, ms));
+                }
+            }
+            readback.buffer.unmap();
+            readback.ready.store(false, Ordering::Release);
+            readback.in_flight = false;
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_of_the_window() {
+        assert_eq!(RollingStats::default().summary().max, 0.0);
+
+        let mut stats = RollingStats::default();
+        // 1..=100 shuffled a bit, p99 is the 99th smallest
+        for i in (51..=100).chain(1..=50) {
+            stats.push(i as f32);
+        }
+        let s = stats.summary();
+        assert_eq!((s.min, s.max, s.p99, s.last), (1.0, 100.0, 99.0, 50.0));
+        assert!((s.avg - 50.5).abs() < 1e-4);
+
+        // only the last STATS_WINDOW samples count
+        for _ in 0..STATS_WINDOW {
+            stats.push(2.0);
+        }
+        let s = stats.summary();
+        assert_eq!((s.min, s.avg, s.max, s.p99), (2.0, 2.0, 2.0, 2.0));
+
+        let mut single = RollingStats::default();
+        single.push(3.0);
+        assert_eq!(single.summary().p99, 3.0);
+    }
+}
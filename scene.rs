@@ -0,0 +1,523 @@
+use cgmath::{Matrix, Matrix3, Matrix4, One, Quaternion, SquareMatrix, Vector3, Zero};
+
+use crate::assets::{Handle, LinearTexture};
+use crate::mesh::Mesh;
+use crate::pbr::PbrMaterial;
+use crate::texture::Texture;
+
+// SCENE GRAPH
+// Nodes with a local transform (translation, rotation, scale) relative to their parent.
+// Changing a transform only marks the node dirty, update_transforms() recomputes the world matrix
+// of every dirty node and everything under it, once per frame from prepare().
+// A node can reference a mesh and a material by index into State::meshes / State::materials,
+// prepare() writes the world matrices into an instance buffer and draws() hands the renderable
+// nodes to the passes, see scene.wgsl for the shader side. The buffer is made by the first
+// prepare(), the graph itself needs no device.
+
+// index into State::meshes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MeshId(pub usize);
+
+// index into State::materials
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MaterialId(pub usize);
+
+// What a node is drawn with, one bind group per shading mode:
+// the texture + sampler group of shader.wgsl (unlit, Blinn-Phong) and the pbr material.
+// Built from a scene_file::MaterialDesc, the desc fields stay for saving.
+// The bind groups are made by scene_file::prepare_material once the textures are loaded,
+// nodes with a material that is not ready yet are skipped.
+pub struct Material {
+    pub name: String,
+    pub base_color_texture: Option<String>,
+    pub metallic_roughness_texture: Option<String>,
+    pub normal_texture: Option<String>,
+    pub occlusion_texture: Option<String>,
+    pub emissive_texture: Option<String>,
+    pub base_color: [f32; 4],
+    pub metallic: f32,
+    pub roughness: f32,
+    // None = a solid base_color texture
+    pub texture: Option<Handle<Texture>>,
+    pub maps: MaterialMaps,
+    pub bind_groups: Option<MaterialBindGroups>,
+}
+
+// the pbr maps besides base color, None = the PbrTextures::defaults one
+#[derive(Clone, Default)]
+pub struct MaterialMaps {
+    pub metallic_roughness: Option<Handle<LinearTexture>>,
+    pub normal: Option<Handle<LinearTexture>>,
+    pub occlusion: Option<Handle<LinearTexture>>,
+    pub emissive: Option<Handle<Texture>>,
+}
+
+pub struct MaterialBindGroups {
+    // AssetServer::version of every texture they were made from:
+    // base color, metallic roughness, normal, occlusion, emissive
+    pub texture_versions: [u64; 5],
+    pub diffuse: wgpu::BindGroup,
+    pub pbr: PbrMaterial,
+}
+
+// Slot index + generation, an id of a removed node never finds the node that reuses its slot.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NodeId {
+    index: u32,
+    generation: u32,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            translation: Vector3::zero(),
+            rotation: Quaternion::one(),
+            scale: Vector3::new(1.0, 1.0, 1.0),
+        }
+    }
+}
+
+impl Transform {
+    pub fn from_translation(translation: Vector3<f32>) -> Self {
+        Self { translation, ..Default::default() }
+    }
+
+    pub fn with_rotation(self, rotation: Quaternion<f32>) -> Self {
+        Self { rotation, ..self }
+    }
+
+    pub fn with_scale(self, scale: Vector3<f32>) -> Self {
+        Self { scale, ..self }
+    }
+
+    // scale, then rotate, then translate
+    pub fn matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+}
+
+pub struct Node {
+    pub name: String,
+    pub mesh: Option<MeshId>,
+    pub material: Option<MaterialId>,
+    // hides the node and everything under it
+    pub visible: bool,
+    local: Transform,
+    world: Matrix4<f32>,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+    dirty: bool,
+    // visible and every parent visible, set by update_transforms
+    visible_in_tree: bool,
+}
+
+impl Node {
+    pub fn transform(&self) -> &Transform {
+        &self.local
+    }
+
+    // valid after update_transforms
+    pub fn world(&self) -> Matrix4<f32> {
+        self.world
+    }
+
+    pub fn world_position(&self) -> Vector3<f32> {
+        self.world.w.truncate()
+    }
+
+    pub fn parent(&self) -> Option<NodeId> {
+        self.parent
+    }
+
+    pub fn children(&self) -> &[NodeId] {
+        &self.children
+    }
+}
+
+struct Slot {
+    generation: u32,
+    node: Option<Node>,
+}
+
+// Vertex buffer entry per node, the layout matches InstanceInput in scene.wgsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct InstanceRaw {
+    model: [[f32; 4]; 4],
+    normal: [[f32; 3]; 3],
+}
+
+impl InstanceRaw {
+    // 0..4 belong to the Vertex
+    const ATTRIBS: [wgpu::VertexAttribute; 7] = wgpu::vertex_attr_array![
+        5 => Float32x4, 6 => Float32x4, 7 => Float32x4, 8 => Float32x4,
+        9 => Float32x3, 10 => Float32x3, 11 => Float32x3,
+    ];
+
+    pub fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Instance,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+
+    fn from_world(world: Matrix4<f32>) -> Self {
+        let upper = Matrix3::from_cols(world.x.truncate(), world.y.truncate(), world.z.truncate());
+        let normal = upper.invert().map(|m| m.transpose()).unwrap_or_else(Matrix3::identity);
+        Self {
+            model: world.into(),
+            normal: normal.into(),
+        }
+    }
+}
+
+// One renderable node for a pass: bind `instance` to vertex buffer slot 1, then draw the mesh.
+#[derive(Copy, Clone)]
+pub struct Draw<'a> {
+    pub node: NodeId,
+    pub mesh: &'a Mesh,
+    pub material: Option<MaterialId>,
+    pub instance: wgpu::BufferSlice<'a>,
+}
+
+pub struct Scene {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    roots: Vec<NodeId>,
+    // None until the first prepare()
+    instance_buffer: Option<wgpu::Buffer>,
+    // in instances
+    instance_capacity: usize,
+    // a world matrix changed since the last upload
+    instances_dirty: bool,
+}
+
+impl Scene {
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            roots: Vec::new(),
+            instance_buffer: None,
+            instance_capacity: 16,
+            instances_dirty: true,
+        }
+    }
+
+    fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("scene instance buffer"),
+            size: (capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    // NODES
+
+    pub fn add(&mut self, name: &str, transform: Transform) -> NodeId {
+        self.insert(name, transform, None)
+    }
+
+    pub fn add_child(&mut self, parent: NodeId, name: &str, transform: Transform) -> NodeId {
+        assert!(self.get(parent).is_some(), "add_child: parent {:?} does not exist", parent);
+        self.insert(name, transform, Some(parent))
+    }
+
+    // mesh node in one call
+    pub fn add_mesh(&mut self, parent: Option<NodeId>, name: &str, transform: Transform, mesh: MeshId, material: MaterialId) -> NodeId {
+        let id = match parent {
+            Some(parent) => self.add_child(parent, name, transform),
+            None => self.add(name, transform),
+        };
+        let node = self.get_mut(id).unwrap();
+        node.mesh = Some(mesh);
+        node.material = Some(material);
+        id
+    }
+
+    fn insert(&mut self, name: &str, transform: Transform, parent: Option<NodeId>) -> NodeId {
+        let node = Node {
+            name: name.to_string(),
+            mesh: None,
+            material: None,
+            visible: true,
+            local: transform,
+            world: Matrix4::identity(),
+            parent,
+            children: Vec::new(),
+            dirty: true,
+            visible_in_tree: true,
+        };
+        let id = match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.node = Some(node);
+                NodeId { index, generation: slot.generation }
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, node: Some(node) });
+                NodeId { index: self.slots.len() as u32 - 1, generation: 0 }
+            }
+        };
+        match parent {
+            Some(parent) => self.get_mut(parent).unwrap().children.push(id),
+            None => self.roots.push(id),
+        }
+        id
+    }
+
+    // Removes the node and everything under it.
+    pub fn remove(&mut self, id: NodeId) {
+        let Some(node) = self.get(id) else { return };
+        let parent = node.parent;
+        self.detach(id, parent);
+
+        let mut stack = vec![id];
+        while let Some(id) = stack.pop() {
+            let slot = &mut self.slots[id.index as usize];
+            if let Some(node) = slot.node.take() {
+                stack.extend(node.children);
+                slot.generation += 1;
+                self.free.push(id.index);
+            }
+        }
+        self.instances_dirty = true;
+    }
+
+    // Moves the node under another parent (None = root). The local transform is kept,
+    // so the node moves with its new parent. Making a node its own ancestor is ignored.
+    pub fn set_parent(&mut self, id: NodeId, parent: Option<NodeId>) {
+        let Some(node) = self.get(id) else { return };
+        let old_parent = node.parent;
+        if let Some(parent) = parent {
+            if self.get(parent).is_none() || self.is_ancestor_or_self(id, parent) {
+                log::warn!("set_parent: {:?} can not go under {:?}", id, parent);
+                return;
+            }
+        }
+        self.detach(id, old_parent);
+        match parent {
+            Some(parent) => self.get_mut(parent).unwrap().children.push(id),
+            None => self.roots.push(id),
+        }
+        let node = self.get_mut(id).unwrap();
+        node.parent = parent;
+        node.dirty = true;
+    }
+
+    fn detach(&mut self, id: NodeId, parent: Option<NodeId>) {
+        let siblings = match parent {
+            Some(parent) => &mut self.get_mut(parent).unwrap().children,
+            None => &mut self.roots,
+        };
+        siblings.retain(|&child| child != id);
+    }
+
+    fn is_ancestor_or_self(&self, ancestor: NodeId, mut id: NodeId) -> bool {
+        loop {
+            if id == ancestor {
+                return true;
+            }
+            match self.get(id).and_then(|node| node.parent) {
+                Some(parent) => id = parent,
+                None => return false,
+            }
+        }
+    }
+
+    pub fn get(&self, id: NodeId) -> Option<&Node> {
+        self.slots
+            .get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.node.as_ref())
+    }
+
+    // the local transform stays behind set_transform, so the dirty flag can not be missed
+    pub fn get_mut(&mut self, id: NodeId) -> Option<&mut Node> {
+        self.slots
+            .get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.node.as_mut())
+    }
+
+    pub fn find(&self, name: &str) -> Option<NodeId> {
+        self.iter().find(|(_, node)| node.name == name).map(|(id, _)| id)
+    }
+
+    pub fn roots(&self) -> &[NodeId] {
+        &self.roots
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (NodeId, &Node)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.node.as_ref().map(|node| (NodeId { index: index as u32, generation: slot.generation }, node))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // TRANSFORMS
+
+    pub fn set_transform(&mut self, id: NodeId, transform: Transform) {
+        if let Some(node) = self.get_mut(id) {
+            node.local = transform;
+            node.dirty = true;
+        }
+    }
+
+    // changes the local transform in place, e.g. scene.update_transform(id, |t| t.rotation = ...)
+    pub fn update_transform(&mut self, id: NodeId, f: impl FnOnce(&mut Transform)) {
+        if let Some(node) = self.get_mut(id) {
+            f(&mut node.local);
+            node.dirty = true;
+        }
+    }
+
+    // Recomputes the world matrix of every dirty node and of everything under it.
+    pub fn update_transforms(&mut self) {
+        // (node, parent world, parent visible, an ancestor changed)
+        let mut stack: Vec<(NodeId, Matrix4<f32>, bool, bool)> =
+            self.roots.iter().map(|&root| (root, Matrix4::identity(), true, false)).collect();
+
+        while let Some((id, parent_world, parent_visible, parent_changed)) = stack.pop() {
+            let Some(node) = self.get_mut(id) else { continue };
+            let changed = parent_changed || node.dirty;
+            if changed {
+                node.world = parent_world * node.local.matrix();
+                node.dirty = false;
+            }
+            let visible = parent_visible && node.visible;
+            let changed = changed || visible != node.visible_in_tree;
+            node.visible_in_tree = visible;
+
+            let world = node.world;
+            stack.extend(node.children.iter().map(|&child| (child, world, visible, changed)));
+            self.instances_dirty |= changed;
+        }
+    }
+
+    // GPU
+
+    // Once per frame before rendering: transforms, then the world matrices into the instance buffer.
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        self.update_transforms();
+        if !self.instances_dirty {
+            return;
+        }
+        self.instances_dirty = false;
+
+        if self.slots.len() > self.instance_capacity {
+            self.instance_capacity = self.slots.len().next_power_of_two();
+            self.instance_buffer = None;
+        }
+        let instance_buffer = self.instance_buffer.get_or_insert_with(|| Self::create_instance_buffer(device, self.instance_capacity));
+
+        // one entry per slot, so the instance of a node is at its slot index
+        let instances: Vec<InstanceRaw> = self
+            .slots
+            .iter()
+            .map(|slot| match &slot.node {
+                Some(node) => InstanceRaw::from_world(node.world),
+                None => <InstanceRaw as bytemuck::Zeroable>::zeroed(),
+            })
+            .collect();
+        queue.write_buffer(instance_buffer, 0, bytemuck::cast_slice(&instances));
+    }
+
+    // Every visible node with a mesh that is loaded, `mesh` looks the MeshId up.
+    pub fn draws<'a>(&'a self, mesh: impl Fn(MeshId) -> Option<&'a Mesh>) -> Vec<Draw<'a>> {
+        // nothing was prepared yet
+        let Some(instance_buffer) = &self.instance_buffer else { return Vec::new() };
+        let stride = std::mem::size_of::<InstanceRaw>() as wgpu::BufferAddress;
+        self.iter()
+            .filter(|(_, node)| node.visible_in_tree)
+            .filter_map(|(id, node)| {
+                let mesh = mesh(node.mesh?)?;
+                let offset = id.index as wgpu::BufferAddress * stride;
+                Some(Draw {
+                    node: id,
+                    mesh,
+                    material: node.material,
+                    instance: instance_buffer.slice(offset..offset + stride),
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn world_matrices_follow_the_parents() {
+        let mut scene = Scene::new();
+        let parent = scene.add("parent", Transform::from_translation(Vector3::new(1.0, 0.0, 0.0)));
+        let child = scene.add_child(parent, "child", Transform::from_translation(Vector3::new(0.0, 2.0, 0.0)));
+        scene.update_transforms();
+        assert_eq!(scene.get(child).unwrap().world_position(), Vector3::new(1.0, 2.0, 0.0));
+
+        // only the parent changes, the child is recomputed with it
+        scene.update_transform(parent, |t| t.translation.z = 3.0);
+        scene.update_transforms();
+        assert_eq!(scene.get(child).unwrap().world_position(), Vector3::new(1.0, 2.0, 3.0));
+
+        scene.get_mut(parent).unwrap().visible = false;
+        scene.update_transforms();
+        assert!(!scene.get(child).unwrap().visible_in_tree);
+    }
+
+    #[test]
+    fn set_parent_refuses_cycles() {
+        let mut scene = Scene::new();
+        let a = scene.add("a", Transform::default());
+        let b = scene.add_child(a, "b", Transform::default());
+        let c = scene.add_child(b, "c", Transform::default());
+
+        scene.set_parent(a, Some(c));
+        scene.set_parent(a, Some(a));
+        assert_eq!(scene.get(a).unwrap().parent(), None);
+        assert_eq!(scene.roots(), [a]);
+
+        scene.set_parent(c, None);
+        assert_eq!(scene.get(c).unwrap().parent(), None);
+        assert!(scene.get(b).unwrap().children().is_empty());
+        assert_eq!(scene.roots(), [a, c]);
+    }
+
+    #[test]
+    fn remove_takes_the_subtree_and_ids_do_not_come_back() {
+        let mut scene = Scene::new();
+        let a = scene.add("a", Transform::default());
+        let b = scene.add_child(a, "b", Transform::default());
+        let other = scene.add("other", Transform::default());
+        scene.remove(a);
+        assert!(scene.get(a).is_none() && scene.get(b).is_none());
+        assert_eq!(scene.len(), 1);
+        assert_eq!(scene.roots(), [other]);
+
+        // the new node reuses a freed slot, the old ids still find nothing
+        let reused = scene.add("reused", Transform::default());
+        assert!(reused.index == a.index || reused.index == b.index);
+        assert!(scene.get(a).is_none() && scene.get(b).is_none());
+        assert_eq!(scene.get(reused).unwrap().name, "reused");
+        assert_eq!(scene.find("reused"), Some(reused));
+    }
+}
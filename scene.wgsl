@@ -0,0 +1,21 @@
+// Per node transform from the scene graph (scene.rs), bound as a second vertex buffer with step mode Instance.
+// main.rs and shadow.rs glue this file in front of the pipeline's own shader with concat!.
+
+struct InstanceInput {
+    @location(5) model_0: vec4<f32>,
+    @location(6) model_1: vec4<f32>,
+    @location(7) model_2: vec4<f32>,
+    @location(8) model_3: vec4<f32>,
+    // inverse transpose of the model matrix, normals stay perpendicular under non uniform scale
+    @location(9) normal_0: vec3<f32>,
+    @location(10) normal_1: vec3<f32>,
+    @location(11) normal_2: vec3<f32>,
+}
+
+fn instance_model(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(instance.model_0, instance.model_1, instance.model_2, instance.model_3);
+}
+
+fn instance_normal(instance: InstanceInput) -> mat3x3<f32> {
+    return mat3x3<f32>(instance.normal_0, instance.normal_1, instance.normal_2);
+}
@@ -0,0 +1,915 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::*;
+use cgmath::{Point3, Quaternion, Rad, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::assets::{Asset, AssetServer, Handle, LinearTexture, LoadState};
+use crate::audio::{Attenuation, Bus, PlayParams, Spatial};
+use crate::camera::Camera;
+use crate::ecs::{self, Ecs};
+use crate::light::Light;
+use crate::mesh::Mesh;
+use crate::pbr::{self, PbrMaterial, PbrParams, PbrTextures};
+use crate::scene::{Material, MaterialBindGroups, MaterialId, MaterialMaps, MeshId, Transform};
+use crate::texture::Texture;
+
+// SCENE FILES
+// A level on disk: camera, lights, materials and entities, as RON (.ron) or JSON (.json).
+// Meshes and textures are referenced by asset path, "builtin:<name>" are the ones compiled into the engine.
+// Entities point at their parent by index into `entities` and at their material by name.
+//
+// VERSIONING
+// Every file starts with `version`. Loading reads only that first, refuses files newer than
+// SCENE_VERSION, then reads the rest and runs migrate() from the file's version up.
+// Adding an optional field: #[serde(default)], no new version needed.
+// Renaming / changing the meaning of a field: bump SCENE_VERSION and convert in migrate().
+
+pub const SCENE_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SceneFile {
+    pub version: u32,
+    #[serde(default)]
+    pub camera: Option<CameraDesc>,
+    #[serde(default)]
+    pub lights: Vec<LightDesc>,
+    #[serde(default)]
+    pub materials: Vec<MaterialDesc>,
+    #[serde(default)]
+    pub entities: Vec<EntityDesc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct CameraDesc {
+    pub eye: [f32; 3],
+    pub target: [f32; 3],
+    // degrees
+    pub fovy: f32,
+    pub znear: f32,
+    pub zfar: f32,
+}
+
+// angles in radians, directions don't have to be normalized
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type")]
+pub enum LightDesc {
+    Directional {
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        #[serde(default)]
+        cast_shadows: bool,
+    },
+    Point {
+        position: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        range: f32,
+    },
+    Spot {
+        position: [f32; 3],
+        direction: [f32; 3],
+        color: [f32; 3],
+        intensity: f32,
+        range: f32,
+        inner_angle: f32,
+        outer_angle: f32,
+        #[serde(default)]
+        cast_shadows: bool,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MaterialDesc {
+    pub name: String,
+    // asset path, a solid `base_color` texture without one
+    #[serde(default)]
+    pub base_color_texture: Option<String>,
+    // asset paths of the other glTF maps, see pbr.rs for the channels
+    #[serde(default)]
+    pub metallic_roughness_texture: Option<String>,
+    #[serde(default)]
+    pub normal_texture: Option<String>,
+    #[serde(default)]
+    pub occlusion_texture: Option<String>,
+    #[serde(default)]
+    pub emissive_texture: Option<String>,
+    // multiplies the base color texture
+    #[serde(default = "white")]
+    pub base_color: [f32; 4],
+    #[serde(default)]
+    pub metallic: f32,
+    #[serde(default = "one")]
+    pub roughness: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EntityDesc {
+    pub name: String,
+    #[serde(default)]
+    pub transform: TransformDesc,
+    // index into SceneFile::entities
+    #[serde(default)]
+    pub parent: Option<usize>,
+    // asset path
+    #[serde(default)]
+    pub mesh: Option<String>,
+    // MaterialDesc::name
+    #[serde(default)]
+    pub material: Option<String>,
+    #[serde(default = "yes")]
+    pub visible: bool,
+    #[serde(default)]
+    pub spin: Option<SpinDesc>,
+    #[serde(default)]
+    pub emitter: Option<EmitterDesc>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransformDesc {
+    pub translation: [f32; 3],
+    // quaternion x, y, z, w
+    pub rotation: [f32; 4],
+    pub scale: [f32; 3],
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SpinDesc {
+    pub axis: [f32; 3],
+    // radians per second
+    pub speed: f32,
+}
+
+// a sound playing at the entity, see audio.rs
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct EmitterDesc {
+    // asset path
+    pub sound: String,
+    #[serde(default)]
+    pub bus: Bus,
+    #[serde(default = "one")]
+    pub volume: f32,
+    #[serde(default = "one")]
+    pub pitch: f32,
+    #[serde(default = "yes")]
+    pub looping: bool,
+    #[serde(default)]
+    pub attenuation: Attenuation,
+    #[serde(default = "one")]
+    pub min_distance: f32,
+    #[serde(default = "fifty")]
+    pub max_distance: f32,
+    #[serde(default = "one")]
+    pub rolloff: f32,
+}
+
+impl EmitterDesc {
+    fn params(&self) -> PlayParams {
+        PlayParams {
+            bus: self.bus,
+            volume: self.volume,
+            pitch: self.pitch,
+            looping: self.looping,
+            spatial: Some(Spatial {
+                attenuation: self.attenuation,
+                min_distance: self.min_distance,
+                max_distance: self.max_distance,
+                rolloff: self.rolloff,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn from_emitter(emitter: &ecs::Emitter, assets: &AssetServer) -> Self {
+        let params = emitter.params;
+        let spatial = params.spatial.unwrap_or_default();
+        Self {
+            sound: assets.path(&emitter.sound).to_string(),
+            bus: params.bus,
+            volume: params.volume,
+            pitch: params.pitch,
+            looping: params.looping,
+            attenuation: spatial.attenuation,
+            min_distance: spatial.min_distance,
+            max_distance: spatial.max_distance,
+            rolloff: spatial.rolloff,
+        }
+    }
+}
+
+fn white() -> [f32; 4] {
+    [1.0; 4]
+}
+
+fn one() -> f32 {
+    1.0
+}
+
+fn fifty() -> f32 {
+    50.0
+}
+
+fn yes() -> bool {
+    true
+}
+
+impl Default for TransformDesc {
+    fn default() -> Self {
+        Transform::default().into()
+    }
+}
+
+impl From<Transform> for TransformDesc {
+    fn from(t: Transform) -> Self {
+        Self {
+            translation: t.translation.into(),
+            rotation: [t.rotation.v.x, t.rotation.v.y, t.rotation.v.z, t.rotation.s],
+            scale: t.scale.into(),
+        }
+    }
+}
+
+impl From<&TransformDesc> for Transform {
+    fn from(t: &TransformDesc) -> Self {
+        let [x, y, z, w] = t.rotation;
+        Self {
+            translation: t.translation.into(),
+            rotation: Quaternion::new(w, x, y, z),
+            scale: t.scale.into(),
+        }
+    }
+}
+
+impl From<&Light> for LightDesc {
+    fn from(light: &Light) -> Self {
+        match *light {
+            Light::Directional { direction, color, intensity, cast_shadows } => LightDesc::Directional {
+                direction: direction.into(),
+                color,
+                intensity,
+                cast_shadows,
+            },
+            Light::Point { position, color, intensity, range } => LightDesc::Point {
+                position: position.into(),
+                color,
+                intensity,
+                range,
+            },
+            Light::Spot { position, direction, color, intensity, range, inner_angle, outer_angle, cast_shadows } => LightDesc::Spot {
+                position: position.into(),
+                direction: direction.into(),
+                color,
+                intensity,
+                range,
+                inner_angle: inner_angle.0,
+                outer_angle: outer_angle.0,
+                cast_shadows,
+            },
+        }
+    }
+}
+
+impl From<&LightDesc> for Light {
+    fn from(light: &LightDesc) -> Self {
+        match *light {
+            LightDesc::Directional { direction, color, intensity, cast_shadows } => Light::Directional {
+                direction: Vector3::from(direction),
+                color,
+                intensity,
+                cast_shadows,
+            },
+            LightDesc::Point { position, color, intensity, range } => Light::Point {
+                position: Point3::from(position),
+                color,
+                intensity,
+                range,
+            },
+            LightDesc::Spot { position, direction, color, intensity, range, inner_angle, outer_angle, cast_shadows } => Light::Spot {
+                position: Point3::from(position),
+                direction: Vector3::from(direction),
+                color,
+                intensity,
+                range,
+                inner_angle: Rad(inner_angle),
+                outer_angle: Rad(outer_angle),
+                cast_shadows,
+            },
+        }
+    }
+}
+
+impl CameraDesc {
+    pub fn from_camera(camera: &Camera) -> Self {
+        Self {
+            eye: camera.eye.into(),
+            target: camera.target.into(),
+            fovy: camera.fovy,
+            znear: camera.znear,
+            zfar: camera.zfar,
+        }
+    }
+
+    // aspect and up stay, they are not part of the level
+    pub fn apply(&self, camera: &mut Camera) {
+        camera.eye = self.eye.into();
+        camera.target = self.target.into();
+        camera.fovy = self.fovy;
+        camera.znear = self.znear;
+        camera.zfar = self.zfar;
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Ron,
+    Json,
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("ron") => Ok(Format::Ron),
+            Some("json") => Ok(Format::Json),
+            _ => bail!("{}: scene files are .ron or .json", path.display()),
+        }
+    }
+}
+
+// only the version, read before the rest of the file
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+impl SceneFile {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        // through the vfs, a shipped level can come from the archive
+        let text = crate::vfs::read_to_string(&path.to_string_lossy())?;
+        Self::parse(&text, Format::from_path(path)?).with_context(|| format!("loading {}", path.display()))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let text = self.to_string(Format::from_path(path)?)?;
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, text).with_context(|| format!("writing {}", path.display()))
+    }
+
+    pub fn parse(text: &str, format: Format) -> Result<Self> {
+        let header: Header = match format {
+            Format::Ron => ron::from_str(text)?,
+            Format::Json => serde_json::from_str(text)?,
+        };
+        if header.version == 0 || header.version > SCENE_VERSION {
+            bail!("scene version {} is not supported, this build reads 1 to {}", header.version, SCENE_VERSION);
+        }
+
+        let mut file: SceneFile = match format {
+            Format::Ron => ron::from_str(text)?,
+            Format::Json => serde_json::from_str(text)?,
+        };
+        file.migrate(header.version);
+        file.validate()?;
+        Ok(file)
+    }
+
+    pub fn to_string(&self, format: Format) -> Result<String> {
+        Ok(match format {
+            Format::Ron => ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?,
+            Format::Json => serde_json::to_string_pretty(self)?,
+        })
+    }
+
+    // One step per version: `if from < 2 { ... }`, `if from < 3 { ... }`, then the current version.
+    fn migrate(&mut self, from: u32) {
+        if from < SCENE_VERSION {
+            log::info!("scene migrated from version {} to {}", from, SCENE_VERSION);
+        }
+        self.version = SCENE_VERSION;
+    }
+
+    // references that instantiate() would trip over
+    fn validate(&self) -> Result<()> {
+        for (i, entity) in self.entities.iter().enumerate() {
+            if let Some(parent) = entity.parent {
+                ensure!(parent < self.entities.len(), "entity '{}': parent {} out of range", entity.name, parent);
+                ensure!(parent != i, "entity '{}' is its own parent", entity.name);
+            }
+            if entity.mesh.is_some() {
+                ensure!(entity.material.is_some(), "entity '{}' has a mesh but no material", entity.name);
+            }
+            if let Some(material) = &entity.material {
+                ensure!(
+                    self.materials.iter().any(|m| &m.name == material),
+                    "entity '{}': unknown material '{}'",
+                    entity.name,
+                    material
+                );
+            }
+        }
+        // the parents are in range, a chain longer than the entity list has to go around in a loop
+        for entity in &self.entities {
+            let mut parent = entity.parent;
+            for _ in 0..self.entities.len() {
+                parent = parent.and_then(|p| self.entities[p].parent);
+            }
+            ensure!(parent.is_none(), "entity '{}': its parents form a cycle", entity.name);
+        }
+        for light in &self.lights {
+            if let LightDesc::Spot { range, inner_angle, outer_angle, .. } = *light {
+                ensure!(range > 0.0, "spot light: range {} is not positive", range);
+                // the shadow projection covers twice the outer angle, which has to stay below 180 degrees
+                ensure!(
+                    outer_angle > 0.0 && outer_angle < std::f32::consts::FRAC_PI_2,
+                    "spot light: outer angle {} is not between 0 and pi/2",
+                    outer_angle
+                );
+                ensure!(
+                    (0.0..=outer_angle).contains(&inner_angle),
+                    "spot light: inner angle {} is not between 0 and the outer angle",
+                    inner_angle
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // The level the engine starts with when there is no scene file.
+    pub fn default_scene() -> Self {
+        let camera = Camera::new(1, 1);
+        Self {
+            version: SCENE_VERSION,
+            camera: Some(CameraDesc::from_camera(&camera)),
+            lights: crate::light::default_lights().iter().map(LightDesc::from).collect(),
+            materials: vec![
+                MaterialDesc {
+                    name: "happy tree".to_string(),
+                    base_color_texture: Some("builtin:happy-tree.png".to_string()),
+                    metallic_roughness_texture: None,
+                    normal_texture: None,
+                    occlusion_texture: None,
+                    emissive_texture: None,
+                    base_color: white(),
+                    metallic: 0.0,
+                    roughness: 0.5,
+                },
+                MaterialDesc {
+                    name: "ground".to_string(),
+                    base_color_texture: None,
+                    metallic_roughness_texture: None,
+                    normal_texture: None,
+                    occlusion_texture: None,
+                    emissive_texture: None,
+                    base_color: [180.0 / 255.0, 180.0 / 255.0, 180.0 / 255.0, 1.0],
+                    metallic: 0.0,
+                    roughness: 0.9,
+                },
+            ],
+            entities: vec![
+                EntityDesc {
+                    name: "pentagon".to_string(),
+                    transform: TransformDesc::default(),
+                    parent: None,
+                    mesh: Some("builtin:pentagon".to_string()),
+                    material: Some("happy tree".to_string()),
+                    visible: true,
+                    spin: None,
+                    emitter: None,
+                },
+                // turned by the spin system, carries the satellite around the pentagon
+                EntityDesc {
+                    name: "satellite pivot".to_string(),
+                    transform: TransformDesc::default(),
+                    parent: Some(0),
+                    mesh: None,
+                    material: None,
+                    visible: true,
+                    spin: Some(SpinDesc { axis: [0.0, 1.0, 0.0], speed: 1.0 }),
+                    emitter: None,
+                },
+                EntityDesc {
+                    name: "satellite".to_string(),
+                    transform: TransformDesc {
+                        translation: [0.9, 0.3, 0.0],
+                        rotation: [0.0, 0.0, 0.0, 1.0],
+                        scale: [0.3, 0.3, 0.3],
+                    },
+                    parent: Some(1),
+                    mesh: Some("builtin:pentagon".to_string()),
+                    material: Some("happy tree".to_string()),
+                    visible: true,
+                    spin: None,
+                    emitter: None,
+                },
+                EntityDesc {
+                    name: "ground".to_string(),
+                    transform: TransformDesc::default(),
+                    parent: None,
+                    mesh: Some("builtin:ground".to_string()),
+                    material: Some("ground".to_string()),
+                    visible: true,
+                    spin: None,
+                    emitter: None,
+                },
+            ],
+        }
+    }
+
+    // ECS entities + meshes + materials + lights + camera -> file.
+    pub fn capture(ecs: &Ecs, assets: &AssetServer, meshes: &[Handle<Mesh>], materials: &[Material], lights: &[Light], camera: &Camera) -> Self {
+        let mut query = ecs.world.query::<(
+            &Transform,
+            Option<&ecs::Name>,
+            Option<&ecs::Parent>,
+            Option<&ecs::Renderable>,
+            Option<&ecs::Visible>,
+            Option<&ecs::Spin>,
+            Option<&ecs::Emitter>,
+        )>();
+        let rows: Vec<_> = query.iter().collect();
+        let index: HashMap<ecs::Entity, usize> = rows.iter().enumerate().map(|(i, (entity, _))| (*entity, i)).collect();
+
+        let entities = rows
+            .iter()
+            .map(|(entity, (transform, name, parent, renderable, visible, spin, emitter))| EntityDesc {
+                name: name.map(|n| n.0.clone()).unwrap_or_else(|| format!("entity {}", entity.id())),
+                transform: (**transform).into(),
+                parent: parent.and_then(|p| index.get(&p.0).copied()),
+                mesh: renderable.and_then(|r| meshes.get(r.mesh.0)).map(|mesh| assets.path(mesh).to_string()),
+                material: renderable.and_then(|r| materials.get(r.material.0)).map(|material| material.name.clone()),
+                visible: visible.is_none_or(|v| v.0),
+                spin: spin.map(|s| SpinDesc { axis: s.axis.into(), speed: s.speed }),
+                emitter: emitter.map(|e| EmitterDesc::from_emitter(e, assets)),
+            })
+            .collect();
+
+        Self {
+            version: SCENE_VERSION,
+            camera: Some(CameraDesc::from_camera(camera)),
+            lights: lights.iter().map(LightDesc::from).collect(),
+            materials: materials.iter().map(Material::desc).collect(),
+            entities,
+        }
+    }
+
+    // File -> mesh handles and materials + ECS entities. The world is cleared first,
+    // lights and camera are left to the caller (State::load_scene).
+    // Meshes and textures load in the background, see assets.rs.
+    pub fn instantiate(&self, assets: &mut AssetServer, ecs: &mut Ecs) -> Result<(Vec<Handle<Mesh>>, Vec<Material>)> {
+        let materials = self.materials.iter().map(|desc| Material::new(desc, assets)).collect::<Vec<_>>();
+
+        // every mesh path once
+        let mut meshes = Vec::new();
+        let mut mesh_ids: HashMap<&str, MeshId> = HashMap::new();
+        for path in self.entities.iter().filter_map(|entity| entity.mesh.as_deref()) {
+            if !mesh_ids.contains_key(path) {
+                mesh_ids.insert(path, MeshId(meshes.len()));
+                meshes.push(assets.load::<Mesh>(path));
+            }
+        }
+
+        ecs.world.clear();
+        let entities: Vec<ecs::Entity> = self
+            .entities
+            .iter()
+            .map(|desc| ecs.world.spawn((ecs::Name(desc.name.clone()), Transform::from(&desc.transform))))
+            .collect();
+
+        for (desc, &entity) in self.entities.iter().zip(&entities) {
+            if let Some(parent) = desc.parent {
+                ecs.world.insert_one(entity, ecs::Parent(entities[parent]))?;
+            }
+            if let Some(path) = &desc.mesh {
+                // both checked in validate()
+                let material = desc
+                    .material
+                    .as_ref()
+                    .and_then(|name| self.materials.iter().position(|m| &m.name == name))
+                    .with_context(|| format!("entity '{}': no material", desc.name))?;
+                ecs.world.insert_one(entity, ecs::Renderable { mesh: mesh_ids[path.as_str()], material: MaterialId(material) })?;
+            }
+            if !desc.visible {
+                ecs.world.insert_one(entity, ecs::Visible(false))?;
+            }
+            if let Some(spin) = &desc.spin {
+                ecs.world.insert_one(entity, ecs::Spin { axis: spin.axis.into(), speed: spin.speed })?;
+            }
+            if let Some(emitter) = &desc.emitter {
+                ecs.world.insert_one(entity, ecs::Emitter { sound: assets.load(&emitter.sound), params: emitter.params() })?;
+            }
+        }
+
+        Ok((meshes, materials))
+    }
+}
+
+// MATERIALS
+
+// what prepare_material needs to build both bind groups of a Material
+pub struct MaterialLayouts {
+    // texture + sampler of shader.wgsl
+    pub diffuse: wgpu::BindGroupLayout,
+    pub pbr: wgpu::BindGroupLayout,
+}
+
+impl MaterialLayouts {
+    pub fn new(device: &wgpu::Device, diffuse: wgpu::BindGroupLayout) -> Self {
+        Self { diffuse, pbr: pbr::material_bind_group_layout(device) }
+    }
+}
+
+impl Material {
+    // starts loading the textures, the bind groups come later from prepare_material
+    pub fn new(desc: &MaterialDesc, assets: &mut AssetServer) -> Self {
+        let mut linear = |path: &Option<String>| path.as_deref().map(|path| assets.load::<LinearTexture>(path));
+        let maps = MaterialMaps {
+            metallic_roughness: linear(&desc.metallic_roughness_texture),
+            normal: linear(&desc.normal_texture),
+            occlusion: linear(&desc.occlusion_texture),
+            emissive: desc.emissive_texture.as_deref().map(|path| assets.load::<Texture>(path)),
+        };
+        Self {
+            name: desc.name.clone(),
+            base_color_texture: desc.base_color_texture.clone(),
+            metallic_roughness_texture: desc.metallic_roughness_texture.clone(),
+            normal_texture: desc.normal_texture.clone(),
+            occlusion_texture: desc.occlusion_texture.clone(),
+            emissive_texture: desc.emissive_texture.clone(),
+            base_color: desc.base_color,
+            metallic: desc.metallic,
+            roughness: desc.roughness,
+            texture: desc.base_color_texture.as_deref().map(|path| assets.load::<Texture>(path)),
+            maps,
+            bind_groups: None,
+        }
+    }
+
+    pub fn desc(&self) -> MaterialDesc {
+        MaterialDesc {
+            name: self.name.clone(),
+            base_color_texture: self.base_color_texture.clone(),
+            metallic_roughness_texture: self.metallic_roughness_texture.clone(),
+            normal_texture: self.normal_texture.clone(),
+            occlusion_texture: self.occlusion_texture.clone(),
+            emissive_texture: self.emissive_texture.clone(),
+            base_color: self.base_color,
+            metallic: self.metallic,
+            roughness: self.roughness,
+        }
+    }
+}
+
+// Once per frame after AssetServer::update: (re)builds the bind groups of a material
+// whose textures all finished loading since the last time.
+pub fn prepare_material(device: &wgpu::Device, queue: &wgpu::Queue, layouts: &MaterialLayouts, assets: &AssetServer, material: &mut Material) {
+    let maps = &material.maps;
+    let versions = [
+        texture_version(assets, &material.texture),
+        texture_version(assets, &maps.metallic_roughness),
+        texture_version(assets, &maps.normal),
+        texture_version(assets, &maps.occlusion),
+        texture_version(assets, &maps.emissive),
+    ];
+    // still loading, or failed
+    if versions.contains(&None) {
+        return;
+    }
+    let texture_versions = versions.map(Option::unwrap);
+    if material.bind_groups.as_ref().is_some_and(|b| b.texture_versions == texture_versions) {
+        return;
+    }
+
+    // the texture carries the color when there is none, the factor would apply it twice
+    let (base_color, base_color_factor) = match material.texture.as_ref().and_then(|handle| assets.get(handle)) {
+        Some(texture) => (texture.clone(), material.base_color),
+        None => {
+            let rgba = material.base_color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8);
+            (Texture::solid(device, queue, rgba, &material.name, true), [1.0; 4])
+        }
+    };
+
+    let diffuse = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout: &layouts.diffuse,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&base_color.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&base_color.sampler),
+            },
+        ],
+        label: Some(&material.name),
+    });
+
+    let mut textures = PbrTextures::defaults(device, queue, base_color);
+    let linear = |handle: &Option<Handle<LinearTexture>>| handle.as_ref().and_then(|handle| assets.get(handle)).map(|t| t.0.clone());
+    if let Some(texture) = linear(&maps.metallic_roughness) {
+        textures.metallic_roughness = texture;
+    }
+    if let Some(texture) = linear(&maps.normal) {
+        textures.normal = texture;
+    }
+    if let Some(texture) = linear(&maps.occlusion) {
+        textures.occlusion = texture;
+    }
+    let emissive = maps.emissive.as_ref().and_then(|handle| assets.get(handle));
+    if let Some(texture) = emissive {
+        textures.emissive = texture.clone();
+    }
+
+    let pbr = PbrMaterial::new(
+        device,
+        &layouts.pbr,
+        textures,
+        PbrParams {
+            base_color_factor,
+            metallic_factor: material.metallic,
+            roughness_factor: material.roughness,
+            // glTF's default of 0 would hide the map
+            emissive_factor: if emissive.is_some() { [1.0; 3] } else { [0.0; 3] },
+            ..Default::default()
+        },
+        &material.name,
+    );
+
+    material.bind_groups = Some(MaterialBindGroups { texture_versions, diffuse, pbr });
+}
+
+// AssetServer::version of an optional texture, None while it has no value yet.
+// 0 without one, or when it failed to load: the material falls back to the default texture.
+fn texture_version<T: Asset>(assets: &AssetServer, handle: &Option<Handle<T>>) -> Option<u64> {
+    match handle {
+        Some(handle) => match (assets.version(handle), assets.state(handle)) {
+            (0, LoadState::Failed(_)) => Some(0),
+            (0, _) => None,
+            (version, _) => Some(version),
+        },
+        None => Some(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the default level plus what it does not use: texture maps, hidden entities, emitters
+    fn sample() -> SceneFile {
+        let mut file = SceneFile::default_scene();
+        file.materials.push(MaterialDesc {
+            name: "brick".to_string(),
+            base_color_texture: Some("textures/brick.png".to_string()),
+            metallic_roughness_texture: Some("textures/brick_mr.png".to_string()),
+            normal_texture: Some("textures/brick_normal.png".to_string()),
+            occlusion_texture: Some("textures/brick_ao.png".to_string()),
+            emissive_texture: Some("textures/brick_glow.png".to_string()),
+            base_color: [0.8, 0.5, 0.25, 1.0],
+            metallic: 0.1,
+            roughness: 0.7,
+        });
+        file.entities.push(EntityDesc {
+            name: "wall".to_string(),
+            transform: TransformDesc {
+                translation: [1.5, -0.25, 3.0],
+                rotation: [0.0, 0.38268343, 0.0, 0.9238795],
+                scale: [2.0, 1.0, 0.5],
+            },
+            parent: Some(0),
+            mesh: Some("models/wall.obj".to_string()),
+            material: Some("brick".to_string()),
+            visible: false,
+            spin: None,
+            emitter: Some(EmitterDesc {
+                sound: "sounds/hum.ogg".to_string(),
+                bus: Bus::Sfx,
+                volume: 0.5,
+                pitch: 1.25,
+                looping: true,
+                attenuation: Attenuation::Exponential,
+                min_distance: 2.0,
+                max_distance: 20.0,
+                rolloff: 1.5,
+            }),
+        });
+        file
+    }
+
+    fn round_trip(format: Format) {
+        let file = sample();
+        let text = file.to_string(format).unwrap();
+        let loaded = SceneFile::parse(&text, format).unwrap();
+        assert_eq!(loaded, file);
+        assert_eq!(loaded.to_string(format).unwrap(), text);
+    }
+
+    #[test]
+    fn ron_round_trip() {
+        round_trip(Format::Ron);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        round_trip(Format::Json);
+    }
+
+    #[test]
+    fn save_and_load_through_a_file() {
+        let path = std::env::temp_dir().join(format!("scene_file_test_{}.ron", std::process::id()));
+        let file = sample();
+        file.save(&path).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(SceneFile::parse(&text, Format::from_path(&path).unwrap()).unwrap(), file);
+    }
+
+    #[test]
+    fn engine_types_round_trip() {
+        let file = sample();
+        for light in &file.lights {
+            assert_eq!(&LightDesc::from(&Light::from(light)), light);
+        }
+        for entity in &file.entities {
+            assert_eq!(TransformDesc::from(Transform::from(&entity.transform)), entity.transform);
+        }
+        let desc = file.camera.unwrap();
+        let mut camera = Camera::new(16, 9);
+        desc.apply(&mut camera);
+        assert_eq!(CameraDesc::from_camera(&camera), desc);
+    }
+
+    #[test]
+    fn optional_fields_default() {
+        let file = SceneFile::parse("(version: 1)", Format::Ron).unwrap();
+        assert_eq!(file.camera, None);
+        assert!(file.lights.is_empty() && file.materials.is_empty() && file.entities.is_empty());
+
+        let text = r#"{"version": 1, "materials": [{"name": "plain"}], "entities": [{"name": "a", "mesh": "m.obj", "material": "plain"}]}"#;
+        let file = SceneFile::parse(text, Format::Json).unwrap();
+        assert_eq!(file.materials[0].base_color, [1.0; 4]);
+        assert_eq!(file.materials[0].normal_texture, None);
+        assert!(file.entities[0].visible);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        for version in [0, SCENE_VERSION + 1, u32::MAX] {
+            let mut file = sample();
+            file.version = version;
+            for format in [Format::Ron, Format::Json] {
+                let text = file.to_string(format).unwrap();
+                assert!(SceneFile::parse(&text, format).is_err(), "version {} was accepted", version);
+            }
+        }
+    }
+
+    #[test]
+    fn migrate_stamps_the_current_version() {
+        for from in 0..=SCENE_VERSION {
+            let mut file = sample();
+            file.version = from;
+            file.migrate(from);
+            assert_eq!(file.version, SCENE_VERSION);
+        }
+    }
+
+    #[test]
+    fn rejects_broken_references() {
+        let mut file = sample();
+        file.entities[0].parent = Some(file.entities.len());
+        assert!(SceneFile::parse(&file.to_string(Format::Ron).unwrap(), Format::Ron).is_err());
+
+        let mut file = sample();
+        file.entities[0].material = Some("missing".to_string());
+        assert!(SceneFile::parse(&file.to_string(Format::Ron).unwrap(), Format::Ron).is_err());
+
+        let mut file = sample();
+        file.entities[0].parent = Some(1);
+        file.entities[1].parent = Some(0);
+        assert!(SceneFile::parse(&file.to_string(Format::Ron).unwrap(), Format::Ron).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_spot_lights() {
+        let spot = sample().lights.iter().position(|light| matches!(light, LightDesc::Spot { .. })).unwrap();
+        let broken: [fn(&mut f32, &mut f32, &mut f32); 4] = [
+            |range, _, _| *range = 0.0,
+            |_, _, outer| *outer = std::f32::consts::FRAC_PI_2,
+            |_, inner, outer| *inner = *outer + 0.1,
+            |_, inner, _| *inner = -0.1,
+        ];
+        for breakage in broken {
+            let mut file = sample();
+            let LightDesc::Spot { range, inner_angle, outer_angle, .. } = &mut file.lights[spot] else { unreachable!() };
+            breakage(range, inner_angle, outer_angle);
+            assert!(SceneFile::parse(&file.to_string(Format::Ron).unwrap(), Format::Ron).is_err());
+        }
+        assert!(SceneFile::parse(&sample().to_string(Format::Ron).unwrap(), Format::Ron).is_ok());
+    }
+}
@@ -0,0 +1,410 @@
+use cgmath::{EuclideanSpace, InnerSpace, Matrix4, Point3, Rad, SquareMatrix, Transform, Vector3};
+
+use crate::camera::{Camera, OPENGL_TO_WGPU_MATRIX};
+use crate::light::Light;
+use crate::scene::{Draw, InstanceRaw};
+
+pub const SHADOW_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+pub const SHADOW_MAP_SIZE: u32 = 2048;
+
+// Layers of the shadow texture array. Must match MAX_SHADOW_LAYERS in light.wgsl.
+// The directional light takes CASCADE_COUNT layers, every shadowed spot light takes one.
+pub const MAX_SHADOW_LAYERS: usize = 8;
+pub const CASCADE_COUNT: usize = 4;
+
+// Every shadow pass reads its matrix (64 bytes) from the same buffer with a dynamic offset,
+// offsets have to be multiples of the device's min_uniform_buffer_offset_alignment.
+const SHADOW_PASS_MATRIX_SIZE: wgpu::BufferAddress = 64;
+
+// Spot shadows: the perspective projection needs 0 < fov < 180 degrees and far > near.
+const SPOT_NEAR: f32 = 0.05;
+const SPOT_MIN_FOV: f32 = 0.01;
+const SPOT_MAX_FOV: f32 = std::f32::consts::PI * 0.99;
+
+// How far behind a cascade the light "camera" is pulled back, so casters
+// outside the view frustum still throw their shadow into it.
+const CASTER_MARGIN: f32 = 10.0;
+
+#[derive(Copy, Clone, Debug)]
+pub struct ShadowSettings {
+    // subtracted from the fragment depth before the comparison, fights shadow acne
+    pub depth_bias: f32,
+    // world units the lookup position is pushed along the surface normal
+    pub normal_bias: f32,
+    // 0 = hard shadows, 1 = 3x3 PCF, 2 = 5x5 PCF ...
+    pub pcf_radius: u32,
+    // 0 = evenly spaced cascades, 1 = logarithmic
+    pub cascade_split_lambda: f32,
+    // directional shadows stop at this view distance
+    pub max_distance: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            depth_bias: 0.0005,
+            normal_bias: 0.01,
+            pcf_radius: 1,
+            cascade_split_lambda: 0.75,
+            max_distance: 20.0,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniform {
+    light_view_proj: [[[f32; 4]; 4]; MAX_SHADOW_LAYERS],
+    // far end of each cascade in view space
+    cascade_splits: [f32; CASCADE_COUNT],
+    depth_bias: f32,
+    normal_bias: f32,
+    pcf_radius: u32,
+    texel_size: f32,
+}
+
+// Pick the texture array layers for every shadow casting light.
+// Returns the first layer of each light, -1 when the light has no shadow.
+pub fn assign_layers(lights: &[Light]) -> Vec<i32> {
+    let mut next = 0;
+    let mut has_sun = false;
+
+    lights.iter().map(|light| match light {
+        // only one directional light gets cascades
+        Light::Directional { cast_shadows: true, .. } if !has_sun && next + CASCADE_COUNT <= MAX_SHADOW_LAYERS => {
+            has_sun = true;
+            next += CASCADE_COUNT;
+            (next - CASCADE_COUNT) as i32
+        }
+        Light::Spot { cast_shadows: true, .. } if next < MAX_SHADOW_LAYERS => {
+            next += 1;
+            (next - 1) as i32
+        }
+        _ => -1,
+    }).collect()
+}
+
+pub struct ShadowMap {
+    pub settings: ShadowSettings,
+    // the whole array, sampled by the lit shaders
+    pub view: wgpu::TextureView,
+    // one view per layer to render into
+    layer_views: Vec<wgpu::TextureView>,
+    pub sampler: wgpu::Sampler,
+    uniform: ShadowUniform,
+    pub buffer: wgpu::Buffer,
+    pass_buffer: wgpu::Buffer,
+    pass_stride: wgpu::BufferAddress,
+    pass_bind_group: wgpu::BindGroup,
+    pipeline: wgpu::RenderPipeline,
+    active_layers: usize,
+}
+
+impl ShadowMap {
+    pub fn new(device: &wgpu::Device, vertex_layout: wgpu::VertexBufferLayout<'static>) -> Self {
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("shadow texture"),
+            size: wgpu::Extent3d {
+                width: SHADOW_MAP_SIZE,
+                height: SHADOW_MAP_SIZE,
+                depth_or_array_layers: MAX_SHADOW_LAYERS as u32,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: SHADOW_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: Some("shadow view"),
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+
+        let layer_views = (0..MAX_SHADOW_LAYERS as u32)
+            .map(|layer| texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("shadow layer view"),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            }))
+            .collect();
+
+        // Comparison sampler: the hardware compares the stored depth with ours
+        // and, with Linear filtering, already blends the 2x2 results.
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow uniform buffer"),
+            size: std::mem::size_of::<ShadowUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let pass_stride = wgpu::util::align_to(
+            SHADOW_PASS_MATRIX_SIZE,
+            device.limits().min_uniform_buffer_offset_alignment as wgpu::BufferAddress,
+        );
+        let pass_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("shadow pass buffer"),
+            size: pass_stride * MAX_SHADOW_LAYERS as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let pass_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: true,
+                        min_binding_size: wgpu::BufferSize::new(SHADOW_PASS_MATRIX_SIZE),
+                    },
+                    count: None,
+                }
+            ],
+            label: Some("shadow_pass_bind_group_layout"),
+        });
+
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pass_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                        buffer: &pass_buffer,
+                        offset: 0,
+                        size: wgpu::BufferSize::new(SHADOW_PASS_MATRIX_SIZE),
+                    }),
+                }
+            ],
+            label: Some("shadow_pass_bind_group"),
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow pipeline layout"),
+            bind_group_layouts: &[&pass_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("shadow shader"),
+            source: wgpu::ShaderSource::Wgsl(concat!(include_str!("scene.wgsl"), include_str!("shadow.wgsl")).into()),
+        });
+
+        // depth only, no fragment shader and no color targets
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("shadow pipeline"),
+            layout: Some(&pipeline_layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                buffers: &[vertex_layout, InstanceRaw::desc()],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: None,
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                strip_index_format: None,
+                front_face: wgpu::FrontFace::Ccw,
+                // single sided geometry (the pentagon) must cast from both sides
+                cull_mode: None,
+                polygon_mode: wgpu::PolygonMode::Fill,
+                unclipped_depth: false,
+                conservative: false,
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: SHADOW_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                // slope scaled bias in hardware, the constant part is in ShadowSettings
+                bias: wgpu::DepthBiasState {
+                    constant: 2,
+                    slope_scale: 2.0,
+                    clamp: 0.0,
+                },
+            }),
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        Self {
+            settings: ShadowSettings::default(),
+            view,
+            layer_views,
+            sampler,
+            uniform: bytemuck::Zeroable::zeroed(),
+            buffer,
+            pass_buffer,
+            pass_stride,
+            pass_bind_group,
+            pipeline,
+            active_layers: 0,
+        }
+    }
+
+    // Recompute every light matrix for this frame.
+    // Returns the first shadow layer of every light (see assign_layers).
+    pub fn update(&mut self, queue: &wgpu::Queue, camera: &Camera, lights: &[Light]) -> Vec<i32> {
+        let layers = assign_layers(lights);
+        let splits = cascade_splits(camera, &self.settings);
+
+        self.active_layers = 0;
+        for (light, &layer) in lights.iter().zip(&layers) {
+            if layer < 0 {
+                continue;
+            }
+            let layer = layer as usize;
+
+            match *light {
+                Light::Directional { direction, .. } => {
+                    let mut near = camera.znear;
+                    for (cascade, &far) in splits.iter().enumerate() {
+                        self.uniform.light_view_proj[layer + cascade] = cascade_matrix(camera, direction, near, far).into();
+                        near = far;
+                    }
+                    self.active_layers = self.active_layers.max(layer + CASCADE_COUNT);
+                }
+                Light::Spot { position, direction, range, outer_angle, .. } => {
+                    self.uniform.light_view_proj[layer] = spot_matrix(position, direction, range, outer_angle).into();
+                    self.active_layers = self.active_layers.max(layer + 1);
+                }
+                Light::Point { .. } => {}
+            }
+        }
+
+        self.uniform.cascade_splits = splits;
+        self.uniform.depth_bias = self.settings.depth_bias;
+        self.uniform.normal_bias = self.settings.normal_bias;
+        self.uniform.pcf_radius = self.settings.pcf_radius;
+        self.uniform.texel_size = 1.0 / SHADOW_MAP_SIZE as f32;
+
+        queue.write_buffer(&self.buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+        for layer in 0..self.active_layers {
+            queue.write_buffer(
+                &self.pass_buffer,
+                layer as wgpu::BufferAddress * self.pass_stride,
+                bytemuck::cast_slice(&self.uniform.light_view_proj[layer]),
+            );
+        }
+
+        layers
+    }
+
+    // One depth pass per used layer, has to run before the main pass samples the shadows.
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, draws: &[Draw]) {
+        for layer in 0..self.active_layers {
+            let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("shadow pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.layer_views[layer],
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: wgpu::StoreOp::Store,
+                    }),
+                    stencil_ops: None,
+                }),
+                timestamp_writes: None,
+                occlusion_query_set: None,
+            });
+
+            shadow_pass.set_pipeline(&self.pipeline);
+            shadow_pass.set_bind_group(0, &self.pass_bind_group, &[(layer as wgpu::BufferAddress * self.pass_stride) as u32]);
+
+            for draw in draws {
+                shadow_pass.set_vertex_buffer(1, draw.instance);
+                draw.mesh.draw(&mut shadow_pass);
+            }
+        }
+    }
+}
+
+// "Practical split scheme": blend between uniform and logarithmic split distances.
+fn cascade_splits(camera: &Camera, settings: &ShadowSettings) -> [f32; CASCADE_COUNT] {
+    let near = camera.znear;
+    let far = camera.zfar.min(settings.max_distance);
+    let lambda = settings.cascade_split_lambda;
+
+    let mut splits = [0.0; CASCADE_COUNT];
+    for (i, split) in splits.iter_mut().enumerate() {
+        let p = (i + 1) as f32 / CASCADE_COUNT as f32;
+        let log = near * (far / near).powf(p);
+        let uniform = near + (far - near) * p;
+        *split = lambda * log + (1.0 - lambda) * uniform;
+    }
+    splits
+}
+
+// Orthographic light matrix that covers the part of the view frustum between `near` and `far`.
+fn cascade_matrix(camera: &Camera, direction: Vector3<f32>, near: f32, far: f32) -> Matrix4<f32> {
+    let forward = (camera.target - camera.eye).normalize();
+    let right = forward.cross(camera.up).normalize();
+    let up = right.cross(forward);
+    let tan_half_fovy = (Rad::from(cgmath::Deg(camera.fovy)).0 / 2.0).tan();
+
+    // the 8 corners of this slice of the frustum
+    let mut corners = Vec::with_capacity(8);
+    for distance in [near, far] {
+        let half_height = distance * tan_half_fovy;
+        let half_width = half_height * camera.aspect;
+        let center = camera.eye + forward * distance;
+        for (sx, sy) in [(-1.0, -1.0), (1.0, -1.0), (1.0, 1.0), (-1.0, 1.0)] {
+            corners.push(center + right * half_width * sx + up * half_height * sy);
+        }
+    }
+
+    // A bounding sphere instead of a tight box keeps the projection size constant
+    // while the camera rotates, so the shadow edges don't swim.
+    let center = Point3::from_vec(corners.iter().map(|c| c.to_vec()).sum::<Vector3<f32>>() / 8.0);
+    let radius = corners.iter().map(|c| (c - center).magnitude()).fold(0.0, f32::max);
+    let radius = (radius * 16.0).ceil() / 16.0;
+
+    let direction = direction.normalize();
+    let light_up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+
+    // snap the center to whole shadow texels, otherwise edges shimmer while moving
+    let rotation = Matrix4::look_to_rh(Point3::origin(), direction, light_up);
+    let texel = 2.0 * radius / SHADOW_MAP_SIZE as f32;
+    let mut snapped = rotation.transform_point(center);
+    snapped.x = (snapped.x / texel).floor() * texel;
+    snapped.y = (snapped.y / texel).floor() * texel;
+    let center = rotation.invert().unwrap().transform_point(snapped);
+
+    let eye = center - direction * (radius + CASTER_MARGIN);
+    let view = Matrix4::look_to_rh(eye, direction, light_up);
+    let proj = OPENGL_TO_WGPU_MATRIX * cgmath::ortho(-radius, radius, -radius, radius, 0.0, 2.0 * radius + CASTER_MARGIN);
+
+    proj * view
+}
+
+fn spot_matrix(position: Point3<f32>, direction: Vector3<f32>, range: f32, outer_angle: Rad<f32>) -> Matrix4<f32> {
+    let direction = direction.normalize();
+    let up = if direction.y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() };
+
+    // scene files are validated, but lights can also be changed from code
+    let fov = Rad((outer_angle.0 * 2.0).clamp(SPOT_MIN_FOV, SPOT_MAX_FOV));
+    let far = range.max(SPOT_NEAR * 2.0);
+
+    let view = Matrix4::look_to_rh(position, direction, up);
+    let proj = OPENGL_TO_WGPU_MATRIX * cgmath::perspective(fov, 1.0, SPOT_NEAR, far);
+
+    proj * view
+}
@@ -0,0 +1,14 @@
+// Depth only pass, renders the scene from a light into one layer of the shadow texture.
+// scene.wgsl is glued in front of this file for the instance transform.
+
+struct ShadowPass {
+    light_view_proj: mat4x4<f32>,
+}
+// dynamic offset picks the layer's matrix
+@group(0) @binding(0)
+var<uniform> shadow_pass: ShadowPass;
+
+@vertex
+fn vs_main(@location(0) position: vec3<f32>, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+    return shadow_pass.light_view_proj * instance_model(instance) * vec4<f32>(position, 1.0);
+}
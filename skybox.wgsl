@@ -0,0 +1,36 @@
+// Skybox: one fullscreen triangle on the far plane, every pixel looks up the environment cube
+// along its view ray. light.wgsl is glued in front of this file for the camera.
+
+@group(0) @binding(0)
+var t_environment: texture_cube<f32>;
+@group(0) @binding(1)
+var s_environment: sampler;
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+}
+
+@vertex
+fn vs_main(@builtin(vertex_index) vi: u32) -> VertexOutput {
+    var out: VertexOutput;
+    let x = f32(i32(vi) / 2) * 4.0 - 1.0;
+    let y = f32(i32(vi) % 2) * -4.0 + 3.0;
+    // z = w, so the depth is exactly 1.0 and only the pixels no geometry covered pass the depth test
+    out.clip_position = vec4<f32>(x, y, 1.0, 1.0);
+    out.ndc = vec2<f32>(x, y);
+    return out;
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    // screen position -> view space ray
+    let view_ray = camera.inv_proj * vec4<f32>(in.ndc, 1.0, 1.0);
+    // view -> world space with the rotation only, the sky does not move with the camera
+    // the transpose of a rotation is its inverse
+    let rotation = mat3x3<f32>(camera.view[0].xyz, camera.view[1].xyz, camera.view[2].xyz);
+    let dir = transpose(rotation) * (view_ray.xyz / view_ray.w);
+
+    let color = textureSampleLevel(t_environment, s_environment, normalize(dir), 0.0).rgb;
+    return vec4<f32>(color, 1.0);
+}
@@ -0,0 +1,361 @@
+use wgpu::util::DeviceExt;
+
+use crate::texture::Texture;
+
+// 2D SPRITES
+// Every frame: begin() -> draw() any number of sprites -> prepare() -> render() inside a pass.
+// prepare() sorts the sprites by layer and texture, builds 4 vertices per sprite on the cpu
+// and streams them into one vertex buffer, so the draw calls are one per run of sprites
+// sharing a texture, not one per sprite.
+
+// sprites start with room for this many, the buffers double when it is not enough
+const INITIAL_CAPACITY: usize = 1024;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SpriteVertex {
+    position: [f32; 2],
+    tex_coords: [f32; 2],
+    color: [f32; 4],
+}
+
+impl SpriteVertex {
+    const ATTRIBS: [wgpu::VertexAttribute; 3] = wgpu::vertex_attr_array![0 => Float32x2, 1 => Float32x2, 2 => Float32x4];
+
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<SpriteVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &Self::ATTRIBS,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SpriteUniform {
+    // pixels -> clip space
+    proj: [[f32; 4]; 4],
+    // 1 when the target is not srgb and the shader has to encode
+    encode_srgb: u32,
+    _padding: [u32; 3],
+}
+
+// Handle returned by SpriteBatch::add_texture
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SpriteTexture(usize);
+
+// Position and size are in pixels, (0, 0) is the top left corner of the screen.
+#[derive(Copy, Clone, Debug)]
+pub struct Sprite {
+    pub texture: SpriteTexture,
+    // where the origin ends up
+    pub position: [f32; 2],
+    // in radians, clockwise on screen
+    pub rotation: f32,
+    // size in pixels, a negative value flips the sprite
+    pub scale: [f32; 2],
+    // rotation and scale pivot, 0..1 inside the sprite, [0.5, 0.5] = center
+    pub origin: [f32; 2],
+    // part of the texture to show: [u_min, v_min, u_max, v_max]
+    pub uv_rect: [f32; 4],
+    // multiplies the texture color (linear)
+    pub tint: [f32; 4],
+    // higher layers are drawn on top
+    pub layer: i32,
+}
+
+impl Sprite {
+    pub fn new(texture: SpriteTexture, position: [f32; 2], size: [f32; 2]) -> Self {
+        Self {
+            texture,
+            position,
+            rotation: 0.0,
+            scale: size,
+            origin: [0.5, 0.5],
+            uv_rect: [0.0, 0.0, 1.0, 1.0],
+            tint: [1.0; 4],
+            layer: 0,
+        }
+    }
+}
+
+// run of sorted sprites sharing one texture
+struct Batch {
+    texture: SpriteTexture,
+    indices: std::ops::Range<u32>,
+}
+
+pub struct SpriteBatch {
+    pipeline: wgpu::RenderPipeline,
+    texture_layout: wgpu::BindGroupLayout,
+    textures: Vec<wgpu::BindGroup>,
+    uniform_buffer: wgpu::Buffer,
+    uniform_bind_group: wgpu::BindGroup,
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    // in sprites
+    capacity: usize,
+    sprites: Vec<Sprite>,
+    vertices: Vec<SpriteVertex>,
+    batches: Vec<Batch>,
+    surface_is_srgb: bool,
+}
+
+impl SpriteBatch {
+    // `format` is the format of the target the sprites are drawn on
+    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat) -> Self {
+        let texture_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+            ],
+            label: Some("sprite_texture_bind_group_layout"),
+        });
+
+        let uniform_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: false,
+                    min_binding_size: None,
+                },
+                count: None,
+            }],
+            label: Some("sprite_uniform_bind_group_layout"),
+        });
+
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("sprite uniform buffer"),
+            contents: bytemuck::cast_slice(&[<SpriteUniform as bytemuck::Zeroable>::zeroed()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let uniform_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &uniform_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            }],
+            label: Some("sprite_uniform_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("sprite shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("sprite.wgsl").into()),
+        });
+
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("sprite pipeline layout"),
+            bind_group_layouts: &[&texture_layout, &uniform_layout],
+            push_constant_ranges: &[],
+        });
+
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("sprite pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: Some("vs_main"),
+                buffers: &[SpriteVertex::desc()],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: Some("fs_main"),
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+                compilation_options: wgpu::PipelineCompilationOptions::default(),
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::TriangleList,
+                // flipped sprites change the winding
+                cull_mode: None,
+                ..Default::default()
+            },
+            // sorted by layer instead of a depth buffer, needed for alpha blending anyway
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+            cache: None,
+        });
+
+        let (vertex_buffer, index_buffer) = create_buffers(device, INITIAL_CAPACITY);
+
+        Self {
+            pipeline,
+            texture_layout,
+            textures: Vec::new(),
+            uniform_buffer,
+            uniform_bind_group,
+            vertex_buffer,
+            index_buffer,
+            capacity: INITIAL_CAPACITY,
+            sprites: Vec::new(),
+            vertices: Vec::new(),
+            batches: Vec::new(),
+            surface_is_srgb: format.is_srgb(),
+        }
+    }
+
+    pub fn add_texture(&mut self, device: &wgpu::Device, texture: &Texture) -> SpriteTexture {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.texture_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("sprite_texture_bind_group"),
+        });
+        self.textures.push(bind_group);
+        SpriteTexture(self.textures.len() - 1)
+    }
+
+    // forget the sprites of the last frame
+    pub fn begin(&mut self) {
+        self.sprites.clear();
+    }
+
+    pub fn draw(&mut self, sprite: Sprite) {
+        self.sprites.push(sprite);
+    }
+
+    pub fn sprite_count(&self) -> usize {
+        self.sprites.len()
+    }
+
+    // number of draw calls render() will issue
+    pub fn batch_count(&self) -> usize {
+        self.batches.len()
+    }
+
+    // Sort, build the vertices and upload them. `width` and `height` are the target size in pixels.
+    pub fn prepare(&mut self, device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32) {
+        // back to front, then by texture so equal textures in a layer end up next to each other
+        // (stable, so sprites with the same key keep their draw order)
+        self.sprites.sort_by_key(|sprite| (sprite.layer, sprite.texture));
+
+        if self.sprites.len() > self.capacity {
+            self.capacity = self.sprites.len().next_power_of_two();
+            (self.vertex_buffer, self.index_buffer) = create_buffers(device, self.capacity);
+        }
+
+        self.vertices.clear();
+        self.batches.clear();
+        for (i, sprite) in self.sprites.iter().enumerate() {
+            self.vertices.extend_from_slice(&sprite_vertices(sprite));
+
+            let indices = i as u32 * 6..(i as u32 + 1) * 6;
+            match self.batches.last_mut() {
+                Some(batch) if batch.texture == sprite.texture => batch.indices.end = indices.end,
+                _ => self.batches.push(Batch { texture: sprite.texture, indices }),
+            }
+        }
+
+        if !self.vertices.is_empty() {
+            queue.write_buffer(&self.vertex_buffer, 0, bytemuck::cast_slice(&self.vertices));
+        }
+
+        // pixels, y down -> clip space, y up
+        let (w, h) = (width.max(1) as f32, height.max(1) as f32);
+        #[rustfmt::skip]
+        let proj = [
+            [2.0 / w, 0.0, 0.0, 0.0],
+            [0.0, -2.0 / h, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [-1.0, 1.0, 0.0, 1.0],
+        ];
+        let uniform = SpriteUniform {
+            proj,
+            encode_srgb: (!self.surface_is_srgb) as u32,
+            _padding: [0; 3],
+        };
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[uniform]));
+    }
+
+    pub fn render(&self, render_pass: &mut wgpu::RenderPass) {
+        if self.batches.is_empty() {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(1, &self.uniform_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.slice(..));
+        render_pass.set_index_buffer(self.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+
+        for batch in &self.batches {
+            render_pass.set_bind_group(0, &self.textures[batch.texture.0], &[]);
+            render_pass.draw_indexed(batch.indices.clone(), 0, 0..1);
+        }
+    }
+}
+
+// corners of the quad in pixels: scale, rotate around the origin, move to the position
+fn sprite_vertices(sprite: &Sprite) -> [SpriteVertex; 4] {
+    let (sin, cos) = sprite.rotation.sin_cos();
+    let [u0, v0, u1, v1] = sprite.uv_rect;
+    let corners = [(0.0, 0.0, u0, v0), (1.0, 0.0, u1, v0), (1.0, 1.0, u1, v1), (0.0, 1.0, u0, v1)];
+
+    corners.map(|(x, y, u, v)| {
+        let local_x = (x - sprite.origin[0]) * sprite.scale[0];
+        let local_y = (y - sprite.origin[1]) * sprite.scale[1];
+        SpriteVertex {
+            // y points down, so this turns clockwise on screen
+            position: [
+                sprite.position[0] + local_x * cos - local_y * sin,
+                sprite.position[1] + local_x * sin + local_y * cos,
+            ],
+            tex_coords: [u, v],
+            color: sprite.tint,
+        }
+    })
+}
+
+// The index buffer never changes: quad i uses vertices 4i..4i+3.
+// u32 indices, u16 would stop at 16384 sprites.
+fn create_buffers(device: &wgpu::Device, capacity: usize) -> (wgpu::Buffer, wgpu::Buffer) {
+    let vertex_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("sprite vertex buffer"),
+        size: (capacity * 4 * std::mem::size_of::<SpriteVertex>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+
+    let indices: Vec<u32> = (0..capacity as u32)
+        .flat_map(|quad| {
+            let i = quad * 4;
+            [i, i + 1, i + 2, i, i + 2, i + 3]
+        })
+        .collect();
+    let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("sprite index buffer"),
+        contents: bytemuck::cast_slice(&indices),
+        usage: wgpu::BufferUsages::INDEX,
+    });
+
+    (vertex_buffer, index_buffer)
+}
@@ -0,0 +1,49 @@
+// 2D sprites, vertices are already in pixels (see sprite.rs).
+
+struct Sprites {
+    proj: mat4x4<f32>,
+    encode_srgb: u32,
+}
+@group(1) @binding(0)
+var<uniform> sprites: Sprites;
+
+@group(0) @binding(0)
+var t_sprite: texture_2d<f32>;
+@group(0) @binding(1)
+var s_sprite: sampler;
+
+struct VertexInput {
+    @location(0) position: vec2<f32>,
+    @location(1) tex_coords: vec2<f32>,
+    @location(2) color: vec4<f32>,
+}
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+    @location(0) tex_coords: vec2<f32>,
+    @location(1) color: vec4<f32>,
+}
+
+@vertex
+fn vs_main(model: VertexInput) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = sprites.proj * vec4<f32>(model.position, 0.0, 1.0);
+    out.tex_coords = model.tex_coords;
+    out.color = model.color;
+    return out;
+}
+
+fn linear_to_srgb(c: vec3<f32>) -> vec3<f32> {
+    let low = c * 12.92;
+    let high = 1.055 * pow(c, vec3<f32>(1.0 / 2.4)) - 0.055;
+    return select(high, low, c <= vec3<f32>(0.0031308));
+}
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+    var color = textureSample(t_sprite, s_sprite, in.tex_coords) * in.color;
+    if sprites.encode_srgb == 1u {
+        color = vec4<f32>(linear_to_srgb(color.rgb), color.a);
+    }
+    return color;
+}
@@ -0,0 +1,321 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::*;
+use image::imageops::FilterType;
+
+// shared with the engine, the reading half is unused here
+#[allow(dead_code)]
+#[path = "../../cooked.rs"]
+mod cooked;
+
+// for the archive writer, see pack
+#[allow(dead_code)]
+#[path = "../../vfs.rs"]
+mod vfs;
+
+use cooked::{Manifest, ManifestEntry, MeshFile};
+
+// ASSET COOKER
+// A separate binary next to the engine (cargo run --bin cook), run before shipping or after
+// changing source assets:
+//   cook [source dir] [cooked dir]        defaults: assets, cooked
+//   cook pack [cooked dir] [archive]      defaults: cooked, assets.pak
+// Every file under the source directory ends up in the cooked directory:
+//   png, jpg, tga, bmp  -> KTX2, BC7 srgb with the full mip chain (compressed.rs reads it back),
+//                          copied when the size is not a multiple of 4
+//   obj, gltf, glb      -> .mesh (cooked.rs), all meshes of the file merged into one
+//   wgsl                -> validated with naga, copied
+//   anything else       -> copied
+// The manifest keeps the content hash of every source and output. A source whose hash did not
+// change and whose output is still there untouched is skipped, outputs of deleted sources are removed.
+// A source that fails keeps its previous output and manifest entry.
+// A .gltf is hashed without its .bin buffers, use .glb when the buffers change on their own.
+// `pack` puts the cooked files into one archive for shipping (vfs.rs), see PACKING.
+
+const VK_FORMAT_BC7_SRGB_BLOCK: u32 = 146;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "pack") {
+        return pack(&args[1..]);
+    }
+    let source_dir = PathBuf::from(args.first().map_or("assets", String::as_str));
+    let cooked_dir = PathBuf::from(args.get(1).map_or(cooked::COOKED_DIR, String::as_str));
+    ensure!(source_dir.is_dir(), "{} is not a directory", source_dir.display());
+
+    let manifest_path = cooked_dir.join(cooked::MANIFEST_FILE);
+    let old = std::fs::read_to_string(&manifest_path)
+        .ok()
+        .and_then(|text| Manifest::parse(&text).ok())
+        .filter(|manifest| manifest.version == cooked::COOK_VERSION)
+        .unwrap_or_default();
+    let mut manifest = Manifest { version: cooked::COOK_VERSION, ..Default::default() };
+
+    let mut files = Vec::new();
+    collect_files(&source_dir, &cooked_dir, &mut files)?;
+    files.sort();
+
+    let (mut cooked, mut skipped, mut failed) = (0, 0, 0);
+    for path in &files {
+        // the name the engine loads it by, relative to the working directory
+        let source = normalize(&path.to_string_lossy());
+        let relative = normalize(&path.strip_prefix(&source_dir)?.to_string_lossy());
+
+        let result = (|| -> Result<bool> {
+            let bytes = std::fs::read(path)?;
+            let source_hash = cooked::hash(&bytes);
+            if let Some(entry) = old.entries.get(&source) {
+                let output = std::fs::read(cooked_dir.join(&entry.output)).ok();
+                if entry.source_hash == source_hash && output.is_some_and(|output| cooked::hash(&output) == entry.output_hash) {
+                    manifest.entries.insert(source.clone(), entry.clone());
+                    return Ok(false);
+                }
+            }
+
+            let (output, output_bytes) = cook(path, &relative, &bytes)?;
+            let output_path = cooked_dir.join(&output);
+            if let Some(dir) = output_path.parent() {
+                std::fs::create_dir_all(dir)?;
+            }
+            std::fs::write(&output_path, &output_bytes)?;
+            let output_hash = cooked::hash(&output_bytes);
+            manifest.entries.insert(source.clone(), ManifestEntry { output, source_hash, output_hash });
+            Ok(true)
+        })();
+
+        match result {
+            std::result::Result::Ok(true) => {
+                println!("cooked  {}", source);
+                cooked += 1;
+            }
+            std::result::Result::Ok(false) => skipped += 1,
+            Err(e) => {
+                eprintln!("failed  {}: {:#}", source, e);
+                failed += 1;
+                // the last good output stays until the source cooks again
+                if let Some(entry) = old.entries.get(&source) {
+                    manifest.entries.insert(source.clone(), entry.clone());
+                }
+            }
+        }
+    }
+
+    // outputs nothing produces anymore: their source is gone, or it cooks to another file now
+    for entry in old.entries.values() {
+        if !manifest.entries.values().any(|e| e.output == entry.output) {
+            let _ = std::fs::remove_file(cooked_dir.join(&entry.output));
+            println!("removed {}", entry.output);
+        }
+    }
+
+    std::fs::create_dir_all(&cooked_dir)?;
+    std::fs::write(&manifest_path, manifest.to_string()?)?;
+    println!("{} cooked, {} up to date, {} failed", cooked, skipped, failed);
+    ensure!(failed == 0, "{} assets failed to cook", failed);
+    Ok(())
+}
+
+fn collect_files(dir: &Path, skip: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("reading {}", dir.display()))? {
+        let path = entry?.path();
+        // hidden files, and the output when it sits inside the sources
+        if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')) || path == skip {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(&path, skip, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn normalize(path: &str) -> String {
+    let path = path.replace('\\', "/");
+    path.strip_prefix("./").unwrap_or(&path).to_string()
+}
+
+// (output path relative to the cooked directory, output bytes)
+fn cook(path: &Path, relative: &str, bytes: &[u8]) -> Result<(String, Vec<u8>)> {
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let with_extension = |extension: &str| normalize(&Path::new(relative).with_extension(extension).to_string_lossy());
+    Ok(match extension.as_str() {
+        "png" | "jpg" | "jpeg" | "tga" | "bmp" => {
+            let image = image::load_from_memory(bytes)?.to_rgba8();
+            let (width, height) = image.dimensions();
+            // wgpu wants whole blocks at level 0, padding would move the uvs
+            if width % 4 != 0 || height % 4 != 0 {
+                eprintln!("warning {}: {}x{} is not a multiple of 4, copied uncompressed", relative, width, height);
+                return Ok((relative.to_string(), bytes.to_vec()));
+            }
+            (with_extension("ktx2"), cook_texture(&image))
+        }
+        "obj" => (with_extension("mesh"), MeshFile::from_obj(bytes)?.encode()),
+        "gltf" | "glb" => (with_extension("mesh"), cook_gltf(path)?.encode()),
+        "wgsl" => {
+            validate_wgsl(bytes)?;
+            (relative.to_string(), bytes.to_vec())
+        }
+        _ => (relative.to_string(), bytes.to_vec()),
+    })
+}
+
+// PACKING
+// Every cooked file goes into the archive under its source path, the name scene files use,
+// so the engine needs neither the manifest nor the cooked directory to find it.
+
+fn pack(args: &[String]) -> Result<()> {
+    let cooked_dir = PathBuf::from(args.first().map_or(cooked::COOKED_DIR, String::as_str));
+    let pak_path = args.get(1).map_or(vfs::PAK_PATH, String::as_str);
+
+    let manifest_path = cooked_dir.join(cooked::MANIFEST_FILE);
+    let text = std::fs::read_to_string(&manifest_path).with_context(|| format!("reading {}, cook first", manifest_path.display()))?;
+    let manifest = Manifest::parse(&text)?;
+    ensure!(manifest.version == cooked::COOK_VERSION, "{} is from another cook version, cook again", cooked_dir.display());
+
+    // the old archive stays until the new one is complete
+    let temp_path = format!("{}.tmp", pak_path);
+    let file = std::fs::File::create(&temp_path).with_context(|| format!("creating {}", temp_path))?;
+    let mut writer = vfs::PakWriter::new(std::io::BufWriter::new(file))?;
+    let (mut deflated, mut stored) = (0, 0);
+    for (source, entry) in &manifest.entries {
+        let bytes = std::fs::read(cooked_dir.join(&entry.output)).with_context(|| format!("reading {}", entry.output))?;
+        ensure!(cooked::hash(&bytes) == entry.output_hash, "{} changed since it was cooked, cook again", entry.output);
+        match writer.add(source, &bytes, true)? {
+            vfs::Compression::Deflate => deflated += 1,
+            vfs::Compression::Stored => stored += 1,
+        }
+    }
+    writer.finish()?;
+    std::fs::rename(&temp_path, pak_path).with_context(|| format!("writing {}", pak_path))?;
+    println!("{} files packed into {}, {} deflated, {} stored", deflated + stored, pak_path, deflated, stored);
+    Ok(())
+}
+
+// TEXTURES
+
+// `image` is a multiple of 4 in both directions, the smaller levels are padded in compress_bc7
+fn cook_texture(image: &image::RgbaImage) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let level_count = 32 - width.max(height).leading_zeros();
+    let levels = (0..level_count)
+        .map(|level| {
+            let (w, h) = ((width >> level).max(1), (height >> level).max(1));
+            // every level from the full image, the triangle filter widens with the ratio
+            let mip = if level == 0 { image.clone() } else { image::imageops::resize(image, w, h, FilterType::Triangle) };
+            compress_bc7(&mip)
+        })
+        .collect::<Vec<_>>();
+
+    write_ktx2_bc7(width, height, &levels)
+}
+
+fn compress_bc7(image: &image::RgbaImage) -> Vec<u8> {
+    // the last levels are smaller than a block, the edge pixels fill it up
+    let (width, height) = image.dimensions();
+    let (padded_width, padded_height) = (width.div_ceil(4) * 4, height.div_ceil(4) * 4);
+    let padded = image::RgbaImage::from_fn(padded_width, padded_height, |x, y| *image.get_pixel(x.min(width - 1), y.min(height - 1)));
+    let surface = intel_tex_2::RgbaSurface {
+        width: padded_width,
+        height: padded_height,
+        stride: padded_width * 4,
+        data: &padded,
+    };
+    intel_tex_2::bc7::compress_blocks(&intel_tex_2::bc7::alpha_basic_settings(), &surface)
+}
+
+// Single 2d image, no supercompression. The data goes smallest level first as the spec asks,
+// each level aligned to the 16 byte block.
+fn write_ktx2_bc7(width: u32, height: u32, levels: &[Vec<u8>]) -> Vec<u8> {
+    const IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+
+    // basic data format descriptor: BC7 color model, BT.709 primaries, srgb transfer, one 128 bit sample
+    let mut dfd = Vec::new();
+    dfd.extend_from_slice(&44u32.to_le_bytes()); // total size
+    dfd.extend_from_slice(&0u32.to_le_bytes()); // vendor, descriptor type
+    dfd.extend_from_slice(&2u16.to_le_bytes()); // version
+    dfd.extend_from_slice(&40u16.to_le_bytes()); // block size
+    dfd.extend_from_slice(&[134, 1, 2, 0]); // model, primaries, transfer, flags
+    dfd.extend_from_slice(&[3, 3, 0, 0]); // texel block dimensions - 1
+    dfd.extend_from_slice(&[16, 0, 0, 0, 0, 0, 0, 0]); // bytes per plane
+    dfd.extend_from_slice(&0u16.to_le_bytes()); // bit offset
+    dfd.extend_from_slice(&[127, 0]); // bit length - 1, channel
+    dfd.extend_from_slice(&[0, 0, 0, 0]); // sample position
+    dfd.extend_from_slice(&0u32.to_le_bytes()); // lower
+    dfd.extend_from_slice(&u32::MAX.to_le_bytes()); // upper
+
+    let level_index_size = 24 * levels.len();
+    let dfd_offset = 80 + level_index_size;
+    let mut offset = (dfd_offset + dfd.len()).next_multiple_of(16);
+    let mut offsets = vec![0; levels.len()];
+    for (level, data) in levels.iter().enumerate().rev() {
+        offsets[level] = offset;
+        offset = (offset + data.len()).next_multiple_of(16);
+    }
+
+    let mut bytes = Vec::with_capacity(offset);
+    bytes.extend_from_slice(&IDENTIFIER);
+    for value in [VK_FORMAT_BC7_SRGB_BLOCK, 1, width, height, 0, 0, 1, levels.len() as u32, 0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    // dfd offset and length, no key/value data
+    for value in [dfd_offset as u32, dfd.len() as u32, 0, 0] {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    // no supercompression global data
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    bytes.extend_from_slice(&0u64.to_le_bytes());
+    for (level, data) in levels.iter().enumerate() {
+        for value in [offsets[level], data.len(), data.len()] {
+            bytes.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+    }
+    bytes.extend_from_slice(&dfd);
+    for (level, data) in levels.iter().enumerate().rev() {
+        bytes.resize(offsets[level], 0);
+        bytes.extend_from_slice(data);
+    }
+    bytes
+}
+
+// MESHES
+
+fn cook_gltf(path: &Path) -> Result<MeshFile> {
+    let (document, buffers, _) = gltf::import(path)?;
+    let mut mesh = MeshFile::default();
+    for primitive in document.meshes().flat_map(|m| m.primitives()) {
+        ensure!(primitive.mode() == gltf::mesh::Mode::Triangles, "only triangle primitives are supported");
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let positions: Vec<[f32; 3]> = reader.read_positions().context("primitive without positions")?.collect();
+        let normals: Option<Vec<[f32; 3]>> = reader.read_normals().map(|normals| normals.collect());
+        let tex_coords: Option<Vec<[f32; 2]>> = reader.read_tex_coords(0).map(|tex_coords| tex_coords.into_f32().collect());
+
+        let base = mesh.vertices.len();
+        for (i, &position) in positions.iter().enumerate() {
+            // gltf uvs already start at the top left
+            let tex_coord = tex_coords.as_ref().map_or([0.0, 0.0], |t| t[i]);
+            let normal = normals.as_ref().map_or([0.0, 1.0, 0.0], |n| n[i]);
+            mesh.push_vertex(position, tex_coord, normal);
+        }
+        match reader.read_indices() {
+            Some(indices) => mesh.push_indices(base, indices.into_u32().map(|i| i as usize))?,
+            None => mesh.push_indices(base, 0..positions.len())?,
+        }
+    }
+    ensure!(!mesh.vertices.is_empty(), "no meshes");
+    Ok(mesh)
+}
+
+// SHADERS
+
+fn validate_wgsl(bytes: &[u8]) -> Result<()> {
+    use wgpu::naga;
+
+    let source = std::str::from_utf8(bytes)?;
+    let module = naga::front::wgsl::parse_str(source).map_err(|e| anyhow!(e.emit_to_string(source)))?;
+    naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(&module)
+        .map_err(|e| anyhow!(e.emit_to_string(source)))?;
+    Ok(())
+}
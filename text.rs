@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+
+use anyhow::*;
+
+use crate::sprite::{Sprite, SpriteBatch, SpriteTexture};
+use crate::texture::Texture;
+
+// TEXT
+// TTF/OTF fonts through fontdue. Glyphs are rasterized the first time a (font, char, size)
+// is drawn and kept in one glyph atlas texture: white rgb, coverage in alpha. That makes every
+// glyph an ordinary sprite, so text goes through the SpriteBatch like everything else 2D,
+// tint = text color.
+
+const GLYPH_ATLAS_SIZE: u32 = 1024;
+// empty pixels around every glyph, so linear filtering never reaches the neighbour
+const GLYPH_PADDING: u32 = 1;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FontId(usize);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Align {
+    Left,
+    Center,
+    Right,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct TextStyle {
+    pub font: FontId,
+    // pixels per em
+    pub size: f32,
+    // linear rgba
+    pub color: [f32; 4],
+    pub align: Align,
+    // lines are wrapped at spaces when they get wider than this
+    pub max_width: Option<f32>,
+    // multiplies the line height of the font
+    pub line_spacing: f32,
+    pub layer: i32,
+}
+
+impl TextStyle {
+    pub fn new(font: FontId, size: f32) -> Self {
+        Self {
+            font,
+            size,
+            color: [1.0; 4],
+            align: Align::Left,
+            max_width: None,
+            line_spacing: 1.0,
+            layer: 100,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+struct GlyphKey {
+    font: FontId,
+    c: char,
+    size: u32,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Glyph {
+    // None for glyphs without pixels (space)
+    uv_rect: Option<[f32; 4]>,
+    width: f32,
+    height: f32,
+    // from the pen position on the baseline to the top left corner of the bitmap, y down
+    offset: [f32; 2],
+}
+
+// A glyph placed by layout(), relative to the top left corner of the text block.
+#[derive(Copy, Clone, Debug)]
+pub struct PositionedGlyph {
+    pub c: char,
+    // pen position on the baseline
+    pub x: f32,
+    pub baseline: f32,
+}
+
+pub struct TextLayout {
+    pub glyphs: Vec<PositionedGlyph>,
+    pub width: f32,
+    pub height: f32,
+}
+
+pub struct TextRenderer {
+    fonts: Vec<fontdue::Font>,
+    atlas: Texture,
+    sprite_texture: SpriteTexture,
+    glyphs: HashMap<GlyphKey, Glyph>,
+    // shelf packing of the glyph atlas
+    cursor: (u32, u32),
+    shelf_height: u32,
+}
+
+impl TextRenderer {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, sprites: &mut SpriteBatch) -> Self {
+        // transparent white, glyphs only write alpha
+        let pixels = vec![[255u8, 255, 255, 0]; (GLYPH_ATLAS_SIZE * GLYPH_ATLAS_SIZE) as usize];
+        let mut atlas = Texture::from_rgba8(
+            device,
+            queue,
+            bytemuck::cast_slice(&pixels),
+            GLYPH_ATLAS_SIZE,
+            GLYPH_ATLAS_SIZE,
+            Some("glyph atlas"),
+            false,
+        );
+        // from_rgba8 minifies with Nearest, glyphs drawn at fractional scales look better filtered
+        atlas.sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+        let sprite_texture = sprites.add_texture(device, &atlas);
+
+        Self {
+            fonts: Vec::new(),
+            atlas,
+            sprite_texture,
+            glyphs: HashMap::new(),
+            cursor: (0, 0),
+            shelf_height: 0,
+        }
+    }
+
+    pub fn add_font(&mut self, bytes: &[u8]) -> Result<FontId> {
+        let font = fontdue::Font::from_bytes(bytes, fontdue::FontSettings::default()).map_err(|e| anyhow!("font: {e}"))?;
+        self.fonts.push(font);
+        Ok(FontId(self.fonts.len() - 1))
+    }
+
+    // Position every glyph: kerning between neighbours, wrapping at spaces, '\n' for new lines,
+    // each line aligned inside the widest one (or max_width when set).
+    pub fn layout(&self, text: &str, style: &TextStyle) -> TextLayout {
+        let font = &self.fonts[style.font.0];
+        let size = style.size.round();
+        let (ascent, line_height) = match font.horizontal_line_metrics(size) {
+            Some(metrics) => (metrics.ascent, metrics.new_line_size * style.line_spacing),
+            None => (size, size * 1.2 * style.line_spacing),
+        };
+
+        // lines of (char, x), first without alignment
+        let mut lines: Vec<(Vec<(char, f32)>, f32)> = Vec::new();
+        for paragraph in text.split('\n') {
+            let mut line: Vec<(char, f32)> = Vec::new();
+            let mut pen = 0.0;
+            let mut previous: Option<char> = None;
+            // where the line can be broken: index in `line` after the last space, and the pen there
+            let mut last_break: Option<usize> = None;
+
+            for c in paragraph.chars() {
+                let kern = previous.and_then(|p| font.horizontal_kern(p, c, size)).unwrap_or(0.0);
+                let advance = font.metrics(c, size).advance_width;
+
+                if let (Some(max_width), false) = (style.max_width, c == ' ') {
+                    if pen + kern + advance > max_width {
+                        if let Some(index) = last_break {
+                            // move the last word to a new line
+                            let rest: Vec<char> = line.drain(index..).map(|(c, _)| c).collect();
+                            let width = trimmed_width(&line, font, size);
+                            lines.push((std::mem::take(&mut line), width));
+                            pen = 0.0;
+                            previous = None;
+                            for c in rest {
+                                let kern = previous.and_then(|p| font.horizontal_kern(p, c, size)).unwrap_or(0.0);
+                                pen += kern;
+                                line.push((c, pen));
+                                pen += font.metrics(c, size).advance_width;
+                                previous = Some(c);
+                            }
+                            last_break = None;
+                        }
+                    }
+                }
+
+                let kern = previous.and_then(|p| font.horizontal_kern(p, c, size)).unwrap_or(0.0);
+                pen += kern;
+                line.push((c, pen));
+                pen += advance;
+                previous = Some(c);
+                if c == ' ' {
+                    last_break = Some(line.len());
+                }
+            }
+
+            let width = trimmed_width(&line, font, size);
+            lines.push((line, width));
+        }
+
+        let block_width = style
+            .max_width
+            .unwrap_or_else(|| lines.iter().map(|(_, width)| *width).fold(0.0, f32::max));
+
+        let mut glyphs = Vec::new();
+        for (i, (line, width)) in lines.iter().enumerate() {
+            let shift = match style.align {
+                Align::Left => 0.0,
+                Align::Center => (block_width - width) / 2.0,
+                Align::Right => block_width - width,
+            };
+            let baseline = ascent + i as f32 * line_height;
+            glyphs.extend(line.iter().map(|&(c, x)| PositionedGlyph { c, x: x + shift, baseline }));
+        }
+
+        TextLayout {
+            glyphs,
+            width: block_width,
+            height: lines.len() as f32 * line_height,
+        }
+    }
+
+    // Lay out `text` with its top left corner at `position` (pixels) and add its glyphs to `sprites`.
+    pub fn draw(&mut self, queue: &wgpu::Queue, sprites: &mut SpriteBatch, text: &str, position: [f32; 2], style: &TextStyle) {
+        let layout = self.layout(text, style);
+        for placed in &layout.glyphs {
+            let Some(glyph) = self.glyph(queue, style.font, placed.c, style.size) else {
+                continue;
+            };
+            let Some(uv_rect) = glyph.uv_rect else {
+                continue;
+            };
+
+            // whole pixels keep the glyph bitmap 1:1 with the screen
+            let x = (position[0] + placed.x + glyph.offset[0]).round();
+            let y = (position[1] + placed.baseline + glyph.offset[1]).round();
+            let mut sprite = Sprite::new(self.sprite_texture, [x, y], [glyph.width, glyph.height]);
+            sprite.origin = [0.0, 0.0];
+            sprite.uv_rect = uv_rect;
+            sprite.tint = style.color;
+            sprite.layer = style.layer;
+            sprites.draw(sprite);
+        }
+    }
+
+    // cached glyph, rasterized into the atlas on first use
+    fn glyph(&mut self, queue: &wgpu::Queue, font: FontId, c: char, size: f32) -> Option<Glyph> {
+        let key = GlyphKey { font, c, size: size.round() as u32 };
+        if let Some(glyph) = self.glyphs.get(&key) {
+            return Some(*glyph);
+        }
+
+        let (metrics, coverage) = self.fonts[font.0].rasterize(c, key.size as f32);
+        let (width, height) = (metrics.width as u32, metrics.height as u32);
+        let uv_rect = if width == 0 || height == 0 {
+            None
+        } else {
+            let (x, y) = self.allocate(width, height)?;
+            let pixels: Vec<[u8; 4]> = coverage.iter().map(|&a| [255, 255, 255, a]).collect();
+            queue.write_texture(
+                wgpu::TexelCopyTextureInfo {
+                    texture: &self.atlas.texture,
+                    mip_level: 0,
+                    origin: wgpu::Origin3d { x, y, z: 0 },
+                    aspect: wgpu::TextureAspect::All,
+                },
+                bytemuck::cast_slice(&pixels),
+                wgpu::TexelCopyBufferLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * width),
+                    rows_per_image: Some(height),
+                },
+                wgpu::Extent3d { width, height, depth_or_array_layers: 1 },
+            );
+            let size = GLYPH_ATLAS_SIZE as f32;
+            Some([x as f32 / size, y as f32 / size, (x + width) as f32 / size, (y + height) as f32 / size])
+        };
+
+        let glyph = Glyph {
+            uv_rect,
+            width: width as f32,
+            height: height as f32,
+            // fontdue measures ymin upwards from the baseline to the bottom of the bitmap
+            offset: [metrics.xmin as f32, -(metrics.ymin as f32 + height as f32)],
+        };
+        self.glyphs.insert(key, glyph);
+        Some(glyph)
+    }
+
+    // shelf packing, None when the atlas is full
+    fn allocate(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        let (w, h) = (width + GLYPH_PADDING, height + GLYPH_PADDING);
+        if self.cursor.0 + w > GLYPH_ATLAS_SIZE {
+            self.cursor = (0, self.cursor.1 + self.shelf_height);
+            self.shelf_height = 0;
+        }
+        if self.cursor.1 + h > GLYPH_ATLAS_SIZE || w > GLYPH_ATLAS_SIZE {
+            log::warn!("glyph atlas is full, glyph skipped");
+            return None;
+        }
+        let position = self.cursor;
+        self.cursor.0 += w;
+        self.shelf_height = self.shelf_height.max(h);
+        Some(position)
+    }
+}
+
+// width of a laid out line without its trailing spaces
+fn trimmed_width(line: &[(char, f32)], font: &fontdue::Font, size: f32) -> f32 {
+    line.iter()
+        .rev()
+        .find(|(c, _)| *c != ' ')
+        .map(|&(c, x)| x + font.metrics(c, size).advance_width)
+        .unwrap_or(0.0)
+}
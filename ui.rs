@@ -0,0 +1,131 @@
+use std::sync::Arc;
+
+use winit::window::Window;
+
+// DEBUG UI
+// egui on top of everything else. Per frame:
+//   App::window_event -> on_window_event() for every event, consumed ones never reach the game keys
+//   State::update     -> begin() / build the windows with context() / end()
+//   State::render     -> prepare() before the render graph, paint() inside its "ui" pass
+pub struct DebugUi {
+    context: egui::Context,
+    winit_state: egui_winit::State,
+    renderer: egui_wgpu::Renderer,
+    paint_jobs: Vec<egui::ClippedPrimitive>,
+    textures_delta: egui::TexturesDelta,
+    screen: egui_wgpu::ScreenDescriptor,
+    pub visible: bool,
+}
+
+impl DebugUi {
+    // `format` is the format of the target the ui is drawn on (the surface)
+    pub fn new(device: &wgpu::Device, window: &Arc<Window>, format: wgpu::TextureFormat) -> Self {
+        let context = egui::Context::default();
+        let winit_state = egui_winit::State::new(
+            context.clone(),
+            egui::ViewportId::ROOT,
+            window,
+            Some(window.scale_factor() as f32),
+            None,
+            Some(device.limits().max_texture_dimension_2d as usize),
+        );
+        // no depth, no msaa, no dithering
+        let renderer = egui_wgpu::Renderer::new(
+            device,
+            format,
+            egui_wgpu::RendererOptions { msaa_samples: 1, depth_stencil_format: None, dithering: false, ..Default::default() },
+        );
+        let size = window.inner_size();
+
+        Self {
+            context,
+            winit_state,
+            renderer,
+            paint_jobs: Vec::new(),
+            textures_delta: egui::TexturesDelta::default(),
+            screen: egui_wgpu::ScreenDescriptor {
+                size_in_pixels: [size.width, size.height],
+                pixels_per_point: window.scale_factor() as f32,
+            },
+            visible: true,
+        }
+    }
+
+    // true when egui used the event (typing into a field, clicking a button...),
+    // the caller should not handle it again
+    pub fn on_window_event(&mut self, window: &Window, event: &winit::event::WindowEvent) -> bool {
+        if !self.visible {
+            return false;
+        }
+        self.winit_state.on_window_event(window, event).consumed
+    }
+
+    pub fn context(&self) -> &egui::Context {
+        &self.context
+    }
+
+    pub fn begin(&mut self, window: &Window) {
+        let input = self.winit_state.take_egui_input(window);
+        self.context.begin_pass(input);
+    }
+
+    pub fn end(&mut self, window: &Window) {
+        let output = self.context.end_pass();
+        self.winit_state.handle_platform_output(window, output.platform_output);
+
+        self.paint_jobs = if self.visible {
+            self.context.tessellate(output.shapes, output.pixels_per_point)
+        } else {
+            Vec::new()
+        };
+        // texture changes are applied even when hidden, egui assumes they were
+        self.textures_delta.append(output.textures_delta);
+
+        let size = window.inner_size();
+        self.screen = egui_wgpu::ScreenDescriptor {
+            size_in_pixels: [size.width, size.height],
+            pixels_per_point: output.pixels_per_point,
+        };
+    }
+
+    // Upload textures and vertices. The returned command buffers go to the queue before the encoder.
+    pub fn prepare(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Vec<wgpu::CommandBuffer> {
+        let delta = std::mem::take(&mut self.textures_delta);
+        for (id, image_delta) in &delta.set {
+            self.renderer.update_texture(device, queue, *id, image_delta);
+        }
+        for id in &delta.free {
+            self.renderer.free_texture(id);
+        }
+        self.renderer.update_buffers(device, queue, encoder, &self.paint_jobs, &self.screen)
+    }
+
+    pub fn has_output(&self) -> bool {
+        !self.paint_jobs.is_empty()
+    }
+
+    pub fn paint(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        let render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("ui pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                depth_slice: None,
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            timestamp_writes: None,
+            occlusion_query_set: None,
+        });
+        // egui_wgpu wants a 'static pass, the encoder stays borrowed until it is dropped anyway
+        self.renderer.render(&mut render_pass.forget_lifetime(), &self.paint_jobs, &self.screen);
+    }
+}
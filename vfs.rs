@@ -0,0 +1,534 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use anyhow::*;
+
+use crate::cooked;
+
+// VIRTUAL FILESYSTEM
+// Every asset read goes through read(path). Sources are mounted under a prefix:
+//   Directory - loose files on disk
+//   Embedded  - files compiled into the binary with embed!, instead of raw include_bytes!
+//   Pak       - one archive file with an index, see PAK FORMAT
+// "builtin:happy-tree.png" asks the source mounted at "builtin:" for "happy-tree.png".
+// The last mount wins, mount_defaults() puts the loose directory over the archive so single files
+// can be patched without repacking.
+// Aliases send a path to another one before the lookup, that is how a source file is read from
+// its cooked version (cooked.rs). A source edited after its cook is read loose again: at startup
+// when it is newer than the cooked file, while running when the asset watcher reports it (source_changed).
+// The same code runs on the web: there is no disk there, a Directory finds nothing and the archive
+// is fetched from the server next to the page (mount_fetched_pak) before the engine starts.
+
+pub const PAK_PATH: &str = "assets.pak";
+
+// where files come from, shared by the loader threads
+pub trait Source: Send + Sync {
+    // None when the source has no such file
+    fn read(&self, path: &str) -> Option<Result<Vec<u8>>>;
+    // whether read() would find the file, without reading it
+    fn contains(&self, path: &str) -> bool;
+}
+
+pub struct Vfs {
+    // searched from the back
+    mounts: Vec<(String, Box<dyn Source>)>,
+    aliases: BTreeMap<String, String>,
+}
+
+impl Vfs {
+    pub const fn new() -> Self {
+        Self { mounts: Vec::new(), aliases: BTreeMap::new() }
+    }
+
+    pub fn alias(&mut self, path: &str, target: &str) {
+        self.aliases.insert(path.to_string(), target.to_string());
+    }
+
+    pub fn unalias(&mut self, path: &str) {
+        self.aliases.remove(path);
+    }
+
+    pub fn mount(&mut self, prefix: &str, source: impl Source + 'static) {
+        self.mounts.push((prefix.to_string(), Box::new(source)));
+    }
+
+    pub fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let path = self.aliases.get(path).map_or(path, String::as_str);
+        for (prefix, source) in self.mounts.iter().rev() {
+            let Some(inner) = path.strip_prefix(prefix.as_str()) else { continue };
+            if let Some(result) = source.read(inner) {
+                return result.with_context(|| format!("reading {}", path));
+            }
+        }
+        bail!("{}: no such file", path)
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        let path = self.aliases.get(path).map_or(path, String::as_str);
+        self.mounts
+            .iter()
+            .any(|(prefix, source)| path.strip_prefix(prefix.as_str()).is_some_and(|inner| source.contains(inner)))
+    }
+}
+
+static VFS: RwLock<Vfs> = RwLock::new(Vfs::new());
+
+pub fn mount(prefix: &str, source: impl Source + 'static) {
+    VFS.write().unwrap().mount(prefix, source);
+}
+
+pub fn read(path: &str) -> Result<Vec<u8>> {
+    VFS.read().unwrap().read(path)
+}
+
+pub fn read_to_string(path: &str) -> Result<String> {
+    String::from_utf8(read(path)?).with_context(|| format!("{} is not utf-8", path))
+}
+
+// A watched file changed on disk, it is read from the mounts from now on instead of from what it
+// was aliased to (its cooked version is older now).
+pub fn source_changed(path: &str) {
+    let mut vfs = VFS.write().unwrap();
+    if vfs.aliases.contains_key(path) {
+        log::info!("{} changed, reading it instead of its cooked version", path);
+        vfs.unalias(path);
+    }
+}
+
+pub fn exists(path: &str) -> bool {
+    VFS.read().unwrap().contains(path)
+}
+
+// embed!("a.png", "b.ttf") -> an Embedded with both files, paths relative to this directory
+macro_rules! embed {
+    ($($path:literal),* $(,)?) => {
+        $crate::vfs::Embedded::new(&[$(($path, include_bytes!($path) as &[u8])),*])
+    };
+}
+
+// Once at startup: the engine's builtin files, the archive when there is one, the working directory.
+// On the web the archive comes later from mount_fetched_pak.
+pub fn mount_defaults() {
+    mount("builtin:", embed!("happy-tree.png"));
+    #[cfg(not(target_arch = "wasm32"))]
+    match Pak::open(PAK_PATH) {
+        std::result::Result::Ok(pak) => {
+            log::info!("mounted {} ({} files)", PAK_PATH, pak.len());
+            mount("", pak);
+        }
+        Err(e) if Path::new(PAK_PATH).exists() => log::error!("{:#}", e),
+        Err(_) => {}
+    }
+    mount("", Directory::new("."));
+    mount_cooked();
+}
+
+// The web version of the archive in mount_defaults: PAK_PATH relative to the page,
+// awaited before State::new so the first asset loads already find it. A 404 means no archive.
+#[cfg(target_arch = "wasm32")]
+pub async fn mount_fetched_pak() {
+    match fetch(PAK_PATH).await.and_then(|bytes| bytes.map(Pak::from_bytes).transpose()) {
+        std::result::Result::Ok(Some(pak)) => {
+            log::info!("mounted {} ({} files)", PAK_PATH, pak.len());
+            mount("", pak);
+        }
+        std::result::Result::Ok(None) => {}
+        Err(e) => log::error!("{}: {:#}", PAK_PATH, e),
+    }
+}
+
+// None when the server has no such file
+#[cfg(target_arch = "wasm32")]
+async fn fetch(url: &str) -> Result<Option<Vec<u8>>> {
+    use wasm_bindgen::JsCast;
+    use wasm_bindgen_futures::JsFuture;
+    use wgpu::web_sys::{self, js_sys};
+
+    let window = web_sys::window().context("no window")?;
+    let response: web_sys::Response = JsFuture::from(window.fetch_with_str(url))
+        .await
+        .map_err(|e| anyhow!("fetching {}: {:?}", url, e))?
+        .dyn_into()
+        .map_err(|_| anyhow!("fetching {}: not a response", url))?;
+    if response.status() == 404 {
+        return Ok(None);
+    }
+    ensure!(response.ok(), "fetching {}: http status {}", url, response.status());
+    let buffer = JsFuture::from(response.array_buffer().map_err(|e| anyhow!("{:?}", e))?)
+        .await
+        .map_err(|e| anyhow!("fetching {}: {:?}", url, e))?;
+    Ok(Some(js_sys::Uint8Array::new(&buffer).to_vec()))
+}
+
+// The cook output when there is one, every cooked source path pointing at its output.
+fn mount_cooked() {
+    let dir = Path::new(cooked::COOKED_DIR);
+    let Some(text) = std::fs::read_to_string(dir.join(cooked::MANIFEST_FILE)).ok() else { return };
+    let manifest = match cooked::Manifest::parse(&text) {
+        std::result::Result::Ok(manifest) if manifest.version == cooked::COOK_VERSION => manifest,
+        std::result::Result::Ok(_) => {
+            log::warn!("{} is from another cook version, cook again", cooked::COOKED_DIR);
+            return;
+        }
+        Err(e) => {
+            log::error!("{}: {:#}", cooked::MANIFEST_FILE, e);
+            return;
+        }
+    };
+
+    let mut vfs = VFS.write().unwrap();
+    // under its own prefix so the aliases cannot loop back into the sources
+    vfs.mount("cooked:", Directory::new(dir));
+    for (source, entry) in &manifest.entries {
+        if newer(Path::new(source), &dir.join(&entry.output)) {
+            log::info!("{} changed since it was cooked, reading it instead", source);
+            continue;
+        }
+        vfs.alias(source, &format!("cooked:{}", entry.output));
+    }
+    log::info!("mounted {} ({} cooked assets)", cooked::COOKED_DIR, manifest.entries.len());
+}
+
+// true when `a` was modified after `b`, or `b` is gone. False when `a` is not on disk (in the archive).
+fn newer(a: &Path, b: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    modified(a) > modified(b)
+}
+
+// SOURCES
+
+pub struct Directory {
+    root: PathBuf,
+}
+
+impl Directory {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+}
+
+impl Source for Directory {
+    fn read(&self, path: &str) -> Option<Result<Vec<u8>>> {
+        let path = self.root.join(path);
+        // no files on the web, is_file is false there
+        if !path.is_file() {
+            return None;
+        }
+        Some(std::fs::read(path).map_err(Error::from))
+    }
+
+    fn contains(&self, path: &str) -> bool {
+        self.root.join(path).is_file()
+    }
+}
+
+pub struct Embedded {
+    files: HashMap<&'static str, &'static [u8]>,
+}
+
+impl Embedded {
+    pub fn new(files: &[(&'static str, &'static [u8])]) -> Self {
+        Self { files: files.iter().copied().collect() }
+    }
+}
+
+impl Source for Embedded {
+    fn read(&self, path: &str) -> Option<Result<Vec<u8>>> {
+        self.files.get(path).map(|bytes| Ok(bytes.to_vec()))
+    }
+
+    fn contains(&self, path: &str) -> bool {
+        self.files.contains_key(path)
+    }
+}
+
+// PAK FORMAT
+// little endian
+//   "PAK1", u64 offset of the index
+//   file data, one block after the other
+//   index: u32 file count, then per file
+//     u16 path length, path (utf-8, '/' separated), u8 compression (0 = stored, 1 = deflate),
+//     u64 offset, u64 stored size, u64 size
+// Files only get compressed when that makes them smaller, textures that are already
+// compressed (png, ktx2) usually stay stored. `cook pack` writes one from the cooked assets.
+
+const PAK_MAGIC: &[u8; 4] = b"PAK1";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Stored = 0,
+    Deflate = 1,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct PakEntry {
+    compression: Compression,
+    offset: u64,
+    stored_size: u64,
+    size: u64,
+}
+
+trait ReadSeek: Read + Seek + Send {}
+impl<T: Read + Seek + Send> ReadSeek for T {}
+
+pub struct Pak {
+    entries: HashMap<String, PakEntry>,
+    // loader threads take turns
+    reader: Mutex<Box<dyn ReadSeek>>,
+}
+
+impl Pak {
+    // no files to open on the web, see mount_fetched_pak
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn open(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("opening {}", path))?;
+        Self::from_reader(Box::new(std::io::BufReader::new(file))).with_context(|| format!("reading {}", path))
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Result<Self> {
+        Self::from_reader(Box::new(std::io::Cursor::new(bytes)))
+    }
+
+    // Every size in the file is checked against the length of the archive before anything is
+    // allocated for it, a broken archive fails to open instead of asking for terabytes.
+    fn from_reader(mut reader: Box<dyn ReadSeek>) -> Result<Self> {
+        let len = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(0))?;
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        ensure!(&magic == PAK_MAGIC, "not a pak archive");
+        let index = read_u64(&mut reader)?;
+        ensure!((12..=len).contains(&index), "index offset {} outside the archive", index);
+        reader.seek(SeekFrom::Start(index))?;
+
+        let count = read_u32(&mut reader)?;
+        // path length, compression, offset, stored size, size
+        const MIN_ENTRY_SIZE: u64 = 2 + 1 + 8 + 8 + 8;
+        ensure!(count as u64 * MIN_ENTRY_SIZE <= len - index, "index of {} files does not fit in the archive", count);
+        let mut entries = HashMap::with_capacity(count as usize);
+        for _ in 0..count {
+            let mut path = vec![0; read_u16(&mut reader)? as usize];
+            reader.read_exact(&mut path)?;
+            let mut compression = [0];
+            reader.read_exact(&mut compression)?;
+            let compression = match compression[0] {
+                0 => Compression::Stored,
+                1 => Compression::Deflate,
+                other => bail!("unknown compression {}", other),
+            };
+            let entry = PakEntry {
+                compression,
+                offset: read_u64(&mut reader)?,
+                stored_size: read_u64(&mut reader)?,
+                size: read_u64(&mut reader)?,
+            };
+            let path = String::from_utf8(path)?;
+            // the data lies between the header and the index
+            let end = entry.offset.checked_add(entry.stored_size);
+            ensure!(entry.offset >= 12 && end.is_some_and(|end| end <= index), "{}: data outside the archive", path);
+            ensure!(entry.compression == Compression::Deflate || entry.size == entry.stored_size, "{}: size mismatch", path);
+            entries.insert(path, entry);
+        }
+        Ok(Self { entries, reader: Mutex::new(reader) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn read_entry(&self, entry: PakEntry) -> Result<Vec<u8>> {
+        let mut stored = vec![0; entry.stored_size as usize];
+        {
+            let mut reader = self.reader.lock().unwrap();
+            reader.seek(SeekFrom::Start(entry.offset))?;
+            reader.read_exact(&mut stored)?;
+        }
+        match entry.compression {
+            Compression::Stored => Ok(stored),
+            Compression::Deflate => {
+                // `size` is only trusted as a limit, a stream inflating past it is broken
+                let mut bytes = Vec::new();
+                flate2::read::DeflateDecoder::new(stored.as_slice()).take(entry.size.saturating_add(1)).read_to_end(&mut bytes)?;
+                ensure!(bytes.len() as u64 == entry.size, "size mismatch after inflating");
+                Ok(bytes)
+            }
+        }
+    }
+}
+
+impl Source for Pak {
+    fn read(&self, path: &str) -> Option<Result<Vec<u8>>> {
+        let entry = *self.entries.get(path)?;
+        Some(self.read_entry(entry))
+    }
+
+    fn contains(&self, path: &str) -> bool {
+        self.entries.contains_key(path)
+    }
+}
+
+// Writes a pak: add() every file, then finish() writes the index.
+pub struct PakWriter<W: Write + Seek> {
+    out: W,
+    index: Vec<(String, PakEntry)>,
+    offset: u64,
+}
+
+impl<W: Write + Seek> PakWriter<W> {
+    pub fn new(mut out: W) -> Result<Self> {
+        out.write_all(PAK_MAGIC)?;
+        // index offset, filled in by finish
+        out.write_all(&0u64.to_le_bytes())?;
+        Ok(Self { out, index: Vec::new(), offset: 12 })
+    }
+
+    pub fn add(&mut self, path: &str, bytes: &[u8], compress: bool) -> Result<Compression> {
+        ensure!(path.len() <= u16::MAX as usize, "path too long: {}", path);
+        ensure!(!self.index.iter().any(|(p, _)| p == path), "{} added twice", path);
+
+        let deflated = if compress {
+            let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(bytes)?;
+            Some(encoder.finish()?).filter(|deflated| deflated.len() < bytes.len())
+        } else {
+            None
+        };
+        let (compression, stored) = match &deflated {
+            Some(deflated) => (Compression::Deflate, deflated.as_slice()),
+            None => (Compression::Stored, bytes),
+        };
+
+        self.out.write_all(stored)?;
+        self.index.push((
+            path.to_string(),
+            PakEntry {
+                compression,
+                offset: self.offset,
+                stored_size: stored.len() as u64,
+                size: bytes.len() as u64,
+            },
+        ));
+        self.offset += stored.len() as u64;
+        Ok(compression)
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.out.write_all(&(self.index.len() as u32).to_le_bytes())?;
+        for (path, entry) in &self.index {
+            self.out.write_all(&(path.len() as u16).to_le_bytes())?;
+            self.out.write_all(path.as_bytes())?;
+            self.out.write_all(&[entry.compression as u8])?;
+            self.out.write_all(&entry.offset.to_le_bytes())?;
+            self.out.write_all(&entry.stored_size.to_le_bytes())?;
+            self.out.write_all(&entry.size.to_le_bytes())?;
+        }
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&self.offset.to_le_bytes())?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn read_u16(reader: &mut impl Read) -> Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn archive() -> Vec<u8> {
+        let mut writer = PakWriter::new(std::io::Cursor::new(Vec::new())).unwrap();
+        writer.add("a.txt", b"hello", false).unwrap();
+        writer.add("b/zeros.bin", &[0; 4096], true).unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn pak_round_trip() {
+        let pak = Pak::from_bytes(archive()).unwrap();
+        assert_eq!(pak.len(), 2);
+        assert!(pak.contains("a.txt") && !pak.contains("missing.txt"));
+        assert_eq!(pak.read("a.txt").unwrap().unwrap(), b"hello");
+        assert_eq!(pak.read("b/zeros.bin").unwrap().unwrap(), vec![0; 4096]);
+        assert!(pak.read("c.txt").is_none());
+    }
+
+    #[test]
+    fn broken_sizes_are_rejected_before_allocating() {
+        let bytes = archive();
+        let index = u64::from_le_bytes(bytes[4..12].try_into().unwrap()) as usize;
+
+        // the index offset past the end
+        let mut broken = bytes.clone();
+        broken[4..12].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Pak::from_bytes(broken).is_err());
+
+        // four billion files
+        let mut broken = bytes.clone();
+        broken[index..index + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Pak::from_bytes(broken).is_err());
+
+        // the stored size of the first file: u16 path length, "a.txt", u8 compression, u64 offset
+        let stored_size = index + 4 + 2 + 5 + 1 + 8;
+        let mut broken = bytes;
+        broken[stored_size..stored_size + 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        assert!(Pak::from_bytes(broken).is_err());
+    }
+
+    #[test]
+    fn later_mounts_and_aliases_win() {
+        let mut vfs = Vfs::new();
+        vfs.mount("", Embedded::new(&[("a.txt", b"archive".as_slice()), ("b.txt", b"b".as_slice())]));
+        vfs.mount("", Embedded::new(&[("a.txt", b"patched".as_slice())]));
+        vfs.alias("c.txt", "b.txt");
+        assert_eq!(vfs.read("a.txt").unwrap(), b"patched");
+        assert_eq!(vfs.read("c.txt").unwrap(), b"b");
+        assert!(vfs.read("d.txt").is_err());
+        assert!(vfs.contains("a.txt") && vfs.contains("c.txt"));
+        assert!(!vfs.contains("d.txt"));
+    }
+
+    #[test]
+    fn edited_sources_win_over_their_cooked_version() {
+        let mut vfs = Vfs::new();
+        vfs.mount("", Embedded::new(&[("tree.png", b"edited".as_slice())]));
+        vfs.mount("cooked:", Embedded::new(&[("tree.ktx2", b"cooked".as_slice())]));
+        vfs.alias("tree.png", "cooked:tree.ktx2");
+        assert_eq!(vfs.read("tree.png").unwrap(), b"cooked");
+        vfs.unalias("tree.png");
+        assert_eq!(vfs.read("tree.png").unwrap(), b"edited");
+
+        let dir = std::env::temp_dir().join(format!("vfs_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (source, output) = (dir.join("tree.png"), dir.join("tree.ktx2"));
+        std::fs::write(&source, b"edited").unwrap();
+        std::fs::write(&output, b"cooked").unwrap();
+        let now = std::time::SystemTime::now();
+        let set_modified = |path: &Path, time| std::fs::File::options().write(true).open(path).unwrap().set_modified(time).unwrap();
+        set_modified(&output, now);
+        set_modified(&source, now - std::time::Duration::from_secs(60));
+        assert!(!newer(&source, &output));
+        set_modified(&source, now + std::time::Duration::from_secs(60));
+        assert!(newer(&source, &output));
+        assert!(!newer(&dir.join("only_in_the_archive.png"), &output));
+        assert!(newer(&source, &dir.join("missing.ktx2")));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
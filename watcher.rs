@@ -0,0 +1,95 @@
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+
+use anyhow::*;
+use notify::Watcher as _;
+use web_time::{Duration, Instant};
+
+use crate::assets::normalize;
+
+// HOT RELOAD
+// Watches the directories of the files handed to watch() (not recursive) and reports which of
+// them changed on disk. Editors save in bursts (truncate, write, rename a temp file ...), so a path
+// is only reported once it was quiet for SETTLE.
+// Paths go in and come out relative to the working directory with '/', the way assets are named.
+// Native only, AssetServer has no watcher on the web.
+
+const SETTLE: Duration = Duration::from_millis(150);
+
+pub struct FileWatcher {
+    watcher: notify::RecommendedWatcher,
+    events: Receiver<notify::Result<notify::Event>>,
+    // canonical working directory, event paths are made relative to it
+    root: PathBuf,
+    dirs: HashSet<PathBuf>,
+    files: HashSet<String>,
+    // changed file -> time of its last event
+    pending: HashMap<String, Instant>,
+}
+
+impl FileWatcher {
+    pub fn new() -> Result<Self> {
+        let (sender, events) = mpsc::channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })?;
+        Ok(Self {
+            watcher,
+            events,
+            root: std::env::current_dir()?.canonicalize()?,
+            dirs: HashSet::new(),
+            files: HashSet::new(),
+            pending: HashMap::new(),
+        })
+    }
+
+    pub fn watch(&mut self, path: &str) -> Result<()> {
+        let dir = match Path::new(path).parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let dir = dir.canonicalize().with_context(|| format!("watching {}", path))?;
+        if !self.dirs.contains(&dir) {
+            self.watcher.watch(&dir, notify::RecursiveMode::NonRecursive)?;
+            self.dirs.insert(dir);
+        }
+        self.files.insert(normalize(path));
+        Ok(())
+    }
+
+    // Watched files that changed and settled since the last call.
+    pub fn changed(&mut self) -> Vec<String> {
+        while let std::result::Result::Ok(event) = self.events.try_recv() {
+            let event = match event {
+                std::result::Result::Ok(event) => event,
+                Err(e) => {
+                    log::warn!("file watcher: {}", e);
+                    continue;
+                }
+            };
+            // a save shows up as modify, or as create when the editor renames a temp file over it
+            if !(event.kind.is_modify() || event.kind.is_create()) {
+                continue;
+            }
+            for path in &event.paths {
+                let Some(path) = self.relative(path) else { continue };
+                if self.files.contains(&path) {
+                    self.pending.insert(path, Instant::now());
+                }
+            }
+        }
+
+        let now = Instant::now();
+        let settled: Vec<String> = self.pending.iter().filter(|(_, &time)| now - time >= SETTLE).map(|(path, _)| path.clone()).collect();
+        for path in &settled {
+            self.pending.remove(path);
+        }
+        settled
+    }
+
+    fn relative(&self, path: &Path) -> Option<String> {
+        let path = path.strip_prefix(&self.root).ok()?;
+        Some(normalize(&path.to_string_lossy()))
+    }
+}